@@ -0,0 +1,196 @@
+// fsck.rs
+// Check a Minix 3 image for consistency
+// Stephen Marz
+// 19 October 2026
+
+use crate::{image::*,
+            minix::{Inode, NUM_IPTRS}};
+
+/// What we learn about the image by walking it from the root.
+struct Checker<'a> {
+	img:      &'a Image,
+	// How many directory entries name each inode, indexed by inode number.
+	refs:     Vec<u32>,
+	// Which inode owns each zone, indexed by zone number. 0 is nobody.
+	owner:    Vec<u32>,
+	problems: usize
+}
+
+impl<'a> Checker<'a> {
+	fn problem(&mut self, msg: String) {
+		println!("{}", msg);
+		self.problems += 1;
+	}
+
+	/// Claim zone for inode num, along with everything under it if it is an
+	/// indirect block with levels levels below it.
+	fn claim(&mut self, num: u32, zone: u32, levels: u32) {
+		if zone == 0 {
+			return;
+		}
+		if !self.img.valid_zone(zone) {
+			self.problem(format!("inode {}: zone {} is outside the data area", num, zone));
+			return;
+		}
+		let other = self.owner[zone as usize];
+		if other != 0 {
+			self.problem(format!("inode {}: zone {} is also used by inode {}", num, zone, other));
+			return;
+		}
+		self.owner[zone as usize] = num;
+		if levels > 0 {
+			for i in 0..NUM_IPTRS {
+				let next = self.img.pointer(zone, i);
+				self.claim(num, next, levels - 1);
+			}
+		}
+	}
+
+	fn claim_inode(&mut self, num: u32, inode: &Inode) {
+		for i in 0..7 {
+			self.claim(num, inode.zones[i], 0);
+		}
+		for level in 0..3 {
+			self.claim(num, inode.zones[7 + level], level as u32 + 1);
+		}
+	}
+
+	/// Walk the directory num, whose parent is parent. Every inode we find is
+	/// counted and claims its zones the first time we see it.
+	fn walk(&mut self, path: &str, num: u32, parent: u32) {
+		let inode = self.img.inode(num);
+		if !(inode.size as usize).is_multiple_of(DIRENT_SIZE) {
+			self.problem(format!("{}: directory size {} isn't a multiple of {}", path, inode.size, DIRENT_SIZE));
+		}
+		let entries = match self.img.dir_entries(&inode) {
+			Ok(entries) => entries,
+			Err(e) => {
+				self.problem(format!("{}: can't read directory: {}", path, e));
+				return;
+			}
+		};
+		let mut dot = false;
+		let mut dotdot = false;
+		for (ino, name) in entries {
+			let child_path = if path == "/" { format!("/{}", name) } else { format!("{}/{}", path, name) };
+			if !self.img.valid_inode(ino) {
+				self.problem(format!("{}: bad inode number {}", child_path, ino));
+				continue;
+			}
+			let child = self.img.inode(ino);
+			if child.mode == 0 {
+				self.problem(format!("{}: inode {} is free", child_path, ino));
+				continue;
+			}
+			self.refs[ino as usize] += 1;
+			if name == "." {
+				dot = true;
+				if ino != num {
+					self.problem(format!("{}: \".\" is inode {}, not {}", path, ino, num));
+				}
+				continue;
+			}
+			if name == ".." {
+				dotdot = true;
+				if ino != parent {
+					self.problem(format!("{}: \"..\" is inode {}, not {}", path, ino, parent));
+				}
+				continue;
+			}
+			if self.refs[ino as usize] > 1 && is_dir(&child) {
+				self.problem(format!("{}: directory inode {} is linked more than once", child_path, ino));
+				continue;
+			}
+			if self.refs[ino as usize] == 1 {
+				self.claim_inode(ino, &child);
+				if is_dir(&child) {
+					self.walk(&child_path, ino, num);
+				}
+			}
+		}
+		if !dot {
+			self.problem(format!("{}: no \".\" entry", path));
+		}
+		if !dotdot {
+			self.problem(format!("{}: no \"..\" entry", path));
+		}
+	}
+}
+
+/// Check img and print every problem. Returns how many there were.
+pub fn fsck(img: &Image) -> usize {
+	let sb = img.sb;
+	let mut c = Checker { img,
+	                      refs: vec![0; sb.ninodes as usize + 1],
+	                      owner: vec![0; sb.zones as usize],
+	                      problems: 0 };
+	let table_blocks = sb.ninodes.div_ceil(INODES_PER_BLOCK);
+	if img.inode_table() + table_blocks != sb.first_data_zone as u32 {
+		c.problem(format!("superblock: first data zone is {}, but the inode table ends at {}",
+		                  sb.first_data_zone,
+		                  img.inode_table() + table_blocks));
+	}
+	if sb.imap_blocks as u32 * BITS_PER_BLOCK <= sb.ninodes {
+		c.problem(format!("superblock: {} inode map blocks can't hold {} inodes", sb.imap_blocks, sb.ninodes));
+	}
+	if (sb.zmap_blocks as u32 * BITS_PER_BLOCK) < sb.zones.saturating_sub(sb.first_data_zone as u32) + 1 {
+		c.problem(format!("superblock: {} zone map blocks can't hold {} zones", sb.zmap_blocks, sb.zones));
+	}
+	if sb.zones > img.blocks() {
+		c.problem(format!("superblock: {} zones, but the image only has {} blocks", sb.zones, img.blocks()));
+	}
+	if c.problems > 0 || !img.valid_inode(ROOT_INODE) {
+		println!("giving up, the superblock is wrong");
+		return c.problems + 1;
+	}
+
+	let root = img.inode(ROOT_INODE);
+	if !is_dir(&root) {
+		c.problem(String::from("/: root inode isn't a directory"));
+		return c.problems;
+	}
+	c.claim_inode(ROOT_INODE, &root);
+	c.walk("/", ROOT_INODE, ROOT_INODE);
+
+	let mut inodes_used = 0;
+	for num in 1..=sb.ninodes {
+		let inode = img.inode(num);
+		let refs = c.refs[num as usize];
+		let marked = img.bit(IMAP_START, num);
+		if refs > 0 {
+			inodes_used += 1;
+			if inode.nlinks as u32 != refs {
+				c.problem(format!("inode {}: link count is {}, but {} entries name it", num, inode.nlinks, refs));
+			}
+			if !marked {
+				c.problem(format!("inode {}: in use, but free in the inode map", num));
+			}
+		}
+		else if marked {
+			c.problem(format!("inode {}: marked in the inode map, but no directory names it", num));
+		}
+	}
+
+	let zmap = img.zmap_start();
+	let mut zones_used = 0;
+	for zone in sb.first_data_zone as u32..sb.zones.min(img.blocks()) {
+		let owned = c.owner[zone as usize] != 0;
+		let marked = img.bit(zmap, img.zone_bit(zone));
+		if owned {
+			zones_used += 1;
+		}
+		if owned && !marked {
+			c.problem(format!("zone {}: used by inode {}, but free in the zone map", zone, c.owner[zone as usize]));
+		}
+		else if !owned && marked {
+			c.problem(format!("zone {}: marked in the zone map, but no inode uses it", zone));
+		}
+	}
+
+	println!("{} of {} inodes and {} of {} zones in use",
+	         inodes_used,
+	         sb.ninodes,
+	         zones_used,
+	         sb.zones - sb.first_data_zone as u32);
+	c.problems
+}
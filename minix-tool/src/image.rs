@@ -0,0 +1,239 @@
+// image.rs
+// A Minix 3 disk image held in memory
+// Stephen Marz
+// 19 October 2026
+
+use crate::minix::{DirEntry, Inode, SuperBlock, BLOCK_SIZE, MAGIC, NUM_IPTRS, S_IFDIR, S_IFMT};
+use std::{fs, io, mem::size_of, path::Path, ptr};
+
+pub const BS: usize = BLOCK_SIZE as usize;
+pub const INODE_SIZE: usize = size_of::<Inode>();
+pub const INODES_PER_BLOCK: u32 = (BS / INODE_SIZE) as u32;
+pub const DIRENT_SIZE: usize = size_of::<DirEntry>();
+pub const BITS_PER_BLOCK: u32 = BLOCK_SIZE * 8;
+pub const ROOT_INODE: u32 = 1;
+// Blocks 0 and 1 are the boot block and the superblock, and the inode
+// bitmap comes right after them.
+pub const IMAP_START: u32 = 2;
+
+pub fn error(msg: String) -> io::Error {
+	io::Error::other(msg)
+}
+
+pub fn is_dir(inode: &Inode) -> bool {
+	inode.mode & S_IFMT == S_IFDIR
+}
+
+/// The whole disk image. Our disks are a few megabytes, so it's simpler to
+/// read all of it and write all of it back than to seek around the file.
+pub struct Image {
+	pub data: Vec<u8>,
+	pub sb:   SuperBlock
+}
+
+impl Image {
+	/// A zeroed image of the given number of blocks. The caller fills in the
+	/// superblock.
+	pub fn new(blocks: u32, sb: SuperBlock) -> Image {
+		let mut img = Image { data: vec![0; blocks as usize * BS], sb };
+		img.put(BS, sb);
+		img
+	}
+
+	pub fn open(path: &Path) -> io::Result<Image> {
+		let data = fs::read(path)?;
+		if data.len() < 2 * BS {
+			return Err(error(format!("{}: too small to be a disk image", path.display())));
+		}
+		let sb: SuperBlock = unsafe { ptr::read_unaligned(data[BS..].as_ptr() as *const SuperBlock) };
+		if sb.magic != MAGIC {
+			return Err(error(format!("{}: not a Minix 3 file system (magic {:#x})", path.display(), sb.magic)));
+		}
+		if sb.block_size as u32 != BLOCK_SIZE {
+			return Err(error(format!("{}: block size {} isn't supported", path.display(), sb.block_size)));
+		}
+		Ok(Image { data, sb })
+	}
+
+	pub fn save(&self, path: &Path) -> io::Result<()> {
+		fs::write(path, &self.data)
+	}
+
+	fn get<T: Copy>(&self, offset: usize) -> T {
+		let bytes = &self.data[offset..offset + size_of::<T>()];
+		unsafe { ptr::read_unaligned(bytes.as_ptr() as *const T) }
+	}
+
+	pub fn put<T>(&mut self, offset: usize, val: T) {
+		let bytes = &mut self.data[offset..offset + size_of::<T>()];
+		unsafe {
+			ptr::write_unaligned(bytes.as_mut_ptr() as *mut T, val);
+		}
+	}
+
+	/// Number of blocks actually in the file, which may be fewer than the
+	/// superblock claims if the image is damaged.
+	pub fn blocks(&self) -> u32 {
+		(self.data.len() / BS) as u32
+	}
+
+	pub fn block(&self, n: u32) -> &[u8] {
+		&self.data[n as usize * BS..(n as usize + 1) * BS]
+	}
+
+	pub fn block_mut(&mut self, n: u32) -> &mut [u8] {
+		&mut self.data[n as usize * BS..(n as usize + 1) * BS]
+	}
+
+	/// Zone numbers are block numbers, since we only do log_zone_size 0. A
+	/// zone is usable if it is in the data area and in the file.
+	pub fn valid_zone(&self, zone: u32) -> bool {
+		zone >= self.sb.first_data_zone as u32 && zone < self.sb.zones && zone < self.blocks()
+	}
+
+	pub fn zmap_start(&self) -> u32 {
+		IMAP_START + self.sb.imap_blocks as u32
+	}
+
+	pub fn inode_table(&self) -> u32 {
+		self.zmap_start() + self.sb.zmap_blocks as u32
+	}
+
+	/// Inodes are numbered from 1, so inode 1 is the first one in the table.
+	fn inode_offset(&self, num: u32) -> usize {
+		self.inode_table() as usize * BS + (num as usize - 1) * INODE_SIZE
+	}
+
+	pub fn valid_inode(&self, num: u32) -> bool {
+		num >= 1 && num <= self.sb.ninodes && self.inode_offset(num) + INODE_SIZE <= self.data.len()
+	}
+
+	pub fn inode(&self, num: u32) -> Inode {
+		self.get(self.inode_offset(num))
+	}
+
+	pub fn put_inode(&mut self, num: u32, inode: &Inode) {
+		let offset = self.inode_offset(num);
+		self.put(offset, *inode);
+	}
+
+	/// Bit n of the bitmap that starts at block start.
+	pub fn bit(&self, start: u32, n: u32) -> bool {
+		let byte = start as usize * BS + n as usize / 8;
+		self.data[byte] & (1 << (n % 8)) != 0
+	}
+
+	pub fn set_bit(&mut self, start: u32, n: u32, on: bool) {
+		let byte = start as usize * BS + n as usize / 8;
+		if on {
+			self.data[byte] |= 1 << (n % 8);
+		}
+		else {
+			self.data[byte] &= !(1 << (n % 8));
+		}
+	}
+
+	/// Bit 0 of the zone map is reserved, so bit 1 is the first data zone.
+	pub fn zone_bit(&self, zone: u32) -> u32 {
+		zone - self.sb.first_data_zone as u32 + 1
+	}
+
+	/// Entry index of the indirect block zone.
+	pub fn pointer(&self, zone: u32, index: usize) -> u32 {
+		self.get(zone as usize * BS + index * 4)
+	}
+
+	pub fn set_pointer(&mut self, zone: u32, index: usize, val: u32) {
+		self.put(zone as usize * BS + index * 4, val);
+	}
+
+	/// The zone holding block index of a file, or 0 if that block is a hole.
+	/// An error means the inode points outside of the data area.
+	pub fn zone_of(&self, inode: &Inode, index: usize) -> io::Result<u32> {
+		if index < 7 {
+			return Ok(inode.zones[index]);
+		}
+		let mut index = index - 7;
+		let mut per = 1;
+		for level in 0..3 {
+			per *= NUM_IPTRS;
+			if index < per {
+				return self.walk_indirect(inode.zones[7 + level], index, per / NUM_IPTRS);
+			}
+			index -= per;
+		}
+		Err(error(format!("block {} is past the largest file", index)))
+	}
+
+	fn walk_indirect(&self, mut zone: u32, mut index: usize, mut per: usize) -> io::Result<u32> {
+		loop {
+			if zone == 0 {
+				return Ok(0);
+			}
+			if !self.valid_zone(zone) {
+				return Err(error(format!("indirect zone {} is out of range", zone)));
+			}
+			zone = self.pointer(zone, index / per);
+			if per == 1 {
+				return Ok(zone);
+			}
+			index %= per;
+			per /= NUM_IPTRS;
+		}
+	}
+
+	/// The contents of a file, directory or symbolic link. Holes read as
+	/// zeros.
+	pub fn read_file(&self, inode: &Inode) -> io::Result<Vec<u8>> {
+		let size = inode.size as usize;
+		let mut ret = Vec::with_capacity(size);
+		let mut index = 0;
+		while ret.len() < size {
+			let zone = self.zone_of(inode, index)?;
+			let count = (size - ret.len()).min(BS);
+			if zone == 0 {
+				ret.resize(ret.len() + count, 0);
+			}
+			else if self.valid_zone(zone) {
+				ret.extend_from_slice(&self.block(zone)[..count]);
+			}
+			else {
+				return Err(error(format!("zone {} is out of range", zone)));
+			}
+			index += 1;
+		}
+		Ok(ret)
+	}
+
+	/// The used entries of a directory as (inode, name).
+	pub fn dir_entries(&self, inode: &Inode) -> io::Result<Vec<(u32, String)>> {
+		let data = self.read_file(inode)?;
+		let mut ret = Vec::new();
+		for chunk in data.chunks_exact(DIRENT_SIZE) {
+			let entry: DirEntry = unsafe { ptr::read_unaligned(chunk.as_ptr() as *const DirEntry) };
+			if entry.inode != 0 {
+				ret.push((entry.inode, String::from_utf8_lossy(entry.name()).into_owned()));
+			}
+		}
+		Ok(ret)
+	}
+
+	/// Find path, starting at the root. Symbolic links aren't followed, so
+	/// this is only for looking at the image, not for what the kernel would
+	/// find.
+	pub fn lookup(&self, path: &str) -> io::Result<u32> {
+		let mut num = ROOT_INODE;
+		for name in path.split('/').filter(|s| !s.is_empty()) {
+			let inode = self.inode(num);
+			if !is_dir(&inode) {
+				return Err(error(format!("{}: not a directory", path)));
+			}
+			num = match self.dir_entries(&inode)?.into_iter().find(|(_, n)| n == name) {
+				Some((ino, _)) if self.valid_inode(ino) => ino,
+				Some((ino, _)) => return Err(error(format!("{}: bad inode {}", path, ino))),
+				None => return Err(error(format!("{}: no such file or directory", path))),
+			};
+		}
+		Ok(num)
+	}
+}
@@ -0,0 +1,312 @@
+// main.rs
+// Build, list, extract and check Minix 3 disk images for the kernel
+// Stephen Marz
+// 19 October 2026
+
+// The kernel's own definitions of what is on the disk, so that the two
+// can't disagree.
+#[allow(dead_code)]
+#[path = "../../risc_v/src/minix.rs"]
+mod minix;
+
+mod fsck;
+mod image;
+mod mkfs;
+
+use image::{error, is_dir, Image};
+use minix::{S_IFLNK, S_IFMT};
+use std::{env,
+          fs,
+          io,
+          os::unix::fs::{symlink, PermissionsExt},
+          path::{Path, PathBuf},
+          process};
+
+const USAGE: &str = "usage:
+  minix-tool mkfs <image> [<directory>] [--size <bytes>] [--inodes <count>] [--journal]
+  minix-tool ls <image> [<path>]
+  minix-tool extract <image> <directory> [<path>]
+  minix-tool fsck <image>
+
+Sizes may end in K, M or G. mkfs makes a 32M image by default.
+--journal makes /.journal, which the kernel journals changes to the disk in.";
+
+/// Parse a size such as 4096, 512K or 32M.
+fn parse_size(s: &str) -> Option<u64> {
+	let (digits, mult) = match s.chars().last()? {
+		'k' | 'K' => (&s[..s.len() - 1], 1 << 10),
+		'm' | 'M' => (&s[..s.len() - 1], 1 << 20),
+		'g' | 'G' => (&s[..s.len() - 1], 1 << 30),
+		_ => (s, 1),
+	};
+	digits.parse::<u64>().ok()?.checked_mul(mult)
+}
+
+fn mode_string(mode: u16) -> String {
+	let kind = match mode & S_IFMT {
+		minix::S_IFDIR => 'd',
+		minix::S_IFLNK => 'l',
+		minix::S_IFCHR => 'c',
+		minix::S_IFREG => '-',
+		_ => '?',
+	};
+	let mut ret = String::new();
+	ret.push(kind);
+	for shift in &[6, 3, 0] {
+		let bits = (mode >> shift) & 7;
+		ret.push(if bits & 4 != 0 { 'r' } else { '-' });
+		ret.push(if bits & 2 != 0 { 'w' } else { '-' });
+		ret.push(if bits & 1 != 0 { 'x' } else { '-' });
+	}
+	ret
+}
+
+/// Print one line per entry of the directory at path, or just the one
+/// line if path isn't a directory.
+fn ls(img: &Image, path: &str) -> io::Result<()> {
+	let num = img.lookup(path)?;
+	let inode = img.inode(num);
+	let entries = if is_dir(&inode) {
+		img.dir_entries(&inode)?
+	}
+	else {
+		vec![(num, path.to_string())]
+	};
+	for (ino, name) in entries {
+		if !img.valid_inode(ino) {
+			println!("?????????? {:>5} {}", ino, name);
+			continue;
+		}
+		let inode = img.inode(ino);
+		let target = if inode.mode & S_IFMT == S_IFLNK {
+			format!(" -> {}", String::from_utf8_lossy(&img.read_file(&inode)?))
+		}
+		else {
+			String::new()
+		};
+		println!("{} {:>3} {:>5} {:>5} {:>9} {:>5} {}{}",
+		         mode_string(inode.mode),
+		         inode.nlinks,
+		         inode.uid,
+		         inode.gid,
+		         inode.size,
+		         ino,
+		         name,
+		         target);
+	}
+	Ok(())
+}
+
+/// Copy inode num and everything under it to dest on the host.
+fn extract(img: &Image, num: u32, dest: &Path) -> io::Result<()> {
+	let inode = img.inode(num);
+	let perm = fs::Permissions::from_mode((inode.mode & 0o7777) as u32);
+	match inode.mode & S_IFMT {
+		minix::S_IFDIR => {
+			fs::create_dir_all(dest)?;
+			for (ino, name) in img.dir_entries(&inode)? {
+				if name == "." || name == ".." {
+					continue;
+				}
+				if !img.valid_inode(ino) {
+					return Err(error(format!("{}: bad inode {}", name, ino)));
+				}
+				extract(img, ino, &dest.join(name))?;
+			}
+			fs::set_permissions(dest, perm)
+		},
+		minix::S_IFREG => {
+			fs::write(dest, img.read_file(&inode)?)?;
+			fs::set_permissions(dest, perm)
+		},
+		minix::S_IFLNK => {
+			let target = String::from_utf8_lossy(&img.read_file(&inode)?).into_owned();
+			symlink(target, dest)
+		},
+		_ => {
+			eprintln!("warning: skipping {}, which isn't a file, directory or link", dest.display());
+			Ok(())
+		},
+	}
+}
+
+fn run(args: &[String]) -> io::Result<i32> {
+	let usage = || error(String::from(USAGE));
+	let cmd = args.first().ok_or_else(usage)?;
+	match cmd.as_str() {
+		"mkfs" => {
+			let mut opts = mkfs::Options { size: 32 << 20, inodes: None, journal: false };
+			let mut paths = Vec::new();
+			let mut i = 1;
+			while i < args.len() {
+				match args[i].as_str() {
+					"--size" => {
+						i += 1;
+						opts.size = args.get(i).and_then(|s| parse_size(s)).ok_or_else(usage)?;
+					},
+					"--inodes" => {
+						i += 1;
+						opts.inodes = Some(args.get(i).and_then(|s| s.parse().ok()).ok_or_else(usage)?);
+					},
+					"--journal" => opts.journal = true,
+					_ => paths.push(PathBuf::from(&args[i])),
+				}
+				i += 1;
+			}
+			if paths.is_empty() || paths.len() > 2 {
+				return Err(usage());
+			}
+			mkfs::mkfs(&paths[0], paths.get(1).map(|p| p.as_path()), &opts)?;
+			Ok(0)
+		},
+		"ls" if args.len() == 2 || args.len() == 3 => {
+			let img = Image::open(Path::new(&args[1]))?;
+			ls(&img, args.get(2).map(|s| s.as_str()).unwrap_or("/"))?;
+			Ok(0)
+		},
+		"extract" if args.len() == 3 || args.len() == 4 => {
+			let img = Image::open(Path::new(&args[1]))?;
+			let num = img.lookup(args.get(3).map(|s| s.as_str()).unwrap_or("/"))?;
+			extract(&img, num, Path::new(&args[2]))?;
+			Ok(0)
+		},
+		"fsck" if args.len() == 2 => {
+			let img = Image::open(Path::new(&args[1]))?;
+			let problems = fsck::fsck(&img);
+			if problems == 0 {
+				println!("{}: clean", args[1]);
+				Ok(0)
+			}
+			else {
+				println!("{}: {} problems", args[1], problems);
+				Ok(1)
+			}
+		},
+		_ => Err(usage()),
+	}
+}
+
+fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	let code = match run(&args) {
+		Ok(code) => code,
+		Err(e) => {
+			eprintln!("{}", e);
+			2
+		},
+	};
+	process::exit(code);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// A scratch directory under the host's temp directory that goes away
+	/// when the test is done.
+	struct Scratch(PathBuf);
+
+	impl Scratch {
+		fn new(name: &str) -> Scratch {
+			let dir = env::temp_dir().join(format!("minix-tool-{}-{}", name, process::id()));
+			let _ = fs::remove_dir_all(&dir);
+			fs::create_dir_all(&dir).unwrap();
+			Scratch(dir)
+		}
+	}
+
+	impl Drop for Scratch {
+		fn drop(&mut self) {
+			let _ = fs::remove_dir_all(&self.0);
+		}
+	}
+
+	/// Every file, directory and link under dir, by path relative to it.
+	fn tree(dir: &Path, rel: &Path, out: &mut Vec<(PathBuf, String)>) {
+		let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+		entries.sort_by_key(|e| e.file_name());
+		for entry in entries {
+			let path = entry.path();
+			let rel = rel.join(entry.file_name());
+			let meta = fs::symlink_metadata(&path).unwrap();
+			let mode = meta.permissions().mode() & 0o7777;
+			if meta.file_type().is_symlink() {
+				out.push((rel, format!("link {}", fs::read_link(&path).unwrap().display())));
+			}
+			else if meta.is_dir() {
+				out.push((rel.clone(), format!("dir {:o}", mode)));
+				tree(&path, &rel, out);
+			}
+			else {
+				out.push((rel, format!("file {:o} {:?}", mode, fs::read(&path).unwrap())));
+			}
+		}
+	}
+
+	#[test]
+	fn round_trip() {
+		let scratch = Scratch::new("round-trip");
+		let source = scratch.0.join("source");
+		fs::create_dir_all(source.join("etc/deep/er")).unwrap();
+		fs::write(source.join("hello.txt"), b"hello, world\n").unwrap();
+		fs::write(source.join("empty"), b"").unwrap();
+		// Big enough to need the indirect and double indirect zones.
+		let big: Vec<u8> = (0..2_500_000u32).map(|i| (i * 7 + i / 4096) as u8).collect();
+		fs::write(source.join("etc/big.bin"), &big).unwrap();
+		fs::write(source.join("etc/deep/er/script"), b"#!/bin/sh\n").unwrap();
+		fs::set_permissions(source.join("etc/deep/er/script"), fs::Permissions::from_mode(0o755)).unwrap();
+		fs::hard_link(source.join("hello.txt"), source.join("etc/hello-again.txt")).unwrap();
+		symlink("../hello.txt", source.join("etc/hello.lnk")).unwrap();
+
+		let image = scratch.0.join("hdd.dsk");
+		let opts = mkfs::Options { size: 8 << 20, inodes: None, journal: false };
+		mkfs::mkfs(&image, Some(&source), &opts).unwrap();
+
+		let img = Image::open(&image).unwrap();
+		assert_eq!(fsck::fsck(&img), 0);
+		let hello = img.inode(img.lookup("/hello.txt").unwrap());
+		assert_eq!(hello.nlinks, 2);
+		assert_eq!(img.lookup("/etc/hello-again.txt").unwrap(), img.lookup("/hello.txt").unwrap());
+
+		let out = scratch.0.join("out");
+		extract(&img, img.lookup("/").unwrap(), &out).unwrap();
+		let (mut want, mut got) = (Vec::new(), Vec::new());
+		tree(&source, Path::new(""), &mut want);
+		tree(&out, Path::new(""), &mut got);
+		assert_eq!(want, got);
+	}
+
+	#[test]
+	fn journal() {
+		let scratch = Scratch::new("journal");
+		let image = scratch.0.join("hdd.dsk");
+		let opts = mkfs::Options { size: 1 << 20, inodes: None, journal: true };
+		mkfs::mkfs(&image, None, &opts).unwrap();
+		let img = Image::open(&image).unwrap();
+		assert_eq!(fsck::fsck(&img), 0);
+		let journal = img.inode(img.lookup("/.journal").unwrap());
+		assert_eq!(journal.mode, minix::S_IFREG | 0o600);
+		assert_eq!(journal.size, minix::JOURNAL_BLOCKS * minix::BLOCK_SIZE);
+		// Every zone is there, and the header says there's nothing to replay.
+		for index in 0..minix::JOURNAL_BLOCKS as usize {
+			assert_ne!(img.zone_of(&journal, index).unwrap(), 0);
+		}
+		assert!(img.read_file(&journal).unwrap().iter().all(|b| *b == 0));
+	}
+
+	#[test]
+	fn image_too_small() {
+		let scratch = Scratch::new("too-small");
+		let opts = mkfs::Options { size: 4096, inodes: None, journal: false };
+		assert!(mkfs::mkfs(&scratch.0.join("hdd.dsk"), None, &opts).is_err());
+	}
+
+	#[test]
+	fn sizes() {
+		assert_eq!(parse_size("4096"), Some(4096));
+		assert_eq!(parse_size("512K"), Some(512 << 10));
+		assert_eq!(parse_size("32m"), Some(32 << 20));
+		assert_eq!(parse_size("1G"), Some(1 << 30));
+		assert_eq!(parse_size("lots"), None);
+	}
+}
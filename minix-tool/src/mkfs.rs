@@ -0,0 +1,286 @@
+// mkfs.rs
+// Build a Minix 3 image from a directory on the host
+// Stephen Marz
+// 19 October 2026
+
+use crate::{image::*,
+            minix::{DirEntry, Inode, SuperBlock, JOURNAL_BLOCKS, JOURNAL_NAME, MAGIC, NUM_IPTRS, S_IFDIR, S_IFLNK, S_IFREG}};
+use std::{collections::HashMap,
+          fs,
+          io,
+          os::unix::{ffi::OsStrExt, fs::MetadataExt},
+          path::Path};
+
+pub struct Options {
+	/// Size of the image in bytes. It is rounded down to whole blocks.
+	pub size:    u64,
+	/// Number of inodes, or None to pick one for every three blocks.
+	pub inodes:  Option<u32>,
+	/// Make /.journal, so that the kernel journals changes to the disk.
+	pub journal: bool
+}
+
+/// Inodes and zones are handed out in order, since nothing is ever freed
+/// while we build the image.
+struct Builder {
+	img:        Image,
+	next_inode: u32,
+	next_zone:  u32,
+	// Host (device, inode) to image inode, so that hard links on the host
+	// stay hard links in the image.
+	links:      HashMap<(u64, u64), u32>,
+	journal:    bool
+}
+
+/// Lay out an empty file system. Everything after the superblock is in this
+/// order: inode map, zone map, inode table, data zones. The bits past the end
+/// of either map are set so that nothing ever allocates them.
+fn format(opts: &Options) -> io::Result<Image> {
+	let blocks = (opts.size / BS as u64).min(u32::MAX as u64) as u32;
+	if blocks < 16 {
+		return Err(error(format!("{} bytes is too small for a file system", opts.size)));
+	}
+	let wanted = opts.inodes.unwrap_or(blocks / 3).max(INODES_PER_BLOCK);
+	let ninodes = wanted.div_ceil(INODES_PER_BLOCK) * INODES_PER_BLOCK;
+	let imap_blocks = (ninodes + 1).div_ceil(BITS_PER_BLOCK);
+	let inode_blocks = ninodes / INODES_PER_BLOCK;
+	// The zone map depends on how many zones are left over, which depends on
+	// how big the zone map is.
+	let mut zmap_blocks = 1;
+	let first_data_zone = loop {
+		let first = IMAP_START + imap_blocks + zmap_blocks + inode_blocks;
+		if first >= blocks {
+			return Err(error(format!("{} inodes don't fit in {} blocks", ninodes, blocks)));
+		}
+		let needed = (blocks - first + 1).div_ceil(BITS_PER_BLOCK);
+		if needed <= zmap_blocks {
+			break first;
+		}
+		zmap_blocks = needed;
+	};
+	if first_data_zone > u16::MAX as u32 || imap_blocks > u16::MAX as u32 {
+		return Err(error(format!("too many inodes ({})", ninodes)));
+	}
+	let sb = SuperBlock { ninodes,
+	                      pad0: 0,
+	                      imap_blocks: imap_blocks as u16,
+	                      zmap_blocks: zmap_blocks as u16,
+	                      first_data_zone: first_data_zone as u16,
+	                      log_zone_size: 0,
+	                      pad1: 0,
+	                      max_size: i32::MAX as u32,
+	                      zones: blocks,
+	                      magic: MAGIC,
+	                      pad2: 0,
+	                      block_size: BS as u16,
+	                      disk_version: 0 };
+	let mut img = Image::new(blocks, sb);
+	img.set_bit(IMAP_START, 0, true);
+	for n in ninodes + 1..imap_blocks * BITS_PER_BLOCK {
+		img.set_bit(IMAP_START, n, true);
+	}
+	let zmap = img.zmap_start();
+	img.set_bit(zmap, 0, true);
+	for n in img.zone_bit(blocks)..zmap_blocks * BITS_PER_BLOCK {
+		img.set_bit(zmap, n, true);
+	}
+	Ok(img)
+}
+
+impl Builder {
+	fn alloc_inode(&mut self) -> io::Result<u32> {
+		let num = self.next_inode;
+		if num > self.img.sb.ninodes {
+			return Err(error(format!("out of inodes ({}), try --inodes", self.img.sb.ninodes)));
+		}
+		self.next_inode += 1;
+		self.img.set_bit(IMAP_START, num, true);
+		Ok(num)
+	}
+
+	fn alloc_zone(&mut self) -> io::Result<u32> {
+		let zone = self.next_zone;
+		if zone >= self.img.sb.zones {
+			return Err(error(String::from("out of space, try a bigger --size")));
+		}
+		self.next_zone += 1;
+		let bit = self.img.zone_bit(zone);
+		let zmap = self.img.zmap_start();
+		self.img.set_bit(zmap, bit, true);
+		Ok(zone)
+	}
+
+	/// Point entry index of the indirect tree at *table to zone. The tree
+	/// is levels deep and its blocks are made as we go.
+	fn set_indirect(&mut self, table: &mut u32, index: usize, levels: u32, zone: u32) -> io::Result<()> {
+		if *table == 0 {
+			*table = self.alloc_zone()?;
+		}
+		if levels == 1 {
+			self.img.set_pointer(*table, index, zone);
+			return Ok(());
+		}
+		let per = NUM_IPTRS.pow(levels - 1);
+		let mut child = self.img.pointer(*table, index / per);
+		self.set_indirect(&mut child, index % per, levels - 1, zone)?;
+		self.img.set_pointer(*table, index / per, child);
+		Ok(())
+	}
+
+	fn set_zone(&mut self, inode: &mut Inode, index: usize, zone: u32) -> io::Result<()> {
+		if index < 7 {
+			inode.zones[index] = zone;
+			return Ok(());
+		}
+		let mut index = index - 7;
+		for levels in 1..=3 {
+			let per = NUM_IPTRS.pow(levels);
+			if index < per {
+				let mut table = inode.zones[6 + levels as usize];
+				self.set_indirect(&mut table, index, levels, zone)?;
+				inode.zones[6 + levels as usize] = table;
+				return Ok(());
+			}
+			index -= per;
+		}
+		Err(error(String::from("file is too big for Minix")))
+	}
+
+	/// Give inode a copy of data.
+	fn write_data(&mut self, inode: &mut Inode, data: &[u8]) -> io::Result<()> {
+		for (index, chunk) in data.chunks(BS).enumerate() {
+			let zone = self.alloc_zone()?;
+			self.img.block_mut(zone)[..chunk.len()].copy_from_slice(chunk);
+			self.set_zone(inode, index, zone)?;
+		}
+		inode.size = data.len() as u32;
+		Ok(())
+	}
+
+	fn new_inode(&mut self, mode: u16, meta: Option<&fs::Metadata>) -> Inode {
+		let time = meta.map(|m| m.mtime() as u32).unwrap_or(0);
+		Inode { mode,
+		        nlinks: 1,
+		        uid: 0,
+		        gid: 0,
+		        size: 0,
+		        atime: time,
+		        mtime: time,
+		        ctime: time,
+		        zones: [0; 10] }
+	}
+
+	/// Copy the directory at host into the image. Files, directories and
+	/// symbolic links are copied. Anything else (devices, sockets, pipes) is
+	/// skipped with a warning.
+	fn add_dir(&mut self, host: Option<&Path>, parent: Option<u32>) -> io::Result<u32> {
+		let num = self.alloc_inode()?;
+		let meta = match host {
+			Some(path) => Some(fs::metadata(path)?),
+			None => None,
+		};
+		let perm = meta.as_ref().map(|m| m.mode() as u16 & 0o7777).unwrap_or(0o755);
+		let mut inode = self.new_inode(S_IFDIR | perm, meta.as_ref());
+		inode.nlinks = 2;
+		let mut entries = vec![(num, b".".to_vec()), (parent.unwrap_or(num), b"..".to_vec())];
+		let mut children = match host {
+			Some(path) => fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?,
+			None => Vec::new(),
+		};
+		children.sort_by_key(|c| c.file_name());
+		if parent.is_none() && self.journal {
+			if children.iter().any(|c| c.file_name() == JOURNAL_NAME) {
+				return Err(error(format!("{} is in the way of the journal", JOURNAL_NAME)));
+			}
+			// The kernel only needs the journal's zones to be there, and a
+			// header of zeros means there's nothing to replay.
+			let mut journal = self.new_inode(S_IFREG | 0o600, None);
+			self.write_data(&mut journal, &vec![0; JOURNAL_BLOCKS as usize * BS])?;
+			let journal_num = self.alloc_inode()?;
+			self.img.put_inode(journal_num, &journal);
+			entries.push((journal_num, JOURNAL_NAME.as_bytes().to_vec()));
+		}
+		for child in children {
+			let name = child.file_name().as_bytes().to_vec();
+			let path = child.path();
+			if name.len() > 60 {
+				return Err(error(format!("{}: name is longer than 60 bytes", path.display())));
+			}
+			let meta = fs::symlink_metadata(&path)?;
+			let kind = meta.file_type();
+			let child_num = if kind.is_dir() {
+				inode.nlinks += 1;
+				self.add_dir(Some(&path), Some(num))?
+			}
+			else if kind.is_file() {
+				self.add_file(&path, &meta)?
+			}
+			else if kind.is_symlink() {
+				let target = fs::read_link(&path)?;
+				let mut link = self.new_inode(S_IFLNK | 0o777, Some(&meta));
+				self.write_data(&mut link, target.as_os_str().as_bytes())?;
+				let link_num = self.alloc_inode()?;
+				self.img.put_inode(link_num, &link);
+				link_num
+			}
+			else {
+				eprintln!("warning: skipping {}, which isn't a file, directory or link", path.display());
+				continue;
+			};
+			entries.push((child_num, name));
+		}
+		let mut data = Vec::with_capacity(entries.len() * DIRENT_SIZE);
+		for (ino, name) in entries {
+			let mut entry = DirEntry::new(ino, "");
+			entry.name[..name.len()].copy_from_slice(&name);
+			let bytes: [u8; DIRENT_SIZE] = unsafe { std::mem::transmute(entry) };
+			data.extend_from_slice(&bytes);
+		}
+		self.write_data(&mut inode, &data)?;
+		self.img.put_inode(num, &inode);
+		Ok(num)
+	}
+
+	fn add_file(&mut self, path: &Path, meta: &fs::Metadata) -> io::Result<u32> {
+		if meta.nlink() > 1 {
+			if let Some(&num) = self.links.get(&(meta.dev(), meta.ino())) {
+				let mut inode = self.img.inode(num);
+				inode.nlinks += 1;
+				self.img.put_inode(num, &inode);
+				return Ok(num);
+			}
+		}
+		if meta.len() > i32::MAX as u64 {
+			return Err(error(format!("{}: too big for Minix", path.display())));
+		}
+		let mut inode = self.new_inode(S_IFREG | (meta.mode() as u16 & 0o7777), Some(meta));
+		self.write_data(&mut inode, &fs::read(path)?)?;
+		let num = self.alloc_inode()?;
+		self.img.put_inode(num, &inode);
+		self.links.insert((meta.dev(), meta.ino()), num);
+		Ok(num)
+	}
+}
+
+/// Make a file system of opts.size bytes at image, holding a copy of source
+/// if there is one. Everything is owned by root, since the kernel doesn't
+/// know about the host's users.
+pub fn mkfs(image: &Path, source: Option<&Path>, opts: &Options) -> io::Result<()> {
+	let img = format(opts)?;
+	let next_zone = img.sb.first_data_zone as u32;
+	let mut builder = Builder { img,
+	                            next_inode: ROOT_INODE,
+	                            next_zone,
+	                            links: HashMap::new(),
+	                            journal: opts.journal };
+	builder.add_dir(source, None)?;
+	let img = builder.img;
+	println!("{}: {} blocks, {} inodes ({} used), {} data zones ({} used)",
+	         image.display(),
+	         img.sb.zones,
+	         img.sb.ninodes,
+	         builder.next_inode - 1,
+	         img.sb.zones - img.sb.first_data_zone as u32,
+	         builder.next_zone - img.sb.first_data_zone as u32);
+	img.save(image)
+}
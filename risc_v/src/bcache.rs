@@ -0,0 +1,260 @@
+// bcache.rs
+// Block buffer cache
+// Stephen Marz
+// 19 October 2026
+
+use crate::{block,
+            buffer::Buffer,
+            cpu::{memcpy, FREQ},
+            journal,
+            lock::Mutex,
+            syscall::{syscall_block_read, syscall_block_write, syscall_sleep}};
+use alloc::collections::BTreeMap;
+
+// Everything goes through the cache in blocks of this size, which is also
+// the Minix block size.
+pub const BCACHE_BLOCK_SIZE: u32 = 1024;
+// How many blocks we keep before we start evicting.
+pub const BCACHE_BLOCKS: usize = 256;
+// How often the flusher writes dirty blocks back.
+pub const BCACHE_FLUSH_INTERVAL: usize = 5 * FREQ as usize;
+
+/// A block of some disk along with whether it has been written to since
+/// it was read. A pinned block is part of a journal transaction that hasn't
+/// been committed yet, so it can't go to the device, even when it's dirty.
+struct CacheBlock {
+	data:      Buffer,
+	dirty:     bool,
+	pinned:    bool,
+	last_used: usize
+}
+
+/// Blocks are kept by the disk they're on and where they are on it, not by
+/// the partition they were asked for through, so that a disk and its
+/// partitions never have two copies of the same block.
+struct BlockCache {
+	blocks: BTreeMap<(usize, u32), CacheBlock>,
+	clock:  usize
+}
+
+// The cache stays locked while we wait on the block device, so that two
+// processes can't read the same block in at once. This is a sleep lock, so
+// the cache can only be used from a process.
+static mut BCACHE: Option<BlockCache> = None;
+static mut BCACHE_LOCK: Mutex = Mutex::new();
+
+pub fn init() {
+	unsafe {
+		BCACHE.replace(BlockCache { blocks: BTreeMap::new(),
+		                            clock:  0 });
+	}
+}
+
+/// Run f with the cache locked.
+fn with_cache<T>(f: impl FnOnce(&mut BlockCache) -> T) -> Option<T> {
+	unsafe {
+		BCACHE_LOCK.sleep_lock();
+		let ret = if let Some(mut cache) = BCACHE.take() {
+			let ret = f(&mut cache);
+			BCACHE.replace(cache);
+			Some(ret)
+		}
+		else {
+			None
+		};
+		BCACHE_LOCK.unlock();
+		ret
+	}
+}
+
+/// Turn size bytes at offset of dev into the disk they're on and where on
+/// it they are. None if dev isn't there or the range isn't all on it.
+fn locate(dev: usize, size: u32, offset: u64) -> Option<(usize, u64)> {
+	let (disk, start, len) = block::extent(dev)?;
+	if offset.saturating_add(size as u64) > len {
+		return None;
+	}
+	Some((disk, start + offset))
+}
+
+/// Is block of disk part of dev? A block that is only partly in a partition
+/// that doesn't start on a block boundary counts.
+fn covers(dev: usize, disk: usize, block: u32) -> bool {
+	match block::extent(dev) {
+		Some((d, start, len)) => {
+			let first = block as u64 * BCACHE_BLOCK_SIZE as u64;
+			d == disk && first + (BCACHE_BLOCK_SIZE as u64) > start && first < start.saturating_add(len)
+		},
+		None => false,
+	}
+}
+
+fn write_back(disk: usize, block: u32, cb: &mut CacheBlock) -> bool {
+	if cb.dirty && !cb.pinned {
+		if syscall_block_write(disk, cb.data.get_mut(), BCACHE_BLOCK_SIZE, block as u64 * BCACHE_BLOCK_SIZE as u64) != 0 {
+			return false;
+		}
+		cb.dirty = false;
+	}
+	true
+}
+
+/// Get block of disk into the cache if it isn't already, and return it. If
+/// the cache is full, the block that was used longest ago makes room,
+/// after it is written back if it is dirty. Pinned blocks stay, and if every
+/// block is pinned, the cache grows past BCACHE_BLOCKS until the journal
+/// commits. If fill is false, the caller is about to overwrite the whole
+/// block, so we don't bother reading it.
+fn get<'a>(cache: &'a mut BlockCache, disk: usize, block: u32, fill: bool) -> Option<&'a mut CacheBlock> {
+	cache.clock += 1;
+	let clock = cache.clock;
+	if !cache.blocks.contains_key(&(disk, block)) {
+		let victim = if cache.blocks.len() >= BCACHE_BLOCKS {
+			cache.blocks.iter().filter(|(_, cb)| !cb.pinned).min_by_key(|(_, cb)| cb.last_used).map(|(&key, _)| key)
+		}
+		else {
+			None
+		};
+		let data = if let Some(key) = victim {
+			let mut victim = cache.blocks.remove(&key).unwrap();
+			if !write_back(key.0, key.1, &mut victim) {
+				cache.blocks.insert(key, victim);
+				return None;
+			}
+			victim.data
+		}
+		else {
+			Buffer::new(BCACHE_BLOCK_SIZE as usize)
+		};
+		let mut cb = CacheBlock { data,
+		                          dirty: false,
+		                          pinned: false,
+		                          last_used: clock };
+		if cb.data.get().is_null() {
+			return None;
+		}
+		if fill && syscall_block_read(disk, cb.data.get_mut(), BCACHE_BLOCK_SIZE, block as u64 * BCACHE_BLOCK_SIZE as u64) != 0 {
+			return None;
+		}
+		cache.blocks.insert((disk, block), cb);
+	}
+	let cb = cache.blocks.get_mut(&(disk, block))?;
+	cb.last_used = clock;
+	Some(cb)
+}
+
+/// Read size bytes starting at byte offset of dev into buffer. The range
+/// doesn't have to line up with blocks. Returns false if the device failed.
+pub fn read(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> bool {
+	let (disk, offset) = match locate(dev, size, offset) {
+		Some(at) => at,
+		None => return false,
+	};
+	with_cache(|cache| {
+		let mut done = 0u32;
+		while done < size {
+			let pos = offset + done as u64;
+			let block = (pos / BCACHE_BLOCK_SIZE as u64) as u32;
+			let in_block = (pos % BCACHE_BLOCK_SIZE as u64) as u32;
+			let count = if BCACHE_BLOCK_SIZE - in_block < size - done { BCACHE_BLOCK_SIZE - in_block } else { size - done };
+			let cb = match get(cache, disk, block, true) {
+				Some(cb) => cb,
+				None => return false,
+			};
+			unsafe {
+				memcpy(buffer.add(done as usize), cb.data.get().add(in_block as usize), count as usize);
+			}
+			done += count;
+		}
+		true
+	}).unwrap_or(false)
+}
+
+/// Write size bytes from buffer to dev starting at byte offset. This only
+/// changes the cache. The device sees it when the block is evicted, when the
+/// flusher runs, or when someone calls sync.
+pub fn write(dev: usize, buffer: *const u8, size: u32, offset: u64) -> bool {
+	write_range(dev, buffer, size, offset, false)
+}
+
+/// Like write, but the blocks written stay pinned in the cache until
+/// unpin(). The journal uses this for the blocks of a transaction, which
+/// may only go to the device once the transaction is in the log.
+pub fn write_pinned(dev: usize, buffer: *const u8, size: u32, offset: u64) -> bool {
+	write_range(dev, buffer, size, offset, true)
+}
+
+fn write_range(dev: usize, buffer: *const u8, size: u32, offset: u64, pin: bool) -> bool {
+	let (disk, offset) = match locate(dev, size, offset) {
+		Some(at) => at,
+		None => return false,
+	};
+	with_cache(|cache| {
+		let mut done = 0u32;
+		while done < size {
+			let pos = offset + done as u64;
+			let block = (pos / BCACHE_BLOCK_SIZE as u64) as u32;
+			let in_block = (pos % BCACHE_BLOCK_SIZE as u64) as u32;
+			let count = if BCACHE_BLOCK_SIZE - in_block < size - done { BCACHE_BLOCK_SIZE - in_block } else { size - done };
+			let cb = match get(cache, disk, block, count != BCACHE_BLOCK_SIZE) {
+				Some(cb) => cb,
+				None => return false,
+			};
+			unsafe {
+				memcpy(cb.data.get_mut().add(in_block as usize), buffer.add(done as usize), count as usize);
+			}
+			cb.dirty = true;
+			cb.pinned |= pin;
+			done += count;
+		}
+		true
+	}).unwrap_or(false)
+}
+
+/// Write every dirty block of dev back to the device. If dev is None, do it
+/// for every device. Pinned blocks are left for the journal. Returns false
+/// if any write failed.
+pub fn sync(dev: Option<usize>) -> bool {
+	with_cache(|cache| {
+		let mut ok = true;
+		for (&(disk, block), cb) in cache.blocks.iter_mut() {
+			if dev.map_or(true, |dev| covers(dev, disk, block)) {
+				ok &= write_back(disk, block, cb);
+			}
+		}
+		ok
+	}).unwrap_or(false)
+}
+
+/// Let every pinned block of dev go to the device again. The next sync or
+/// eviction writes them back.
+pub fn unpin(dev: usize) {
+	with_cache(|cache| {
+		for (_, cb) in cache.blocks.iter_mut().filter(|((disk, block), _)| covers(dev, *disk, *block)) {
+			cb.pinned = false;
+		}
+	});
+}
+
+/// Throw away every block of dev without writing it back. Whatever was on
+/// the device has to be read again, which is what we want after the device
+/// goes away or changes underneath us.
+pub fn invalidate(dev: usize) {
+	with_cache(|cache| {
+		let keys: alloc::vec::Vec<(usize, u32)> = cache.blocks.keys().filter(|k| covers(dev, k.0, k.1)).cloned().collect();
+		for key in keys {
+			cache.blocks.remove(&key);
+		}
+	});
+}
+
+/// The flusher is a kernel process that writes dirty blocks back every
+/// BCACHE_FLUSH_INTERVAL, so that a crash loses at most that much. Journal
+/// transactions are committed first so their blocks can go too.
+pub fn flush_proc() {
+	loop {
+		syscall_sleep(BCACHE_FLUSH_INTERVAL);
+		journal::commit_all();
+		sync(None);
+	}
+}
@@ -3,8 +3,11 @@
 // Stephen Marz
 // 10 March 2020
 
-use crate::{kmem::{kfree, kmalloc},
+use crate::{bcache,
+            devfs::{self, Device, DeviceKind},
+            kmem::{kfree, kmalloc},
             page::{zalloc, PAGE_SIZE},
+            partition::{self, Partition, MAX_PARTITIONS, SECTOR_SIZE},
             process::{add_kernel_process_args,
                       get_by_pid,
                       set_running,
@@ -14,9 +17,10 @@ use crate::{kmem::{kfree, kmalloc},
                      MmioOffsets,
                      Queue,
                      StatusField,
-                     VIRTIO_RING_SIZE}};
+                     VIRTIO_RING_SIZE},
+            vfs::FsError};
 use core::mem::size_of;
-use alloc::boxed::Box;
+use alloc::{boxed::Box, format, sync::Arc};
 
 #[repr(C)]
 pub struct Geometry {
@@ -108,6 +112,8 @@ pub struct BlockDevice {
 	idx:          u16,
 	ack_used_idx: u16,
 	read_only:    bool,
+	/// The device has a write cache that a flush request empties.
+	flush:        bool,
 }
 
 // Type values
@@ -150,6 +156,72 @@ pub enum BlockErrors {
 static mut BLOCK_DEVICES: [Option<BlockDevice>; 8] =
 	[None, None, None, None, None, None, None, None];
 
+// The partitions of each disk, by partition number. A partition is a block
+// device of its own: partition p of disk d is device d * 16 + p, which
+// stays clear of the disks (1 through 8) and of the device numbers below.
+static mut PARTITIONS: [[Option<Partition>; MAX_PARTITIONS]; 8] = [[None; MAX_PARTITIONS]; 8];
+
+/// The device number of partition part of disk.
+pub fn partition_dev(disk: usize, part: usize) -> usize {
+	disk * 16 + part
+}
+
+// File systems that aren't on a disk still need a device number no disk or
+// partition has. The last partition there can be is partition_dev(8, 15),
+// which is 143, so these start well past that. /dev is DEVFS_DEV, and each
+// tmpfs gets its own, from TMPFS_FIRST_DEV up.
+pub const DEVFS_DEV: usize = 0xff;
+pub const TMPFS_FIRST_DEV: usize = 0x100;
+
+/// Where dev is: the disk it's on, and the byte it starts at on that disk
+/// and how many it has. A disk is all of itself. The block cache keeps
+/// blocks by disk with this, so that a disk and its partitions share them.
+pub fn extent(dev: usize) -> Option<(usize, u64, u64)> {
+	if (1..=8).contains(&dev) {
+		return Some((dev, 0, u64::MAX));
+	}
+	let (disk, part) = (dev / 16, dev % 16);
+	unsafe {
+		match PARTITIONS.get(disk.wrapping_sub(1)).and_then(|p| p.get(part.wrapping_sub(1))) {
+			Some(Some(p)) => Some((disk, p.start * SECTOR_SIZE, p.sectors * SECTOR_SIZE)),
+			_ => None,
+		}
+	}
+}
+
+/// Turn offset on dev, which may be a partition, into the disk it's on and
+/// the offset on that disk. The request has to fit in the partition.
+fn translate(dev: usize, offset: u64, size: u32) -> Result<(usize, u64), BlockErrors> {
+	let (disk, start, len) = extent(dev).ok_or(BlockErrors::BlockDeviceNotFound)?;
+	if offset.saturating_add(size as u64) > len {
+		return Err(BlockErrors::InvalidArgument);
+	}
+	Ok((disk, start + offset))
+}
+
+/// The device numbers of the partitions of disk, in order.
+pub fn partitions(disk: usize) -> alloc::vec::Vec<usize> {
+	unsafe {
+		match PARTITIONS.get(disk.wrapping_sub(1)) {
+			Some(parts) => parts.iter().flatten().map(|p| partition_dev(disk, p.number)).collect(),
+			None => alloc::vec::Vec::new(),
+		}
+	}
+}
+
+/// Every block device there is: each disk in the order we found it
+/// (/dev/vda first), followed by its partitions.
+pub fn devices() -> alloc::vec::Vec<usize> {
+	let mut devices = alloc::vec::Vec::new();
+	for disk in 1..=8 {
+		if unsafe { BLOCK_DEVICES[disk - 1].is_some() } {
+			devices.push(disk);
+			devices.extend(partitions(disk));
+		}
+	}
+	devices
+}
+
 pub fn setup_block_device(ptr: *mut u32) -> bool {
 	unsafe {
 		// We can get the index of the device based on its address.
@@ -178,6 +250,7 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 			   .read_volatile();
 		let guest_features = host_features & !(1 << VIRTIO_BLK_F_RO);
 		let ro = host_features & (1 << VIRTIO_BLK_F_RO) != 0;
+		let flush = host_features & (1 << VIRTIO_BLK_F_FLUSH) != 0;
 		ptr.add(MmioOffsets::GuestFeatures.scale32())
 		   .write_volatile(guest_features);
 		// 5. Set the FEATURES_OK status bit
@@ -251,14 +324,42 @@ pub fn setup_block_device(ptr: *mut u32) -> bool {
 		                       dev:          ptr,
 		                       idx:          0,
 		                       ack_used_idx: 0,
-		                       read_only:    ro, };
+		                       read_only:    ro,
+		                       flush, };
 		BLOCK_DEVICES[idx] = Some(bd);
+		// The capacity is in 512-byte sectors. The configuration space
+		// is read 32 bits at a time.
+		let config = ptr.add(MmioOffsets::Config.scale32());
+		let capacity = config.read_volatile() as u64
+		               | (config.add(1).read_volatile() as u64) << 32;
+		// The disks are /dev/vda, /dev/vdb and so on in the order we find
+		// them.
+		let letter = (b'a' + BLOCK_DEVICES[..idx].iter().filter(|b| b.is_some()).count() as u8) as char;
+		devfs::register(&format!("vd{}", letter),
+		                DeviceKind::Block,
+		                0o660,
+		                Arc::new(BlockNode { dev: idx + 1,
+		                                     size: capacity * 512,
+		                                     read_only: ro }));
 
 		// 8. Set the DRIVER_OK status bit. Device is now "live"
 		status_bits |= StatusField::DriverOk.val32();
 		ptr.add(MmioOffsets::Status.scale32())
 		   .write_volatile(status_bits);
 
+		// Now that it's live, see if the disk is split up. Each partition
+		// is /dev/vda1 and so on.
+		let mut read = |buffer: *mut u8, size: u32, offset: u64| read_polled(idx + 1, buffer, size, offset);
+		for p in partition::read_table(&mut read, capacity) {
+			PARTITIONS[idx][p.number - 1] = Some(p);
+			devfs::register(&format!("vd{}{}", letter, p.number),
+			                DeviceKind::Block,
+			                0o660,
+			                Arc::new(BlockNode { dev: partition_dev(idx + 1, p.number),
+			                                     size: p.sectors * SECTOR_SIZE,
+			                                     read_only: ro }));
+		}
+
 		true
 	}
 }
@@ -300,6 +401,8 @@ pub fn block_op(dev: usize,
                 -> Result<u32, BlockErrors>
 {
 	unsafe {
+		// A partition is just part of its disk.
+		let (dev, offset) = translate(dev, offset, size)?;
 		if let Some(bdev) = BLOCK_DEVICES[dev - 1].as_mut() {
 			// Check to see if we are trying to write to a read only
 			// device.
@@ -381,6 +484,88 @@ pub fn block_op(dev: usize,
 	}
 }
 
+/// Ask the device to put everything it has been told to write on the disk.
+/// Completed writes may still be sitting in the device's cache (QEMU's page
+/// cache, for one) until then. Like block_op(), watcher is woken up when
+/// it's done. Returns false if nothing was sent because the device has no
+/// cache to flush, in which case nobody will wake the watcher.
+pub fn block_flush(dev: usize, watcher: u16) -> Result<bool, BlockErrors> {
+	// Flushing a partition flushes its whole disk.
+	let (dev, _) = translate(dev, 0, 0)?;
+	unsafe {
+		if let Some(bdev) = BLOCK_DEVICES[dev - 1].as_mut() {
+			if !bdev.flush {
+				return Ok(false);
+			}
+			// A flush is a header and a status with no data in between.
+			let blk_request = kmalloc(size_of::<Request>()) as *mut Request;
+			let desc =
+				Descriptor { addr:  &(*blk_request).header
+				                    as *const Header
+				                    as u64,
+				             len:   size_of::<Header>() as u32,
+				             flags: virtio::VIRTIO_DESC_F_NEXT,
+				             next:  0, };
+			let head_idx = fill_next_descriptor(bdev, desc);
+			(*blk_request).header.blktype = VIRTIO_BLK_T_FLUSH;
+			(*blk_request).header.reserved = 0;
+			(*blk_request).header.sector = 0;
+			(*blk_request).data.data = core::ptr::null_mut();
+			(*blk_request).status.status = 111;
+			(*blk_request).watcher = watcher;
+			let desc =
+				Descriptor { addr:  &(*blk_request).status
+				                    as *const Status
+				                    as u64,
+				             len:   size_of::<Status>() as u32,
+				             flags: virtio::VIRTIO_DESC_F_WRITE,
+				             next:  0, };
+			let _status_idx = fill_next_descriptor(bdev, desc);
+			(*bdev.queue).avail.ring[(*bdev.queue).avail.idx
+			                         as usize
+			                         % virtio::VIRTIO_RING_SIZE] = head_idx;
+			(*bdev.queue).avail.idx =
+				(*bdev.queue).avail.idx.wrapping_add(1);
+			bdev.dev
+			    .add(MmioOffsets::QueueNotify.scale32())
+			    .write_volatile(0);
+			Ok(true)
+		}
+		else {
+			Err(BlockErrors::BlockDeviceNotFound)
+		}
+	}
+}
+
+/// Is dev a disk we can't write to? A disk that isn't there can't be
+/// written either.
+pub fn is_read_only(dev: usize) -> bool {
+	match translate(dev, 0, 0) {
+		Ok((dev, _)) => unsafe { BLOCK_DEVICES[dev - 1].as_ref().map(|b| b.read_only).unwrap_or(true) },
+		Err(_) => true,
+	}
+}
+
+/// Read from disk dev and spin until the device is done. This is only for
+/// probing, before there are processes to put to sleep and interrupts to
+/// wake them up.
+fn read_polled(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> bool {
+	if block_op(dev, buffer, size, offset, false, 0).is_err() {
+		return false;
+	}
+	unsafe {
+		let bdev = BLOCK_DEVICES[dev - 1].as_mut().unwrap();
+		let used_idx = &(*bdev.queue).used.idx as *const u16;
+		while used_idx.read_volatile() == bdev.ack_used_idx {}
+		let elem = &(*bdev.queue).used.ring[bdev.ack_used_idx as usize % VIRTIO_RING_SIZE];
+		let rq = (*bdev.queue).desc[elem.id as usize].addr as *const Request;
+		let ok = (&(*rq).status.status as *const u8).read_volatile() == VIRTIO_BLK_S_OK;
+		// This frees the request, just like the interrupt would have.
+		pending(bdev);
+		ok
+	}
+}
+
 pub fn read(dev: usize,
             buffer: *mut u8,
             size: u32,
@@ -422,8 +607,12 @@ pub fn pending(bd: &mut BlockDevice) {
 			// A PID of 0 means that we don't have a watcher.
 			if pid_of_watcher > 0 {
 				set_running(pid_of_watcher);
+				// The watcher could have been killed (say by the OOM
+				// killer) while we were busy.
 				let proc = get_by_pid(pid_of_watcher);
-				(*(*proc).frame).regs[10] = (*rq).status.status as usize;
+				if !proc.is_null() {
+					(*(*proc).frame).regs[10] = (*rq).status.status as usize;
+				}
 				// TODO: Set GpA0 to the value of the return
 				// status.
 			}
@@ -448,6 +637,56 @@ pub fn handle_interrupt(idx: usize) {
 	}
 }
 
+/// A disk or a partition in /dev. It goes through the block cache like the
+/// file systems do, so both see the same data, whichever of the disk and
+/// its partitions each of them uses.
+struct BlockNode {
+	dev:       usize,
+	/// In bytes.
+	size:      u64,
+	read_only: bool,
+}
+
+impl BlockNode {
+	/// How much of size bytes at offset is on the disk.
+	fn clamp(&self, size: u32, offset: u32) -> u32 {
+		let left = self.size.saturating_sub(offset as u64);
+		if (size as u64) < left { size } else { left as u32 }
+	}
+}
+
+impl Device for BlockNode {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let size = self.clamp(size, offset);
+		if size > 0 && !bcache::read(self.dev, buffer, size, offset as u64) {
+			return Err(FsError::Io);
+		}
+		Ok(size)
+	}
+
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		if self.read_only {
+			return Err(FsError::ReadOnly);
+		}
+		let count = self.clamp(size, offset);
+		if count == 0 && size > 0 {
+			return Err(FsError::NoSpace);
+		}
+		if !bcache::write(self.dev, buffer, count, offset as u64) {
+			return Err(FsError::Io);
+		}
+		Ok(count)
+	}
+
+	fn size(&self) -> u32 {
+		if self.size > u32::MAX as u64 { u32::MAX } else { self.size as u32 }
+	}
+
+	fn block_dev(&self) -> Option<usize> {
+		Some(self.dev)
+	}
+}
+
 // ///////////////////////////////////////////////
 // //  BLOCK PROCESSES (KERNEL PROCESSES)
 // ///////////////////////////////////////////////
@@ -459,19 +698,24 @@ struct ProcArgs {
 	pub offset: u64,
 }
 
-/// This will be a
+/// Raw block access from a process goes through the block cache, so it sees
+/// the same data the file systems do. The cache blocks, so this has to run
+/// as a kernel process. When it's done, it wakes up pid with the status in
+/// A0 like a request with a watcher would.
 fn read_proc(args_addr: usize) {
 	let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
-	let _ = block_op(
-	                 args.dev,
-	                 args.buffer,
-	                 args.size,
-	                 args.offset,
-	                 false,
-	                 args.pid,
-	);
-	// This should be handled by the RA now.
-	// syscall_exit();
+	let ok = bcache::read(args.dev, args.buffer, args.size, args.offset);
+	finish_proc(args.pid, ok);
+}
+
+fn finish_proc(pid: u16, ok: bool) {
+	unsafe {
+		let proc = get_by_pid(pid);
+		if !proc.is_null() {
+			(*(*proc).frame).regs[10] = if ok { 0 } else { -1isize as usize };
+		}
+	}
+	set_running(pid);
 }
 
 pub fn process_read(pid: u16,
@@ -499,16 +743,8 @@ pub fn process_read(pid: u16,
 
 fn write_proc(args_addr: usize) {
 	let args = unsafe { Box::from_raw(args_addr as *mut ProcArgs) };
-
-	let _ = block_op(
-	                 args.dev,
-	                 args.buffer,
-	                 args.size,
-	                 args.offset,
-	                 true,
-	                 args.pid,
-	);
-	// syscall_exit();
+	let ok = bcache::write(args.dev, args.buffer, args.size, args.offset);
+	finish_proc(args.pid, ok);
 }
 
 pub fn process_write(pid: u16,
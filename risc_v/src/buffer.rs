@@ -15,6 +15,9 @@ pub struct Buffer {
 }
 
 impl Buffer {
+	// The kmem-debug heap records where an allocation came from. Buffers
+	// are used everywhere, so pass our caller along instead of this line.
+	#[track_caller]
 	pub fn new(sz: usize) -> Self {
 		Self { 
 			buffer: kmalloc(sz), 
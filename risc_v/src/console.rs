@@ -4,8 +4,11 @@
 // 4 June 2020
 
 use alloc::collections::VecDeque;
+use crate::devfs::Device;
 use crate::lock::Mutex;
-use crate::process::{get_by_pid, set_running};
+use crate::process::{get_by_pid, set_running, set_waiting};
+use crate::syscall::{syscall_get_pid, syscall_yield};
+use crate::vfs::FsError;
 
 pub static mut IN_BUFFER: Option<VecDeque<u8>> = None;
 pub static mut OUT_BUFFER: Option<VecDeque<u8>> = None;
@@ -96,3 +99,46 @@ pub fn push_queue(pid: u16) {
         }
     }
 }
+
+/// /dev/console. Reading waits for a line like stdin does, and writing
+/// prints like stdout does.
+pub struct Console;
+
+impl Device for Console {
+    fn read(&self, buffer: *mut u8, size: u32, _offset: u32) -> Result<u32, FsError> {
+        let pid = syscall_get_pid();
+        loop {
+            let mut got = 0u32;
+            unsafe {
+                IN_LOCK.spin_lock();
+                if let Some(mut inb) = IN_BUFFER.take() {
+                    while got < size {
+                        match inb.pop_front() {
+                            Some(c) => buffer.add(got as usize).write(c),
+                            None => break,
+                        }
+                        got += 1;
+                    }
+                    if got == 0 && size > 0 {
+                        // push_stdin() wakes us up when a line comes in.
+                        push_queue(pid);
+                        set_waiting(pid);
+                    }
+                    IN_BUFFER.replace(inb);
+                }
+                IN_LOCK.unlock();
+            }
+            if got > 0 || size == 0 {
+                return Ok(got);
+            }
+            syscall_yield();
+        }
+    }
+
+    fn write(&self, buffer: *const u8, size: u32, _offset: u32) -> Result<u32, FsError> {
+        for i in 0..size as usize {
+            print!("{}", unsafe { *buffer.add(i) } as char);
+        }
+        Ok(size)
+    }
+}
@@ -174,6 +174,10 @@ pub fn mie_write(val: usize) {
 	}
 }
 
+// Machine interrupt enable in mstatus. It is off in the trap handler and on
+// in processes, including kernel processes.
+pub const MSTATUS_MIE: usize = 1 << 3;
+
 pub fn mstatus_write(val: usize) {
 	unsafe {
 		llvm_asm!("csrw	mstatus, $0" ::"r"(val));
@@ -316,6 +320,21 @@ pub fn get_mtime() -> usize {
 	unsafe { (*MMIO_MTIME) as usize }
 }
 
+// mtime only counts from when the machine started. For the wall clock, QEMU's
+// virt machine has a Goldfish real-time clock, which counts nanoseconds since
+// the epoch. Reading the low word latches the high word, so low goes first.
+const MMIO_RTC_TIME_LOW: *const u32 = 0x0010_1000 as *const u32;
+const MMIO_RTC_TIME_HIGH: *const u32 = 0x0010_1004 as *const u32;
+
+/// Seconds since the UNIX epoch. This is what goes in inode times.
+pub fn get_unix_time() -> u32 {
+	unsafe {
+		let low = MMIO_RTC_TIME_LOW.read_volatile() as u64;
+		let high = MMIO_RTC_TIME_HIGH.read_volatile() as u64;
+		((high << 32 | low) / 1_000_000_000) as u32
+	}
+}
+
 /// Copy one data from one memory location to another.
 pub unsafe fn memcpy(dest: *mut u8, src: *const u8, bytes: usize) {
 	let bytes_as_8 = bytes / 8;
@@ -327,7 +346,7 @@ pub unsafe fn memcpy(dest: *mut u8, src: *const u8, bytes: usize) {
 	}
 	let bytes_completed = bytes_as_8 * 8;
 	let bytes_remaining = bytes - bytes_completed;
-	for i in bytes_completed..bytes_remaining {
+	for i in bytes_completed..bytes_completed + bytes_remaining {
 		*(dest.add(i)) = *(src.add(i));
 	}
 }
@@ -0,0 +1,310 @@
+// devfs.rs
+// Device file system
+// Stephen Marz
+// 19 October 2026
+
+use crate::{block::DEVFS_DEV,
+            fs::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFMT},
+            lock::Mutex,
+            vfs::{dirent_type, DirEnt, File, FileSystem, FsError, Inode as VfsInode, InodeRef, Stat}};
+use alloc::{string::String, sync::Arc, vec::Vec};
+
+const ROOT_INO: u32 = 1;
+
+pub enum DeviceKind {
+	/// A stream of bytes, like the console, where offsets don't mean much.
+	Char,
+	/// Something with a size that can be read and written anywhere in it,
+	/// like a disk.
+	Block
+}
+
+/// What a driver hands us for each of its devices. open() finds the node
+/// in /dev, and read() and write() on it come straight here. These run in
+/// a kernel process, so they may block.
+pub trait Device {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError>;
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError>;
+	/// How many bytes there are, for block devices and the like. Streams
+	/// have no size.
+	fn size(&self) -> u32 {
+		0
+	}
+	/// The block device number file systems use for it, if it is a disk
+	/// that something can be mounted from.
+	fn block_dev(&self) -> Option<usize> {
+		None
+	}
+}
+
+struct Node {
+	name:   String,
+	mode:   u16,
+	uid:    u16,
+	gid:    u16,
+	device: Arc<dyn Device>
+}
+
+// Drivers register while we probe, before there are any processes, so this
+// is a spin lock. Nothing holds it while calling into a device.
+static mut DEVICES: Option<Vec<Node>> = None;
+static mut DEVICES_LOCK: Mutex = Mutex::new();
+
+pub fn init() {
+	unsafe {
+		DEVICES.replace(Vec::new());
+	}
+	register("console", DeviceKind::Char, 0o666, Arc::new(crate::console::Console));
+	register("null", DeviceKind::Char, 0o666, Arc::new(Null));
+	register("zero", DeviceKind::Char, 0o666, Arc::new(Zero));
+}
+
+fn with_devices<T>(f: impl FnOnce(&mut Vec<Node>) -> T) -> Option<T> {
+	unsafe {
+		DEVICES_LOCK.spin_lock();
+		let ret = if let Some(mut devices) = DEVICES.take() {
+			let ret = f(&mut devices);
+			DEVICES.replace(devices);
+			Some(ret)
+		}
+		else {
+			None
+		};
+		DEVICES_LOCK.unlock();
+		ret
+	}
+}
+
+/// Put a node called name in /dev for device. perm is the permission bits
+/// it starts out with. A name that's already taken keeps its old device.
+pub fn register(name: &str, kind: DeviceKind, perm: u16, device: Arc<dyn Device>) {
+	let mode = match kind {
+		DeviceKind::Char => S_IFCHR,
+		DeviceKind::Block => S_IFBLK,
+	} | (perm & !S_IFMT);
+	let added = with_devices(|devices| {
+		if devices.iter().any(|n| n.name == name) {
+			return false;
+		}
+		devices.push(Node { name: String::from(name),
+		                    mode,
+		                    uid: 0,
+		                    gid: 0,
+		                    device });
+		true
+	});
+	if added != Some(true) {
+		println!("devfs: could not add /dev/{}", name);
+	}
+}
+
+/// If inode is a node in /dev for a disk, give back the block device number
+/// behind it, which is what mount() needs.
+pub fn block_dev(inode: &InodeRef) -> Option<usize> {
+	if inode.dev() != DEVFS_DEV || inode.ino() == ROOT_INO {
+		return None;
+	}
+	let index = (inode.ino() - ROOT_INO - 1) as usize;
+	with_devices(|devices| {
+		devices.get(index)
+		       .filter(|n| n.mode & S_IFMT == S_IFBLK)
+		       .and_then(|n| n.device.block_dev())
+	}).flatten()
+}
+
+/// Reads as nothing and throws away whatever is written.
+struct Null;
+
+impl Device for Null {
+	fn read(&self, _buffer: *mut u8, _size: u32, _offset: u32) -> Result<u32, FsError> {
+		Ok(0)
+	}
+
+	fn write(&self, _buffer: *const u8, size: u32, _offset: u32) -> Result<u32, FsError> {
+		Ok(size)
+	}
+}
+
+/// Reads as all the zeros you want and throws away whatever is written.
+struct Zero;
+
+impl Device for Zero {
+	fn read(&self, buffer: *mut u8, size: u32, _offset: u32) -> Result<u32, FsError> {
+		unsafe {
+			for i in 0..size as usize {
+				buffer.add(i).write(0);
+			}
+		}
+		Ok(size)
+	}
+
+	fn write(&self, _buffer: *const u8, size: u32, _offset: u32) -> Result<u32, FsError> {
+		Ok(size)
+	}
+}
+
+/// Every device that has been registered, in one flat directory. Mounting
+/// it more than once shows the same devices.
+pub struct DevFileSystem;
+
+impl FileSystem for DevFileSystem {
+	fn root(&self) -> Result<InodeRef, FsError> {
+		Ok(Arc::new(DevInode { ino: ROOT_INO }))
+	}
+}
+
+/// The root directory is ROOT_INO, and the devices follow it in the order
+/// they were registered. Nodes are never removed, so these don't go stale.
+pub struct DevInode {
+	ino: u32
+}
+
+impl DevInode {
+	fn device(&self) -> Result<Arc<dyn Device>, FsError> {
+		if self.ino == ROOT_INO {
+			return Err(FsError::IsDirectory);
+		}
+		let index = (self.ino - ROOT_INO - 1) as usize;
+		with_devices(|devices| devices.get(index).map(|n| n.device.clone())).flatten()
+		                                                                      .ok_or(FsError::FileNotFound)
+	}
+
+	/// Change the node's mode, uid or gid. The root directory can't be
+	/// changed.
+	fn update(&self, f: impl FnOnce(&mut Node)) -> Result<(), FsError> {
+		if self.ino == ROOT_INO {
+			return Err(FsError::NotPermitted);
+		}
+		let index = (self.ino - ROOT_INO - 1) as usize;
+		with_devices(|devices| devices.get_mut(index).map(f)).flatten()
+		                                                    .ok_or(FsError::FileNotFound)
+	}
+}
+
+impl File for DevInode {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		self.device()?.read(buffer, size, offset)
+	}
+
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		self.device()?.write(buffer, size, offset)
+	}
+}
+
+impl VfsInode for DevInode {
+	fn dev(&self) -> usize {
+		DEVFS_DEV
+	}
+
+	fn ino(&self) -> u32 {
+		self.ino
+	}
+
+	fn stat(&self) -> Result<Stat, FsError> {
+		let (mode, uid, gid, size) = if self.ino == ROOT_INO {
+			(S_IFDIR | 0o755, 0, 0, 0)
+		}
+		else {
+			let index = (self.ino - ROOT_INO - 1) as usize;
+			let (mode, uid, gid, device) = with_devices(|devices| devices.get(index).map(|n| (n.mode, n.uid, n.gid, n.device.clone())))
+				.flatten()
+				.ok_or(FsError::FileNotFound)?;
+			(mode, uid, gid, device.size())
+		};
+		Ok(Stat { dev: DEVFS_DEV,
+		          ino: self.ino,
+		          mode,
+		          nlinks: if self.ino == ROOT_INO { 2 } else { 1 },
+		          uid,
+		          gid,
+		          size,
+		          blocks: 0,
+		          blksize: 1024,
+		          atime: 0,
+		          mtime: 0,
+		          ctime: 0 })
+	}
+
+	/// Opening a device with O_TRUNC, like a shell does for > /dev/null,
+	/// has nothing to throw away.
+	fn truncate(&self) -> Result<(), FsError> {
+		self.device().map(|_| ())
+	}
+
+	fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+		if self.ino != ROOT_INO {
+			return Err(FsError::IsFile);
+		}
+		let index = with_devices(|devices| devices.iter().position(|n| n.name == name)).flatten()
+		                                                                                .ok_or(FsError::FileNotFound)?;
+		Ok(Arc::new(DevInode { ino: index as u32 + ROOT_INO + 1 }))
+	}
+
+	// Only drivers put things in /dev.
+	fn create(&self, _name: &str, _mode: u16) -> Result<InodeRef, FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn mkdir(&self, _name: &str, _mode: u16) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn unlink(&self, _name: &str) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn rmdir(&self, _name: &str) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn link(&self, _name: &str, _target: &InodeRef) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn rename(&self, _old_name: &str, _new_dir: &InodeRef, _new_name: &str) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn symlink(&self, _name: &str, _target: &str) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn readlink(&self) -> Result<String, FsError> {
+		Err(FsError::InvalidArgument)
+	}
+
+	fn chmod(&self, mode: u16) -> Result<(), FsError> {
+		self.update(|n| n.mode = (n.mode & S_IFMT) | (mode & !S_IFMT))
+	}
+
+	fn chown(&self, uid: u16, gid: u16) -> Result<(), FsError> {
+		self.update(|n| {
+			    n.uid = uid;
+			    n.gid = gid;
+		    })
+	}
+
+	fn readdir(&self, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		if self.ino != ROOT_INO {
+			return Err(FsError::IsFile);
+		}
+		let mut ents = Vec::new();
+		for (i, name) in [".", ".."].iter().enumerate().skip(offset as usize) {
+			ents.push(DirEnt { ino:  ROOT_INO,
+			                   kind: dirent_type(S_IFDIR),
+			                   name: String::from(*name),
+			                   next: i as u32 + 1 });
+		}
+		// The devices start at offset 2, after . and ..
+		let first = if offset > 2 { offset as usize - 2 } else { 0 };
+		with_devices(|devices| {
+			for (i, n) in devices.iter().enumerate().skip(first) {
+				ents.push(DirEnt { ino:  i as u32 + ROOT_INO + 1,
+				                   kind: dirent_type(n.mode),
+				                   name: n.name.clone(),
+				                   next: i as u32 + 3 });
+			}
+		});
+		Ok(ents)
+	}
+}
@@ -5,9 +5,9 @@
 // Stephen Marz
 
 use crate::{buffer::Buffer,
-            cpu::{build_satp, memcpy, satp_fence_asid, CpuMode, Registers, SatpMode, TrapFrame},
+            cpu::{build_satp, memcpy, satp_fence_asid, CpuMode, Registers, SatpMode},
             page::{map, zalloc, EntryBits, Table, PAGE_SIZE},
-            process::{Process, ProcessData, ProcessState, NEXT_PID, STACK_ADDR, STACK_PAGES}};
+            process::{alloc_frame, Process, ProcessData, ProcessState, NEXT_PID, STACK_ADDR, STACK_PAGES}};
 use alloc::collections::VecDeque;
 // Every ELF file starts with ELF "magic", which is a sequence of four bytes 0x7f followed by capital ELF, which is 0x45, 0x4c, and 0x46 respectively.
 pub const MAGIC: u32 = 0x464c_457f;
@@ -73,7 +73,8 @@ pub enum LoadErrors {
 	Magic,
 	Machine,
 	TypeExec,
-	FileRead
+	FileRead,
+	OutOfMemory
 }
 
 pub struct File {
@@ -120,6 +121,9 @@ impl File {
 					continue;
 				}
 				let mut ph_buffer = Buffer::new(ph.memsz);
+				if ph_buffer.get().is_null() {
+					return Err(LoadErrors::OutOfMemory);
+				}
 
 				memcpy(ph_buffer.get_mut(), buffer.get().add(ph.off), ph.memsz);
 				ret.programs.push_back(Program { header: *ph,
@@ -151,7 +155,7 @@ impl File {
 			NEXT_PID += 1;
 			p
 		};
-		let mut my_proc = Process { frame:       zalloc(1) as *mut TrapFrame,
+		let mut my_proc = Process { frame:       alloc_frame(),
 		                            stack:       zalloc(STACK_PAGES),
 		                            pid:         my_pid,
 		                            mmu_table:        zalloc(1) as *mut Table,
@@ -162,6 +166,11 @@ impl File {
 									brk:         0,
 								 };
 
+		// If we couldn't get any of the memory above, dropping my_proc will
+		// give back whatever we did get.
+		if !my_proc.is_allocated() || my_proc.program.is_null() {
+			return Err(LoadErrors::OutOfMemory);
+		}
 		let program_mem = my_proc.program;
 		let table = unsafe { my_proc.mmu_table.as_mut().unwrap() };
 		// The ELF has several "program headers". This usually mimics the .text,
@@ -202,7 +211,9 @@ impl File {
 				// There is no checking here! This is very dangerous, and I have already
 				// been bitten by it. I mapped too far and mapped userspace into the MMU
 				// table, which is AWFUL!
-				map(table, vaddr, paddr, bits, 0);
+				if !map(table, vaddr, paddr, bits, 0) {
+					return Err(LoadErrors::OutOfMemory);
+				}
 				if vaddr > my_proc.brk {
 					my_proc.brk = vaddr;
 				}
@@ -220,7 +231,9 @@ impl File {
 			let paddr = ptr as usize + i * PAGE_SIZE;
 			// We create the stack. We don't load a stack from the disk.
 			// This is why I don't need to make the stack executable.
-			map(table, vaddr, paddr, EntryBits::UserReadWrite.val(), 0);
+			if !map(table, vaddr, paddr, EntryBits::UserReadWrite.val(), 0) {
+				return Err(LoadErrors::OutOfMemory);
+			}
 		}
 		// Set everything up in the trap frame
 		unsafe {
@@ -0,0 +1,446 @@
+// ext2.rs
+// Read-only ext2 file system
+// Stephen Marz
+// 19 October 2026
+
+use crate::{bcache,
+            buffer::Buffer,
+            fs::{S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+            vfs::{dirent_type, DirEnt, File, FileSystem, FsError, Inode as VfsInode, InodeRef, Stat}};
+use alloc::{string::String, sync::Arc, vec::Vec};
+use core::mem::size_of;
+
+pub const EXT2_MAGIC: u16 = 0xef53;
+// Inode 1 holds the bad blocks, so the root directory is inode 2.
+pub const EXT2_ROOT_INODE: u32 = 2;
+// Blocks 0..12 of a file are in the inode. Block pointers 12, 13 and 14 are
+// the single, double and triple indirect blocks.
+const NUM_DIRECT: usize = 12;
+
+// Incompatible features are ones we have to understand to read the disk at
+// all. Directory entries with a file type and block groups whose tables are
+// packed together are all we know about. Anything else (extents, 64-bit
+// block numbers, a journal that needs replaying) and we refuse to mount.
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG;
+
+/// The part of the ext2 superblock we use. It sits 1024 bytes into the
+/// disk no matter what the block size is.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SuperBlock {
+	pub inodes_count:      u32,
+	pub blocks_count:      u32,
+	pub r_blocks_count:    u32,
+	pub free_blocks_count: u32,
+	pub free_inodes_count: u32,
+	pub first_data_block:  u32,
+	pub log_block_size:    u32,
+	pub log_frag_size:     u32,
+	pub blocks_per_group:  u32,
+	pub frags_per_group:   u32,
+	pub inodes_per_group:  u32,
+	pub mtime:             u32,
+	pub wtime:             u32,
+	pub mnt_count:         u16,
+	pub max_mnt_count:     u16,
+	pub magic:             u16,
+	pub state:             u16,
+	pub errors:            u16,
+	pub minor_rev_level:   u16,
+	pub lastcheck:         u32,
+	pub checkinterval:     u32,
+	pub creator_os:        u32,
+	pub rev_level:         u32,
+	pub def_resuid:        u16,
+	pub def_resgid:        u16,
+	// Only good if rev_level is 1 or more.
+	pub first_ino:         u32,
+	pub inode_size:        u16,
+	pub block_group_nr:    u16,
+	pub feature_compat:    u32,
+	pub feature_incompat:  u32,
+	pub feature_ro_compat: u32
+}
+
+/// Each block group has one of these in the table that starts in the block
+/// after the superblock. They say where the group's bitmaps and inodes are.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct GroupDesc {
+	pub block_bitmap:      u32,
+	pub inode_bitmap:      u32,
+	pub inode_table:       u32,
+	pub free_blocks_count: u16,
+	pub free_inodes_count: u16,
+	pub used_dirs_count:   u16,
+	pub pad:               u16,
+	pub reserved:          [u32; 3]
+}
+
+/// The first 128 bytes of an inode, which is all revision 0 has. Later
+/// revisions can have bigger inodes, but the rest is nothing we need.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Inode {
+	pub mode:        u16,
+	pub uid:         u16,
+	pub size:        u32,
+	pub atime:       u32,
+	pub ctime:       u32,
+	pub mtime:       u32,
+	pub dtime:       u32,
+	pub gid:         u16,
+	pub links_count: u16,
+	/// In 512-byte sectors, not file system blocks.
+	pub blocks:      u32,
+	pub flags:       u32,
+	pub osd1:        u32,
+	pub block:       [u32; 15],
+	pub generation:  u32,
+	pub file_acl:    u32,
+	pub size_high:   u32,
+	pub faddr:       u32,
+	pub osd2:        [u8; 12]
+}
+
+/// Directories are a list of these, each followed by its name. rec_len
+/// takes us to the next one, and a record with inode 0 is unused. Without
+/// the file type feature, file_type is the high byte of the name length,
+/// which is always 0 since names are at most 255 bytes.
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct DirEntryHead {
+	inode:     u32,
+	rec_len:   u16,
+	name_len:  u8,
+	file_type: u8
+}
+
+// What the file types in a directory entry are, as a mode.
+const FT_MODES: [u16; 8] = [0, S_IFREG, S_IFDIR, S_IFCHR, 0o060_000, 0o010_000, 0o140_000, S_IFLNK];
+
+/// Like the Minix file system, everything is done by associated functions
+/// that take the block device and go to the disk (through the block cache)
+/// every time, so the struct only remembers the device.
+pub struct Ext2FileSystem {
+	bdev: usize
+}
+
+impl Ext2FileSystem {
+	pub fn new(bdev: usize) -> Self {
+		Self { bdev }
+	}
+
+	/// Read a T from byte offset of the device. It doesn't have to be
+	/// aligned to anything.
+	fn read_at<T: Copy>(bdev: usize, offset: u64) -> Result<T, FsError> {
+		let mut buffer = Buffer::new(size_of::<T>());
+		if !bcache::read(bdev, buffer.get_mut(), size_of::<T>() as u32, offset) {
+			return Err(FsError::Io);
+		}
+		unsafe { Ok((buffer.get() as *const T).read_unaligned()) }
+	}
+
+	/// The superblock, if bdev has an ext2 file system we know how to read.
+	pub fn get_super_block(bdev: usize) -> Option<SuperBlock> {
+		let sb: SuperBlock = Self::read_at(bdev, 1024).ok()?;
+		if sb.magic != EXT2_MAGIC || sb.log_block_size > 2 || sb.inodes_per_group == 0 {
+			return None;
+		}
+		if sb.rev_level > 0 && sb.feature_incompat & !INCOMPAT_SUPPORTED != 0 {
+			println!("ext2: device {} has features we don't know ({:x})", bdev, sb.feature_incompat);
+			return None;
+		}
+		Some(sb)
+	}
+
+	fn block_size(sb: &SuperBlock) -> u64 {
+		1024 << sb.log_block_size
+	}
+
+	fn inode_size(sb: &SuperBlock) -> u64 {
+		if sb.rev_level == 0 { 128 } else { sb.inode_size as u64 }
+	}
+
+	/// Inodes are numbered from 1 and split evenly between the block
+	/// groups. The group descriptor tells us where that group's inode table
+	/// is.
+	pub fn get_inode(bdev: usize, inode_num: u32) -> Result<Inode, FsError> {
+		let sb = Self::get_super_block(bdev).ok_or(FsError::Io)?;
+		if inode_num == 0 || inode_num > sb.inodes_count {
+			return Err(FsError::FileNotFound);
+		}
+		let group = ((inode_num - 1) / sb.inodes_per_group) as u64;
+		let index = ((inode_num - 1) % sb.inodes_per_group) as u64;
+		let bs = Self::block_size(&sb);
+		let desc_offset = (sb.first_data_block as u64 + 1) * bs + group * size_of::<GroupDesc>() as u64;
+		let desc: GroupDesc = Self::read_at(bdev, desc_offset)?;
+		Self::read_at(bdev, desc.inode_table as u64 * bs + index * Self::inode_size(&sb))
+	}
+
+	/// The disk block holding block index of a file, or 0 if it is a hole.
+	fn get_block(bdev: usize, sb: &SuperBlock, inode: &Inode, index: u64) -> Result<u32, FsError> {
+		if index < NUM_DIRECT as u64 {
+			return Ok(inode.block[index as usize]);
+		}
+		let bs = Self::block_size(sb);
+		let ptrs = bs / 4;
+		let mut index = index - NUM_DIRECT as u64;
+		// per is how many blocks of the file one pointer at the top of the
+		// indirect tree covers.
+		let mut per = 1;
+		for level in 0..3 {
+			if index < per * ptrs {
+				let mut block = inode.block[NUM_DIRECT + level];
+				loop {
+					if block == 0 {
+						return Ok(0);
+					}
+					block = Self::read_at(bdev, block as u64 * bs + (index / per) * 4)?;
+					if per == 1 {
+						return Ok(block);
+					}
+					index %= per;
+					per /= ptrs;
+				}
+			}
+			index -= per * ptrs;
+			per *= ptrs;
+		}
+		Err(FsError::InvalidArgument)
+	}
+
+	/// Read size bytes at offset of a file into buffer. Holes read as zeros.
+	/// Returns how many bytes we read, which stops at the end of the file.
+	pub fn read(bdev: usize, inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let sb = Self::get_super_block(bdev).ok_or(FsError::Io)?;
+		let bs = Self::block_size(&sb);
+		if offset >= inode.size {
+			return Ok(0);
+		}
+		let size = if size > inode.size - offset { inode.size - offset } else { size };
+		let mut done = 0u32;
+		while done < size {
+			let pos = offset as u64 + done as u64;
+			let in_block = pos % bs;
+			let count = if bs - in_block < (size - done) as u64 { (bs - in_block) as u32 } else { size - done };
+			let block = Self::get_block(bdev, &sb, inode, pos / bs)?;
+			unsafe {
+				let dst = buffer.add(done as usize);
+				if block == 0 {
+					for i in 0..count as usize {
+						dst.add(i).write(0);
+					}
+				}
+				else if !bcache::read(bdev, dst, count, block as u64 * bs + in_block) {
+					return Err(FsError::Io);
+				}
+			}
+			done += count;
+		}
+		Ok(done)
+	}
+
+	/// The entries of directory dir_num from byte offset on. The offset of
+	/// an entry is where its record starts, and the record after it starts
+	/// rec_len later.
+	pub fn readdir(bdev: usize, dir_num: u32, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		let sb = Self::get_super_block(bdev).ok_or(FsError::Io)?;
+		let dir = Self::get_inode(bdev, dir_num)?;
+		if dir.mode & S_IFMT != S_IFDIR {
+			return Err(FsError::IsFile);
+		}
+		let mut buffer = Buffer::new(dir.size as usize);
+		let size = Self::read(bdev, &dir, buffer.get_mut(), dir.size, 0)?;
+		let head_size = size_of::<DirEntryHead>() as u32;
+		let mut ret = Vec::new();
+		let mut pos = offset;
+		while pos + head_size <= size {
+			let head = unsafe { (buffer.get().add(pos as usize) as *const DirEntryHead).read_unaligned() };
+			let next = pos + head.rec_len as u32;
+			if head.rec_len < head_size as u16 || next > size || head.name_len as u32 > head.rec_len as u32 - head_size {
+				println!("ext2: bad directory entry at {} in inode {}", pos, dir_num);
+				return Err(FsError::Io);
+			}
+			if head.inode != 0 {
+				let name = unsafe {
+					let start = buffer.get().add((pos + head_size) as usize);
+					String::from_utf8_lossy(core::slice::from_raw_parts(start, head.name_len as usize)).into_owned()
+				};
+				let mode = if sb.feature_incompat & INCOMPAT_FILETYPE != 0 {
+					FT_MODES.get(head.file_type as usize).cloned().unwrap_or(0)
+				}
+				else {
+					Self::get_inode(bdev, head.inode).map(|inode| inode.mode).unwrap_or(0)
+				};
+				ret.push(DirEnt { ino: head.inode,
+				                  kind: dirent_type(mode),
+				                  name,
+				                  next });
+			}
+			pos = next;
+		}
+		Ok(ret)
+	}
+
+	/// Find name in the directory dir_num and give back its inode number.
+	pub fn lookup(bdev: usize, dir_num: u32, name: &str) -> Result<u32, FsError> {
+		Self::readdir(bdev, dir_num, 0)?.into_iter()
+		                                  .find(|ent| ent.name == name)
+		                                  .map(|ent| ent.ino)
+		                                  .ok_or(FsError::FileNotFound)
+	}
+
+	/// A short symbolic link keeps its target in the block pointers instead
+	/// of in a block. We can tell because it has no blocks, other than an
+	/// extended attribute block if it has one.
+	pub fn readlink(bdev: usize, inode_num: u32) -> Result<String, FsError> {
+		let sb = Self::get_super_block(bdev).ok_or(FsError::Io)?;
+		let inode = Self::get_inode(bdev, inode_num)?;
+		if inode.mode & S_IFMT != S_IFLNK {
+			return Err(FsError::InvalidArgument);
+		}
+		let acl_sectors = if inode.file_acl != 0 { (Self::block_size(&sb) / 512) as u32 } else { 0 };
+		let mut target = Vec::new();
+		if inode.blocks == acl_sectors && (inode.size as usize) <= size_of::<[u32; 15]>() {
+			let bytes = inode.block.as_ptr() as *const u8;
+			for i in 0..inode.size as usize {
+				target.push(unsafe { *bytes.add(i) });
+			}
+		}
+		else {
+			let mut buffer = Buffer::new(inode.size as usize);
+			let size = Self::read(bdev, &inode, buffer.get_mut(), inode.size, 0)?;
+			for i in 0..size as usize {
+				target.push(buffer[i]);
+			}
+		}
+		String::from_utf8(target).map_err(|_| FsError::InvalidArgument)
+	}
+}
+
+impl FileSystem for Ext2FileSystem {
+	fn root(&self) -> Result<InodeRef, FsError> {
+		if Self::get_super_block(self.bdev).is_none() {
+			return Err(FsError::InvalidArgument);
+		}
+		Ok(Arc::new(Ext2Inode { bdev: self.bdev,
+		                        num:  EXT2_ROOT_INODE }))
+	}
+
+	/// We never write, so there's nothing to save, only cached blocks to
+	/// throw away.
+	fn unmount(&self) -> Result<(), FsError> {
+		bcache::invalidate(self.bdev);
+		Ok(())
+	}
+}
+
+/// An inode on an ext2 file system. Everything that would change the disk
+/// fails with ReadOnly.
+pub struct Ext2Inode {
+	bdev: usize,
+	num:  u32
+}
+
+impl Ext2Inode {
+	fn get(&self) -> Result<Inode, FsError> {
+		Ext2FileSystem::get_inode(self.bdev, self.num)
+	}
+}
+
+impl File for Ext2Inode {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let inode = self.get()?;
+		if inode.mode & S_IFMT == S_IFDIR {
+			return Err(FsError::IsDirectory);
+		}
+		Ext2FileSystem::read(self.bdev, &inode, buffer, size, offset)
+	}
+
+	fn write(&self, _buffer: *const u8, _size: u32, _offset: u32) -> Result<u32, FsError> {
+		Err(FsError::ReadOnly)
+	}
+}
+
+impl VfsInode for Ext2Inode {
+	fn dev(&self) -> usize {
+		self.bdev
+	}
+
+	fn ino(&self) -> u32 {
+		self.num
+	}
+
+	fn stat(&self) -> Result<Stat, FsError> {
+		let inode = self.get()?;
+		let sb = Ext2FileSystem::get_super_block(self.bdev).ok_or(FsError::Io)?;
+		Ok(Stat { dev:     self.bdev,
+		          ino:     self.num,
+		          mode:    inode.mode,
+		          nlinks:  inode.links_count,
+		          uid:     inode.uid,
+		          gid:     inode.gid,
+		          size:    inode.size,
+		          blocks:  inode.blocks,
+		          blksize: Ext2FileSystem::block_size(&sb) as u32,
+		          atime:   inode.atime,
+		          mtime:   inode.mtime,
+		          ctime:   inode.ctime })
+	}
+
+	fn truncate(&self) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+		let num = Ext2FileSystem::lookup(self.bdev, self.num, name)?;
+		Ok(Arc::new(Ext2Inode { bdev: self.bdev, num }))
+	}
+
+	fn create(&self, _name: &str, _mode: u16) -> Result<InodeRef, FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn mkdir(&self, _name: &str, _mode: u16) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn unlink(&self, _name: &str) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn rmdir(&self, _name: &str) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn link(&self, _name: &str, _target: &InodeRef) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn rename(&self, _old_name: &str, _new_dir: &InodeRef, _new_name: &str) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn symlink(&self, _name: &str, _target: &str) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn readlink(&self) -> Result<String, FsError> {
+		Ext2FileSystem::readlink(self.bdev, self.num)
+	}
+
+	fn chmod(&self, _mode: u16) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn chown(&self, _uid: u16, _gid: u16) -> Result<(), FsError> {
+		Err(FsError::ReadOnly)
+	}
+
+	fn readdir(&self, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		Ext2FileSystem::readdir(self.bdev, self.num, offset)
+	}
+}
@@ -0,0 +1,1358 @@
+// fat.rs
+// FAT32 file system
+// Stephen Marz
+// 19 October 2026
+
+use crate::{bcache,
+            buffer::Buffer,
+            cpu::get_unix_time,
+            fs::{S_IFDIR, S_IFREG},
+            lock::{Mutex, DEFAULT_LOCK_SLEEP},
+            syscall::syscall_sleep,
+            vfs::{dirent_type, DirEnt, File, FileSystem, FsError, Inode as VfsInode, InodeRef, Stat}};
+use alloc::{collections::BTreeSet, string::String, sync::Arc, vec::Vec};
+use core::mem::size_of;
+
+// FAT32 entries are really 28 bits. The top 4 bits are reserved, and we
+// have to leave them alone when we change an entry.
+const FAT_MASK: u32 = 0x0fff_ffff;
+const FAT_FREE: u32 = 0;
+// Anything from here up ends a chain. We write FAT_EOC.
+const FAT_EOC_MIN: u32 = 0x0fff_fff8;
+const FAT_EOC: u32 = 0x0fff_ffff;
+// Bit 7 of ext_flags means only the FAT in bits 0..4 is used.
+const EXT_FLAGS_NO_MIRROR: u16 = 0x80;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+// A long name entry has read only, hidden, system and volume ID all set,
+// which nothing else does.
+pub const ATTR_LFN: u8 = 0x0f;
+
+// The first byte of a name says whether the entry is in use.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_FREE: u8 = 0xe5;
+// A name that really starts with 0xe5 is stored starting with 0x05.
+const ENTRY_KANJI: u8 = 0x05;
+// Windows NT keeps "this part is all lower case" in ntres, so that names
+// like readme.txt don't need a long name entry.
+const NTRES_LOWER_BASE: u8 = 0x08;
+const NTRES_LOWER_EXT: u8 = 0x10;
+// The long name entry with this bit set in its order is the last piece of
+// the name, which comes first in the directory.
+const LFN_LAST: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+const MAX_NAME: usize = 255;
+// A directory can't have more entries than this.
+const MAX_DIR_SLOTS: u32 = 65536;
+const DIR_ENTRY_SIZE: u32 = size_of::<DirEntry>() as u32;
+
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIG: u32 = 0x6141_7272;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+/// The start of the boot sector, which holds the BIOS parameter block.
+/// Everything in it is little endian and most of it isn't aligned.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+pub struct BootSector {
+	pub jump:                [u8; 3],
+	pub oem_name:            [u8; 8],
+	pub bytes_per_sector:    u16,
+	pub sectors_per_cluster: u8,
+	pub reserved_sectors:    u16,
+	pub num_fats:            u8,
+	pub root_entry_count:    u16,
+	pub total_sectors_16:    u16,
+	pub media:               u8,
+	pub fat_size_16:         u16,
+	pub sectors_per_track:   u16,
+	pub num_heads:           u16,
+	pub hidden_sectors:      u32,
+	pub total_sectors_32:    u32,
+	// From here on is only in FAT32.
+	pub fat_size_32:         u32,
+	pub ext_flags:           u16,
+	pub fs_version:          u16,
+	pub root_cluster:        u32,
+	pub fs_info:             u16,
+	pub backup_boot:         u16
+}
+
+/// A short (8.3) directory entry. Every file has one of these, and it's the
+/// only place the file's first cluster and size are kept.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct DirEntry {
+	pub name:           [u8; 11],
+	pub attr:           u8,
+	pub ntres:          u8,
+	pub crt_time_tenth: u8,
+	pub crt_time:       u16,
+	pub crt_date:       u16,
+	pub acc_date:       u16,
+	pub cluster_hi:     u16,
+	pub wrt_time:       u16,
+	pub wrt_date:       u16,
+	pub cluster_lo:     u16,
+	pub size:           u32
+}
+
+/// A piece of a long name. Each one holds 13 UTF-16 characters, and they
+/// come right before the short entry they name, last piece first.
+#[repr(C, packed)]
+#[derive(Copy, Clone)]
+struct LfnEntry {
+	order:      u8,
+	name1:      [u16; 5],
+	attr:       u8,
+	kind:       u8,
+	checksum:   u8,
+	name2:      [u16; 6],
+	cluster_lo: u16,
+	name3:      [u16; 2]
+}
+
+impl DirEntry {
+	fn cluster(&self) -> u32 {
+		(self.cluster_hi as u32) << 16 | self.cluster_lo as u32
+	}
+
+	fn set_cluster(&mut self, cluster: u32) {
+		self.cluster_hi = (cluster >> 16) as u16;
+		self.cluster_lo = cluster as u16;
+	}
+
+	fn is_dir(&self) -> bool {
+		self.attr & ATTR_DIRECTORY != 0
+	}
+
+	/// The 8.3 name as "NAME.EXT", lower cased where ntres says so. The
+	/// padding spaces aren't part of it, and neither is the dot if there is
+	/// no extension.
+	fn short_name(&self) -> String {
+		let mut raw = self.name;
+		if raw[0] == ENTRY_KANJI {
+			raw[0] = ENTRY_FREE;
+		}
+		let part = |bytes: &[u8], lower: bool| -> String {
+			bytes.iter()
+			     .filter(|c| **c != b' ')
+			     .map(|&c| if lower { c.to_ascii_lowercase() } else { c } as char)
+			     .collect()
+		};
+		let mut ret = part(&raw[..8], self.ntres & NTRES_LOWER_BASE != 0);
+		let ext = part(&raw[8..], self.ntres & NTRES_LOWER_EXT != 0);
+		if !ext.is_empty() {
+			ret.push('.');
+			ret.push_str(&ext);
+		}
+		ret
+	}
+}
+
+/// The checksum of a short name that every long name entry for it carries,
+/// so we can tell long names that were orphaned by something that doesn't
+/// know about them.
+fn lfn_checksum(name: &[u8; 11]) -> u8 {
+	name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+// ///////////////////////////////////
+// / TIME
+// ///////////////////////////////////
+
+// FAT keeps times as a date and a time of day with 2-second resolution, in
+// local time. We don't have time zones, so ours are UTC. The conversions
+// are Howard Hinnant's days_from_civil and civil_from_days.
+
+fn days_from_civil(y: u32, m: u32, d: u32) -> u32 {
+	let y = if m <= 2 { y - 1 } else { y };
+	let era = y / 400;
+	let yoe = y - era * 400;
+	let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+	let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+	era * 146_097 + doe - 719_468
+}
+
+fn civil_from_days(z: u32) -> (u32, u32, u32) {
+	let z = z + 719_468;
+	let era = z / 146_097;
+	let doe = z - era * 146_097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let d = doy - (153 * mp + 2) / 5 + 1;
+	let m = if mp < 10 { mp + 3 } else { mp - 9 };
+	(yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
+
+fn to_unix(date: u16, time: u16) -> u32 {
+	if date == 0 {
+		return 0;
+	}
+	let year = 1980 + (date >> 9) as u32;
+	let month = ((date >> 5) & 0xf).max(1) as u32;
+	let day = (date & 0x1f).max(1) as u32;
+	let secs = (time >> 11) as u32 * 3600 + ((time >> 5) & 0x3f) as u32 * 60 + (time & 0x1f) as u32 * 2;
+	days_from_civil(year, month, day) * 86400 + secs
+}
+
+/// A unix time as (date, time). FAT can't go back before 1980.
+fn from_unix(t: u32) -> (u16, u16) {
+	let t = t.max(days_from_civil(1980, 1, 1) * 86400);
+	let (year, month, day) = civil_from_days(t / 86400);
+	let secs = t % 86400;
+	let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+	let time = ((secs / 3600) << 11 | (secs / 60 % 60) << 5 | (secs % 60 / 2)) as u16;
+	(date, time)
+}
+
+// ///////////////////////////////////
+// / THE FILE SYSTEM
+// ///////////////////////////////////
+
+/// Where everything is, worked out from the boot sector. Offsets are in
+/// bytes from the start of the device.
+#[derive(Copy, Clone)]
+struct Geometry {
+	cluster_size: u32,
+	/// Where the first FAT starts, and how big each one is.
+	fats_start:   u64,
+	fat_size:     u64,
+	/// The FAT we read from.
+	fat_start:    u64,
+	/// Which FATs we write to. Normally all of them are kept the same, but
+	/// a FAT32 volume can say that only one of them is used.
+	write_fats:   (u32, u32),
+	/// Where cluster 2, the first one, starts.
+	data_start:   u64,
+	/// One more than the last cluster number there is.
+	clusters:     u32,
+	root_cluster: u32,
+	/// Where the FSInfo sector is, or 0 if there isn't one.
+	fs_info:      u64
+}
+
+impl Geometry {
+	fn cluster_offset(&self, cluster: u32) -> u64 {
+		self.data_start + (cluster - 2) as u64 * self.cluster_size as u64
+	}
+
+	fn valid(&self, cluster: u32) -> bool {
+		cluster >= 2 && cluster < self.clusters
+	}
+}
+
+/// Something in a directory that we found by name or by reading the
+/// directory. first is the slot its long name starts at (or the short entry
+/// itself if it has no long name) and index is the short entry's slot.
+struct Found {
+	name:  String,
+	first: u32,
+	index: u32,
+	ino:   u32,
+	entry: DirEntry
+}
+
+// Finding a free cluster or slot and taking it are separate reads and
+// writes of the cache, which can sleep, so two processes changing the same
+// volume could both take the same one. One process at a time gets to
+// change a volume, and the rest wait their turn.
+static mut VOLUMES_BUSY: Option<BTreeSet<usize>> = None;
+static mut VOLUMES_LOCK: Mutex = Mutex::new();
+
+/// Run f with the volume on bdev to ourselves.
+fn with_volume<T>(bdev: usize, f: impl FnOnce() -> T) -> T {
+	loop {
+		let got = unsafe {
+			VOLUMES_LOCK.sleep_lock();
+			let got = VOLUMES_BUSY.get_or_insert_with(BTreeSet::new).insert(bdev);
+			VOLUMES_LOCK.unlock();
+			got
+		};
+		if got {
+			break;
+		}
+		syscall_sleep(DEFAULT_LOCK_SLEEP);
+	}
+	let ret = f();
+	unsafe {
+		VOLUMES_LOCK.sleep_lock();
+		VOLUMES_BUSY.get_or_insert_with(BTreeSet::new).remove(&bdev);
+		VOLUMES_LOCK.unlock();
+	}
+	ret
+}
+
+/// Like the other file systems, all of the work is done by associated
+/// functions that take the block device, and the struct only remembers the
+/// device. A directory is named by its first cluster.
+pub struct FatFileSystem {
+	bdev: usize
+}
+
+impl FatFileSystem {
+	pub fn new(bdev: usize) -> Self {
+		Self { bdev }
+	}
+
+	fn read_at<T: Copy>(bdev: usize, offset: u64) -> Result<T, FsError> {
+		let mut buffer = Buffer::new(size_of::<T>());
+		if !bcache::read(bdev, buffer.get_mut(), size_of::<T>() as u32, offset) {
+			return Err(FsError::Io);
+		}
+		unsafe { Ok((buffer.get() as *const T).read_unaligned()) }
+	}
+
+	fn write_at<T: Copy>(bdev: usize, offset: u64, val: &T) -> Result<(), FsError> {
+		if !bcache::write(bdev, val as *const T as *const u8, size_of::<T>() as u32, offset) {
+			return Err(FsError::Io);
+		}
+		Ok(())
+	}
+
+	/// Read the boot sector and work out where everything is. Only FAT32
+	/// is supported, which is what a FAT12 or FAT16 boot sector isn't: their
+	/// root directory is a fixed area instead of a cluster chain.
+	fn geometry(bdev: usize) -> Option<Geometry> {
+		let bs: BootSector = Self::read_at(bdev, 0).ok()?;
+		let signature: u16 = Self::read_at(bdev, 510).ok()?;
+		let bps = bs.bytes_per_sector as u64;
+		let spc = bs.sectors_per_cluster as u32;
+		if signature != 0xaa55
+		   || !(512..=4096).contains(&bps)
+		   || !bps.is_power_of_two()
+		   || spc == 0
+		   || !spc.is_power_of_two()
+		   || bs.num_fats == 0
+		   || bs.fat_size_16 != 0
+		   || bs.root_entry_count != 0
+		   || bs.fat_size_32 == 0
+		{
+			return None;
+		}
+		let total = if bs.total_sectors_16 != 0 { bs.total_sectors_16 as u64 } else { bs.total_sectors_32 as u64 };
+		let fat_start = bs.reserved_sectors as u64 * bps;
+		let fat_size = bs.fat_size_32 as u64 * bps;
+		let first_data = bs.reserved_sectors as u64 + bs.num_fats as u64 * bs.fat_size_32 as u64;
+		if total <= first_data {
+			return None;
+		}
+		let count = (total - first_data) / spc as u64 + 2;
+		let clusters = count.min(fat_size / 4).min(FAT_EOC_MIN as u64) as u32;
+		let (read_fat, write_fats) = if bs.ext_flags & EXT_FLAGS_NO_MIRROR != 0 {
+			let active = (bs.ext_flags & 0xf) as u32;
+			(active, (active, active + 1))
+		}
+		else {
+			(0, (0, bs.num_fats as u32))
+		};
+		let g = Geometry { cluster_size: spc * bps as u32,
+		                   fats_start: fat_start,
+		                   fat_size,
+		                   fat_start: fat_start + read_fat as u64 * fat_size,
+		                   write_fats,
+		                   data_start: first_data * bps,
+		                   clusters,
+		                   root_cluster: bs.root_cluster,
+		                   fs_info: if bs.fs_info == 0 || bs.fs_info == 0xffff { 0 } else { bs.fs_info as u64 * bps } };
+		if !g.valid(g.root_cluster) {
+			return None;
+		}
+		// Inode numbers are where entries are on the disk, in entries, so
+		// the end of the volume has to be one that fits in 32 bits.
+		if g.cluster_offset(g.clusters) / DIR_ENTRY_SIZE as u64 > u32::MAX as u64 {
+			println!("fat: volume on device {} is too big", bdev);
+			return None;
+		}
+		Some(g)
+	}
+
+	// ///////////////////////////////////
+	// / CLUSTERS
+	// ///////////////////////////////////
+
+	fn get_fat(bdev: usize, g: &Geometry, cluster: u32) -> Result<u32, FsError> {
+		Ok(Self::read_at::<u32>(bdev, g.fat_start + cluster as u64 * 4)? & FAT_MASK)
+	}
+
+	/// Change the entry for cluster in every FAT we keep.
+	fn set_fat(bdev: usize, g: &Geometry, cluster: u32, val: u32) -> Result<(), FsError> {
+		for fat in g.write_fats.0..g.write_fats.1 {
+			let offset = g.fats_start + fat as u64 * g.fat_size + cluster as u64 * 4;
+			let old: u32 = Self::read_at(bdev, offset)?;
+			Self::write_at(bdev, offset, &((old & !FAT_MASK) | (val & FAT_MASK)))?;
+		}
+		Ok(())
+	}
+
+	/// Keep the free cluster count and the next free hint in the FSInfo
+	/// sector up to date, so that other systems don't have to count.
+	fn update_fs_info(bdev: usize, g: &Geometry, freed: i64, next: Option<u32>) {
+		if g.fs_info == 0 {
+			return;
+		}
+		let lead: u32 = Self::read_at(bdev, g.fs_info).unwrap_or(0);
+		let sig: u32 = Self::read_at(bdev, g.fs_info + 484).unwrap_or(0);
+		if lead != FSINFO_LEAD_SIG || sig != FSINFO_STRUCT_SIG {
+			return;
+		}
+		if let Ok(free) = Self::read_at::<u32>(bdev, g.fs_info + 488) {
+			if free != FSINFO_UNKNOWN {
+				let free = (free as i64 + freed).max(0) as u32;
+				let _ = Self::write_at(bdev, g.fs_info + 488, &free);
+			}
+		}
+		if let Some(next) = next {
+			let _ = Self::write_at(bdev, g.fs_info + 492, &next);
+		}
+	}
+
+	/// Find a free cluster, starting at the hint in FSInfo and going around.
+	/// The FAT is read a cache block at a time.
+	fn find_free(bdev: usize, g: &Geometry) -> Result<u32, FsError> {
+		let hint = if g.fs_info != 0 { Self::read_at::<u32>(bdev, g.fs_info + 492).unwrap_or(2) } else { 2 };
+		let hint = if g.valid(hint) { hint } else { 2 };
+		let per_block = bcache::BCACHE_BLOCK_SIZE / 4;
+		let mut buffer = Buffer::new(bcache::BCACHE_BLOCK_SIZE as usize);
+		for (from, to) in [(hint, g.clusters), (2, hint)].iter() {
+			let mut cluster = *from;
+			while cluster < *to {
+				let chunk = cluster / per_block * per_block;
+				if !bcache::read(bdev, buffer.get_mut(), bcache::BCACHE_BLOCK_SIZE, g.fat_start + chunk as u64 * 4) {
+					return Err(FsError::Io);
+				}
+				let entries = buffer.get() as *const u32;
+				while cluster < *to && cluster < chunk + per_block {
+					if unsafe { entries.add((cluster - chunk) as usize).read() } & FAT_MASK == FAT_FREE {
+						return Ok(cluster);
+					}
+					cluster += 1;
+				}
+			}
+		}
+		Err(FsError::NoSpace)
+	}
+
+	/// Take a free cluster, zero it, and put it on the end of the chain
+	/// whose last cluster is prev, if there is one.
+	fn alloc_cluster(bdev: usize, g: &Geometry, prev: u32) -> Result<u32, FsError> {
+		let cluster = Self::find_free(bdev, g)?;
+		Self::set_fat(bdev, g, cluster, FAT_EOC)?;
+		let mut zeros = Buffer::new(g.cluster_size as usize);
+		for i in 0..g.cluster_size as usize {
+			zeros[i] = 0;
+		}
+		if !bcache::write(bdev, zeros.get(), g.cluster_size, g.cluster_offset(cluster)) {
+			return Err(FsError::Io);
+		}
+		if prev != 0 {
+			Self::set_fat(bdev, g, prev, cluster)?;
+		}
+		Self::update_fs_info(bdev, g, -1, Some(cluster + 1));
+		Ok(cluster)
+	}
+
+	/// Give back every cluster of the chain starting at start.
+	fn free_chain(bdev: usize, g: &Geometry, start: u32) -> Result<(), FsError> {
+		let mut cluster = start;
+		let mut freed = 0;
+		while g.valid(cluster) && freed < g.clusters {
+			let next = Self::get_fat(bdev, g, cluster)?;
+			Self::set_fat(bdev, g, cluster, FAT_FREE)?;
+			freed += 1;
+			cluster = next;
+		}
+		Self::update_fs_info(bdev, g, freed as i64, None);
+		Ok(())
+	}
+
+	/// The clusters of the chain starting at start, in order. A chain that
+	/// goes somewhere it can't, or around in a circle, is an I/O error.
+	fn chain(bdev: usize, g: &Geometry, start: u32) -> Result<Vec<u32>, FsError> {
+		let mut ret = Vec::new();
+		let mut cluster = start;
+		while cluster != 0 && cluster < FAT_EOC_MIN {
+			if !g.valid(cluster) || ret.len() as u32 >= g.clusters {
+				println!("fat: bad cluster chain starting at {}", start);
+				return Err(FsError::Io);
+			}
+			ret.push(cluster);
+			cluster = Self::get_fat(bdev, g, cluster)?;
+		}
+		Ok(ret)
+	}
+
+	/// The cluster after this one in its chain, adding a new one if this is
+	/// the end.
+	fn next_or_alloc(bdev: usize, g: &Geometry, cluster: u32) -> Result<u32, FsError> {
+		let next = Self::get_fat(bdev, g, cluster)?;
+		if g.valid(next) {
+			Ok(next)
+		}
+		else if next >= FAT_EOC_MIN {
+			Self::alloc_cluster(bdev, g, cluster)
+		}
+		else {
+			Err(FsError::Io)
+		}
+	}
+
+	/// Read size bytes starting at offset of the chain at start into
+	/// buffer. The caller makes sure that doesn't go past the end of the
+	/// file.
+	fn read_chain(bdev: usize, g: &Geometry, start: u32, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let cs = g.cluster_size;
+		let mut cluster = start;
+		for _ in 0..offset / cs {
+			cluster = Self::get_fat(bdev, g, cluster)?;
+		}
+		let mut in_cluster = offset % cs;
+		let mut done = 0;
+		while done < size {
+			if !g.valid(cluster) {
+				return Err(FsError::Io);
+			}
+			let count = if cs - in_cluster < size - done { cs - in_cluster } else { size - done };
+			if !bcache::read(bdev, unsafe { buffer.add(done as usize) }, count, g.cluster_offset(cluster) + in_cluster as u64) {
+				return Err(FsError::Io);
+			}
+			done += count;
+			in_cluster = 0;
+			if done < size {
+				cluster = Self::get_fat(bdev, g, cluster)?;
+			}
+		}
+		Ok(done)
+	}
+
+	/// Write size bytes from buffer at offset of the chain at *start. The
+	/// chain grows as needed, and if it was empty, *start is its new first
+	/// cluster. New clusters are zeroed, so skipping over some leaves zeros.
+	/// If we run out of room partway, we say how much we wrote.
+	fn write_chain(bdev: usize, g: &Geometry, start: &mut u32, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		if size == 0 {
+			return Ok(0);
+		}
+		let cs = g.cluster_size;
+		if *start == 0 {
+			*start = Self::alloc_cluster(bdev, g, 0)?;
+		}
+		let mut cluster = *start;
+		for _ in 0..offset / cs {
+			cluster = Self::next_or_alloc(bdev, g, cluster)?;
+		}
+		let mut in_cluster = offset % cs;
+		let mut done = 0;
+		while done < size {
+			let count = if cs - in_cluster < size - done { cs - in_cluster } else { size - done };
+			if !bcache::write(bdev, unsafe { buffer.add(done as usize) }, count, g.cluster_offset(cluster) + in_cluster as u64) {
+				return Err(FsError::Io);
+			}
+			done += count;
+			in_cluster = 0;
+			if done < size {
+				cluster = match Self::next_or_alloc(bdev, g, cluster) {
+					Ok(next) => next,
+					Err(_) => break,
+				};
+			}
+		}
+		Ok(done)
+	}
+
+	// ///////////////////////////////////
+	// / DIRECTORIES
+	// ///////////////////////////////////
+
+	/// Read all of the directory starting at cluster dir. Returns the
+	/// entries along with where each cluster of it is, so that we can tell
+	/// where on the disk a slot is.
+	fn read_dir(bdev: usize, g: &Geometry, dir: u32) -> Result<(Buffer, Vec<u32>), FsError> {
+		let clusters = Self::chain(bdev, g, dir)?;
+		let cs = g.cluster_size;
+		let mut buffer = Buffer::new(clusters.len() * cs as usize);
+		for (i, &cluster) in clusters.iter().enumerate() {
+			if !bcache::read(bdev, unsafe { buffer.get_mut().add(i * cs as usize) }, cs, g.cluster_offset(cluster)) {
+				return Err(FsError::Io);
+			}
+		}
+		Ok((buffer, clusters))
+	}
+
+	/// An inode number for the file whose short entry is in slot index of a
+	/// directory: where that entry is on the disk, in entries. No two files
+	/// can have the same one, and geometry() turns down a volume too big
+	/// for it to fit.
+	fn slot_ino(g: &Geometry, clusters: &[u32], index: u32) -> u32 {
+		let byte = index * DIR_ENTRY_SIZE;
+		((g.cluster_offset(clusters[(byte / g.cluster_size) as usize]) + (byte % g.cluster_size) as u64)
+		 / DIR_ENTRY_SIZE as u64) as u32
+	}
+
+	/// Everything in the directory at dir, other than . and .. and the
+	/// volume label. Long names are put back together from their pieces. A
+	/// long name whose checksum doesn't match what it's in front of was left
+	/// behind by something that didn't understand it, so we use the short
+	/// name instead.
+	fn scan_dir(bdev: usize, g: &Geometry, dir: u32) -> Result<Vec<Found>, FsError> {
+		let (buffer, clusters) = Self::read_dir(bdev, g, dir)?;
+		let slots = (buffer.len() / DIR_ENTRY_SIZE as usize) as u32;
+		let mut ret = Vec::new();
+		// The pieces of the long name we're in the middle of, by order.
+		let mut lfn: Vec<[u16; LFN_CHARS]> = Vec::new();
+		let mut lfn_first = 0;
+		let mut lfn_sum = 0;
+		let mut expect = 0;
+		for index in 0..slots {
+			let at = unsafe { buffer.get().add((index * DIR_ENTRY_SIZE) as usize) };
+			let entry = unsafe { (at as *const DirEntry).read_unaligned() };
+			if entry.name[0] == ENTRY_END {
+				break;
+			}
+			if entry.name[0] == ENTRY_FREE {
+				expect = 0;
+				continue;
+			}
+			if entry.attr & ATTR_LFN == ATTR_LFN {
+				let piece = unsafe { (at as *const LfnEntry).read_unaligned() };
+				let order = piece.order & !LFN_LAST;
+				if piece.order & LFN_LAST != 0 && (1..=20).contains(&order) {
+					lfn = alloc::vec![[0xffff; LFN_CHARS]; order as usize];
+					lfn_first = index;
+					lfn_sum = piece.checksum;
+				}
+				else if order != expect || piece.checksum != lfn_sum {
+					expect = 0;
+					continue;
+				}
+				let (name1, name2, name3) = (piece.name1, piece.name2, piece.name3);
+				let chars = &mut lfn[order as usize - 1];
+				chars[..5].copy_from_slice(&name1);
+				chars[5..11].copy_from_slice(&name2);
+				chars[11..].copy_from_slice(&name3);
+				expect = order - 1;
+				continue;
+			}
+			let had_lfn = expect == 0 && !lfn.is_empty() && lfn_sum == lfn_checksum(&entry.name);
+			let (name, first) = if had_lfn {
+				let units = lfn.iter().flat_map(|chars| chars.iter()).cloned().take_while(|c| *c != 0 && *c != 0xffff);
+				(core::char::decode_utf16(units).map(|c| c.unwrap_or(core::char::REPLACEMENT_CHARACTER)).collect(), lfn_first)
+			}
+			else {
+				(entry.short_name(), index)
+			};
+			lfn.clear();
+			expect = 0;
+			if entry.attr & ATTR_VOLUME_ID != 0 || name == "." || name == ".." {
+				continue;
+			}
+			ret.push(Found { name,
+			                 first,
+			                 index,
+			                 ino: Self::slot_ino(g, &clusters, index),
+			                 entry });
+		}
+		Ok(ret)
+	}
+
+	/// FAT names don't care about case.
+	fn find(bdev: usize, g: &Geometry, dir: u32, name: &str) -> Result<Found, FsError> {
+		Self::scan_dir(bdev, g, dir)?.into_iter()
+		                              .find(|f| f.name.eq_ignore_ascii_case(name) || f.entry.short_name().eq_ignore_ascii_case(name))
+		                              .ok_or(FsError::FileNotFound)
+	}
+
+	fn check_name(name: &str) -> Result<(), FsError> {
+		if name.is_empty() || name == "." || name == ".." {
+			return Err(FsError::InvalidArgument);
+		}
+		if name.encode_utf16().count() > MAX_NAME {
+			return Err(FsError::NameTooLong);
+		}
+		if name.chars().any(|c| (c as u32) < 0x20 || "\"*/:<>?\\|".contains(c)) {
+			return Err(FsError::InvalidArgument);
+		}
+		Ok(())
+	}
+
+	/// Characters a short name can have, besides letters and digits.
+	fn short_char(c: u8) -> bool {
+		c.is_ascii_uppercase() || c.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&c) || c >= 0x80
+	}
+
+	/// If name fits in 8.3 as it is (or all lower case in either half),
+	/// the short name and ntres for it. Otherwise it needs a long name.
+	fn exact_short(name: &str) -> Option<([u8; 11], u8)> {
+		let (base, ext) = match name.rfind('.') {
+			Some(i) => (&name[..i], &name[i + 1..]),
+			None => (name, ""),
+		};
+		if base.is_empty() || base.len() > 8 || ext.len() > 3 || !name.is_ascii() {
+			return None;
+		}
+		let mut ret = [b' '; 11];
+		let mut ntres = 0;
+		for (part, at, flag) in [(base, 0, NTRES_LOWER_BASE), (ext, 8, NTRES_LOWER_EXT)].iter() {
+			let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+			let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+			if upper && lower {
+				return None;
+			}
+			if lower {
+				ntres |= flag;
+			}
+			for (i, c) in part.bytes().enumerate() {
+				let c = c.to_ascii_uppercase();
+				if !Self::short_char(c) {
+					return None;
+				}
+				ret[at + i] = c;
+			}
+		}
+		if ret[0] == ENTRY_FREE {
+			ret[0] = ENTRY_KANJI;
+		}
+		Some((ret, ntres))
+	}
+
+	/// Make up a short name for a name that needs a long one, the way
+	/// Windows does: the first six good characters, ~ and a number, and the
+	/// first three of the extension. The number is whatever makes it
+	/// different from everything else in the directory.
+	fn make_short(name: &str, taken: &[[u8; 11]]) -> Result<[u8; 11], FsError> {
+		let clean = |s: &str| -> Vec<u8> {
+			s.chars()
+			 .filter(|c| *c != ' ' && *c != '.')
+			 .map(|c| {
+				 let c = if c.is_ascii() { c.to_ascii_uppercase() as u8 } else { b'_' };
+				 if Self::short_char(c) { c } else { b'_' }
+			 })
+			 .collect()
+		};
+		let trimmed = name.trim_start_matches('.');
+		let (base, ext) = match trimmed.rfind('.') {
+			Some(i) => (clean(&trimmed[..i]), clean(&trimmed[i + 1..])),
+			None => (clean(trimmed), Vec::new()),
+		};
+		let mut ret = [b' '; 11];
+		for (i, c) in ext.iter().take(3).enumerate() {
+			ret[8 + i] = *c;
+		}
+		for n in 1..1_000_000u32 {
+			let tail = alloc::format!("~{}", n).into_bytes();
+			let mut start: Vec<u8> = base.iter().cloned().take((8 - tail.len()).min(6)).collect();
+			if start.is_empty() {
+				start.push(b'_');
+			}
+			let mut short = ret;
+			for (i, c) in start.iter().chain(tail.iter()).enumerate() {
+				short[i] = *c;
+			}
+			if !taken.contains(&short) {
+				return Ok(short);
+			}
+		}
+		Err(FsError::Exists)
+	}
+
+	/// Write entry into slot index of the directory at dir, which grows if
+	/// the slot is past its end.
+	fn put_slot<T: Copy>(bdev: usize, g: &Geometry, dir: u32, index: u32, entry: &T) -> Result<(), FsError> {
+		let mut start = dir;
+		let size = size_of::<T>() as u32;
+		if Self::write_chain(bdev, g, &mut start, entry as *const T as *const u8, size, index * DIR_ENTRY_SIZE)? != size {
+			return Err(FsError::NoSpace);
+		}
+		Ok(())
+	}
+
+	/// Add name to the directory at dir. entry has everything but the
+	/// short name, which we make up. The long name entries and the short one
+	/// go in the first run of free slots that is long enough, or at the end.
+	/// Returns where the entry went and its inode number.
+	fn add_entry(bdev: usize, g: &Geometry, dir: u32, name: &str, mut entry: DirEntry) -> Result<(u32, u32, u32), FsError> {
+		Self::check_name(name)?;
+		let found = Self::scan_dir(bdev, g, dir)?;
+		if found.iter().any(|f| f.name.eq_ignore_ascii_case(name)) {
+			return Err(FsError::Exists);
+		}
+		let units: Vec<u16> = name.encode_utf16().collect();
+		let pieces = match Self::exact_short(name) {
+			Some((short, ntres)) if !found.iter().any(|f| f.entry.name == short) => {
+				entry.name = short;
+				entry.ntres = ntres;
+				0
+			}
+			_ => {
+				let taken: Vec<[u8; 11]> = found.iter().map(|f| f.entry.name).collect();
+				entry.name = Self::make_short(name, &taken)?;
+				entry.ntres = 0;
+				(units.len() + LFN_CHARS - 1) / LFN_CHARS
+			}
+		};
+		let needed = pieces as u32 + 1;
+
+		// Find a run of free slots. Everything from the end marker on is
+		// free, and so is everything past the end of the directory.
+		let (buffer, _) = Self::read_dir(bdev, g, dir)?;
+		let slots = (buffer.len() / DIR_ENTRY_SIZE as usize) as u32;
+		let mut first = 0;
+		let mut run = 0;
+		for index in 0..slots {
+			let c = buffer[(index * DIR_ENTRY_SIZE) as usize];
+			if c != ENTRY_END && c != ENTRY_FREE {
+				run = 0;
+				continue;
+			}
+			if run == 0 {
+				first = index;
+			}
+			run += 1;
+			if c == ENTRY_END || run == needed {
+				break;
+			}
+		}
+		if run == 0 {
+			first = slots;
+		}
+		if first + needed > MAX_DIR_SLOTS {
+			return Err(FsError::NoSpace);
+		}
+
+		let sum = lfn_checksum(&entry.name);
+		for p in 0..pieces {
+			// Pieces are written last first.
+			let order = (pieces - p) as u8;
+			let mut chars = [0xffffu16; LFN_CHARS];
+			for (i, c) in chars.iter_mut().enumerate() {
+				let at = (order as usize - 1) * LFN_CHARS + i;
+				if at < units.len() {
+					*c = units[at];
+				}
+				else if at == units.len() {
+					*c = 0;
+				}
+			}
+			let mut name1 = [0u16; 5];
+			let mut name2 = [0u16; 6];
+			let mut name3 = [0u16; 2];
+			name1.copy_from_slice(&chars[..5]);
+			name2.copy_from_slice(&chars[5..11]);
+			name3.copy_from_slice(&chars[11..]);
+			let piece = LfnEntry { order: if p == 0 { order | LFN_LAST } else { order },
+			                       name1,
+			                       attr: ATTR_LFN,
+			                       kind: 0,
+			                       checksum: sum,
+			                       name2,
+			                       cluster_lo: 0,
+			                       name3 };
+			Self::put_slot(bdev, g, dir, first + p as u32, &piece)?;
+		}
+		let index = first + pieces as u32;
+		Self::put_slot(bdev, g, dir, index, &entry)?;
+		let clusters = Self::chain(bdev, g, dir)?;
+		Ok((first, index, Self::slot_ino(g, &clusters, index)))
+	}
+
+	/// Mark a file's long name entries and short entry free.
+	fn remove_entry(bdev: usize, g: &Geometry, dir: u32, first: u32, index: u32) -> Result<(), FsError> {
+		for slot in first..=index {
+			let mut start = dir;
+			if Self::write_chain(bdev, g, &mut start, &ENTRY_FREE, 1, slot * DIR_ENTRY_SIZE)? != 1 {
+				return Err(FsError::Io);
+			}
+		}
+		Ok(())
+	}
+
+	fn new_entry(attr: u8, cluster: u32) -> DirEntry {
+		let (date, time) = from_unix(get_unix_time());
+		let mut entry = DirEntry { name: [b' '; 11],
+		                           attr,
+		                           ntres: 0,
+		                           crt_time_tenth: 0,
+		                           crt_time: time,
+		                           crt_date: date,
+		                           acc_date: date,
+		                           cluster_hi: 0,
+		                           wrt_time: time,
+		                           wrt_date: date,
+		                           cluster_lo: 0,
+		                           size: 0 };
+		entry.set_cluster(cluster);
+		entry
+	}
+
+	/// What a directory's .. entry holds for its parent, which is 0 for the
+	/// root.
+	fn parent_cluster(g: &Geometry, dir: u32) -> u32 {
+		if dir == g.root_cluster { 0 } else { dir }
+	}
+
+	/// Is ancestor the directory dir or somewhere above it? We climb up
+	/// through the .. entries.
+	fn is_ancestor(bdev: usize, g: &Geometry, ancestor: u32, dir: u32) -> Result<bool, FsError> {
+		let mut current = dir;
+		for _ in 0..g.clusters {
+			if current == ancestor {
+				return Ok(true);
+			}
+			if current == g.root_cluster {
+				return Ok(false);
+			}
+			let dotdot: DirEntry = Self::read_at(bdev, g.cluster_offset(current) + DIR_ENTRY_SIZE as u64)?;
+			current = if dotdot.cluster() == 0 { g.root_cluster } else { dotdot.cluster() };
+		}
+		Err(FsError::Io)
+	}
+
+	/// The inode number of the directory starting at cluster dir. That's
+	/// where its entry is in its parent, which we find through its .. entry.
+	fn dir_ino(bdev: usize, g: &Geometry, dir: u32) -> Result<u32, FsError> {
+		if dir == g.root_cluster {
+			return Ok(1);
+		}
+		let dotdot: DirEntry = Self::read_at(bdev, g.cluster_offset(dir) + DIR_ENTRY_SIZE as u64)?;
+		let parent = if dotdot.cluster() == 0 { g.root_cluster } else { dotdot.cluster() };
+		Self::scan_dir(bdev, g, parent)?.iter()
+		                                 .find(|f| f.entry.is_dir() && f.entry.cluster() == dir)
+		                                 .map(|f| f.ino)
+		                                 .ok_or(FsError::Io)
+	}
+
+	fn mkdir(bdev: usize, g: &Geometry, dir: u32, name: &str) -> Result<(), FsError> {
+		Self::check_name(name)?;
+		let cluster = Self::alloc_cluster(bdev, g, 0)?;
+		let mut dot = Self::new_entry(ATTR_DIRECTORY, cluster);
+		dot.name = *b".          ";
+		let mut dotdot = Self::new_entry(ATTR_DIRECTORY, Self::parent_cluster(g, dir));
+		dotdot.name = *b"..         ";
+		let ret = Self::put_slot(bdev, g, cluster, 0, &dot)
+			.and_then(|_| Self::put_slot(bdev, g, cluster, 1, &dotdot))
+			.and_then(|_| Self::add_entry(bdev, g, dir, name, Self::new_entry(ATTR_DIRECTORY, cluster)));
+		if ret.is_err() {
+			Self::free_chain(bdev, g, cluster)?;
+		}
+		ret.map(|_| ())
+	}
+
+	/// Remove name from dir and give back its clusters. directory says
+	/// whether this is rmdir() or unlink().
+	fn remove(bdev: usize, g: &Geometry, dir: u32, name: &str, directory: bool) -> Result<(), FsError> {
+		let found = Self::find(bdev, g, dir, name)?;
+		match (directory, found.entry.is_dir()) {
+			(false, true) => return Err(FsError::IsDirectory),
+			(true, false) => return Err(FsError::IsFile),
+			(true, true) if !Self::scan_dir(bdev, g, found.entry.cluster())?.is_empty() => return Err(FsError::NotEmpty),
+			_ => {}
+		}
+		Self::remove_entry(bdev, g, dir, found.first, found.index)?;
+		Self::free_chain(bdev, g, found.entry.cluster())
+	}
+
+	/// Move old_name in old_dir to new_name in new_dir. Whatever is at
+	/// new_name already is replaced, if it is the same kind of thing (and
+	/// an empty directory, for a directory).
+	fn rename(bdev: usize, g: &Geometry, old_dir: u32, old_name: &str, new_dir: u32, new_name: &str) -> Result<(), FsError> {
+		let src = Self::find(bdev, g, old_dir, old_name)?;
+		let is_dir = src.entry.is_dir();
+		if is_dir && old_dir != new_dir && Self::is_ancestor(bdev, g, src.entry.cluster(), new_dir)? {
+			return Err(FsError::InvalidArgument);
+		}
+		match Self::find(bdev, g, new_dir, new_name) {
+			// Only the case is changing, which we do like any other rename.
+			Ok(dst) if old_dir == new_dir && dst.index == src.index => {}
+			Ok(dst) => {
+				match (is_dir, dst.entry.is_dir()) {
+					(false, true) => return Err(FsError::IsDirectory),
+					(true, false) => return Err(FsError::IsFile),
+					(true, true) if !Self::scan_dir(bdev, g, dst.entry.cluster())?.is_empty() => return Err(FsError::NotEmpty),
+					_ => {}
+				}
+				Self::remove_entry(bdev, g, new_dir, dst.first, dst.index)?;
+				Self::free_chain(bdev, g, dst.entry.cluster())?;
+			}
+			Err(FsError::FileNotFound) => {}
+			Err(e) => return Err(e),
+		}
+		// A case-only rename finds the old name when it checks for the new
+		// one, so the old entries have to go first. Anything else keeps them
+		// until the new one is safely in place.
+		let same = old_dir == new_dir && old_name.eq_ignore_ascii_case(new_name);
+		if same {
+			Self::remove_entry(bdev, g, old_dir, src.first, src.index)?;
+		}
+		if let Err(e) = Self::add_entry(bdev, g, new_dir, new_name, src.entry) {
+			if same {
+				// Put the old name back rather than lose the file.
+				let _ = Self::add_entry(bdev, g, old_dir, &src.name, src.entry);
+			}
+			return Err(e);
+		}
+		if !same {
+			Self::remove_entry(bdev, g, old_dir, src.first, src.index)?;
+		}
+		if is_dir && old_dir != new_dir {
+			let mut dotdot: DirEntry = Self::read_at(bdev, g.cluster_offset(src.entry.cluster()) + DIR_ENTRY_SIZE as u64)?;
+			dotdot.set_cluster(Self::parent_cluster(g, new_dir));
+			Self::put_slot(bdev, g, src.entry.cluster(), 1, &dotdot)?;
+		}
+		Ok(())
+	}
+}
+
+impl FileSystem for FatFileSystem {
+	fn root(&self) -> Result<InodeRef, FsError> {
+		if Self::geometry(self.bdev).is_none() {
+			return Err(FsError::InvalidArgument);
+		}
+		Ok(Arc::new(FatInode { bdev:  self.bdev,
+		                       slot:  None,
+		                       ino:   1 }))
+	}
+
+	fn unmount(&self) -> Result<(), FsError> {
+		let ok = bcache::sync(Some(self.bdev));
+		bcache::invalidate(self.bdev);
+		if ok { Ok(()) } else { Err(FsError::Io) }
+	}
+}
+
+// ///////////////////////////////////
+// / INODES
+// ///////////////////////////////////
+
+/// Where a file's short entry is: the first cluster of the directory, the
+/// slots of its long name and short entry, and the short name that was
+/// there when we found it.
+#[derive(Copy, Clone)]
+struct Slot {
+	dir:   u32,
+	first: u32,
+	index: u32,
+	short: [u8; 11]
+}
+
+/// FAT has no inodes, just directory entries, so a FatInode is where its
+/// entry is. The root directory has no entry. If the entry goes away or is
+/// reused by a different file (the short name won't match), the FatInode is
+/// gone too. An open file that is renamed is lost like that, since rename
+/// moves the entry.
+pub struct FatInode {
+	bdev: usize,
+	slot: Option<Slot>,
+	ino:  u32
+}
+
+impl FatInode {
+	fn node(&self, dir: u32, found: &Found) -> InodeRef {
+		Arc::new(FatInode { bdev: self.bdev,
+		                    slot: Some(Slot { dir,
+		                                      first: found.first,
+		                                      index: found.index,
+		                                      short: found.entry.name }),
+		                    ino:  found.ino })
+	}
+
+	fn geometry(&self) -> Result<Geometry, FsError> {
+		FatFileSystem::geometry(self.bdev).ok_or(FsError::Io)
+	}
+
+	/// Our short entry, read fresh from the disk. None for the root.
+	fn entry(&self, g: &Geometry) -> Result<Option<DirEntry>, FsError> {
+		match self.slot {
+			None => Ok(None),
+			Some(slot) => {
+				let clusters = FatFileSystem::chain(self.bdev, g, slot.dir)?;
+				let byte = slot.index * DIR_ENTRY_SIZE;
+				let cluster = *clusters.get((byte / g.cluster_size) as usize).ok_or(FsError::FileNotFound)?;
+				let entry: DirEntry = FatFileSystem::read_at(self.bdev, g.cluster_offset(cluster) + (byte % g.cluster_size) as u64)?;
+				if entry.name != slot.short {
+					return Err(FsError::FileNotFound);
+				}
+				Ok(Some(entry))
+			}
+		}
+	}
+
+	fn put_entry(&self, g: &Geometry, entry: &DirEntry) -> Result<(), FsError> {
+		match self.slot {
+			None => Ok(()),
+			Some(slot) => FatFileSystem::put_slot(self.bdev, g, slot.dir, slot.index, entry),
+		}
+	}
+
+	/// The first cluster of this directory. Files aren't directories.
+	fn dir(&self, g: &Geometry) -> Result<u32, FsError> {
+		match self.entry(g)? {
+			None => Ok(g.root_cluster),
+			Some(entry) if entry.is_dir() => Ok(entry.cluster()),
+			Some(_) => Err(FsError::IsFile),
+		}
+	}
+}
+
+impl File for FatInode {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let g = self.geometry()?;
+		let entry = match self.entry(&g)? {
+			Some(entry) if !entry.is_dir() => entry,
+			_ => return Err(FsError::IsDirectory),
+		};
+		if offset >= entry.size {
+			return Ok(0);
+		}
+		let size = if size > entry.size - offset { entry.size - offset } else { size };
+		FatFileSystem::read_chain(self.bdev, &g, entry.cluster(), buffer, size, offset)
+	}
+
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			let mut entry = match self.entry(&g)? {
+				Some(entry) if !entry.is_dir() => entry,
+				_ => return Err(FsError::IsDirectory),
+			};
+			if offset as u64 + size as u64 > u32::MAX as u64 {
+				return Err(FsError::NoSpace);
+			}
+			let mut start = entry.cluster();
+			// Whatever is in the last cluster past the end of the file could be
+			// anything, so a write past the end zeroes up to where it starts.
+			let mut ret = Ok(0);
+			if offset > entry.size {
+				let gap = offset - entry.size;
+				let mut zeros = Buffer::new(g.cluster_size as usize);
+				for i in 0..g.cluster_size as usize {
+					zeros[i] = 0;
+				}
+				let mut done = 0;
+				while done < gap && ret.is_ok() {
+					let count = if gap - done < g.cluster_size { gap - done } else { g.cluster_size };
+					ret = FatFileSystem::write_chain(self.bdev, &g, &mut start, zeros.get(), count, entry.size + done);
+					done += count;
+				}
+			}
+			if ret.is_ok() {
+				ret = FatFileSystem::write_chain(self.bdev, &g, &mut start, buffer, size, offset);
+			}
+			let (date, time) = from_unix(get_unix_time());
+			entry.set_cluster(start);
+			if let Ok(written) = ret {
+				if written > 0 && offset + written > entry.size {
+					entry.size = offset + written;
+				}
+			}
+			entry.wrt_date = date;
+			entry.wrt_time = time;
+			entry.attr |= ATTR_ARCHIVE;
+			self.put_entry(&g, &entry)?;
+			ret
+		})
+	}
+}
+
+impl VfsInode for FatInode {
+	fn dev(&self) -> usize {
+		self.bdev
+	}
+
+	fn ino(&self) -> u32 {
+		self.ino
+	}
+
+	/// FAT has no owners or permissions, so everything belongs to root and
+	/// anyone can do anything, except write to read-only files.
+	fn stat(&self) -> Result<Stat, FsError> {
+		let g = self.geometry()?;
+		let entry = self.entry(&g)?;
+		let (mode, start, size, atime, mtime, ctime) = match entry {
+			None => (S_IFDIR | 0o777, g.root_cluster, 0, 0, 0, 0),
+			Some(e) => {
+				let kind = if e.is_dir() { S_IFDIR } else { S_IFREG };
+				let perm = if e.attr & ATTR_READ_ONLY != 0 { 0o555 } else { 0o777 };
+				(kind | perm, e.cluster(), e.size, to_unix(e.acc_date, 0), to_unix(e.wrt_date, e.wrt_time), to_unix(e.crt_date, e.crt_time))
+			}
+		};
+		let clusters = FatFileSystem::chain(self.bdev, &g, start)?.len() as u32;
+		let size = if mode & S_IFDIR == S_IFDIR && size == 0 { clusters * g.cluster_size } else { size };
+		Ok(Stat { dev: self.bdev,
+		          ino: self.ino,
+		          mode,
+		          nlinks: 1,
+		          uid: 0,
+		          gid: 0,
+		          size,
+		          blocks: clusters * (g.cluster_size / 512),
+		          blksize: g.cluster_size,
+		          atime,
+		          mtime,
+		          ctime })
+	}
+
+	fn truncate(&self) -> Result<(), FsError> {
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			let mut entry = match self.entry(&g)? {
+				Some(entry) if !entry.is_dir() => entry,
+				_ => return Err(FsError::IsDirectory),
+			};
+			if entry.cluster() != 0 {
+				FatFileSystem::free_chain(self.bdev, &g, entry.cluster())?;
+			}
+			let (date, time) = from_unix(get_unix_time());
+			entry.set_cluster(0);
+			entry.size = 0;
+			entry.wrt_date = date;
+			entry.wrt_time = time;
+			self.put_entry(&g, &entry)
+		})
+	}
+
+	fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+		let g = self.geometry()?;
+		let dir = self.dir(&g)?;
+		let found = FatFileSystem::find(self.bdev, &g, dir, name)?;
+		Ok(self.node(dir, &found))
+	}
+
+	/// mode is ignored, other than that a file without any write bits is
+	/// made read-only.
+	fn create(&self, name: &str, mode: u16) -> Result<InodeRef, FsError> {
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			let dir = self.dir(&g)?;
+			let attr = if mode & 0o222 == 0 { ATTR_ARCHIVE | ATTR_READ_ONLY } else { ATTR_ARCHIVE };
+			let entry = FatFileSystem::new_entry(attr, 0);
+			let (first, index, ino) = FatFileSystem::add_entry(self.bdev, &g, dir, name, entry)?;
+			let found = FatFileSystem::find(self.bdev, &g, dir, name)?;
+			Ok(Arc::new(FatInode { bdev: self.bdev,
+			                       slot: Some(Slot { dir, first, index, short: found.entry.name }),
+			                       ino }))
+		})
+	}
+
+	fn mkdir(&self, name: &str, _mode: u16) -> Result<(), FsError> {
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			let dir = self.dir(&g)?;
+			FatFileSystem::mkdir(self.bdev, &g, dir, name)
+		})
+	}
+
+	fn unlink(&self, name: &str) -> Result<(), FsError> {
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			let dir = self.dir(&g)?;
+			FatFileSystem::remove(self.bdev, &g, dir, name, false)
+		})
+	}
+
+	fn rmdir(&self, name: &str) -> Result<(), FsError> {
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			let dir = self.dir(&g)?;
+			FatFileSystem::remove(self.bdev, &g, dir, name, true)
+		})
+	}
+
+	/// A file has exactly one directory entry, so there are no hard links.
+	fn link(&self, _name: &str, _target: &InodeRef) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn rename(&self, old_name: &str, new_dir: &InodeRef, new_name: &str) -> Result<(), FsError> {
+		if new_dir.dev() != self.bdev {
+			return Err(FsError::CrossDevice);
+		}
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			let dir = self.dir(&g)?;
+			// The VFS only has InodeRefs, so we find the other directory's
+			// cluster by its inode number: the root is 1, and anything else has
+			// its entry at the slot the number says.
+			let other = if new_dir.ino() == 1 {
+				g.root_cluster
+			}
+			else {
+				let entry: DirEntry = FatFileSystem::read_at(self.bdev, new_dir.ino() as u64 * DIR_ENTRY_SIZE as u64)?;
+				if !entry.is_dir() {
+					return Err(FsError::IsFile);
+				}
+				entry.cluster()
+			};
+			FatFileSystem::rename(self.bdev, &g, dir, old_name, other, new_name)
+		})
+	}
+
+	fn symlink(&self, _name: &str, _target: &str) -> Result<(), FsError> {
+		Err(FsError::NotPermitted)
+	}
+
+	fn readlink(&self) -> Result<String, FsError> {
+		Err(FsError::InvalidArgument)
+	}
+
+	/// The only permission FAT has is read-only, which goes with the
+	/// owner's write bit.
+	fn chmod(&self, mode: u16) -> Result<(), FsError> {
+		with_volume(self.bdev, || {
+			let g = self.geometry()?;
+			if let Some(mut entry) = self.entry(&g)? {
+				if mode & 0o200 == 0 {
+					entry.attr |= ATTR_READ_ONLY;
+				}
+				else {
+					entry.attr &= !ATTR_READ_ONLY;
+				}
+				self.put_entry(&g, &entry)?;
+			}
+			Ok(())
+		})
+	}
+
+	/// There are no owners to change. This still has to work, since the VFS
+	/// gives every new file to whoever made it.
+	fn chown(&self, _uid: u16, _gid: u16) -> Result<(), FsError> {
+		Ok(())
+	}
+
+	/// The offset of an entry is the slot its long name starts at. Every
+	/// directory but the root starts with . and .. in slots 0 and 1.
+	fn readdir(&self, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		let g = self.geometry()?;
+		let dir = self.dir(&g)?;
+		let mut ret = Vec::new();
+		if let Some(slot) = self.slot {
+			let parent = FatFileSystem::dir_ino(self.bdev, &g, slot.dir)?;
+			for (index, (name, ino)) in [(".", self.ino), ("..", parent)].iter().enumerate() {
+				if index as u32 >= offset {
+					ret.push(DirEnt { ino:  *ino,
+					                  kind: dirent_type(S_IFDIR),
+					                  name: String::from(*name),
+					                  next: index as u32 + 1 });
+				}
+			}
+		}
+		for found in FatFileSystem::scan_dir(self.bdev, &g, dir)? {
+			if found.first < offset {
+				continue;
+			}
+			let mode = if found.entry.is_dir() { S_IFDIR } else { S_IFREG };
+			ret.push(DirEnt { ino:  found.ino,
+			                  kind: dirent_type(mode),
+			                  name: found.name,
+			                  next: found.index + 1 });
+		}
+		Ok(ret)
+	}
+
+	fn sync(&self) -> Result<(), FsError> {
+		if bcache::sync(Some(self.bdev)) { Ok(()) } else { Err(FsError::Io) }
+	}
+}
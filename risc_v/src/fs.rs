@@ -3,74 +3,95 @@
 // Stephen Marz
 // 16 March 2020
 
-use crate::{cpu::Registers,
+use crate::{bcache,
+            block,
+            cpu::{get_unix_time, mstatus_read, mstatus_write, Registers, MSTATUS_MIE},
+            journal,
+            lock::{Mutex, DEFAULT_LOCK_SLEEP},
             process::{add_kernel_process_args, get_by_pid, set_running, set_waiting},
-            syscall::syscall_block_read};
+            syscall::syscall_sleep,
+            vfs::{dirent_type, DirEnt, File, FileSystem, FsError, Inode as VfsInode, InodeRef, Stat, DT_UNKNOWN}};
 
 use crate::{buffer::Buffer, cpu::memcpy};
-use alloc::{boxed::Box, collections::BTreeMap, string::String};
-use core::mem::size_of;
-
-pub const MAGIC: u16 = 0x4d5a;
-pub const BLOCK_SIZE: u32 = 1024;
-pub const NUM_IPTRS: usize = BLOCK_SIZE as usize / 4;
-pub const S_IFDIR: u16 = 0o040_000;
-pub const S_IFREG: u16 = 0o100_000;
-/// The superblock describes the file system on the disk. It gives
-/// us all the information we need to read the file system and navigate
-/// the file system, including where to find the inodes and zones (blocks).
-#[repr(C)]
-pub struct SuperBlock {
-	pub ninodes:         u32,
-	pub pad0:            u16,
-	pub imap_blocks:     u16,
-	pub zmap_blocks:     u16,
-	pub first_data_zone: u16,
-	pub log_zone_size:   u16,
-	pub pad1:            u16,
-	pub max_size:        u32,
-	pub zones:           u32,
-	pub magic:           u16,
-	pub pad2:            u16,
-	pub block_size:      u16,
-	pub disk_version:    u8
-}
+use alloc::{boxed::Box, collections::{BTreeMap, BTreeSet}, string::String, sync::Arc, vec::Vec};
+use core::{cmp::{max, min}, mem::size_of};
 
-/// An inode stores the "meta-data" to a file. The mode stores the permissions
-/// AND type of file. This is how we differentiate a directory from a file. A file
-/// size is in here too, which tells us how many blocks we need to read. Finally, the
-/// zones array points to where we can find the blocks, which is where the data
-/// is contained for the file.
-#[repr(C)]
-#[derive(Copy, Clone)]
-pub struct Inode {
-	pub mode:   u16,
-	pub nlinks: u16,
-	pub uid:    u16,
-	pub gid:    u16,
-	pub size:   u32,
-	pub atime:  u32,
-	pub mtime:  u32,
-	pub ctime:  u32,
-	pub zones:  [u32; 10]
+pub use crate::minix::{DirEntry, Inode, SuperBlock, BLOCK_SIZE, JOURNAL_BLOCKS, JOURNAL_NAME, MAGIC, NUM_IPTRS};
+pub use crate::minix::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT, S_IFREG, S_ISGID, S_ISUID};
+
+// Writes are broken up into pieces of this many bytes, each its own
+// transaction, so a big one doesn't outgrow the journal.
+const WRITE_CHUNK: u32 = 64 * BLOCK_SIZE;
+// The most blocks one operation can journal other than the maps: inodes,
+// directory blocks and indirect zones. A rename that replaces something,
+// or a write that crosses into the next level of indirect zones, comes
+// closest.
+const OP_BLOCKS: usize = 16;
+
+// How many MinixInodes there are for each (device, inode). A descriptor
+// holds one for as long as the file is open.
+static mut OPEN_INODES: Option<BTreeMap<(usize, u32), usize>> = None;
+// Inodes whose last link went away while they were still open. They keep
+// their zones until the last MinixInode for them is dropped, and then the
+// next change to the disk frees them. MinixInodes are dropped when a
+// process closes a file or dies, which happens in the trap handler, so
+// this is a spin lock and nothing under it touches the disk. Kernel
+// processes take it too, and the timer can interrupt them, so interrupts
+// are off while anyone holds it. Otherwise the trap handler could spin on
+// it forever while the process holding it never gets to run again.
+static mut ORPHANS: Option<BTreeSet<(usize, u32)>> = None;
+static mut OPEN_INODES_LOCK: Mutex = Mutex::new();
+
+fn with_open_inodes<T>(f: impl FnOnce(&mut BTreeMap<(usize, u32), usize>, &mut BTreeSet<(usize, u32)>) -> T) -> T {
+	let mstatus = mstatus_read();
+	mstatus_write(mstatus & !MSTATUS_MIE);
+	let ret = unsafe {
+		OPEN_INODES_LOCK.spin_lock();
+		let ret = f(OPEN_INODES.get_or_insert_with(BTreeMap::new), ORPHANS.get_or_insert_with(BTreeSet::new));
+		OPEN_INODES_LOCK.unlock();
+		ret
+	};
+	mstatus_write(mstatus);
+	ret
 }
 
-/// Notice that an inode does not contain the name of a file. This is because
-/// more than one file name may refer to the same inode. These are called "hard links"
-/// Instead, a DirEntry essentially associates a file name with an inode as shown in
-/// the structure below.
-#[repr(C)]
-pub struct DirEntry {
-	pub inode: u32,
-	pub name:  [u8; 60]
+// Devices whose bitmaps someone is changing right now. Setting or clearing
+// a bit is a read, a search and a write of the map block, and each of those
+// can sleep, so two processes working on the same device could both hand
+// out the same bit. One process at a time gets a device's bitmaps, and the
+// rest wait their turn.
+static mut BITMAPS_BUSY: Option<BTreeSet<usize>> = None;
+static mut BITMAPS_LOCK: Mutex = Mutex::new();
+
+/// Run f with the inode and zone maps of bdev to ourselves.
+fn with_bitmaps<T>(bdev: usize, f: impl FnOnce() -> T) -> T {
+	loop {
+		let got = unsafe {
+			BITMAPS_LOCK.sleep_lock();
+			let got = BITMAPS_BUSY.get_or_insert_with(BTreeSet::new).insert(bdev);
+			BITMAPS_LOCK.unlock();
+			got
+		};
+		if got {
+			break;
+		}
+		syscall_sleep(DEFAULT_LOCK_SLEEP);
+	}
+	let ret = f();
+	unsafe {
+		BITMAPS_LOCK.sleep_lock();
+		BITMAPS_BUSY.get_or_insert_with(BTreeSet::new).remove(&bdev);
+		BITMAPS_LOCK.unlock();
+	}
+	ret
 }
 
-/// The MinixFileSystem implements the FileSystem trait for the VFS.
-pub struct MinixFileSystem;
-// The plan for this in the future is to have a single inode cache. What we
-// will do is have a cache of Node structures which will combine the Inode
-// with the block drive.
-static mut MFS_INODE_CACHE: [Option<BTreeMap<String, Inode>>; 8] = [None, None, None, None, None, None, None, None];
+/// The MinixFileSystem implements the FileSystem trait for the VFS. Most of
+/// the work is done by associated functions that take the block device, so
+/// the struct only has to remember which device it is on.
+pub struct MinixFileSystem {
+	bdev: usize
+}
 
 impl MinixFileSystem {
 	/// Inodes are the meta-data of a file, including the mode (permissions and type) and
@@ -124,311 +145,1050 @@ impl MinixFileSystem {
 }
 
 impl MinixFileSystem {
-	/// Init is where we would cache the superblock and inode to avoid having to read
-	/// it over and over again, like we do for read right now.
-	fn cache_at(btm: &mut BTreeMap<String, Inode>, cwd: &String, inode_num: u32, bdev: usize) {
-		let ino = Self::get_inode(bdev, inode_num).unwrap();
-		let mut buf = Buffer::new(((ino.size + BLOCK_SIZE - 1) & !BLOCK_SIZE) as usize);
-		let dirents = buf.get() as *const DirEntry;
-		let sz = Self::read(bdev, &ino, buf.get_mut(), BLOCK_SIZE, 0);
-		let num_dirents = sz as usize / size_of::<DirEntry>();
-		// We start at 2 because the first two entries are . and ..
-		for i in 2..num_dirents {
+	/// Read size bytes of the file at offset into buffer. Nothing past the end
+	/// of the file is read, so this returns how many bytes there were. A zone
+	/// pointer of 0, at any level, is a hole in the file: nothing was ever
+	/// written there, so it reads as zeroes and takes no space on the disk.
+	pub fn read(bdev: usize, inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
+		if offset >= inode.size {
+			return 0;
+		}
+		let super_block = match Self::get_super_block(bdev) {
+			Some(sb) => sb,
+			None => return 0,
+		};
+		// First, the size parameter is the size of the buffer, not necessarily
+		// the size of the file. We can only read up to whichever ends first.
+		let size = min(size, inode.size - offset);
+		// get_zone() can allocate, so it wants to be able to change the
+		// inode. We never let it, but it gets a copy anyway.
+		let mut inode = *inode;
+		// Even if we want 10 bytes, we have to read the entire block first. So,
+		// we use the block_buffer as the middle man, which is then copied into
+		// the buffer.
+		let mut block_buffer = Buffer::new(BLOCK_SIZE as usize);
+		let mut bytes_read = 0u32;
+		// We go block by block starting with the one the offset is in. Only
+		// the first block starts partway through, at offset_byte.
+		let mut block = offset / BLOCK_SIZE;
+		let mut offset_byte = offset % BLOCK_SIZE;
+		while bytes_read < size {
+			// get_zone() goes through the direct zones and the singly,
+			// doubly and triply indirect ones, and gives back 0 for a hole
+			// at any of them.
+			let zone = match Self::get_zone(bdev, &super_block, &mut inode, block, false) {
+				Ok(z) => z,
+				Err(_) => break,
+			};
+			let read_this_many = min(BLOCK_SIZE - offset_byte, size - bytes_read);
 			unsafe {
-				let ref d = *dirents.add(i);
-				let d_ino = Self::get_inode(bdev, d.inode).unwrap();
-				let mut new_cwd = String::with_capacity(120);
-				for i in cwd.bytes() {
-					new_cwd.push(i as char);
+				let dest = buffer.add(bytes_read as usize);
+				if zone == 0 {
+					for i in 0..read_this_many as usize {
+						dest.add(i).write(0);
+					}
 				}
-				// Add a directory separator between this inode and the next.
-				// If we're the root (inode 1), we don't want to double up the
-				// frontslash, so only do it for non-roots.
-				if inode_num != 1 {
-					new_cwd.push('/');
+				else {
+					syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone * BLOCK_SIZE);
+					memcpy(dest, block_buffer.get().add(offset_byte as usize), read_this_many as usize);
 				}
-				for i in 0..60 {
-					if d.name[i] == 0 {
-						break;
+			}
+			offset_byte = 0;
+			bytes_read += read_this_many;
+			block += 1;
+		}
+		bytes_read
+	}
+
+	/// Write size bytes from buffer into the file at offset. Any zones the file
+	/// doesn't have yet are allocated from the zone map, including the indirect
+	/// zones needed to point to them. The inode's size and mtime are updated and
+	/// written back. Like read, this blocks, so it can only run in a process.
+	/// Returns the number of bytes written, which is short if the disk fills up.
+	/// The contents of regular files aren't journaled, but those of
+	/// directories and symbolic links are metadata, so they are.
+	pub fn write(bdev: usize, inode_num: u32, inode: &mut Inode, buffer: *const u8, size: u32, offset: u32) -> u32 {
+		let super_block = match Self::get_super_block(bdev) {
+			Some(sb) => sb,
+			None => return 0,
+		};
+		let journaled = inode.mode & S_IFMT != S_IFREG;
+		let mut block_buffer = Buffer::new(BLOCK_SIZE as usize);
+		let mut bytes_written = 0u32;
+		let mut offset_byte = offset % BLOCK_SIZE;
+		// Only the blocks we write to get zones. Writing past the end leaves
+		// the blocks in between as holes, which read() gives back as zeroes.
+		let mut block = offset / BLOCK_SIZE;
+		while bytes_written < size {
+			let zone = match Self::get_zone(bdev, &super_block, inode, block, true) {
+				Ok(z) => z,
+				Err(_) => break,
+			};
+			let write_this_many = min(BLOCK_SIZE - offset_byte, size - bytes_written);
+			// If we don't cover the whole block, we have to keep what's
+			// already in the rest of it. A zone that was a hole until now
+			// comes zeroed from alloc_zone().
+			if write_this_many != BLOCK_SIZE {
+				syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone * BLOCK_SIZE);
+			}
+			unsafe {
+				memcpy(block_buffer.get_mut().add(offset_byte as usize), buffer.add(bytes_written as usize), write_this_many as usize);
+			}
+			if journaled {
+				syc_write(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone * BLOCK_SIZE);
+			}
+			else {
+				syc_write_data(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone * BLOCK_SIZE);
+			}
+			offset_byte = 0;
+			bytes_written += write_this_many;
+			block += 1;
+		}
+		if bytes_written > 0 && offset + bytes_written > inode.size {
+			inode.size = offset + bytes_written;
+		}
+		inode.mtime = get_unix_time();
+		inode.ctime = inode.mtime;
+		Self::put_inode(bdev, inode_num, inode);
+		bytes_written
+	}
+
+	/// Read the superblock, which sits right after the boot block.
+	pub fn get_super_block(bdev: usize) -> Option<SuperBlock> {
+		let mut buffer = Buffer::new(512);
+		syc_read(bdev, buffer.get_mut(), 512, 1024);
+		let super_block = unsafe { *(buffer.get() as *const SuperBlock) };
+		if super_block.magic == MAGIC {
+			Some(super_block)
+		}
+		else {
+			None
+		}
+	}
+
+	/// Write an inode back to the inode table. Inodes are smaller than a sector,
+	/// so we have to read the block the inode is in, change our inode, and write
+	/// the whole block back.
+	pub fn put_inode(bdev: usize, inode_num: u32, inode: &Inode) -> bool {
+		let super_block = match Self::get_super_block(bdev) {
+			Some(sb) => sb,
+			None => return false,
+		};
+		let inodes_per_block = BLOCK_SIZE as usize / size_of::<Inode>();
+		let inode_offset = (2 + super_block.imap_blocks + super_block.zmap_blocks) as usize * BLOCK_SIZE as usize
+		                   + ((inode_num as usize - 1) / inodes_per_block) * BLOCK_SIZE as usize;
+		let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+		syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, inode_offset as u32);
+		unsafe {
+			let inodes = buffer.get_mut() as *mut Inode;
+			inodes.add((inode_num as usize - 1) % inodes_per_block).write(*inode);
+		}
+		syc_write(bdev, buffer.get_mut(), BLOCK_SIZE, inode_offset as u32);
+		true
+	}
+
+	/// Find a clear bit in a bitmap (the inode map or the zone map) that starts at
+	/// block map_start and is map_blocks long, and set it. Bit 0 is reserved in
+	/// both maps. Only bits below limit can be handed out, and clear bits that
+	/// skip says no to are passed over. The bitmaps are ours until we're done,
+	/// skip included.
+	fn alloc_bit(bdev: usize, map_start: u32, map_blocks: u32, limit: u32, skip: impl Fn(u32) -> bool) -> Result<u32, FsError> {
+		with_bitmaps(bdev, || Self::find_bit(bdev, map_start, map_blocks, limit, skip))
+	}
+
+	fn find_bit(bdev: usize, map_start: u32, map_blocks: u32, limit: u32, skip: impl Fn(u32) -> bool) -> Result<u32, FsError> {
+		let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+		let bits_per_block = BLOCK_SIZE * 8;
+		for map_block in 0..map_blocks {
+			let map_offset = (map_start + map_block) * BLOCK_SIZE;
+			syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, map_offset);
+			for byte in 0..BLOCK_SIZE as usize {
+				// mkfs should have set the reserved bit, but don't hand it
+				// out if it didn't.
+				let taken = if map_block == 0 && byte == 0 { buffer[byte] | 1 } else { buffer[byte] };
+				if taken == 0xff {
+					continue;
+				}
+				for bit in 0..8 {
+					if taken & (1 << bit) != 0 {
+						continue;
 					}
-					new_cwd.push(d.name[i] as char);
+					let n = map_block * bits_per_block + byte as u32 * 8 + bit;
+					if n >= limit {
+						return Err(FsError::NoSpace);
+					}
+					if skip(n) {
+						continue;
+					}
+					buffer[byte] |= 1 << bit;
+					syc_write(bdev, buffer.get_mut(), BLOCK_SIZE, map_offset);
+					return Ok(n);
 				}
-				new_cwd.shrink_to_fit();
-				if d_ino.mode & S_IFDIR != 0 {
-					// This is a directory, cache these. This is a recursive call,
-					// which I don't really like.
-					Self::cache_at(btm, &new_cwd, d.inode, bdev);
+			}
+		}
+		Err(FsError::NoSpace)
+	}
+
+	/// Clear bit n in the bitmap that starts at block map_start.
+	fn free_bit(bdev: usize, map_start: u32, n: u32) {
+		let bits_per_block = BLOCK_SIZE * 8;
+		let map_offset = (map_start + n / bits_per_block) * BLOCK_SIZE;
+		let byte = (n % bits_per_block) as usize / 8;
+		let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+		with_bitmaps(bdev, || {
+			syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, map_offset);
+			buffer[byte] &= !(1 << (n % 8));
+			syc_write(bdev, buffer.get_mut(), BLOCK_SIZE, map_offset);
+		});
+	}
+
+	/// Find a free zone in the zone map, mark it taken, and zero it. Bit n of the
+	/// zone map is zone first_data_zone + n - 1.
+	fn alloc_zone(bdev: usize, super_block: &SuperBlock) -> Result<u32, FsError> {
+		let zmap_start = 2 + super_block.imap_blocks as u32;
+		let limit = super_block.zones - super_block.first_data_zone as u32 + 1;
+		let first_data_zone = super_block.first_data_zone as u32;
+		// A zone freed by the running transaction still belongs to its old
+		// file until that commits. File data isn't journaled, so if we gave
+		// it out now, a crash could leave the old file with the new one's
+		// data.
+		let n = Self::alloc_bit(bdev, zmap_start, super_block.zmap_blocks as u32, limit, |n| {
+			journal::is_freed(bdev, first_data_zone + n - 1)
+		})?;
+		let zone = first_data_zone + n - 1;
+		// New zones are handed out zeroed. Indirect zones need this since a
+		// zero pointer means no zone. They're journaled as a whole when the
+		// first pointer goes in, so the zeroing itself doesn't have to be.
+		let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+		for i in 0..BLOCK_SIZE as usize {
+			buffer[i] = 0;
+		}
+		syc_write_data(bdev, buffer.get_mut(), BLOCK_SIZE, zone * BLOCK_SIZE);
+		Ok(zone)
+	}
+
+	fn free_zone(bdev: usize, super_block: &SuperBlock, zone: u32) {
+		// The journal hears about it first, so alloc_zone() never sees the
+		// bit clear without also knowing to pass it over.
+		journal::freed(bdev, zone);
+		Self::free_bit(bdev, 2 + super_block.imap_blocks as u32, zone - super_block.first_data_zone as u32 + 1);
+	}
+
+	/// Inode numbers start at 1, so bit n of the inode map is inode n.
+	fn alloc_inode(bdev: usize, super_block: &SuperBlock) -> Result<u32, FsError> {
+		Self::alloc_bit(bdev, 2, super_block.imap_blocks as u32, super_block.ninodes + 1, |_| false)
+	}
+
+	fn free_inode(bdev: usize, inode_num: u32) {
+		Self::free_bit(bdev, 2, inode_num);
+	}
+
+	/// Free a zone and, if it is an indirect zone (levels > 0), every zone it
+	/// points to.
+	fn free_zone_tree(bdev: usize, super_block: &SuperBlock, zone: u32, levels: usize) {
+		if levels > 0 {
+			let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+			syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, zone * BLOCK_SIZE);
+			let pointers = buffer.get() as *const u32;
+			for i in 0..NUM_IPTRS {
+				let child = unsafe { pointers.add(i).read() };
+				if child != 0 {
+					Self::free_zone_tree(bdev, super_block, child, levels - 1);
 				}
-				else {
-					btm.insert(new_cwd, d_ino);
+			}
+		}
+		Self::free_zone(bdev, super_block, zone);
+	}
+
+	/// How many zones the tree under zone takes, counting the indirect
+	/// zones themselves.
+	fn count_zone_tree(bdev: usize, zone: u32, levels: usize) -> u32 {
+		let mut count = 1;
+		if levels > 0 {
+			let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+			syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, zone * BLOCK_SIZE);
+			let pointers = buffer.get() as *const u32;
+			for i in 0..NUM_IPTRS {
+				let child = unsafe { pointers.add(i).read() };
+				if child != 0 {
+					count += Self::count_zone_tree(bdev, child, levels - 1);
 				}
 			}
 		}
+		count
+	}
+
+	/// How many zones a file has on disk. This is what stat reports as
+	/// blocks, which may be less than the size says if the file has holes.
+	pub fn zone_count(bdev: usize, inode: &Inode) -> u32 {
+		let mut count = 0;
+		for i in 0..10 {
+			if inode.zones[i] != 0 {
+				let levels = if i < 7 { 0 } else { i - 6 };
+				count += Self::count_zone_tree(bdev, inode.zones[i], levels);
+			}
+		}
+		count
 	}
 
-	// Run this ONLY in a process!
-	pub fn init(bdev: usize) {
-		if unsafe { MFS_INODE_CACHE[bdev - 1].is_none() } {
-			let mut btm = BTreeMap::new();
-			let cwd = String::from("/");
+	/// Give all of a file's zones back to the zone map and set its size to 0.
+	/// The caller writes the inode back.
+	pub fn truncate(bdev: usize, inode: &mut Inode) {
+		let super_block = match Self::get_super_block(bdev) {
+			Some(sb) => sb,
+			None => return,
+		};
+		for i in 0..10 {
+			if inode.zones[i] != 0 {
+				// zones[7..=9] are 1, 2, and 3 levels of indirection.
+				let levels = if i < 7 { 0 } else { i - 6 };
+				Self::free_zone_tree(bdev, &super_block, inode.zones[i], levels);
+				inode.zones[i] = 0;
+			}
+		}
+		inode.size = 0;
+		inode.mtime = get_unix_time();
+		inode.ctime = inode.mtime;
+	}
 
-			// Let's look at the root (inode #1)
-			Self::cache_at(&mut btm, &cwd, 1, bdev);
-			unsafe {
-				MFS_INODE_CACHE[bdev - 1] = Some(btm);
+	/// Get the zone that holds the given block of a file. If the file doesn't have
+	/// one and allocate is true, a zone is allocated and put in the inode (or one
+	/// of its indirect zones). Otherwise, a missing zone comes back as 0.
+	fn get_zone(bdev: usize, super_block: &SuperBlock, inode: &mut Inode, block: u32, allocate: bool) -> Result<u32, FsError> {
+		let mut block = block as usize;
+		if block < 7 {
+			if inode.zones[block] == 0 && allocate {
+				inode.zones[block] = Self::alloc_zone(bdev, super_block)?;
 			}
+			return Ok(inode.zones[block]);
 		}
-		else {
-			println!("KERNEL: Initialized an already initialized filesystem {}", bdev);
+		block -= 7;
+		// zones[7] is singly indirect, zones[8] doubly, and zones[9] triply.
+		let mut covered = NUM_IPTRS;
+		for levels in 1..=3 {
+			if block < covered {
+				return Self::get_indirect_zone(bdev, super_block, &mut inode.zones[6 + levels], block, levels, allocate);
+			}
+			block -= covered;
+			covered *= NUM_IPTRS;
 		}
+		Err(FsError::NoSpace)
 	}
 
-	/// The goal of open is to traverse the path given by path. If we cache the inodes
-	/// in RAM, it might make this much quicker. For now, this doesn't do anything since
-	/// we're just testing read based on if we know the Inode we're looking for.
-	pub fn open(bdev: usize, path: &str) -> Result<Inode, FsError> {
-		if let Some(cache) = unsafe { MFS_INODE_CACHE[bdev - 1].take() } {
-			let ret;
-			if let Some(inode) = cache.get(path) {
-				ret = Ok(*inode);
+	/// Look up index in the tree of pointer blocks at *table_zone, which is levels
+	/// deep. If allocate is true, missing pointer blocks and the data zone at the
+	/// bottom are allocated and linked in on the way down.
+	fn get_indirect_zone(bdev: usize,
+	                     super_block: &SuperBlock,
+	                     table_zone: &mut u32,
+	                     index: usize,
+	                     levels: usize,
+	                     allocate: bool)
+	                     -> Result<u32, FsError>
+	{
+		if *table_zone == 0 {
+			if !allocate {
+				return Ok(0);
 			}
-			else {
-				ret = Err(FsError::FileNotFound);
+			*table_zone = Self::alloc_zone(bdev, super_block)?;
+		}
+		let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+		syc_read(bdev, buffer.get_mut(), BLOCK_SIZE, *table_zone * BLOCK_SIZE);
+		let pointers = buffer.get_mut() as *mut u32;
+		// Each pointer at this level covers this many data zones.
+		let per_pointer = NUM_IPTRS.pow(levels as u32 - 1);
+		let slot = index / per_pointer;
+		let old_zone = unsafe { pointers.add(slot).read() };
+		let mut zone = old_zone;
+		let ret = if levels == 1 {
+			if zone == 0 && allocate {
+				zone = Self::alloc_zone(bdev, super_block)?;
 			}
+			zone
+		}
+		else {
+			Self::get_indirect_zone(bdev, super_block, &mut zone, index % per_pointer, levels - 1, allocate)?
+		};
+		if zone != old_zone {
 			unsafe {
-				MFS_INODE_CACHE[bdev - 1].replace(cache);
+				pointers.add(slot).write(zone);
 			}
-			ret
+			syc_write(bdev, buffer.get_mut(), BLOCK_SIZE, *table_zone * BLOCK_SIZE);
 		}
-		else {
-			Err(FsError::FileNotFound)
+		Ok(ret)
+	}
+
+	/// Read every entry of a directory. Returns the buffer and how many entries
+	/// are in it. Entries with inode 0 are free slots.
+	fn read_dir(bdev: usize, dir: &Inode) -> (Buffer, usize) {
+		let mut buffer = Buffer::new(max(dir.size, BLOCK_SIZE) as usize);
+		let sz = Self::read(bdev, dir, buffer.get_mut(), dir.size, 0);
+		(buffer, sz as usize / size_of::<DirEntry>())
+	}
+
+	/// Find name in a directory. Gives back the index of the entry and the inode
+	/// number it points to.
+	fn find_entry(bdev: usize, dir: &Inode, name: &str) -> Result<(usize, u32), FsError> {
+		let (buffer, num_dirents) = Self::read_dir(bdev, dir);
+		let dirents = buffer.get() as *const DirEntry;
+		for i in 0..num_dirents {
+			let d = unsafe { &*dirents.add(i) };
+			if d.inode != 0 && d.name() == name.as_bytes() {
+				return Ok((i, d.inode));
+			}
 		}
+		Err(FsError::FileNotFound)
 	}
 
-	pub fn read(bdev: usize, inode: &Inode, buffer: *mut u8, size: u32, offset: u32) -> u32 {
-		// Our strategy here is to use blocks to see when we need to start reading
-		// based on the offset. That's offset_block. Then, the actual byte within
-		// that block that we need is offset_byte.
-		let mut blocks_seen = 0u32;
-		let offset_block = offset / BLOCK_SIZE;
-		let mut offset_byte = offset % BLOCK_SIZE;
-		// First, the _size parameter (now in bytes_left) is the size of the buffer, not
-		// necessarily the size of the file. If our buffer is bigger than the file, we're OK.
-		// If our buffer is smaller than the file, then we can only read up to the buffer size.
-		let mut bytes_left = if size > inode.size {
-			inode.size
+	/// Put a new entry in a directory. The first free slot is reused. If there
+	/// isn't one, the directory grows by one entry.
+	fn add_entry(bdev: usize, dir_num: u32, dir: &mut Inode, name: &str, inode_num: u32) -> Result<(), FsError> {
+		let (buffer, num_dirents) = Self::read_dir(bdev, dir);
+		let dirents = buffer.get() as *const DirEntry;
+		let slot = (0..num_dirents).find(|i| unsafe { (*dirents.add(*i)).inode == 0 }).unwrap_or(num_dirents);
+		Self::write_entry(bdev, dir_num, dir, slot, &DirEntry::new(inode_num, name))
+	}
+
+	/// Clear the entry at index in a directory. Its slot is reused by the next
+	/// add_entry.
+	fn remove_entry(bdev: usize, dir_num: u32, dir: &mut Inode, index: usize) -> Result<(), FsError> {
+		Self::write_entry(bdev, dir_num, dir, index, &DirEntry::new(0, ""))
+	}
+
+	fn write_entry(bdev: usize, dir_num: u32, dir: &mut Inode, index: usize, entry: &DirEntry) -> Result<(), FsError> {
+		let sz = size_of::<DirEntry>() as u32;
+		let written = Self::write(bdev, dir_num, dir, entry as *const DirEntry as *const u8, sz, index as u32 * sz);
+		if written == sz {
+			Ok(())
 		}
 		else {
-			size
-		};
-		let mut bytes_read = 0u32;
-		// The block buffer automatically drops when we quit early due to an error or we've read enough. This will be the holding port when we go out and read a block. Recall that even if we want 10 bytes, we have to read the entire block (really only 512 bytes of the block) first. So, we use the block_buffer as the middle man, which is then copied into the buffer.
-		let mut block_buffer = Buffer::new(BLOCK_SIZE as usize);
-		// Triply indirect zones point to a block of pointers (BLOCK_SIZE / 4). Each one of those pointers points to another block of pointers (BLOCK_SIZE / 4). Each one of those pointers yet again points to another block of pointers (BLOCK_SIZE / 4). This is why we have indirect, iindirect (doubly), and iiindirect (triply).
-		let mut indirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-		let mut iindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-		let mut iiindirect_buffer = Buffer::new(BLOCK_SIZE as usize);
-		// I put the pointers *const u32 here. That means we will allocate the indirect, doubly indirect, and triply indirect even for small files. I initially had these in their respective scopes, but that required us to recreate the indirect buffer for doubly indirect and both the indirect and doubly indirect buffers for the triply indirect. Not sure which is better, but I probably wasted brain cells on this.
-		let izones = indirect_buffer.get() as *const u32;
-		let iizones = iindirect_buffer.get() as *const u32;
-		let iiizones = iiindirect_buffer.get() as *const u32;
-
-		// ////////////////////////////////////////////
-		// // DIRECT ZONES
-		// ////////////////////////////////////////////
-		// In Rust, our for loop automatically "declares" i from 0 to < 7. The syntax
-		// 0..7 means 0 through to 7 but not including 7. If we want to include 7, we
-		// would use the syntax 0..=7.
-		for i in 0..7 {
-			// There are 7 direct zones in the Minix 3 file system. So, we can just read them one by one. Any zone that has the value 0 is skipped and we check the next zones. This might happen as we start writing and truncating.
-			if inode.zones[i] == 0 {
+			Err(FsError::NoSpace)
+		}
+	}
+
+	/// A directory is empty if it has nothing other than . and ..
+	fn is_empty_dir(bdev: usize, dir: &Inode) -> bool {
+		let (buffer, num_dirents) = Self::read_dir(bdev, dir);
+		let dirents = buffer.get() as *const DirEntry;
+		(2..num_dirents).all(|i| unsafe { (*dirents.add(i)).inode == 0 })
+	}
+
+	/// Names can't be empty, contain a /, or be . or .., and they have to fit
+	/// in a DirEntry.
+	fn check_name(name: &str) -> Result<(), FsError> {
+		if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+			return Err(FsError::InvalidArgument);
+		}
+		if name.len() > 60 {
+			return Err(FsError::NameTooLong);
+		}
+		Ok(())
+	}
+
+	fn get_dir(bdev: usize, dir_num: u32) -> Result<Inode, FsError> {
+		let dir = Self::get_inode(bdev, dir_num).ok_or(FsError::FileNotFound)?;
+		if dir.mode & S_IFMT != S_IFDIR {
+			return Err(FsError::IsFile);
+		}
+		Ok(dir)
+	}
+
+	/// The entries of directory dir_num from entry number offset on. The
+	/// offset of an entry is its slot in the directory, so removing other
+	/// entries doesn't move it. Free slots are skipped.
+	pub fn readdir(bdev: usize, dir_num: u32, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		let dir = Self::get_dir(bdev, dir_num)?;
+		let (buffer, num_dirents) = Self::read_dir(bdev, &dir);
+		let dirents = buffer.get() as *const DirEntry;
+		let mut ret = Vec::new();
+		for i in offset as usize..num_dirents {
+			let d = unsafe { &*dirents.add(i) };
+			if d.inode == 0 {
 				continue;
 			}
-			// We really use this to keep track of when we need to actually start reading
-			// But an if statement probably takes more time than just incrementing it.
-			if offset_block <= blocks_seen {
-				// If we get here, then our offset is within our window that we want to see.
-				// We need to go to the direct pointer's index. That'll give us a block INDEX.
-				// That makes it easy since all we have to do is multiply the block size
-				// by whatever we get. If it's 0, we skip it and move on.
-				let zone_offset = inode.zones[i] * BLOCK_SIZE;
-				// We read the zone, which is where the data is located. The zone offset is simply the block
-				// size times the zone number. This makes it really easy to read!
-				syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, zone_offset);
-
-				// There's a little bit of math to see how much we need to read. We don't want to read
-				// more than the buffer passed in can handle, and we don't want to read if we haven't
-				// taken care of the offset. For example, an offset of 10000 with a size of 2 means we
-				// can only read bytes 10,000 and 10,001.
-				let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-					bytes_left
-				}
-				else {
-					BLOCK_SIZE - offset_byte
-				};
-				// Once again, here we actually copy the bytes into the final destination, the buffer. This memcpy
-				// is written in cpu.rs.
-				unsafe {
-					memcpy(buffer.add(bytes_read as usize), block_buffer.get().add(offset_byte as usize), read_this_many as usize);
-				}
-				// Regardless of whether we have an offset or not, we reset the offset byte back to 0. This
-				// probably will get set to 0 many times, but who cares?
-				offset_byte = 0;
-				// Reset the statistics to see how many bytes we've read versus how many are left.
-				bytes_read += read_this_many;
-				bytes_left -= read_this_many;
-				// If no more bytes are left, then we're done.
-				if bytes_left == 0 {
-					return bytes_read;
-				}
+			let kind = match Self::get_inode(bdev, d.inode) {
+				Some(inode) => dirent_type(inode.mode),
+				None => DT_UNKNOWN,
+			};
+			ret.push(DirEnt { ino: d.inode,
+			                  kind,
+			                  name: String::from_utf8_lossy(d.name()).into_owned(),
+			                  next: i as u32 + 1 });
+		}
+		Ok(ret)
+	}
+
+	/// Find name in the directory dir_num and give back its inode number.
+	pub fn lookup(bdev: usize, dir_num: u32, name: &str) -> Result<u32, FsError> {
+		let dir = Self::get_dir(bdev, dir_num)?;
+		Ok(Self::find_entry(bdev, &dir, name)?.1)
+	}
+
+	/// Make a new inode with one link and no zones, and write it out.
+	fn new_inode(bdev: usize, mode: u16) -> Result<(u32, Inode), FsError> {
+		let super_block = Self::get_super_block(bdev).ok_or(FsError::FileNotFound)?;
+		let inode_num = Self::alloc_inode(bdev, &super_block)?;
+		let now = get_unix_time();
+		let inode = Inode { mode,
+		                    nlinks: 1,
+		                    uid: 0,
+		                    gid: 0,
+		                    size: 0,
+		                    atime: now,
+		                    mtime: now,
+		                    ctime: now,
+		                    zones: [0; 10] };
+		Self::put_inode(bdev, inode_num, &inode);
+		Ok((inode_num, inode))
+	}
+
+	/// Give back an inode whose last link is gone, along with its zones.
+	fn release_inode(bdev: usize, inode_num: u32, inode: &mut Inode) {
+		Self::truncate(bdev, inode);
+		inode.nlinks = 0;
+		Self::put_inode(bdev, inode_num, inode);
+		Self::free_inode(bdev, inode_num);
+	}
+
+	/// The last link to inode_num was just removed. If someone still has it
+	/// open, reads and writes through their descriptor still have to work,
+	/// so the inode is only written out with no links and released later
+	/// by reap_orphans().
+	fn unlinked_inode(bdev: usize, inode_num: u32, inode: &mut Inode) {
+		let open = with_open_inodes(|open, orphans| {
+			let open = open.get(&(bdev, inode_num)).map_or(false, |count| *count > 0);
+			if open {
+				orphans.insert((bdev, inode_num));
 			}
-			// The blocks_seen is for the offset. We need to skip a certain number of blocks FIRST before getting
-			// to the offset. The reason we need to read the zones is because we need to skip zones of 0, and they
-			// do not contribute as a "seen" block.
-			blocks_seen += 1;
-		}
-		// ////////////////////////////////////////////
-		// // SINGLY INDIRECT ZONES
-		// ////////////////////////////////////////////
-		// Each indirect zone is a list of pointers, each 4 bytes. These then
-		// point to zones where the data can be found. Just like with the direct zones,
-		// we need to make sure the zone isn't 0. A zone of 0 means skip it.
-		if inode.zones[7] != 0 {
-			syc_read(bdev, indirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * inode.zones[7]);
-			let izones = indirect_buffer.get() as *const u32;
-			for i in 0..NUM_IPTRS {
-				// Where do I put unsafe? Dereferencing the pointers and memcpy are the unsafe functions.
-				unsafe {
-					if izones.add(i).read() != 0 {
-						if offset_block <= blocks_seen {
-							syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * izones.add(i).read());
-							let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-								bytes_left
-							}
-							else {
-								BLOCK_SIZE - offset_byte
-							};
-							memcpy(buffer.add(bytes_read as usize), block_buffer.get().add(offset_byte as usize), read_this_many as usize);
-							bytes_read += read_this_many;
-							bytes_left -= read_this_many;
-							offset_byte = 0;
-							if bytes_left == 0 {
-								return bytes_read;
-							}
-						}
-						blocks_seen += 1;
-					}
+			open
+		});
+		if open {
+			inode.nlinks = 0;
+			inode.ctime = get_unix_time();
+			Self::put_inode(bdev, inode_num, inode);
+		}
+		else {
+			Self::release_inode(bdev, inode_num, inode);
+		}
+	}
+
+	/// Release the inodes on bdev that were unlinked while open and have
+	/// since been closed. This can't be part of someone else's transaction,
+	/// so call it before joining one.
+	fn reap_orphans(bdev: usize) {
+		let closed: Vec<u32> = with_open_inodes(|open, orphans| {
+			let closed: Vec<(usize, u32)> = orphans.iter()
+			                                       .filter(|(dev, num)| *dev == bdev && !open.contains_key(&(*dev, *num)))
+			                                       .cloned()
+			                                       .collect();
+			for key in closed.iter() {
+				orphans.remove(key);
+			}
+			closed.into_iter().map(|(_, num)| num).collect()
+		});
+		for inode_num in closed {
+			let _handle = journal::begin(bdev);
+			if let Some(mut inode) = Self::get_inode(bdev, inode_num) {
+				if inode.nlinks == 0 {
+					Self::release_inode(bdev, inode_num, &mut inode);
 				}
 			}
 		}
-		// ////////////////////////////////////////////
-		// // DOUBLY INDIRECT ZONES
-		// ////////////////////////////////////////////
-		if inode.zones[8] != 0 {
-			syc_read(bdev, indirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * inode.zones[8]);
-			unsafe {
-				for i in 0..NUM_IPTRS {
-					if izones.add(i).read() != 0 {
-						syc_read(bdev, iindirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * izones.add(i).read());
-						for j in 0..NUM_IPTRS {
-							if iizones.add(j).read() != 0 {
-								// Notice that this inner code is the same for all end-zone pointers. I'm thinking about
-								// moving this out of here into a function of its own, but that might make it harder
-								// to follow.
-								if offset_block <= blocks_seen {
-									syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * iizones.add(j).read());
-									let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-										bytes_left
-									}
-									else {
-										BLOCK_SIZE - offset_byte
-									};
-									memcpy(
-									       buffer.add(bytes_read as usize),
-									       block_buffer.get().add(offset_byte as usize),
-									       read_this_many as usize
-									);
-									bytes_read += read_this_many;
-									bytes_left -= read_this_many;
-									offset_byte = 0;
-									if bytes_left == 0 {
-										return bytes_read;
-									}
-								}
-								blocks_seen += 1;
-							}
-						}
-					}
-				}
+	}
+
+	/// Create an empty regular file called name in directory dir_num. The
+	/// permission bits come from mode.
+	pub fn create(bdev: usize, dir_num: u32, name: &str, mode: u16) -> Result<u32, FsError> {
+		Self::check_name(name)?;
+		let mut dir = Self::get_dir(bdev, dir_num)?;
+		if Self::find_entry(bdev, &dir, name).is_ok() {
+			return Err(FsError::Exists);
+		}
+		let (inode_num, mut inode) = Self::new_inode(bdev, S_IFREG | (mode & !S_IFMT))?;
+		if let Err(e) = Self::add_entry(bdev, dir_num, &mut dir, name, inode_num) {
+			Self::release_inode(bdev, inode_num, &mut inode);
+			return Err(e);
+		}
+		Ok(inode_num)
+	}
+
+	/// Make a symbolic link called name in directory dir_num. The target is the
+	/// contents of the link, the same way Linux keeps it, and has to fit in
+	/// one block.
+	pub fn symlink(bdev: usize, dir_num: u32, name: &str, target: &str) -> Result<u32, FsError> {
+		Self::check_name(name)?;
+		if target.is_empty() {
+			return Err(FsError::FileNotFound);
+		}
+		if target.len() >= BLOCK_SIZE as usize {
+			return Err(FsError::NameTooLong);
+		}
+		let mut dir = Self::get_dir(bdev, dir_num)?;
+		if Self::find_entry(bdev, &dir, name).is_ok() {
+			return Err(FsError::Exists);
+		}
+		let (inode_num, mut inode) = Self::new_inode(bdev, S_IFLNK | 0o777)?;
+		let len = target.len() as u32;
+		if Self::write(bdev, inode_num, &mut inode, target.as_ptr(), len, 0) != len {
+			Self::release_inode(bdev, inode_num, &mut inode);
+			return Err(FsError::NoSpace);
+		}
+		if let Err(e) = Self::add_entry(bdev, dir_num, &mut dir, name, inode_num) {
+			Self::release_inode(bdev, inode_num, &mut inode);
+			return Err(e);
+		}
+		Ok(inode_num)
+	}
+
+	/// What symbolic link inode_num points to.
+	pub fn readlink(bdev: usize, inode_num: u32) -> Result<String, FsError> {
+		let inode = Self::get_inode(bdev, inode_num).ok_or(FsError::FileNotFound)?;
+		if inode.mode & S_IFMT != S_IFLNK {
+			return Err(FsError::InvalidArgument);
+		}
+		let mut buffer = Buffer::new(inode.size as usize);
+		let len = Self::read(bdev, &inode, buffer.get_mut(), inode.size, 0);
+		let bytes = unsafe { core::slice::from_raw_parts(buffer.get(), len as usize) };
+		Ok(String::from_utf8_lossy(bytes).into_owned())
+	}
+
+	/// Make a new directory. It starts with two links: its entry in the parent
+	/// and its own "." entry. The parent gets a link from the new "..".
+	pub fn mkdir(bdev: usize, dir_num: u32, name: &str, mode: u16) -> Result<u32, FsError> {
+		Self::check_name(name)?;
+		let mut parent = Self::get_dir(bdev, dir_num)?;
+		if Self::find_entry(bdev, &parent, name).is_ok() {
+			return Err(FsError::Exists);
+		}
+		let (inode_num, mut inode) = Self::new_inode(bdev, S_IFDIR | (mode & !S_IFMT))?;
+		inode.nlinks = 2;
+		if let Err(e) = Self::write_entry(bdev, inode_num, &mut inode, 0, &DirEntry::new(inode_num, "."))
+		                .and_then(|_| Self::write_entry(bdev, inode_num, &mut inode, 1, &DirEntry::new(dir_num, "..")))
+		                .and_then(|_| Self::add_entry(bdev, dir_num, &mut parent, name, inode_num))
+		{
+			Self::release_inode(bdev, inode_num, &mut inode);
+			return Err(e);
+		}
+		parent.nlinks += 1;
+		Self::put_inode(bdev, dir_num, &parent);
+		Ok(inode_num)
+	}
+
+	/// Remove a name for a file. The file itself goes away with its last name.
+	pub fn unlink(bdev: usize, dir_num: u32, name: &str) -> Result<(), FsError> {
+		Self::check_name(name)?;
+		let mut dir = Self::get_dir(bdev, dir_num)?;
+		let (index, inode_num) = Self::find_entry(bdev, &dir, name)?;
+		let mut inode = Self::get_inode(bdev, inode_num).ok_or(FsError::FileNotFound)?;
+		if inode.mode & S_IFMT == S_IFDIR {
+			return Err(FsError::IsDirectory);
+		}
+		Self::remove_entry(bdev, dir_num, &mut dir, index)?;
+		inode.nlinks -= 1;
+		if inode.nlinks == 0 {
+			Self::unlinked_inode(bdev, inode_num, &mut inode);
+		}
+		else {
+			inode.ctime = get_unix_time();
+			Self::put_inode(bdev, inode_num, &inode);
+		}
+		Ok(())
+	}
+
+	/// Remove an empty directory.
+	pub fn rmdir(bdev: usize, dir_num: u32, name: &str) -> Result<(), FsError> {
+		Self::check_name(name)?;
+		let mut parent = Self::get_dir(bdev, dir_num)?;
+		let (index, inode_num) = Self::find_entry(bdev, &parent, name)?;
+		let mut inode = Self::get_dir(bdev, inode_num)?;
+		if !Self::is_empty_dir(bdev, &inode) {
+			return Err(FsError::NotEmpty);
+		}
+		Self::remove_entry(bdev, dir_num, &mut parent, index)?;
+		// The directory's ".." was a link to the parent.
+		parent.nlinks -= 1;
+		Self::put_inode(bdev, dir_num, &parent);
+		Self::unlinked_inode(bdev, inode_num, &mut inode);
+		Ok(())
+	}
+
+	/// Give the existing file inode_num another name. Directories can't be hard
+	/// linked.
+	pub fn link(bdev: usize, inode_num: u32, dir_num: u32, name: &str) -> Result<(), FsError> {
+		Self::check_name(name)?;
+		let mut inode = Self::get_inode(bdev, inode_num).ok_or(FsError::FileNotFound)?;
+		if inode.mode & S_IFMT == S_IFDIR {
+			return Err(FsError::Permission);
+		}
+		let mut dir = Self::get_dir(bdev, dir_num)?;
+		if Self::find_entry(bdev, &dir, name).is_ok() {
+			return Err(FsError::Exists);
+		}
+		Self::add_entry(bdev, dir_num, &mut dir, name, inode_num)?;
+		inode.nlinks += 1;
+		inode.ctime = get_unix_time();
+		Self::put_inode(bdev, inode_num, &inode);
+		Ok(())
+	}
+
+	/// Is ancestor the directory dir_num or one of the directories above it?
+	/// We find out by following .. up to the root.
+	fn is_ancestor(bdev: usize, ancestor: u32, dir_num: u32) -> Result<bool, FsError> {
+		let mut dir_num = dir_num;
+		loop {
+			if dir_num == ancestor {
+				return Ok(true);
 			}
+			if dir_num == 1 {
+				return Ok(false);
+			}
+			dir_num = Self::lookup(bdev, dir_num, "..")?;
 		}
-		// ////////////////////////////////////////////
-		// // TRIPLY INDIRECT ZONES
-		// ////////////////////////////////////////////
-		if inode.zones[9] != 0 {
-			syc_read(bdev, indirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * inode.zones[9]);
-			unsafe {
-				for i in 0..NUM_IPTRS {
-					if izones.add(i).read() != 0 {
-						syc_read(bdev, iindirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * izones.add(i).read());
-						for j in 0..NUM_IPTRS {
-							if iizones.add(j).read() != 0 {
-								syc_read(bdev, iiindirect_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * iizones.add(j).read());
-								for k in 0..NUM_IPTRS {
-									if iiizones.add(k).read() != 0 {
-										// Hey look! This again.
-										if offset_block <= blocks_seen {
-											syc_read(bdev, block_buffer.get_mut(), BLOCK_SIZE, BLOCK_SIZE * iiizones.add(k).read());
-											let read_this_many = if BLOCK_SIZE - offset_byte > bytes_left {
-												bytes_left
-											}
-											else {
-												BLOCK_SIZE - offset_byte
-											};
-											memcpy(
-											       buffer.add(bytes_read as usize),
-											       block_buffer.get().add(offset_byte as usize),
-											       read_this_many as usize
-											);
-											bytes_read += read_this_many;
-											bytes_left -= read_this_many;
-											offset_byte = 0;
-											if bytes_left == 0 {
-												return bytes_read;
-											}
-										}
-										blocks_seen += 1;
-									}
-								}
-							}
-						}
-					}
+	}
+
+	/// Move old_name in old_dir to new_name in new_dir, which may be the same
+	/// directory. If something is already at the new name, it is replaced as
+	/// long as it is the same kind of thing and, for a directory, empty.
+	pub fn rename(bdev: usize, old_dir: u32, old_name: &str, new_dir: u32, new_name: &str) -> Result<(), FsError> {
+		Self::check_name(old_name)?;
+		Self::check_name(new_name)?;
+		let inode_num = Self::lookup(bdev, old_dir, old_name)?;
+		let mut inode = Self::get_inode(bdev, inode_num).ok_or(FsError::FileNotFound)?;
+		let is_dir = inode.mode & S_IFMT == S_IFDIR;
+		// A directory can't be moved inside of itself.
+		if is_dir && Self::is_ancestor(bdev, inode_num, new_dir)? {
+			return Err(FsError::InvalidArgument);
+		}
+		if let Ok(target_num) = Self::lookup(bdev, new_dir, new_name) {
+			if target_num == inode_num {
+				return Ok(());
+			}
+			let target = Self::get_inode(bdev, target_num).ok_or(FsError::FileNotFound)?;
+			match (is_dir, target.mode & S_IFMT == S_IFDIR) {
+				(true, true) => Self::rmdir(bdev, new_dir, new_name)?,
+				(false, false) => Self::unlink(bdev, new_dir, new_name)?,
+				(true, false) => return Err(FsError::IsFile),
+				(false, true) => return Err(FsError::IsDirectory),
+			}
+		}
+		// Both parents may be the same directory, and every change we make is
+		// written to disk, so reread each inode right before we change it.
+		let mut new_parent = Self::get_dir(bdev, new_dir)?;
+		Self::add_entry(bdev, new_dir, &mut new_parent, new_name, inode_num)?;
+		let mut old_parent = Self::get_dir(bdev, old_dir)?;
+		let (old_index, _) = Self::find_entry(bdev, &old_parent, old_name)?;
+		Self::remove_entry(bdev, old_dir, &mut old_parent, old_index)?;
+		if is_dir && old_dir != new_dir {
+			// The moved directory's ".." now links to the new parent.
+			Self::write_entry(bdev, inode_num, &mut inode, 1, &DirEntry::new(new_dir, ".."))?;
+			old_parent.nlinks -= 1;
+			Self::put_inode(bdev, old_dir, &old_parent);
+			let mut new_parent = Self::get_dir(bdev, new_dir)?;
+			new_parent.nlinks += 1;
+			Self::put_inode(bdev, new_dir, &new_parent);
+		}
+		Ok(())
+	}
+}
+
+impl MinixFileSystem {
+	pub fn new(bdev: usize) -> Self {
+		Self { bdev }
+	}
+
+	/// Start journaling bdev if it has a journal file, which minix-tool mkfs
+	/// makes with --journal. We never make one ourselves, since that would
+	/// change a disk that nobody asked us to change. Anything committed to
+	/// the journal that didn't get home before the last crash is written
+	/// home now, so this has to happen before anything else uses the disk.
+	/// Without a journal, the file system still works, just without it.
+	fn open_journal(bdev: usize) {
+		// We couldn't replay onto a read-only disk anyway.
+		if block::is_read_only(bdev) {
+			return;
+		}
+		let inode_num = match Self::lookup(bdev, 1, JOURNAL_NAME) {
+			Ok(n) => n,
+			Err(_) => return,
+		};
+		let super_block = match Self::get_super_block(bdev) {
+			Some(sb) => sb,
+			None => return,
+		};
+		let mut inode = match Self::get_inode(bdev, inode_num) {
+			Some(inode) if inode.mode & S_IFMT == S_IFREG && inode.size == JOURNAL_BLOCKS * BLOCK_SIZE => inode,
+			_ => {
+				println!("minixfs: /{} on disk {} isn't a journal", JOURNAL_NAME, bdev);
+				return;
+			},
+		};
+		let mut zones = Vec::new();
+		for block in 0..JOURNAL_BLOCKS {
+			match Self::get_zone(bdev, &super_block, &mut inode, block, false) {
+				Ok(zone) => zones.push(zone),
+				Err(_) => return,
+			}
+		}
+		// Freeing a big file can touch every block of the zone map.
+		let reserve = super_block.imap_blocks as usize + super_block.zmap_blocks as usize + OP_BLOCKS;
+		if !journal::open(bdev, inode_num, zones, reserve) {
+			println!("minixfs: could not open the journal on disk {}", bdev);
+		}
+	}
+}
+
+impl FileSystem for MinixFileSystem {
+	fn root(&self) -> Result<InodeRef, FsError> {
+		if Self::get_super_block(self.bdev).is_none() {
+			return Err(FsError::InvalidArgument);
+		}
+		Self::open_journal(self.bdev);
+		Ok(MinixInode::new(self.bdev, 1))
+	}
+
+	/// Everything still in the journal or the cache goes to the disk, and
+	/// the cache forgets the disk so that whatever is mounted from it next
+	/// reads it fresh.
+	fn unmount(&self) -> Result<(), FsError> {
+		Self::reap_orphans(self.bdev);
+		let ok = journal::close(self.bdev) && bcache::sync(Some(self.bdev));
+		bcache::invalidate(self.bdev);
+		if ok { Ok(()) } else { Err(FsError::Io) }
+	}
+}
+
+/// An inode on a Minix file system. We only hold on to the number and read the
+/// inode from the disk when we need it, so it can't go stale.
+pub struct MinixInode {
+	bdev: usize,
+	num:  u32
+}
+
+impl MinixInode {
+	fn new(bdev: usize, num: u32) -> InodeRef {
+		with_open_inodes(|open, _| *open.entry((bdev, num)).or_insert(0) += 1);
+		Arc::new(MinixInode { bdev, num })
+	}
+
+	fn get(&self) -> Result<Inode, FsError> {
+		MinixFileSystem::get_inode(self.bdev, self.num).ok_or(FsError::FileNotFound)
+	}
+
+	fn node(&self, num: u32) -> InodeRef {
+		MinixInode::new(self.bdev, num)
+	}
+
+	/// Join the running transaction to change the disk. Files that were
+	/// unlinked while open and are closed now get freed first.
+	fn begin(&self) -> journal::Handle {
+		MinixFileSystem::reap_orphans(self.bdev);
+		journal::begin(self.bdev)
+	}
+
+	/// Other inodes we're handed have to be on the same disk as us.
+	fn same_disk(&self, other: &InodeRef) -> Result<u32, FsError> {
+		if other.dev() != self.bdev {
+			return Err(FsError::CrossDevice);
+		}
+		Ok(other.ino())
+	}
+
+	/// The journal file is only for the journal. Nobody gets to write it,
+	/// remove it, or give it another name.
+	fn check_journal(&self, num: u32) -> Result<(), FsError> {
+		if journal::is_journal(self.bdev, num) { Err(FsError::NotPermitted) } else { Ok(()) }
+	}
+
+	fn check_journal_name(&self, dir_num: u32, name: &str) -> Result<(), FsError> {
+		match MinixFileSystem::lookup(self.bdev, dir_num, name) {
+			Ok(num) => self.check_journal(num),
+			Err(_) => Ok(()),
+		}
+	}
+}
+
+impl Drop for MinixInode {
+	fn drop(&mut self) {
+		with_open_inodes(|open, _| {
+			if let Some(count) = open.get_mut(&(self.bdev, self.num)) {
+				*count -= 1;
+				if *count == 0 {
+					open.remove(&(self.bdev, self.num));
 				}
 			}
+		});
+	}
+}
+
+impl File for MinixInode {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let inode = self.get()?;
+		if inode.mode & S_IFMT == S_IFDIR {
+			return Err(FsError::IsDirectory);
 		}
-		// Anyone else love this stairstep style? I probably should put the pointers in a function by themselves,
-		// but I think that'll make it more difficult to see what's actually happening.
+		Ok(MinixFileSystem::read(self.bdev, &inode, buffer, size, offset))
+	}
 
-		bytes_read
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		self.check_journal(self.num)?;
+		if self.get()?.mode & S_IFMT == S_IFDIR {
+			return Err(FsError::IsDirectory);
+		}
+		let mut written = 0;
+		loop {
+			let chunk = min(size - written, WRITE_CHUNK);
+			let _handle = self.begin();
+			let mut inode = self.get()?;
+			let count = MinixFileSystem::write(self.bdev,
+			                                   self.num,
+			                                   &mut inode,
+			                                   unsafe { buffer.add(written as usize) },
+			                                   chunk,
+			                                   offset + written);
+			written += count;
+			if written == size || count < chunk {
+				return Ok(written);
+			}
+		}
+	}
+}
+
+impl VfsInode for MinixInode {
+	fn dev(&self) -> usize {
+		self.bdev
 	}
 
-	pub fn write(&mut self, _desc: &Inode, _buffer: *const u8, _offset: u32, _size: u32) -> u32 {
-		0
+	fn ino(&self) -> u32 {
+		self.num
 	}
 
-	pub fn stat(&self, inode: &Inode) -> Stat {
-		Stat { mode: inode.mode,
-		       size: inode.size,
-		       uid:  inode.uid,
-		       gid:  inode.gid }
+	fn stat(&self) -> Result<Stat, FsError> {
+		let inode = self.get()?;
+		Ok(Stat { dev:     self.bdev,
+		          ino:     self.num,
+		          mode:    inode.mode,
+		          nlinks:  inode.nlinks,
+		          uid:     inode.uid,
+		          gid:     inode.gid,
+		          size:    inode.size,
+		          blocks:  MinixFileSystem::zone_count(self.bdev, &inode) * (BLOCK_SIZE / 512),
+		          blksize: BLOCK_SIZE,
+		          atime:   inode.atime,
+		          mtime:   inode.mtime,
+		          ctime:   inode.ctime })
+	}
+
+	fn truncate(&self) -> Result<(), FsError> {
+		self.check_journal(self.num)?;
+		let _handle = self.begin();
+		let mut inode = self.get()?;
+		if inode.mode & S_IFMT == S_IFDIR {
+			return Err(FsError::IsDirectory);
+		}
+		if inode.size > 0 {
+			MinixFileSystem::truncate(self.bdev, &mut inode);
+			MinixFileSystem::put_inode(self.bdev, self.num, &inode);
+		}
+		Ok(())
+	}
+
+	fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+		MinixFileSystem::lookup(self.bdev, self.num, name).map(|num| self.node(num))
+	}
+
+	fn create(&self, name: &str, mode: u16) -> Result<InodeRef, FsError> {
+		let _handle = self.begin();
+		MinixFileSystem::create(self.bdev, self.num, name, mode).map(|num| self.node(num))
+	}
+
+	fn mkdir(&self, name: &str, mode: u16) -> Result<(), FsError> {
+		let _handle = self.begin();
+		MinixFileSystem::mkdir(self.bdev, self.num, name, mode).map(|_| ())
+	}
+
+	fn unlink(&self, name: &str) -> Result<(), FsError> {
+		self.check_journal_name(self.num, name)?;
+		let _handle = self.begin();
+		MinixFileSystem::unlink(self.bdev, self.num, name)
+	}
+
+	fn rmdir(&self, name: &str) -> Result<(), FsError> {
+		let _handle = self.begin();
+		MinixFileSystem::rmdir(self.bdev, self.num, name)
+	}
+
+	fn link(&self, name: &str, target: &InodeRef) -> Result<(), FsError> {
+		let target = self.same_disk(target)?;
+		self.check_journal(target)?;
+		let _handle = self.begin();
+		MinixFileSystem::link(self.bdev, target, self.num, name)
+	}
+
+	fn rename(&self, old_name: &str, new_dir: &InodeRef, new_name: &str) -> Result<(), FsError> {
+		let new_dir = self.same_disk(new_dir)?;
+		self.check_journal_name(self.num, old_name)?;
+		self.check_journal_name(new_dir, new_name)?;
+		let _handle = self.begin();
+		MinixFileSystem::rename(self.bdev, self.num, old_name, new_dir, new_name)
+	}
+
+	fn symlink(&self, name: &str, target: &str) -> Result<(), FsError> {
+		let _handle = self.begin();
+		MinixFileSystem::symlink(self.bdev, self.num, name, target).map(|_| ())
+	}
+
+	fn readlink(&self) -> Result<String, FsError> {
+		MinixFileSystem::readlink(self.bdev, self.num)
+	}
+
+	fn chmod(&self, mode: u16) -> Result<(), FsError> {
+		let _handle = self.begin();
+		let mut inode = self.get()?;
+		inode.mode = (inode.mode & S_IFMT) | (mode & !S_IFMT);
+		inode.ctime = get_unix_time();
+		MinixFileSystem::put_inode(self.bdev, self.num, &inode);
+		Ok(())
+	}
+
+	fn chown(&self, uid: u16, gid: u16) -> Result<(), FsError> {
+		let _handle = self.begin();
+		let mut inode = self.get()?;
+		inode.uid = uid;
+		inode.gid = gid;
+		inode.ctime = get_unix_time();
+		MinixFileSystem::put_inode(self.bdev, self.num, &inode);
+		Ok(())
+	}
+
+	fn readdir(&self, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		MinixFileSystem::readdir(self.bdev, self.num, offset)
+	}
+
+	fn sync(&self) -> Result<(), FsError> {
+		MinixFileSystem::reap_orphans(self.bdev);
+		if journal::sync(self.bdev) { Ok(()) } else { Err(FsError::Io) }
 	}
 }
 
-/// This is a wrapper function around the syscall_block_read. This allows me to do
-/// other things before I call the system call (or after). However, all the things I
-/// wanted to do are no longer there, so this is a worthless function.
+/// Every block the file system touches goes through the block cache, so the
+/// superblock, the maps, and indirect zones we keep going back to don't
+/// have to come from the device every time. These return 0 on success like
+/// syscall_block_read did.
 fn syc_read(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
-	syscall_block_read(bdev, buffer, size, offset)
+	if bcache::read(bdev, buffer, size, offset as u64) { 0 } else { 1 }
+}
+
+/// The write version of syc_read, for metadata. The blocks are part of the
+/// running journal transaction, and they get to the device after it
+/// commits.
+fn syc_write(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
+	if journal::log(bdev, buffer, size, offset as u64) { 0 } else { 1 }
+}
+
+/// File data skips the journal. The block is only marked dirty; it gets to
+/// the device when the cache writes it back.
+fn syc_write_data(bdev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
+	if bcache::write(bdev, buffer, size, offset as u64) { 0 } else { 1 }
 }
 
 // We have to start a process when reading from a file since the block
@@ -480,22 +1240,3 @@ pub fn process_read(pid: u16, dev: usize, node: u32, buffer: *mut u8, size: u32,
 	set_waiting(pid);
 	let _ = add_kernel_process_args(read_proc, Box::into_raw(boxed_args) as usize);
 }
-
-/// Stats on a file. This generally mimics an inode
-/// since that's the information we want anyway.
-/// However, inodes are filesystem specific, and we
-/// want a more generic stat.
-pub struct Stat {
-	pub mode: u16,
-	pub size: u32,
-	pub uid:  u16,
-	pub gid:  u16
-}
-
-pub enum FsError {
-	Success,
-	FileNotFound,
-	Permission,
-	IsFile,
-	IsDirectory
-}
@@ -6,8 +6,12 @@
 #![allow(dead_code)]
 use crate::{page::{zalloc, PAGE_SIZE},
 			kmem::{kmalloc, kfree},
+            cpu::memcpy,
+            devfs::{self, Device as DevfsDevice, DeviceKind},
             virtio,
-            virtio::{MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE, Descriptor, VIRTIO_DESC_F_WRITE, VIRTIO_DESC_F_NEXT}};
+            virtio::{MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE, Descriptor, VIRTIO_DESC_F_WRITE, VIRTIO_DESC_F_NEXT},
+            vfs::FsError};
+use alloc::sync::Arc;
 use core::{mem::size_of, ptr::null_mut};
 // use alloc::boxed::Box;
 
@@ -724,11 +728,59 @@ pub fn setup_gpu_device(ptr: *mut u32) -> bool {
 		};
 
 		GPU_DEVICES[idx] = Some(dev);
+		// The screens are /dev/fb0, /dev/fb1 and so on in the order we find
+		// them.
+		let n = GPU_DEVICES[..idx].iter().filter(|g| g.is_some()).count();
+		devfs::register(&alloc::format!("fb{}", n), DeviceKind::Char, 0o660, Arc::new(Framebuffer { gdev: idx + 1 }));
 
 		true
 	}
 }
 
+/// A screen in /dev. Its bytes are the pixels, row by row, and whatever is
+/// written goes up on the screen right away.
+struct Framebuffer {
+	gdev: usize,
+}
+
+impl Framebuffer {
+	/// The framebuffer and its size in bytes, along with the width and height.
+	fn get(&self) -> Result<(*mut u8, u32, u32, u32), FsError> {
+		match unsafe { GPU_DEVICES[self.gdev - 1].as_ref() } {
+			Some(dev) => Ok((dev.framebuffer as *mut u8, dev.width * dev.height * size_of::<Pixel>() as u32, dev.width, dev.height)),
+			None => Err(FsError::Io),
+		}
+	}
+}
+
+impl DevfsDevice for Framebuffer {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let (fb, len, ..) = self.get()?;
+		let size = if offset >= len { 0 } else if size > len - offset { len - offset } else { size };
+		unsafe {
+			memcpy(buffer, fb.add(offset as usize), size as usize);
+		}
+		Ok(size)
+	}
+
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let (fb, len, width, height) = self.get()?;
+		if offset >= len {
+			return if size > 0 { Err(FsError::NoSpace) } else { Ok(0) };
+		}
+		let size = if size > len - offset { len - offset } else { size };
+		unsafe {
+			memcpy(fb.add(offset as usize), buffer, size as usize);
+		}
+		transfer(self.gdev, 0, 0, width, height);
+		Ok(size)
+	}
+
+	fn size(&self) -> u32 {
+		self.get().map(|(_, len, ..)| len).unwrap_or(0)
+	}
+}
+
 pub fn pending(dev: &mut Device) {
 	// Here we need to check the used ring and then free the resources
 	// given by the descriptor id.
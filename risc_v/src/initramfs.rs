@@ -0,0 +1,156 @@
+// initramfs.rs
+// Files built into the kernel
+// Stephen Marz
+// 19 October 2026
+
+use crate::{fs::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+            vfs::{self, Credentials, FsError, InodeRef}};
+use alloc::{collections::BTreeMap, string::String};
+
+// With the initramfs feature, the archive is linked into the kernel. It's
+// a cpio archive in the "new" (newc) format, which is what
+// cpio -o -H newc makes. See BUILD.md.
+#[cfg(feature = "initramfs")]
+static ARCHIVE: &[u8] = include_bytes!("../initramfs.cpio");
+#[cfg(not(feature = "initramfs"))]
+static ARCHIVE: &[u8] = &[];
+
+const MAGIC: &[u8] = b"070701";
+const HEADER_SIZE: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+/// One newc header. Every field is 8 hex digits, and the ones we don't use
+/// (the device numbers and the checksum) are left out.
+struct Header {
+	ino:      u32,
+	mode:     u32,
+	uid:      u32,
+	gid:      u32,
+	nlink:    u32,
+	filesize: u32,
+	namesize: u32
+}
+
+fn hex(field: &[u8]) -> Option<u32> {
+	let mut val = 0u32;
+	for &c in field {
+		let digit = match c {
+			b'0'..=b'9' => c - b'0',
+			b'a'..=b'f' => c - b'a' + 10,
+			b'A'..=b'F' => c - b'A' + 10,
+			_ => return None,
+		};
+		val = val << 4 | digit as u32;
+	}
+	Some(val)
+}
+
+impl Header {
+	fn parse(raw: &[u8]) -> Option<Self> {
+		if raw.len() < HEADER_SIZE || &raw[..6] != MAGIC {
+			return None;
+		}
+		// The fields are, in order: ino, mode, uid, gid, nlink, mtime,
+		// filesize, devmajor, devminor, rdevmajor, rdevminor, namesize and
+		// check.
+		let field = |i: usize| hex(&raw[6 + i * 8..14 + i * 8]);
+		Some(Header { ino:      field(0)?,
+		              mode:     field(1)?,
+		              uid:      field(2)?,
+		              gid:      field(3)?,
+		              nlink:    field(4)?,
+		              filesize: field(6)?,
+		              namesize: field(11)? })
+	}
+}
+
+/// Names and file data both start on a 4-byte boundary.
+fn align4(n: usize) -> usize {
+	(n + 3) & !3
+}
+
+/// Is there an archive to unpack?
+pub fn present() -> bool {
+	!ARCHIVE.is_empty()
+}
+
+/// Make everything in the archive under the root of the file system that's
+/// mounted on /. Returns how many entries there were. This goes through the
+/// file system, so it has to run in a process.
+pub fn unpack() -> Result<usize, FsError> {
+	let cred = Credentials::ROOT;
+	// Hard links share an ino in the archive, and only the last one has
+	// the data.
+	let mut links: BTreeMap<u32, InodeRef> = BTreeMap::new();
+	let mut pos = 0;
+	let mut count = 0;
+	loop {
+		let header = ARCHIVE.get(pos..).and_then(Header::parse).ok_or(FsError::InvalidArgument)?;
+		let name_start = pos + HEADER_SIZE;
+		let name_end = name_start + header.namesize as usize;
+		let data_start = align4(name_end);
+		let data_end = data_start + header.filesize as usize;
+		if header.namesize == 0 || data_end > ARCHIVE.len() {
+			return Err(FsError::InvalidArgument);
+		}
+		// The name is NUL terminated.
+		let name = core::str::from_utf8(&ARCHIVE[name_start..name_end - 1]).map_err(|_| FsError::InvalidArgument)?;
+		if name == TRAILER {
+			break;
+		}
+		let data = &ARCHIVE[data_start..data_end];
+		pos = align4(data_end);
+		// find . makes names like ./bin/ls, and the archive might have .
+		// itself, which is the root we already have.
+		let name = name.trim_start_matches("./").trim_start_matches('/');
+		if name.is_empty() || name == "." {
+			continue;
+		}
+		let mut path = String::from("/");
+		path.push_str(name);
+		let (dir, last) = vfs::walk_parent(&path, "/", &cred)?;
+		let mode = header.mode as u16;
+		let perm = mode & !S_IFMT;
+		let inode = match mode & S_IFMT {
+			S_IFDIR => {
+				match dir.mkdir(last, perm) {
+					Ok(()) | Err(FsError::Exists) => {},
+					Err(e) => return Err(e),
+				}
+				dir.lookup(last)?
+			},
+			S_IFLNK => {
+				let target = core::str::from_utf8(data).map_err(|_| FsError::InvalidArgument)?;
+				dir.symlink(last, target)?;
+				dir.lookup(last)?
+			},
+			S_IFREG => {
+				let inode = match links.get(&header.ino) {
+					Some(first) if header.nlink > 1 => {
+						dir.link(last, first)?;
+						first.clone()
+					},
+					_ => dir.create(last, perm)?,
+				};
+				if header.nlink > 1 {
+					links.insert(header.ino, inode.clone());
+				}
+				if !data.is_empty() && inode.write(data.as_ptr(), data.len() as u32, 0)? != data.len() as u32 {
+					return Err(FsError::NoSpace);
+				}
+				inode
+			},
+			// Device nodes and the like have nothing to put here. The
+			// devices are in /dev.
+			_ => continue,
+		};
+		// The file system gave whatever we made its default owner and
+		// whatever mode it wanted, so set them to what the archive says.
+		if mode & S_IFMT != S_IFLNK {
+			inode.chmod(perm)?;
+		}
+		inode.chown(header.uid as u16, header.gid as u16)?;
+		count += 1;
+	}
+	Ok(count)
+}
@@ -5,8 +5,11 @@
 use crate::virtio::{Queue, MmioOffsets, MMIO_VIRTIO_START, StatusField, VIRTIO_RING_SIZE, Descriptor, VIRTIO_DESC_F_WRITE, VIRTIO_F_RING_EVENT_IDX};
 use crate::kmem::kmalloc;
 use crate::page::{PAGE_SIZE, zalloc};
+use crate::cpu::{mstatus_read, mstatus_write, MSTATUS_MIE};
+use crate::devfs::{self, Device as DevfsDevice, DeviceKind};
+use crate::vfs::FsError;
 use core::mem::size_of;
-use alloc::collections::VecDeque;
+use alloc::{collections::VecDeque, sync::Arc};
 
 pub static mut ABS_EVENTS: Option<VecDeque<Event>> = None;
 // pub static mut ABS_OBSERVERS: Option<VecDeque<u16>> = None;
@@ -230,10 +233,16 @@ pub fn setup_input_device(ptr: *mut u32) -> bool {
 			repopulate_event(&mut dev, i);
 		}
 		INPUT_DEVICES[idx] = Some(dev);
-		ABS_EVENTS = Some(VecDeque::with_capacity(100));
-		// ABS_OBSERVERS = Some(VecDeque::new());
-		KEY_EVENTS = Some(VecDeque::with_capacity(10));
-		// KEY_OBSERVERS = Some(VecDeque::new());
+		// Every input device puts its events in the same two queues, so
+		// they and their nodes in /dev are only made for the first one.
+		if ABS_EVENTS.is_none() {
+			ABS_EVENTS = Some(VecDeque::with_capacity(100));
+			// ABS_OBSERVERS = Some(VecDeque::new());
+			KEY_EVENTS = Some(VecDeque::with_capacity(10));
+			// KEY_OBSERVERS = Some(VecDeque::new());
+			devfs::register("butev", DeviceKind::Char, 0o640, Arc::new(EventQueue { abs: false }));
+			devfs::register("absev", DeviceKind::Char, 0o640, Arc::new(EventQueue { abs: true }));
+		}
 
 		true
 	}
@@ -297,6 +306,40 @@ fn pending(dev: &mut Device) {
 	}
 }
 
+/// /dev/butev or /dev/absev. A read gets as many whole Events as fit and
+/// are waiting, which may be none.
+struct EventQueue {
+	abs: bool,
+}
+
+impl DevfsDevice for EventQueue {
+	fn read(&self, buffer: *mut u8, size: u32, _offset: u32) -> Result<u32, FsError> {
+		let queue = unsafe { if self.abs { &mut ABS_EVENTS } else { &mut KEY_EVENTS } };
+		// pending() takes the queue from the interrupt handler, so it can't
+		// be allowed to come in while we have it.
+		let mstatus = mstatus_read();
+		mstatus_write(mstatus & !MSTATUS_MIE);
+		let mut count = 0;
+		if let Some(mut ev) = queue.take() {
+			let buffer = buffer as *mut Event;
+			while count < size as usize / size_of::<Event>() {
+				match ev.pop_front() {
+					Some(event) => unsafe { buffer.add(count).write_unaligned(event) },
+					None => break,
+				}
+				count += 1;
+			}
+			queue.replace(ev);
+		}
+		mstatus_write(mstatus);
+		Ok((count * size_of::<Event>()) as u32)
+	}
+
+	fn write(&self, _buffer: *const u8, _size: u32, _offset: u32) -> Result<u32, FsError> {
+		Err(FsError::InvalidArgument)
+	}
+}
+
 pub fn handle_interrupt(idx: usize) {
 	unsafe {
 		if let Some(bdev) = INPUT_DEVICES[idx].as_mut() {
@@ -0,0 +1,404 @@
+// journal.rs
+// Write-ahead journal for file system metadata
+// Stephen Marz
+// 19 October 2026
+
+use crate::{bcache,
+            buffer::Buffer,
+            lock::{Mutex, DEFAULT_LOCK_SLEEP},
+            syscall::{syscall_block_flush, syscall_sleep}};
+use alloc::{collections::{BTreeMap, BTreeSet}, vec::Vec};
+
+// Journal blocks are cache blocks.
+const BLOCK_SIZE: u32 = bcache::BCACHE_BLOCK_SIZE;
+const MAGIC: u32 = 0x4a52_4e4c;
+// As many home block numbers as fit in the header block with the fields
+// in front of them.
+pub const MAX_BLOCKS: usize = (BLOCK_SIZE as usize - 16) / 4;
+
+/// The first block of the journal. A transaction is committed once its
+/// header is on the disk, and a header of zeros means there is nothing to
+/// replay. The block images follow in the rest of the journal, in the same
+/// order as homes.
+#[repr(C)]
+struct Header {
+	magic:    u32,
+	sequence: u32,
+	count:    u32,
+	/// Covers the sequence, the count, the homes and the images, so that a
+	/// header that only partly made it to the disk doesn't count.
+	checksum: u32,
+	homes:    [u32; MAX_BLOCKS]
+}
+
+struct Journal {
+	/// The inode of the journal file, which nobody else may change.
+	ino:        u32,
+	/// Where the journal is on the disk, in blocks. The header is in the
+	/// first one.
+	zones:      Vec<u32>,
+	sequence:   u32,
+	/// The most blocks one operation can log. Every handle has that much
+	/// of the journal set aside for it.
+	reserve:    usize,
+	/// How many operations are in the running transaction right now.
+	handles:    usize,
+	committing: bool,
+	/// Every block the running transaction has written. They're pinned in
+	/// the block cache until the transaction commits.
+	running:    BTreeSet<u32>,
+	/// Zones the running transaction freed. They can't be handed out again
+	/// until it commits.
+	freed:      BTreeSet<u32>
+}
+
+impl Journal {
+	fn capacity(&self) -> usize {
+		self.zones.len() - 1
+	}
+
+	/// Is there room in the running transaction for one more handle? The
+	/// blocks the open handles have logged so far are counted twice, once
+	/// in running and again in what they set aside, which only errs on the
+	/// safe side.
+	fn has_room(&self) -> bool {
+		self.running.len() + (self.handles + 1) * self.reserve <= self.capacity()
+	}
+}
+
+// One journal per device. Committing sleeps on the disk, but nobody holds
+// the lock while that happens.
+static mut JOURNALS: Option<BTreeMap<usize, Journal>> = None;
+static mut JOURNALS_LOCK: Mutex = Mutex::new();
+
+pub fn init() {
+	unsafe {
+		JOURNALS.replace(BTreeMap::new());
+	}
+}
+
+/// Run f on the journal of dev. Gives back None if dev has no journal.
+fn with_journal<T>(dev: usize, f: impl FnOnce(&mut Journal) -> T) -> Option<T> {
+	with_journals(|journals| journals.get_mut(&dev).map(f)).flatten()
+}
+
+fn with_journals<T>(f: impl FnOnce(&mut BTreeMap<usize, Journal>) -> T) -> Option<T> {
+	unsafe {
+		JOURNALS_LOCK.sleep_lock();
+		let ret = if let Some(mut journals) = JOURNALS.take() {
+			let ret = f(&mut journals);
+			JOURNALS.replace(journals);
+			Some(ret)
+		}
+		else {
+			None
+		};
+		JOURNALS_LOCK.unlock();
+		ret
+	}
+}
+
+/// FNV-1a, which is plenty to tell a whole transaction from a torn one.
+fn checksum(hash: u32, bytes: &[u8]) -> u32 {
+	bytes.iter().fold(hash, |h, &b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+const CHECKSUM_START: u32 = 0x811c_9dc5;
+
+fn block_bytes(buffer: &Buffer) -> &[u8] {
+	unsafe { core::slice::from_raw_parts(buffer.get(), BLOCK_SIZE as usize) }
+}
+
+fn flush(dev: usize) -> bool {
+	syscall_block_flush(dev) == 0
+}
+
+fn read_block(dev: usize, block: u32, buffer: &mut Buffer) -> bool {
+	bcache::read(dev, buffer.get_mut(), BLOCK_SIZE, block as u64 * BLOCK_SIZE as u64)
+}
+
+fn write_block(dev: usize, block: u32, buffer: &Buffer) -> bool {
+	bcache::write(dev, buffer.get(), BLOCK_SIZE, block as u64 * BLOCK_SIZE as u64)
+}
+
+/// Zero the header so that the transaction in the journal is never replayed
+/// again, and make sure that's on the disk before anything else is.
+fn clear(dev: usize, header_block: u32) -> bool {
+	let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+	for i in 0..BLOCK_SIZE as usize {
+		buffer[i] = 0;
+	}
+	write_block(dev, header_block, &buffer) && bcache::sync(Some(dev)) && flush(dev)
+}
+
+/// Start using the journal in zones for dev, where the journal file is inode
+/// ino. reserve is the most blocks one operation on dev can log. A committed
+/// transaction left in the journal, say because QEMU was killed before the
+/// blocks got home, is written home first. This has to run before anything
+/// else changes the file system.
+pub fn open(dev: usize, ino: u32, zones: Vec<u32>, reserve: usize) -> bool {
+	if zones.len() < 2 || zones.len() > MAX_BLOCKS + 1 || zones.contains(&0) {
+		return false;
+	}
+	if with_journal(dev, |_| ()).is_some() {
+		return true;
+	}
+	let mut buffer = Buffer::new(BLOCK_SIZE as usize);
+	if !read_block(dev, zones[0], &mut buffer) {
+		return false;
+	}
+	let header = unsafe { &*(buffer.get() as *const Header) };
+	let mut sequence = 0;
+	if header.magic == MAGIC && header.count as usize <= zones.len() - 1 {
+		let count = header.count as usize;
+		let homes = header.homes;
+		sequence = header.sequence;
+		let mut hash = checksum(CHECKSUM_START, &header.sequence.to_le_bytes());
+		hash = checksum(hash, &header.count.to_le_bytes());
+		for home in homes.iter().take(count) {
+			hash = checksum(hash, &home.to_le_bytes());
+		}
+		let mut images = Vec::with_capacity(count);
+		for &zone in zones[1..=count].iter() {
+			let mut image = Buffer::new(BLOCK_SIZE as usize);
+			if !read_block(dev, zone, &mut image) {
+				return false;
+			}
+			hash = checksum(hash, block_bytes(&image));
+			images.push(image);
+		}
+		if hash == header.checksum {
+			for (home, image) in homes.iter().zip(images.iter()) {
+				if !write_block(dev, *home, image) {
+					return false;
+				}
+			}
+			if !bcache::sync(Some(dev)) || !flush(dev) {
+				return false;
+			}
+			println!("journal: replayed {} blocks on disk {}", count, dev);
+		}
+		// A torn header was never committed, so there's nothing to do but
+		// throw it away.
+		if !clear(dev, zones[0]) {
+			return false;
+		}
+	}
+	if reserve == 0 || reserve > zones.len() - 1 {
+		println!("journal: one operation on disk {} can log {} blocks, but the journal only holds {}",
+		         dev,
+		         reserve,
+		         zones.len() - 1);
+		return false;
+	}
+	with_journals(|journals| {
+		journals.insert(dev,
+		                Journal { ino,
+		                          zones,
+		                          sequence: sequence.wrapping_add(1),
+		                          reserve,
+		                          handles: 0,
+		                          committing: false,
+		                          running: BTreeSet::new(),
+		                          freed: BTreeSet::new() })
+	});
+	true
+}
+
+/// Is ino on dev the journal file?
+pub fn is_journal(dev: usize, ino: u32) -> bool {
+	with_journal(dev, |j| j.ino == ino).unwrap_or(false)
+}
+
+/// An operation that is part of the running transaction. Everything it
+/// writes through log() commits together, and the transaction can't commit
+/// until every handle is dropped.
+pub struct Handle {
+	dev:    usize,
+	joined: bool
+}
+
+impl Drop for Handle {
+	fn drop(&mut self) {
+		if self.joined {
+			with_journal(self.dev, |j| j.handles -= 1);
+		}
+	}
+}
+
+enum Begin {
+	Joined,
+	Wait,
+	Commit
+}
+
+/// Join the running transaction of dev before changing anything on it.
+/// If the transaction doesn't have room for one more operation on top of
+/// the ones already in it, it is committed first, or we wait for the
+/// handles holding the room to finish. Devices without a journal get a
+/// handle that does nothing.
+pub fn begin(dev: usize) -> Handle {
+	loop {
+		let state = with_journal(dev, |j| {
+			if j.committing {
+				Begin::Wait
+			}
+			else if !j.has_room() {
+				if j.running.is_empty() { Begin::Wait } else { Begin::Commit }
+			}
+			else {
+				j.handles += 1;
+				Begin::Joined
+			}
+		});
+		match state {
+			None => return Handle { dev, joined: false },
+			Some(Begin::Joined) => return Handle { dev, joined: true },
+			Some(Begin::Wait) => syscall_sleep(DEFAULT_LOCK_SLEEP),
+			Some(Begin::Commit) => {
+				commit(dev);
+			},
+		}
+	}
+}
+
+/// Write metadata to dev as part of the running transaction. The blocks stay
+/// in the cache until the transaction commits. Without a journal, this is
+/// just a write to the cache.
+pub fn log(dev: usize, buffer: *const u8, size: u32, offset: u64) -> bool {
+	if size == 0 {
+		return true;
+	}
+	with_journal(dev, |j| {
+		if !bcache::write_pinned(dev, buffer, size, offset) {
+			return false;
+		}
+		let first = (offset / BLOCK_SIZE as u64) as u32;
+		let last = ((offset + size as u64 - 1) / BLOCK_SIZE as u64) as u32;
+		j.running.extend(first..=last);
+		true
+	}).unwrap_or_else(|| bcache::write(dev, buffer, size, offset))
+}
+
+/// Zone was freed on dev by the running transaction.
+pub fn freed(dev: usize, zone: u32) {
+	with_journal(dev, |j| j.freed.insert(zone));
+}
+
+/// Was zone freed on dev by a transaction that hasn't committed yet? Without
+/// a journal, nothing ever is.
+pub fn is_freed(dev: usize, zone: u32) -> bool {
+	with_journal(dev, |j| j.freed.contains(&zone)).unwrap_or(false)
+}
+
+/// Commit the running transaction of dev, so that every block in it is on
+/// the disk. Returns false if the disk failed.
+pub fn commit(dev: usize) -> bool {
+	// Only one commit at a time, and no new handles while it runs.
+	loop {
+		let started = with_journal(dev, |j| {
+			if j.committing {
+				return false;
+			}
+			j.committing = true;
+			true
+		});
+		match started {
+			None => return true,
+			Some(true) => break,
+			Some(false) => syscall_sleep(DEFAULT_LOCK_SLEEP),
+		}
+	}
+	while with_journal(dev, |j| j.handles > 0) == Some(true) {
+		syscall_sleep(DEFAULT_LOCK_SLEEP);
+	}
+	let (blocks, zones, sequence) = with_journal(dev, |j| {
+		let blocks: Vec<u32> = core::mem::take(&mut j.running).into_iter().collect();
+		(blocks, j.zones.clone(), j.sequence)
+	}).unwrap();
+	// begin() set aside room for every handle, so this only happens if
+	// an operation logged more than open() was told one could.
+	if blocks.len() > zones.len() - 1 {
+		panic!("journal: transaction of {} blocks on disk {} doesn't fit in the journal", blocks.len(), dev);
+	}
+	let ok = blocks.is_empty() || write_transaction(dev, &zones, &blocks, sequence);
+	with_journal(dev, |j| {
+		if !blocks.is_empty() {
+			j.sequence = j.sequence.wrapping_add(1);
+		}
+		// Whatever the transaction freed is free on the disk now.
+		if ok {
+			j.freed.clear();
+		}
+		j.committing = false;
+	});
+	ok
+}
+
+/// Log blocks, commit them and write them home. A crash at any point leaves
+/// the disk with either none of the transaction or, after replay, all of it.
+fn write_transaction(dev: usize, zones: &[u32], blocks: &[u32], sequence: u32) -> bool {
+	// File data isn't journaled, but it goes out before the metadata that
+	// points to it, so a committed file never points at garbage.
+	if !bcache::sync(Some(dev)) {
+		return false;
+	}
+	let mut header_buffer = Buffer::new(BLOCK_SIZE as usize);
+	for i in 0..BLOCK_SIZE as usize {
+		header_buffer[i] = 0;
+	}
+	let header = unsafe { &mut *(header_buffer.get_mut() as *mut Header) };
+	header.magic = MAGIC;
+	header.sequence = sequence;
+	header.count = blocks.len() as u32;
+	let mut hash = checksum(CHECKSUM_START, &sequence.to_le_bytes());
+	hash = checksum(hash, &header.count.to_le_bytes());
+	for (i, &block) in blocks.iter().enumerate() {
+		header.homes[i] = block;
+		hash = checksum(hash, &block.to_le_bytes());
+	}
+	// 1. The images go in the log.
+	let mut image = Buffer::new(BLOCK_SIZE as usize);
+	for (&block, &zone) in blocks.iter().zip(zones[1..].iter()) {
+		if !read_block(dev, block, &mut image) || !write_block(dev, zone, &image) {
+			return false;
+		}
+		hash = checksum(hash, block_bytes(&image));
+	}
+	if !bcache::sync(Some(dev)) || !flush(dev) {
+		return false;
+	}
+	// 2. The header commits the transaction.
+	header.checksum = hash;
+	if !write_block(dev, zones[0], &header_buffer) || !bcache::sync(Some(dev)) || !flush(dev) {
+		return false;
+	}
+	// 3. Now the blocks can go home.
+	bcache::unpin(dev);
+	if !bcache::sync(Some(dev)) || !flush(dev) {
+		return false;
+	}
+	// 4. Blocks of this transaction may be reused for file data by the next
+	// one, so the log has to be forgotten before any of that gets out.
+	clear(dev, zones[0])
+}
+
+/// Commit the running transaction of every device with a journal.
+pub fn commit_all() -> bool {
+	let devs: Vec<usize> = with_journals(|journals| journals.keys().cloned().collect()).unwrap_or_default();
+	devs.into_iter().fold(true, |ok, dev| commit(dev) && ok)
+}
+
+/// Get everything written to dev so far onto the disk, whether it was
+/// journaled or not.
+pub fn sync(dev: usize) -> bool {
+	commit(dev) && bcache::sync(Some(dev)) && flush(dev)
+}
+
+/// Stop journaling dev because it is being unmounted. The running
+/// transaction goes home first, so the journal is empty on the disk.
+pub fn close(dev: usize) -> bool {
+	let ok = sync(dev);
+	with_journals(|journals| journals.remove(&dev));
+	ok
+}
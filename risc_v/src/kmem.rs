@@ -3,8 +3,16 @@
 // Stephen Marz
 // 7 October 2019
 
-use crate::page::{align_val, zalloc, Table, PAGE_SIZE};
+use crate::{page::{align_val, dealloc, zalloc, zalloc_aligned, Table, PAGE_SIZE},
+            process::oom_reclaim};
 use core::{mem::size_of, ptr::null_mut};
+#[cfg(feature = "kmem-debug")]
+use core::{panic::Location, ptr::null};
+
+/// Every chunk handed out by kmalloc is aligned to this many bytes since
+/// the heap starts on a page boundary and all chunk sizes are rounded up
+/// to a multiple of 8 (including the AllocList header).
+pub const KMEM_MIN_ALIGN: usize = 8;
 
 #[repr(usize)]
 enum AllocListFlags {
@@ -57,6 +65,13 @@ static mut KMEM_HEAD: *mut AllocList = null_mut();
 // see if we actually need to allocate more.
 static mut KMEM_ALLOC: usize = 0;
 static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
+// How many bytes of the heap aren't in a taken chunk, headers included.
+static mut KMEM_FREE: usize = 0;
+// A system call can't back out if an allocation fails halfway through, and
+// only some of them can deal with null. One that starts with less than this
+// free gets ENOMEM before it does anything (see low_memory()), which leaves
+// it plenty for what it allocates along the way.
+const KMEM_RESERVE: usize = 64 * PAGE_SIZE;
 
 // These functions are safe helpers around an unsafe
 // operation.
@@ -72,6 +87,16 @@ pub fn get_num_allocations() -> usize {
 	unsafe { KMEM_ALLOC }
 }
 
+/// Is the heap down to the last KMEM_RESERVE bytes?
+pub fn low_memory() -> bool {
+	unsafe { KMEM_FREE < KMEM_RESERVE }
+}
+
+/// Return the (start, end) physical addresses of the kmalloc heap.
+pub fn heap_range() -> (usize, usize) {
+	unsafe { (KMEM_HEAD as usize, KMEM_HEAD as usize + KMEM_ALLOC * PAGE_SIZE) }
+}
+
 /// Initialize kernel's memory
 /// This is not to be used to allocate memory
 /// for user processes. If that's the case, use
@@ -85,17 +110,28 @@ pub fn init() {
 		KMEM_HEAD = k_alloc as *mut AllocList;
 		(*KMEM_HEAD).set_free();
 		(*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
+		KMEM_FREE = KMEM_ALLOC * PAGE_SIZE;
 		KMEM_PAGE_TABLE = zalloc(1) as *mut Table;
 	}
 }
 
 /// Allocate sub-page level allocation based on bytes and zero the memory
+#[track_caller]
 pub fn kzmalloc(sz: usize) -> *mut u8 {
-	let size = align_val(sz, 3);
-	let ret = kmalloc(size);
+	zero(kmalloc(sz), sz)
+}
+
+/// Same as kzmalloc(), except the OOM killer gets a chance to free memory
+/// before we give up. See kmalloc_reclaim().
+#[track_caller]
+pub fn kzmalloc_reclaim(sz: usize) -> *mut u8 {
+	zero(kmalloc_reclaim(sz), sz)
+}
 
+/// Zero the first sz bytes of a kmalloc chunk, unless it is null.
+fn zero(ret: *mut u8, sz: usize) -> *mut u8 {
 	if !ret.is_null() {
-		for i in 0..size {
+		for i in 0..sz {
 			unsafe {
 				(*ret.add(i)) = 0;
 			}
@@ -105,7 +141,39 @@ pub fn kzmalloc(sz: usize) -> *mut u8 {
 }
 
 /// Allocate sub-page level allocation based on bytes
+/// With the kmem-debug feature, the caller's file and line are recorded
+/// with the allocation so that leaks and overruns can be traced back.
+/// Only direct callers (including Buffer users) are attributed this way.
+/// #[track_caller] can't follow a call through the GlobalAlloc trait, so
+/// every Box, Vec, String or BTreeMap allocation records the same line in
+/// OsGlobalAlloc::alloc, and the leak report lumps them together.
+/// If the heap is full, this returns null. Callers that can deal with that
+/// should use this one, so a large request doesn't kill other processes.
+#[track_caller]
 pub fn kmalloc(sz: usize) -> *mut u8 {
+	#[cfg(feature = "kmem-debug")]
+	let ret = debug_kmalloc(sz, Location::caller());
+	#[cfg(not(feature = "kmem-debug"))]
+	let ret = kmalloc_chunk(sz);
+	ret
+}
+
+/// Same as kmalloc(), but for callers that can't deal with null (such as
+/// the global allocator). If the heap is full, the OOM killer gets a chance
+/// to free memory. See oom_reclaim() for when we can wait for that and when
+/// we have to give up and return null.
+#[track_caller]
+pub fn kmalloc_reclaim(sz: usize) -> *mut u8 {
+	loop {
+		let ret = kmalloc(sz);
+		if !ret.is_null() || !oom_reclaim() {
+			return ret;
+		}
+	}
+}
+
+/// Find a free chunk on the kernel heap that can hold sz bytes and take it.
+fn kmalloc_chunk(sz: usize) -> *mut u8 {
 	unsafe {
 		let size = align_val(sz, 3) + size_of::<AllocList>();
 		let mut head = KMEM_HEAD;
@@ -132,6 +200,7 @@ pub fn kmalloc(sz: usize) -> *mut u8 {
 					// If we get here, take the entire chunk
 					(*head).set_size(chunk_size);
 				}
+				KMEM_FREE -= (*head).get_size();
 				return head.add(1) as *mut u8;
 			}
 			else {
@@ -148,12 +217,23 @@ pub fn kmalloc(sz: usize) -> *mut u8 {
 }
 
 /// Free a sub-page level allocation
+/// With the kmem-debug feature, double frees and writes past either end
+/// of the allocation are caught here, and freed memory is poisoned.
 pub fn kfree(ptr: *mut u8) {
+	#[cfg(feature = "kmem-debug")]
+	debug_kfree(ptr);
+	#[cfg(not(feature = "kmem-debug"))]
+	kfree_chunk(ptr);
+}
+
+/// Give a chunk back to the kernel heap.
+fn kfree_chunk(ptr: *mut u8) {
 	unsafe {
 		if !ptr.is_null() {
 			let p = (ptr as *mut AllocList).offset(-1);
 			if (*p).is_taken() {
 				(*p).set_free();
+				KMEM_FREE += (*p).get_size();
 			}
 			// After we free, see if we can combine adjacent free
 			// spots to see if we can reduce fragmentation.
@@ -162,6 +242,55 @@ pub fn kfree(ptr: *mut u8) {
 	}
 }
 
+/// Resize a sub-page level allocation without moving it. If the chunk
+/// (possibly combined with a free chunk right after it) can hold sz bytes,
+/// the chunk is resized, any leftover is given back to the heap, and this
+/// returns true. Otherwise, nothing changes and this returns false, which
+/// means the caller has to allocate somewhere else and copy.
+pub fn kresize(ptr: *mut u8, sz: usize) -> bool {
+	// The redzone behind a debug allocation would have to move, so the
+	// debug heap always makes the caller copy.
+	if ptr.is_null() || cfg!(feature = "kmem-debug") {
+		return false;
+	}
+	unsafe {
+		let head = (ptr as *mut AllocList).offset(-1);
+		let size = align_val(sz, 3) + size_of::<AllocList>();
+		let old_size = (*head).get_size();
+		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
+		           as *mut AllocList;
+		let next = (head as *mut u8).add((*head).get_size())
+		           as *mut AllocList;
+		// If we need to grow, the only place we can grow into is the
+		// chunk right after us, and only if it is free.
+		if size > (*head).get_size()
+		   && next < tail
+		   && (*next).is_free()
+		   && (*head).get_size() + (*next).get_size() >= size
+		{
+			(*head).set_size((*head).get_size() + (*next).get_size());
+		}
+		let chunk_size = (*head).get_size();
+		if size > chunk_size {
+			return false;
+		}
+		KMEM_FREE -= chunk_size - old_size;
+		// Just like kmalloc, split off whatever we don't need if there's
+		// room for another AllocList header.
+		let rem = chunk_size - size;
+		if rem > size_of::<AllocList>() {
+			let next = (head as *mut u8).add(size) as *mut AllocList;
+			(*next).set_free();
+			(*next).set_size(rem);
+			(*head).set_size(size);
+			KMEM_FREE += rem;
+			// The piece we split off might sit next to a free chunk.
+			coalesce();
+		}
+	}
+	true
+}
+
 /// Merge smaller chunks into a bigger chunk
 pub fn coalesce() {
 	unsafe {
@@ -206,22 +335,215 @@ pub fn coalesce() {
 }
 
 /// For debugging purposes, print the kmem table
+/// With the kmem-debug feature, each taken chunk also shows where it was
+/// allocated, followed by a leak report of live allocations by site.
 pub fn print_table() {
 	unsafe {
 		let mut head = KMEM_HEAD;
 		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
 		           as *mut AllocList;
 		while head < tail {
-			println!(
-			         "{:p}: Length = {:<10} Taken = {}",
-			         head,
-			         (*head).get_size(),
-			         (*head).is_taken()
+			print!(
+			       "{:p}: Length = {:<10} Taken = {}",
+			       head,
+			       (*head).get_size(),
+			       (*head).is_taken()
 			);
+			#[cfg(feature = "kmem-debug")]
+			{
+				let hdr = head.add(1) as *const DebugHeader;
+				if (*head).is_taken() && (*hdr).magic == KMEM_DEBUG_LIVE {
+					print!(" Size = {:<8} Site = {}", (*hdr).size, *(*hdr).site);
+				}
+			}
+			println!();
 			head = (head as *mut u8).add((*head).get_size())
 			       as *mut AllocList;
 		}
 	}
+	#[cfg(feature = "kmem-debug")]
+	print_leaks();
+}
+
+// ///////////////////////////////////
+// / HEAP DEBUGGING (kmem-debug)
+// ///////////////////////////////////
+
+// With the kmem-debug feature, every kmalloc chunk looks like the
+// following:
+// [AllocList][DebugHeader][front redzone][data][slack + back redzone]
+// The pointer handed out is the start of data. The redzones are filled
+// with a known pattern, so if that pattern changes by the time we free,
+// someone wrote outside of their allocation.
+
+// Written into DebugHeader::magic so we can tell a live chunk from a freed
+// one (or from a pointer that never came from kmalloc).
+#[cfg(feature = "kmem-debug")]
+const KMEM_DEBUG_LIVE: usize = 0x6b6d_656d_4c49_5645;
+#[cfg(feature = "kmem-debug")]
+const KMEM_DEBUG_FREED: usize = 0x6b6d_656d_4652_4545;
+// Size of the redzone on either side of the data.
+#[cfg(feature = "kmem-debug")]
+const REDZONE_SIZE: usize = 16;
+// Redzones are filled with this byte.
+#[cfg(feature = "kmem-debug")]
+const REDZONE_BYTE: u8 = 0xfd;
+// Freed memory is filled with this byte, so a use-after-free shows up as
+// 0x6b6b6b6b... instead of something that looks valid.
+#[cfg(feature = "kmem-debug")]
+const POISON_FREE: u8 = 0x6b;
+// Fresh kmalloc (not kzmalloc) memory gets this, so reading
+// uninitialized memory is easier to spot.
+#[cfg(feature = "kmem-debug")]
+const POISON_ALLOC: u8 = 0xa5;
+// How many different allocation sites the leak report can keep track of.
+#[cfg(feature = "kmem-debug")]
+const MAX_LEAK_SITES: usize = 64;
+
+#[cfg(feature = "kmem-debug")]
+#[repr(C)]
+struct DebugHeader {
+	magic: usize,
+	size:  usize,
+	site:  *const Location<'static>,
+	_pad:  usize,
+}
+
+#[cfg(feature = "kmem-debug")]
+fn debug_kmalloc(sz: usize, site: &'static Location<'static>) -> *mut u8 {
+	let total = size_of::<DebugHeader>() + REDZONE_SIZE + align_val(sz, 3) + REDZONE_SIZE;
+	let chunk = kmalloc_chunk(total);
+	if chunk.is_null() {
+		return chunk;
+	}
+	unsafe {
+		let hdr = chunk as *mut DebugHeader;
+		(*hdr).magic = KMEM_DEBUG_LIVE;
+		(*hdr).size = sz;
+		(*hdr).site = site;
+		(*hdr)._pad = 0;
+		let data = chunk.add(size_of::<DebugHeader>() + REDZONE_SIZE);
+		for i in 0..REDZONE_SIZE {
+			data.sub(REDZONE_SIZE).add(i).write(REDZONE_BYTE);
+		}
+		for i in 0..sz {
+			data.add(i).write(POISON_ALLOC);
+		}
+		// The back redzone also covers the slack that rounding up to 8
+		// bytes gave us, so even a 1-byte overrun is caught.
+		for i in sz..align_val(sz, 3) + REDZONE_SIZE {
+			data.add(i).write(REDZONE_BYTE);
+		}
+		data
+	}
+}
+
+#[cfg(feature = "kmem-debug")]
+fn debug_kfree(ptr: *mut u8) {
+	if ptr.is_null() {
+		return;
+	}
+	unsafe {
+		let chunk = ptr.sub(size_of::<DebugHeader>() + REDZONE_SIZE);
+		let hdr = chunk as *mut DebugHeader;
+		let list = (chunk as *mut AllocList).offset(-1);
+		if (*hdr).magic == KMEM_DEBUG_FREED || ((*hdr).magic == KMEM_DEBUG_LIVE && (*list).is_free()) {
+			panic!("kfree: double free of {:p} (allocated at {})", ptr, *(*hdr).site);
+		}
+		if (*hdr).magic != KMEM_DEBUG_LIVE {
+			panic!("kfree: {:p} was not allocated by kmalloc", ptr);
+		}
+		let sz = (*hdr).size;
+		for i in 0..REDZONE_SIZE {
+			if ptr.sub(REDZONE_SIZE).add(i).read() != REDZONE_BYTE {
+				panic!(
+				       "kfree: heap underrun {} byte(s) before {:p} ({} bytes allocated at {})",
+				       REDZONE_SIZE - i,
+				       ptr,
+				       sz,
+				       *(*hdr).site
+				);
+			}
+		}
+		for i in sz..align_val(sz, 3) + REDZONE_SIZE {
+			if ptr.add(i).read() != REDZONE_BYTE {
+				panic!(
+				       "kfree: heap overrun at byte {} of {:p} ({} bytes allocated at {})",
+				       i,
+				       ptr,
+				       sz,
+				       *(*hdr).site
+				);
+			}
+		}
+		// Poison everything the user could have touched, including the
+		// redzones, and mark the header so a second kfree is caught.
+		for i in 0..align_val(sz, 3) + REDZONE_SIZE * 2 {
+			ptr.sub(REDZONE_SIZE).add(i).write(POISON_FREE);
+		}
+		(*hdr).magic = KMEM_DEBUG_FREED;
+		kfree_chunk(chunk);
+	}
+}
+
+/// Print every allocation site that still has live allocations, along
+/// with how many there are and how many bytes they hold. Anything that
+/// keeps growing between two reports is most likely a leak.
+/// Allocations made through the global allocator all share one site.
+#[cfg(feature = "kmem-debug")]
+pub fn print_leaks() {
+	// We're reporting on the heap, so we can't allocate from it while
+	// we walk it. A fixed table on the stack will do.
+	let mut sites: [(*const Location<'static>, usize, usize); MAX_LEAK_SITES] =
+		[(null(), 0, 0); MAX_LEAK_SITES];
+	let mut num_sites = 0;
+	let mut dropped = 0;
+	unsafe {
+		let mut head = KMEM_HEAD;
+		let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE)
+		           as *mut AllocList;
+		while head < tail {
+			let hdr = head.add(1) as *const DebugHeader;
+			if (*head).is_taken() && (*hdr).magic == KMEM_DEBUG_LIVE {
+				let site = (*hdr).site;
+				// Location has no stable identity, so compare the file
+				// and line instead of the pointer.
+				let found = sites[..num_sites].iter().position(|s| {
+					                                 (*s.0).file() == (*site).file()
+					                                 && (*s.0).line() == (*site).line()
+				                                 });
+				if let Some(i) = found {
+					sites[i].1 += 1;
+					sites[i].2 += (*hdr).size;
+				}
+				else if num_sites < MAX_LEAK_SITES {
+					sites[num_sites] = (site, 1, (*hdr).size);
+					num_sites += 1;
+				}
+				else {
+					dropped += 1;
+				}
+			}
+			head = (head as *mut u8).add((*head).get_size())
+			       as *mut AllocList;
+		}
+		println!();
+		println!("LIVE KMALLOC ALLOCATIONS BY SITE");
+		println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+		for s in sites[..num_sites].iter() {
+			print!("{:>6} allocation(s) {:>10} bytes  {}", s.1, s.2, *s.0);
+			// This is the line in OsGlobalAlloc::alloc, not a real
+			// allocation site. See kmalloc().
+			if (*s.0).file() == file!() {
+				print!(" (global allocator: Box, Vec, ...)");
+			}
+			println!();
+		}
+		if dropped > 0 {
+			println!("{} allocation(s) from sites that didn't fit in the report.", dropped);
+		}
+		println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+	}
 }
 
 // ///////////////////////////////////
@@ -239,18 +561,117 @@ use core::alloc::{GlobalAlloc, Layout};
 // structure just to implement alloc and dealloc.
 struct OsGlobalAlloc;
 
+// kmalloc only guarantees KMEM_MIN_ALIGN, so anything that needs more
+// than that has to be handled here. Alignments below a page are
+// over-allocated from kmalloc, and the raw pointer kmalloc gave us is
+// stashed in the 8 bytes right before the aligned pointer so that
+// dealloc can find it again. Page (or larger) alignments go straight to
+// the page allocator since it already deals in 4096-byte chunks.
+impl OsGlobalAlloc {
+	/// Number of bytes we have to ask kmalloc for so that an aligned
+	/// pointer plus size bytes fits inside of the chunk.
+	fn padded_size(layout: &Layout) -> usize {
+		layout.size() + layout.align()
+	}
+
+	/// Number of pages needed for a page-aligned allocation.
+	fn num_pages(size: usize) -> usize {
+		// A zero-sized layout still needs a real address.
+		if size == 0 {
+			1
+		}
+		else {
+			(size + PAGE_SIZE - 1) / PAGE_SIZE
+		}
+	}
+
+	/// Recover the pointer kmalloc gave us from an over-aligned pointer.
+	unsafe fn raw_ptr(ptr: *mut u8) -> *mut u8 {
+		*(ptr as *mut usize).offset(-1) as *mut u8
+	}
+}
+
 unsafe impl GlobalAlloc for OsGlobalAlloc {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-		// We align to the next page size so that when
-		// we divide by PAGE_SIZE, we get exactly the number
-		// of pages necessary.
-		kzmalloc(layout.size())
+		// Box, Vec and friends can't deal with null, so these are the
+		// allocations the OOM killer is for.
+		if layout.align() <= KMEM_MIN_ALIGN {
+			kzmalloc_reclaim(layout.size())
+		}
+		else if layout.align() < PAGE_SIZE {
+			let raw = kzmalloc_reclaim(Self::padded_size(&layout));
+			if raw.is_null() {
+				return null_mut();
+			}
+			// raw is already 8-byte aligned, so aligning raw + 8 up
+			// always leaves at least 8 bytes in front of the returned
+			// pointer to store raw.
+			let aligned = (raw as usize + size_of::<usize>()
+			               + layout.align()
+			               - 1) & !(layout.align() - 1);
+			*(aligned as *mut usize).offset(-1) = raw as usize;
+			aligned as *mut u8
+		}
+		else {
+			zalloc_aligned(Self::num_pages(layout.size()), layout.align())
+		}
 	}
 
-	unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-		// We ignore layout since our allocator uses ptr_start -> last
-		// to determine the span of an allocation.
-		kfree(ptr);
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		// The layout tells us which of the three paths in alloc() gave
+		// us this pointer.
+		if layout.align() <= KMEM_MIN_ALIGN {
+			kfree(ptr);
+		}
+		else if layout.align() < PAGE_SIZE {
+			kfree(Self::raw_ptr(ptr));
+		}
+		else {
+			dealloc(ptr);
+		}
+	}
+
+	unsafe fn realloc(&self,
+	                  ptr: *mut u8,
+	                  layout: Layout,
+	                  new_size: usize)
+	                  -> *mut u8
+	{
+		// First, try to do this in place so we don't have to copy.
+		let in_place = if layout.align() <= KMEM_MIN_ALIGN {
+			kresize(ptr, new_size)
+		}
+		else if layout.align() < PAGE_SIZE {
+			// The aligned pointer doesn't move, so the chunk has to
+			// cover the padding in front of it as well.
+			let raw = Self::raw_ptr(ptr);
+			kresize(raw, ptr as usize - raw as usize + new_size)
+		}
+		else {
+			// The page allocator can't split or extend a run of
+			// pages, but we don't need to if the page count stays
+			// the same.
+			Self::num_pages(new_size) == Self::num_pages(layout.size())
+		};
+		if in_place {
+			return ptr;
+		}
+		let new_layout =
+			Layout::from_size_align_unchecked(new_size, layout.align());
+		let new_ptr = self.alloc(new_layout);
+		if !new_ptr.is_null() {
+			let bytes = if new_size < layout.size() {
+				new_size
+			}
+			else {
+				layout.size()
+			};
+			for i in 0..bytes {
+				new_ptr.add(i).write(ptr.add(i).read());
+			}
+			self.dealloc(ptr, layout);
+		}
+		new_ptr
 	}
 }
 
@@ -262,8 +683,13 @@ static GA: OsGlobalAlloc = OsGlobalAlloc {};
 
 #[alloc_error_handler]
 /// If for some reason alloc() in the global allocator gets null_mut(),
-/// then we come here. This is a divergent function, so we call panic to
-/// let the tester know what's going on.
+/// then we come here. That only happens when the OOM killer has nobody
+/// left to kill, or in the trap handler, which can't wait for the scheduler
+/// to reap the victim. System calls that start with the heap already down
+/// to its reserve get ENOMEM instead (see low_memory()), so we don't get
+/// here halfway through one unless the reserve wasn't enough. We can't back
+/// out of whatever the kernel was doing, and switching away would leave its
+/// locks held, so this is a divergent function that panics.
 pub fn alloc_error(l: Layout) -> ! {
 	panic!(
 	       "Allocator failed to allocate {} bytes with {}-byte alignment.",
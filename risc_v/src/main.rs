@@ -95,7 +95,18 @@ extern "C" fn kinit() {
 	uart::Uart::new(0x1000_0000).init();
 	page::init();
 	kmem::init();
+	test::alloc_test();
 	process::init();
+	shm::init();
+	bcache::init();
+	journal::init();
+	vfs::init();
+	tmpfs::init();
+	// Drivers put their devices in /dev as we probe, so this goes first.
+	devfs::init();
+	// PMP needs the trap frame pool that process::init() sets up.
+	pmp::init();
+	pmp::self_test();
 	// We lower the threshold wall so our interrupts can jump over it.
 	// Any priority > 0 will be able to be "heard"
 	plic::set_threshold(0);
@@ -111,6 +122,8 @@ extern "C" fn kinit() {
 	virtio::probe();
 
 	console::init();
+	// Dirty blocks get written back every so often by their own process.
+	process::add_kernel_process(bcache::flush_proc);
 	process::add_kernel_process(test::test);
 	// Get the GPU going
 	gpu::init(6);
@@ -131,22 +144,33 @@ extern "C" fn kinit_hart(_hartid: usize) {
 // ///////////////////////////////////
 
 pub mod assembly;
+pub mod bcache;
 pub mod block;
 pub mod buffer;
 pub mod console;
 pub mod cpu;
+pub mod devfs;
 pub mod elf;
+pub mod ext2;
+pub mod fat;
 pub mod fs;
 pub mod gpu;
+pub mod initramfs;
 pub mod input;
+pub mod journal;
 pub mod kmem;
 pub mod lock;
+pub mod minix;
 pub mod page;
+pub mod partition;
 pub mod plic;
+pub mod pmp;
 pub mod process;
 pub mod rng;
 pub mod sched;
+pub mod shm;
 pub mod syscall;
+pub mod tmpfs;
 pub mod trap;
 pub mod uart;
 pub mod vfs;
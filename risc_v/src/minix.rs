@@ -0,0 +1,90 @@
+// minix.rs
+// Minix 3 on-disk layout
+// Stephen Marz
+// 19 October 2026
+
+// This file is shared with the host tool in minix-tool/, which builds and
+// checks disk images, so it can't use anything else from the kernel.
+
+pub const MAGIC: u16 = 0x4d5a;
+pub const BLOCK_SIZE: u32 = 1024;
+pub const NUM_IPTRS: usize = BLOCK_SIZE as usize / 4;
+pub const S_IFMT: u16 = 0o170_000;
+pub const S_IFDIR: u16 = 0o040_000;
+pub const S_IFREG: u16 = 0o100_000;
+pub const S_IFCHR: u16 = 0o020_000;
+pub const S_IFBLK: u16 = 0o060_000;
+pub const S_IFLNK: u16 = 0o120_000;
+pub const S_ISUID: u16 = 0o004_000;
+pub const S_ISGID: u16 = 0o002_000;
+/// The journal is a file in the root directory that mkfs makes when asked
+/// to. It has a header block and room for a transaction of
+/// JOURNAL_BLOCKS - 1 blocks.
+pub const JOURNAL_NAME: &str = ".journal";
+pub const JOURNAL_BLOCKS: u32 = 129;
+/// The superblock describes the file system on the disk. It gives
+/// us all the information we need to read the file system and navigate
+/// the file system, including where to find the inodes and zones (blocks).
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SuperBlock {
+	pub ninodes:         u32,
+	pub pad0:            u16,
+	pub imap_blocks:     u16,
+	pub zmap_blocks:     u16,
+	pub first_data_zone: u16,
+	pub log_zone_size:   u16,
+	pub pad1:            u16,
+	pub max_size:        u32,
+	pub zones:           u32,
+	pub magic:           u16,
+	pub pad2:            u16,
+	pub block_size:      u16,
+	pub disk_version:    u8
+}
+
+/// An inode stores the "meta-data" to a file. The mode stores the permissions
+/// AND type of file. This is how we differentiate a directory from a file. A file
+/// size is in here too, which tells us how many blocks we need to read. Finally, the
+/// zones array points to where we can find the blocks, which is where the data
+/// is contained for the file.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Inode {
+	pub mode:   u16,
+	pub nlinks: u16,
+	pub uid:    u16,
+	pub gid:    u16,
+	pub size:   u32,
+	pub atime:  u32,
+	pub mtime:  u32,
+	pub ctime:  u32,
+	pub zones:  [u32; 10]
+}
+
+/// Notice that an inode does not contain the name of a file. This is because
+/// more than one file name may refer to the same inode. These are called "hard links"
+/// Instead, a DirEntry essentially associates a file name with an inode as shown in
+/// the structure below.
+#[repr(C)]
+pub struct DirEntry {
+	pub inode: u32,
+	pub name:  [u8; 60]
+}
+
+impl DirEntry {
+	pub fn new(inode: u32, name: &str) -> Self {
+		let mut ret = Self { inode, name: [0; 60] };
+		for (i, c) in name.bytes().take(60).enumerate() {
+			ret.name[i] = c;
+		}
+		ret
+	}
+
+	/// The name without the NUL padding. A name that is exactly 60 bytes
+	/// long has no NUL at all.
+	pub fn name(&self) -> &[u8] {
+		let len = self.name.iter().position(|c| *c == 0).unwrap_or(60);
+		&self.name[..len]
+	}
+}
@@ -19,6 +19,12 @@ static mut ALLOC_START: usize = 0;
 const PAGE_ORDER: usize = 12;
 pub const PAGE_SIZE: usize = 1 << 12;
 
+/// The first address handed out by the page allocator. Everything from
+/// HEAP_START up to here holds the Page descriptors.
+pub fn alloc_start() -> usize {
+	unsafe { ALLOC_START }
+}
+
 /// Align (set to a multiple of some power of two)
 /// This takes an order which is the exponent to 2^order
 /// Therefore, all alignments must be made as a power of two.
@@ -118,8 +124,18 @@ pub fn init() {
 /// Allocate a page or multiple pages
 /// pages: the number of PAGE_SIZE pages to allocate
 pub fn alloc(pages: usize) -> *mut u8 {
+	alloc_aligned(pages, PAGE_SIZE)
+}
+
+/// Allocate a page or multiple pages where the first page starts
+/// on a multiple of align. The alignment must be a power of two, and
+/// anything at or below PAGE_SIZE is the same as calling alloc().
+/// pages: the number of PAGE_SIZE pages to allocate
+/// align: the byte alignment of the returned address
+pub fn alloc_aligned(pages: usize, align: usize) -> *mut u8 {
 	// We have to find a contiguous allocation of pages
 	assert!(pages > 0);
+	assert!(align.is_power_of_two());
 	unsafe {
 		// We create a Page structure for each page on the heap. We
 		// actually might have more since HEAP_SIZE moves and so does
@@ -127,6 +143,11 @@ pub fn alloc(pages: usize) -> *mut u8 {
 		let num_pages = HEAP_SIZE / PAGE_SIZE;
 		let ptr = HEAP_START as *mut Page;
 		for i in 0..num_pages - pages {
+			// ALLOC_START is only page aligned, so bigger alignments
+			// have to skip the candidates that don't line up.
+			if (ALLOC_START + PAGE_SIZE * i) & (align - 1) != 0 {
+				continue;
+			}
 			let mut found = false;
 			// Check to see if this Page is free. If so, we have our
 			// first candidate memory address.
@@ -177,9 +198,15 @@ pub fn alloc(pages: usize) -> *mut u8 {
 /// Each page is PAGE_SIZE which is calculated as 1 << PAGE_ORDER
 /// On RISC-V, this typically will be 4,096 bytes.
 pub fn zalloc(pages: usize) -> *mut u8 {
+	zalloc_aligned(pages, PAGE_SIZE)
+}
+
+/// Allocate and zero pages whose first page starts on a multiple of
+/// align. See alloc_aligned().
+pub fn zalloc_aligned(pages: usize, align: usize) -> *mut u8 {
 	// Allocate and zero a page.
 	// First, let's get the allocation
-	let ret = alloc(pages);
+	let ret = alloc_aligned(pages, align);
 	if !ret.is_null() {
 		let size = (PAGE_SIZE * pages) / 8;
 		let big_ptr = ret as *mut u64;
@@ -231,6 +258,31 @@ pub fn dealloc(ptr: *mut u8) {
 	}
 }
 
+/// Return the number of pages in the allocation that starts at ptr. This
+/// is 0 if ptr isn't the start of a page allocation.
+pub fn allocation_pages(ptr: *const u8) -> usize {
+	unsafe {
+		if (ptr as usize) < ALLOC_START {
+			return 0;
+		}
+		let addr =
+			HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
+		if addr >= ALLOC_START {
+			return 0;
+		}
+		let mut p = addr as *const Page;
+		let mut pages = 0;
+		while (*p).is_taken() {
+			pages += 1;
+			if (*p).is_last() {
+				break;
+			}
+			p = p.add(1);
+		}
+		pages
+	}
+}
+
 /// Print all page allocations
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
@@ -389,11 +441,14 @@ impl Table {
 ///       The bits MUST include one or more of the following:
 ///          Read, Write, Execute
 ///       The valid bit automatically gets added.
+/// Returns false if we ran out of memory for a page table along the way, in
+/// which case nothing was mapped.
 pub fn map(root: &mut Table,
            vaddr: usize,
            paddr: usize,
            bits: usize,
            level: usize)
+           -> bool
 {
 	// Make sure that Read, Write, or Execute have been provided
 	// otherwise, we'll leak memory and always create a page fault.
@@ -436,6 +491,9 @@ pub fn map(root: &mut Table,
 		if !v.is_valid() {
 			// Allocate a page
 			let page = zalloc(1);
+			if page.is_null() {
+				return false;
+			}
 			// The page is already aligned by 4,096, so store it
 			// directly The page is stored in the entry shifted
 			// right by 2 places.
@@ -462,6 +520,7 @@ pub fn map(root: &mut Table,
 	// Set the entry. V should be set to the correct pointer by the loop
 	// above.
 	v.set_entry(entry);
+	true
 }
 
 /// Unmaps and frees all memory associated with a table.
@@ -499,6 +558,38 @@ pub fn unmap(root: &mut Table) {
 	}
 }
 
+/// Remove the mapping of a single 4,096-byte page. This only clears the
+/// leaf entry. The page tables along the way stay around until unmap()
+/// frees them, and the physical page isn't freed since we don't know who
+/// owns it. Returns the physical address that was mapped, if any.
+/// The caller must fence the TLB afterward.
+pub fn unmap_page(root: &mut Table, vaddr: usize) -> Option<usize> {
+	let vpn = [
+	           // VPN[0] = vaddr[20:12]
+	           (vaddr >> 12) & 0x1ff,
+	           // VPN[1] = vaddr[29:21]
+	           (vaddr >> 21) & 0x1ff,
+	           // VPN[2] = vaddr[38:30]
+	           (vaddr >> 30) & 0x1ff,
+	];
+	let mut v = &mut root.entries[vpn[2]];
+	for i in (0..2).rev() {
+		// We only map 4 KiB pages, so a leaf above level 0 isn't ours
+		// to take apart.
+		if v.is_invalid() || v.is_leaf() {
+			return None;
+		}
+		let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
+		v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
+	}
+	if v.is_invalid() {
+		return None;
+	}
+	let paddr = (v.get_entry() & !0x3ff) << 2;
+	v.set_entry(0);
+	Some(paddr)
+}
+
 /// Walk the page table to convert a virtual address to a
 /// physical address.
 /// If a page fault would occur, this returns None
@@ -0,0 +1,167 @@
+// partition.rs
+// MBR and GPT partition tables
+// Stephen Marz
+// 19 October 2026
+
+use crate::buffer::Buffer;
+use alloc::vec::Vec;
+
+pub const SECTOR_SIZE: u64 = 512;
+// Partition numbers go from 1 to this. Anything past it isn't used.
+pub const MAX_PARTITIONS: usize = 15;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const MBR_TABLE: usize = 446;
+const MBR_ENTRY_SIZE: usize = 16;
+// A disk with a GPT has an MBR with one partition of this type covering it,
+// so that older tools leave it alone.
+const MBR_GPT_PROTECTIVE: u8 = 0xee;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const GPT_ENTRY_MIN: usize = 128;
+// No sane table is bigger than this, and we read it in one go.
+const GPT_TABLE_MAX: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+pub struct Partition {
+	/// 1 through MAX_PARTITIONS. For an MBR, this is the slot in the table,
+	/// and for a GPT it's the entry, the same numbers Linux uses.
+	pub number:  usize,
+	/// Where it starts on the disk, in sectors.
+	pub start:   u64,
+	/// How long it is, in sectors.
+	pub sectors: u64
+}
+
+fn le32(bytes: &[u8], offset: usize) -> u32 {
+	let mut val = 0u32;
+	for i in (0..4).rev() {
+		val = val << 8 | bytes[offset + i] as u32;
+	}
+	val
+}
+
+fn le64(bytes: &[u8], offset: usize) -> u64 {
+	le32(bytes, offset) as u64 | (le32(bytes, offset + 4) as u64) << 32
+}
+
+/// The CRC-32 a GPT uses for its header and entries, which is the common
+/// one (the same as zlib's).
+fn crc32(bytes: &[u8]) -> u32 {
+	let mut crc = !0u32;
+	for &b in bytes {
+		crc ^= b as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+		}
+	}
+	!crc
+}
+
+/// Read sectors sectors starting at sector start into a new buffer, using
+/// whatever the caller reads the disk with.
+fn read_sectors(read: &mut dyn FnMut(*mut u8, u32, u64) -> bool, start: u64, sectors: usize) -> Option<Buffer> {
+	let size = sectors * SECTOR_SIZE as usize;
+	let mut buffer = Buffer::new(size);
+	if buffer.get().is_null() || !read(buffer.get_mut(), size as u32, start * SECTOR_SIZE) {
+		return None;
+	}
+	Some(buffer)
+}
+
+fn bytes(buffer: &Buffer) -> &[u8] {
+	unsafe { core::slice::from_raw_parts(buffer.get(), buffer.len()) }
+}
+
+/// Find the partitions on a disk that is capacity sectors long. read(buffer,
+/// size, offset) reads size bytes at byte offset of the disk, and size is
+/// always whole sectors. A disk without a partition table, or with one that
+/// doesn't make sense, has no partitions. So does one whose partitions run
+/// off the end of it, other than the ones that fit.
+pub fn read_table(read: &mut dyn FnMut(*mut u8, u32, u64) -> bool, capacity: u64) -> Vec<Partition> {
+	let mbr = match read_sectors(read, 0, 1) {
+		Some(b) => b,
+		None => return Vec::new(),
+	};
+	let mbr = bytes(&mbr);
+	if mbr[510..512] != MBR_SIGNATURE {
+		return Vec::new();
+	}
+	// A FAT boot sector has the same signature, with code where the table
+	// would be. Every real entry is either bootable (0x80) or not (0).
+	let entry = |slot: usize| &mbr[MBR_TABLE + slot * MBR_ENTRY_SIZE..MBR_TABLE + (slot + 1) * MBR_ENTRY_SIZE];
+	if (0..4).any(|slot| entry(slot)[0] & 0x7f != 0) {
+		return Vec::new();
+	}
+	let mut partitions = Vec::new();
+	for slot in 0..4 {
+		let entry = entry(slot);
+		match entry[4] {
+			0 => {},
+			MBR_GPT_PROTECTIVE => return read_gpt(read, capacity),
+			// Extended partitions only hold more partitions, in a chain of
+			// tables of their own. We don't follow it, so the logical
+			// partitions in there don't show up.
+			0x05 | 0x0f | 0x85 => {},
+			_ => partitions.push(Partition { number:  slot + 1,
+			                                 start:   le32(entry, 8) as u64,
+			                                 sectors: le32(entry, 12) as u64 }),
+		}
+	}
+	partitions.retain(|p| p.sectors > 0 && p.start > 0 && p.start + p.sectors <= capacity);
+	partitions
+}
+
+/// The GPT header is in sector 1 and points to the table of entries. Both
+/// have a CRC, and we don't trust either unless it matches. There's a copy
+/// of both at the end of the disk, but we only look at the first.
+fn read_gpt(read: &mut dyn FnMut(*mut u8, u32, u64) -> bool, capacity: u64) -> Vec<Partition> {
+	let mut partitions = Vec::new();
+	let header = match read_sectors(read, 1, 1) {
+		Some(b) => b,
+		None => return partitions,
+	};
+	let header = bytes(&header);
+	let header_size = le32(header, 12) as usize;
+	if &header[0..8] != GPT_SIGNATURE || header_size < 92 || header_size > SECTOR_SIZE as usize {
+		return partitions;
+	}
+	// The CRC is over the header with the CRC itself zeroed.
+	let mut copy = [0u8; SECTOR_SIZE as usize];
+	copy[..header_size].copy_from_slice(&header[..header_size]);
+	copy[16..20].copy_from_slice(&[0; 4]);
+	if crc32(&copy[..header_size]) != le32(header, 16) {
+		return partitions;
+	}
+	let table_start = le64(header, 72);
+	let entries = le32(header, 80) as usize;
+	let entry_size = le32(header, 84) as usize;
+	let table_size = entries * entry_size;
+	if entry_size < GPT_ENTRY_MIN || table_size == 0 || table_size > GPT_TABLE_MAX {
+		return partitions;
+	}
+	let sectors = (table_size + SECTOR_SIZE as usize - 1) / SECTOR_SIZE as usize;
+	let table = match read_sectors(read, table_start, sectors) {
+		Some(b) => b,
+		None => return partitions,
+	};
+	let table = &bytes(&table)[..table_size];
+	if crc32(table) != le32(header, 88) {
+		return partitions;
+	}
+	for i in 0..entries.min(MAX_PARTITIONS) {
+		let entry = &table[i * entry_size..(i + 1) * entry_size];
+		// An entry that's not in use has a type of all zeros.
+		if entry[0..16].iter().all(|&b| b == 0) {
+			continue;
+		}
+		let first = le64(entry, 32);
+		let last = le64(entry, 40);
+		if first == 0 || last < first || last >= capacity {
+			continue;
+		}
+		partitions.push(Partition { number:  i + 1,
+		                            start:   first,
+		                            sectors: last - first + 1 });
+	}
+	partitions
+}
@@ -0,0 +1,234 @@
+// pmp.rs
+// Physical Memory Protection
+// Stephen Marz
+// 19 October 2026
+
+use crate::{cpu::{build_satp, satp_fence_asid, CpuMode, Registers, SatpMode},
+            kmem::heap_range,
+            page::{alloc_start, map, zalloc, EntryBits, Table, PAGE_SIZE},
+            process::{alloc_frame,
+                      trap_frame_pool,
+                      Process,
+                      ProcessData,
+                      ProcessState,
+                      NEXT_PID,
+                      PROCESS_LIST,
+                      PROCESS_LIST_MUTEX,
+                      PROCESS_STARTING_ADDR}};
+
+extern "C" {
+	static TEXT_START: usize;
+	static HEAP_START: usize;
+	static HEAP_SIZE: usize;
+}
+
+// Each PMP entry has an 8-bit configuration packed into pmpcfg0 (entries
+// 0 - 7 on RV64). The lower three bits are the permissions, the A field
+// is how the address register is matched, and L locks the entry.
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_RWX: u8 = PMP_R | PMP_W | PMP_X;
+pub const PMP_NONE: u8 = 0;
+// Address matching (A field, bits 4:3)
+pub const PMP_A_OFF: u8 = 0 << 3;
+pub const PMP_A_TOR: u8 = 1 << 3;
+pub const PMP_A_NA4: u8 = 2 << 3;
+pub const PMP_A_NAPOT: u8 = 3 << 3;
+pub const PMP_L: u8 = 1 << 7;
+
+// The kernel runs in machine mode, and an entry without the L bit doesn't
+// apply to machine mode at all. Setting L would also hold machine mode to the
+// entry's permissions, which would lock the kernel out of its own code.
+// Instead, we leave L off and give the protected regions no permissions. Since
+// any user/supervisor access that doesn't match an entry is denied, MMIO
+// is also off limits to user mode. The kernel does all device I/O for users.
+//
+// All entries are top-of-range (TOR), which means entry i covers
+// pmpaddr[i-1] <= addr < pmpaddr[i].
+//
+//  #  Region                                           U/S access
+//  0  (base for entry 1) start of the kernel image     -
+//  1  kernel image, kernel stack, Page descriptors     none
+//  2  allocatable RAM below the first region below     RWX
+//  3  trap frame pool or kmalloc heap                  none
+//  4  allocatable RAM between the two                  RWX
+//  5  kmalloc heap or trap frame pool                  none
+//  6  allocatable RAM above both                       RWX
+//
+// The trap frame pool and the kmalloc heap each come out of the page
+// allocator in one piece, so whichever is lower gets entry 3. Everything
+// else the kernel takes from the page allocator, most notably the stacks
+// of kernel processes, is scattered through allocatable RAM, which we can't
+// cover with the few entries we have. PMP doesn't protect those.
+//
+// RWX here doesn't mean user processes can touch any of it. The MMU still
+// has to map it for them. PMP is just the backstop if a page table is wrong.
+
+/// Write an address register. The address is shifted right by 2 since
+/// pmpaddr holds bits 55:2 of the physical address.
+fn pmpaddr_write(idx: usize, addr: usize) {
+	let val = addr >> 2;
+	unsafe {
+		match idx {
+			0 => llvm_asm!("csrw pmpaddr0, $0" :: "r"(val)),
+			1 => llvm_asm!("csrw pmpaddr1, $0" :: "r"(val)),
+			2 => llvm_asm!("csrw pmpaddr2, $0" :: "r"(val)),
+			3 => llvm_asm!("csrw pmpaddr3, $0" :: "r"(val)),
+			4 => llvm_asm!("csrw pmpaddr4, $0" :: "r"(val)),
+			5 => llvm_asm!("csrw pmpaddr5, $0" :: "r"(val)),
+			6 => llvm_asm!("csrw pmpaddr6, $0" :: "r"(val)),
+			7 => llvm_asm!("csrw pmpaddr7, $0" :: "r"(val)),
+			_ => panic!("PMP entry {} is not supported", idx),
+		}
+	}
+}
+
+fn pmpcfg0_write(val: usize) {
+	unsafe {
+		llvm_asm!("csrw pmpcfg0, $0" :: "r"(val));
+	}
+}
+
+pub fn pmpcfg0_read() -> usize {
+	unsafe {
+		let rval;
+		llvm_asm!("csrr $0, pmpcfg0" :"=r"(rval));
+		rval
+	}
+}
+
+/// Program the PMP entries described above. This has to run after
+/// process::init() since the trap frame pool needs to exist.
+pub fn init() {
+	let (kernel_start, memory_end) = unsafe { (TEXT_START, HEAP_START + HEAP_SIZE) };
+	let (frames_start, frames_end) = trap_frame_pool();
+	let (heap_start, heap_end) = heap_range();
+	let (low, high) = if frames_start < heap_start {
+		((frames_start, frames_end), (heap_start, heap_end))
+	}
+	else {
+		((heap_start, heap_end), (frames_start, frames_end))
+	};
+	let entries = [
+		(kernel_start, PMP_A_OFF),
+		(alloc_start(), PMP_A_TOR | PMP_NONE),
+		(low.0, PMP_A_TOR | PMP_RWX),
+		(low.1, PMP_A_TOR | PMP_NONE),
+		(high.0, PMP_A_TOR | PMP_RWX),
+		(high.1, PMP_A_TOR | PMP_NONE),
+		(memory_end, PMP_A_TOR | PMP_RWX),
+	];
+	let mut cfg = 0usize;
+	for (i, (addr, bits)) in entries.iter().enumerate() {
+		pmpaddr_write(i, *addr);
+		cfg |= (*bits as usize) << (i * 8);
+	}
+	// The configuration is written last so that no entry turns on before
+	// its address is in place.
+	pmpcfg0_write(cfg);
+	println!(
+	         "PMP: kernel 0x{:08x}-0x{:08x}, trap frames 0x{:08x}-0x{:08x} and kmalloc heap 0x{:08x}-0x{:08x} protected.",
+	         kernel_start,
+	         alloc_start(),
+	         frames_start,
+	         frames_end,
+	         heap_start,
+	         heap_end
+	);
+}
+
+// ///////////////////////////////////
+// / SELF TEST
+// ///////////////////////////////////
+
+// The self test runs a tiny user process that loads from the kernel image.
+// Its page table maps the kernel page, so the MMU would allow it, and only
+// PMP is there to stop it. The process should die with a load access fault
+// (cause 5), not a page fault.
+static mut SELF_TEST_PID: u16 = 0;
+// Where the kernel page is mapped in the test process.
+const SELF_TEST_VADDR: usize = 0x4000_0000;
+// The test program:
+//   ld   t0, 0(a0)      # a0 = SELF_TEST_VADDR
+//   li   a7, 93
+//   ecall               # exit, which only happens if the load worked
+const SELF_TEST_CODE: [u32; 3] = [0x0005_3283, 0x05d0_0893, 0x0000_0073];
+
+/// Start the PMP self test. The result is printed when the test process
+/// faults or exits.
+pub fn self_test() {
+	let program = zalloc(1);
+	let my_pid = unsafe {
+		let pid = NEXT_PID;
+		NEXT_PID += 1;
+		pid
+	};
+	let mut my_proc = Process { frame:       alloc_frame(),
+	                            stack:       zalloc(1),
+	                            pid:         my_pid,
+	                            mmu_table:   zalloc(1) as *mut Table,
+	                            state:       ProcessState::Running,
+	                            data:        ProcessData::new(),
+	                            sleep_until: 0,
+	                            program,
+	                            brk:         0, };
+	if !my_proc.is_allocated() || program.is_null() {
+		println!("PMP self-test: out of memory, skipping.");
+		return;
+	}
+	unsafe {
+		let code = program as *mut u32;
+		for (i, inst) in SELF_TEST_CODE.iter().enumerate() {
+			code.add(i).write(*inst);
+		}
+		let table = my_proc.mmu_table.as_mut().unwrap();
+		if !map(table, PROCESS_STARTING_ADDR, program as usize, EntryBits::UserReadExecute.val(), 0)
+		   || !map(table, SELF_TEST_VADDR, TEXT_START, EntryBits::User.val() | EntryBits::Read.val(), 0)
+		{
+			println!("PMP self-test: out of memory, skipping.");
+			return;
+		}
+		(*my_proc.frame).pc = PROCESS_STARTING_ADDR;
+		(*my_proc.frame).regs[Registers::A0 as usize] = SELF_TEST_VADDR;
+		(*my_proc.frame).regs[Registers::Sp as usize] = my_proc.stack as usize + PAGE_SIZE;
+		(*my_proc.frame).mode = CpuMode::User as usize;
+		(*my_proc.frame).pid = my_pid as usize;
+		(*my_proc.frame).satp = build_satp(SatpMode::Sv39, my_pid as usize, my_proc.mmu_table as usize);
+		satp_fence_asid(my_pid as usize);
+		SELF_TEST_PID = my_pid;
+		PROCESS_LIST_MUTEX.spin_lock();
+		if let Some(mut pl) = PROCESS_LIST.take() {
+			pl.push_back(my_proc);
+			PROCESS_LIST.replace(pl);
+		}
+		PROCESS_LIST_MUTEX.unlock();
+	}
+}
+
+/// The trap handler calls this whenever a process is killed for a fault.
+/// If it is the self-test process, report how it went.
+pub fn self_test_fault(pid: u16, cause: usize) {
+	unsafe {
+		if pid == 0 || pid != SELF_TEST_PID {
+			return;
+		}
+		SELF_TEST_PID = 0;
+	}
+	match cause {
+		5 => println!("PMP self-test passed: user load from kernel memory faulted."),
+		_ => println!("PMP self-test FAILED: expected a load access fault (5), got cause {}.", cause),
+	}
+}
+
+/// The exit system call calls this. The self-test process only gets to exit
+/// if its load from kernel memory went through.
+pub fn self_test_exit(pid: u16) {
+	unsafe {
+		if pid == 0 || pid != SELF_TEST_PID {
+			return;
+		}
+		SELF_TEST_PID = 0;
+	}
+	println!("PMP self-test FAILED: user load from kernel memory did not fault.");
+}
@@ -4,17 +4,22 @@
 // 27 Nov 2019
 
 use crate::{cpu::{get_mtime,
+                  mstatus_read,
                   CpuMode,
+                  MSTATUS_MIE,
 				  TrapFrame,
 				  Registers},
-			fs::Inode,
-            page::{dealloc,
+            page::{allocation_pages,
+                   dealloc,
                    unmap,
 				   zalloc,
-				   Table},
-            syscall::{syscall_exit, syscall_yield}};
-use alloc::{string::String, collections::{vec_deque::VecDeque, BTreeMap}};
-use core::ptr::null_mut;
+				   Table,
+				   PAGE_SIZE},
+            shm::{self, ShmMapping},
+            syscall::{syscall_exit, syscall_yield},
+            vfs::OpenFile};
+use alloc::{string::String, vec::Vec, collections::{vec_deque::VecDeque, BTreeMap}};
+use core::{mem::size_of, ptr::null_mut};
 use crate::lock::Mutex;
 
 // How many pages are we going to give a process for their
@@ -27,6 +32,52 @@ pub const STACK_ADDR: usize = 0x1_0000_0000;
 // We will use this later when we load processes from disk.
 pub const PROCESS_STARTING_ADDR: usize = 0x2000_0000;
 
+// All trap frames come out of one contiguous pool of pages instead of
+// one page each from zalloc. This keeps them in a single physical region
+// that PMP can keep user mode away from (see pmp.rs). Each frame gets a
+// 1024-byte slot, so the pool also caps how many processes can exist.
+pub const TRAP_FRAME_POOL_PAGES: usize = 64;
+const TRAP_FRAME_SLOT_SIZE: usize = 1024;
+const TRAP_FRAME_SLOTS: usize = TRAP_FRAME_POOL_PAGES * PAGE_SIZE / TRAP_FRAME_SLOT_SIZE;
+static mut TRAP_FRAME_POOL: *mut u8 = null_mut();
+// One bit per slot, 1 = taken.
+static mut TRAP_FRAME_USED: [u64; TRAP_FRAME_SLOTS / 64] = [0; TRAP_FRAME_SLOTS / 64];
+
+/// Return the (start, end) physical addresses of the trap frame pool.
+pub fn trap_frame_pool() -> (usize, usize) {
+	unsafe {
+		(TRAP_FRAME_POOL as usize, TRAP_FRAME_POOL as usize + TRAP_FRAME_POOL_PAGES * PAGE_SIZE)
+	}
+}
+
+/// Get a zeroed trap frame from the pool. This returns null if every slot
+/// is taken.
+pub fn alloc_frame() -> *mut TrapFrame {
+	unsafe {
+		for i in 0..TRAP_FRAME_SLOTS {
+			if TRAP_FRAME_USED[i / 64] & (1 << (i % 64)) == 0 {
+				TRAP_FRAME_USED[i / 64] |= 1 << (i % 64);
+				let frame = TRAP_FRAME_POOL.add(i * TRAP_FRAME_SLOT_SIZE);
+				for j in 0..TRAP_FRAME_SLOT_SIZE {
+					frame.add(j).write(0);
+				}
+				return frame as *mut TrapFrame;
+			}
+		}
+	}
+	null_mut()
+}
+
+/// Give a trap frame back to the pool.
+pub fn free_frame(frame: *mut TrapFrame) {
+	unsafe {
+		let (start, end) = trap_frame_pool();
+		assert!(frame as usize >= start && (frame as usize) < end, "Freeing a trap frame that isn't in the pool?");
+		let i = (frame as usize - start) / TRAP_FRAME_SLOT_SIZE;
+		TRAP_FRAME_USED[i / 64] &= !(1 << (i % 64));
+	}
+}
+
 // Here, we store a process list. It uses the global allocator
 // that we made before and its job is to store all processes.
 // We will have this list OWN the process. So, anytime we want
@@ -57,6 +108,11 @@ pub fn set_running(pid: u16) -> bool {
 		if let Some(mut pl) = PROCESS_LIST.take() {
 			for proc in pl.iter_mut() {
 				if proc.pid == pid {
+					// The OOM killer's victims stay dead until the scheduler
+					// reaps them, even if their I/O finishes first.
+					if let ProcessState::Dead = proc.state {
+						break;
+					}
 					proc.state = ProcessState::Running;
 					retval = true;
 					break;
@@ -82,6 +138,9 @@ pub fn set_waiting(pid: u16) -> bool {
 		if let Some(mut pl) = PROCESS_LIST.take() {
 			for proc in pl.iter_mut() {
 				if proc.pid == pid {
+					if let ProcessState::Dead = proc.state {
+						break;
+					}
 					proc.state = ProcessState::Waiting;
 					retval = true;
 					break;
@@ -105,6 +164,9 @@ pub fn set_sleeping(pid: u16, duration: usize) -> bool {
 		if let Some(mut pl) = PROCESS_LIST.take() {
 			for proc in pl.iter_mut() {
 				if proc.pid == pid {
+					if let ProcessState::Dead = proc.state {
+						break;
+					}
 					proc.state = ProcessState::Sleeping;
 					proc.sleep_until = get_mtime() + duration;
 					retval = true;
@@ -142,6 +204,79 @@ pub fn delete_process(pid: u16) {
 	}
 }
 
+/// The out-of-memory killer. When the kernel itself can't get memory, we
+/// pick the user process using the most memory and mark it Dead. Init and
+/// kernel processes (which are usually doing I/O on someone's behalf) are
+/// never picked. We can be called from any allocation, in the middle of
+/// anything, so the victim isn't freed here. The scheduler reaps it the
+/// next time it runs, which gives all of its pages and kernel allocations
+/// back. If a victim is already waiting to be reaped, its memory is on the
+/// way and nobody else is picked. Returns true if memory is on the way.
+pub fn oom_kill() -> bool {
+	let mut victim: Option<(u16, usize)> = None;
+	let mut reaping = false;
+	unsafe {
+		// We can be called from anywhere that allocates, including with
+		// the process list lock held. If someone already has it, there's
+		// nothing we can safely do here.
+		if !PROCESS_LIST_MUTEX.try_lock() {
+			return false;
+		}
+		if let Some(mut pl) = PROCESS_LIST.take() {
+			for proc in pl.iter() {
+				if let ProcessState::Dead = proc.state {
+					reaping = true;
+				}
+				else if proc.pid != 1 && (*proc.frame).mode != CpuMode::Machine as usize {
+					let pages = proc.memory_usage();
+					if victim.map_or(true, |(_, most)| pages > most) {
+						victim = Some((proc.pid, pages));
+					}
+				}
+			}
+			if reaping {
+				victim = None;
+			}
+			if let Some((pid, pages)) = victim {
+				println!("Out of memory: killed process {} ({} pages).", pid, pages);
+				if let Some(proc) = pl.iter_mut().find(|p| p.pid == pid) {
+					proc.state = ProcessState::Dead;
+				}
+			}
+			PROCESS_LIST.replace(pl);
+		}
+		PROCESS_LIST_MUTEX.unlock();
+	}
+	reaping || victim.is_some()
+}
+
+/// An allocation the kernel can't do without failed. Let the OOM killer
+/// pick a victim, and if we're a kernel process, yield so that the
+/// scheduler reaps it. Returns true if the caller should try again. The
+/// trap handler runs with interrupts off and can't wait for the scheduler,
+/// so there this returns false and the allocation fails, and so does a
+/// kernel process holding a spin lock with interrupts off.
+pub fn oom_reclaim() -> bool {
+	if !oom_kill() || mstatus_read() & MSTATUS_MIE == 0 {
+		return false;
+	}
+	syscall_yield();
+	true
+}
+
+/// Allocate pages that the kernel needs for itself (such as a kernel
+/// process' stack). If the page allocator is out of memory, we let the OOM
+/// killer free up memory until this succeeds or there's nobody left to
+/// kill. See oom_reclaim().
+fn kernel_zalloc(pages: usize) -> *mut u8 {
+	loop {
+		let ret = zalloc(pages);
+		if !ret.is_null() || !oom_reclaim() {
+			return ret;
+		}
+	}
+}
+
 /// Get a process by PID. Since we leak the process list, this is
 /// unsafe since the process can be deleted and we'll still have a pointer.
 pub unsafe fn get_by_pid(pid: u16) -> *mut Process {
@@ -193,16 +328,20 @@ pub fn add_kernel_process(func: fn()) -> u16 {
 			// a process. Get it to work, then improve it!
 	let my_pid = unsafe { NEXT_PID };
 	let mut ret_proc =
-		Process { frame:       zalloc(1) as *mut TrapFrame,
-					stack:       zalloc(STACK_PAGES),
+		Process { frame:       alloc_frame(),
+					stack:       kernel_zalloc(STACK_PAGES),
 					pid:         my_pid,
-					mmu_table:   zalloc(1) as *mut Table,
+					mmu_table:   kernel_zalloc(1) as *mut Table,
 					state:       ProcessState::Running,
 					data:        ProcessData::new(),
 					sleep_until: 0,
 					program:     null_mut(),
 					brk:         0,
 					};
+	if !ret_proc.is_allocated() {
+		// Dropping ret_proc gives back whatever we did get.
+		return 0;
+	}
 	unsafe {
 		NEXT_PID += 1;
 	}
@@ -267,6 +406,22 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
 	// then move ownership back to the PROCESS_LIST.
 	// This allows mutual exclusion as anyone else trying to grab
 	// the process list will get None rather than the Deque.
+	// We get the memory before taking the process list, since the OOM
+	// killer needs the list if we run out.
+	let mut ret_proc =
+		Process { frame:       alloc_frame(),
+		          stack:       kernel_zalloc(STACK_PAGES),
+		          pid:         0,
+		          mmu_table:        kernel_zalloc(1) as *mut Table,
+		          state:       ProcessState::Running,
+		          data:        ProcessData::new(),
+				  sleep_until: 0, 
+				  program:		null_mut(),
+				  brk:         0,
+				};
+	if !ret_proc.is_allocated() {
+		return 0;
+	}
 	unsafe {PROCESS_LIST_MUTEX.spin_lock(); }
 	if let Some(mut pl) = unsafe { PROCESS_LIST.take() } {
 		// .take() will replace PROCESS_LIST with None and give
@@ -278,17 +433,7 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
 			    // we start getting into multi-hart processing. For now, we want
 			    // a process. Get it to work, then improve it!
 		let my_pid = unsafe { NEXT_PID };
-		let mut ret_proc =
-			Process { frame:       zalloc(1) as *mut TrapFrame,
-			          stack:       zalloc(STACK_PAGES),
-			          pid:         my_pid,
-			          mmu_table:        zalloc(1) as *mut Table,
-			          state:       ProcessState::Running,
-			          data:        ProcessData::new(),
-					  sleep_until: 0, 
-					  program:		null_mut(),
-					  brk:         0,
-					};
+		ret_proc.pid = my_pid;
 		unsafe {
 			NEXT_PID += 1;
 		}
@@ -339,6 +484,9 @@ pub fn add_kernel_process_args(func: fn(args_ptr: usize), args: usize) -> u16 {
 /// but later, it should call the shell.
 pub fn init() -> usize {
 	unsafe {
+		assert!(size_of::<TrapFrame>() <= TRAP_FRAME_SLOT_SIZE);
+		TRAP_FRAME_POOL = zalloc(TRAP_FRAME_POOL_PAGES);
+		assert!(!TRAP_FRAME_POOL.is_null());
 		PROCESS_LIST_MUTEX.spin_lock();
 		PROCESS_LIST = Some(VecDeque::with_capacity(15));
 		// add_process_default(init_process);
@@ -365,8 +513,8 @@ pub fn init() -> usize {
 // Running - means that when the scheduler finds this process, it can run it.
 // Sleeping - means that the process is waiting on a certain amount of time.
 // Waiting - means that the process is waiting on I/O
-// Dead - We should never get here, but we can flag a process as Dead and clean
-//        it out of the list later.
+// Dead - The OOM killer flags a process as Dead, and the scheduler cleans it
+//        out of the list the next time it runs.
 pub enum ProcessState {
 	Running,
 	Sleeping,
@@ -386,25 +534,55 @@ pub struct Process {
 	pub brk:         usize,
 }
 
+impl Process {
+	/// A process is built with several page allocations. If we ran out of
+	/// memory for any of them, this returns false and the process should be
+	/// dropped rather than run.
+	pub fn is_allocated(&self) -> bool {
+		!self.frame.is_null() && !self.stack.is_null() && !self.mmu_table.is_null()
+	}
+
+	/// The number of pages this process is holding on to. This is what the
+	/// OOM killer uses to pick a victim. Page table pages below the root
+	/// aren't counted since we don't keep track of them.
+	pub fn memory_usage(&self) -> usize {
+		allocation_pages(self.stack)
+		+ allocation_pages(self.program)
+		+ allocation_pages(self.mmu_table as *const u8)
+		+ self.data.pages.len()
+	}
+}
+
 impl Drop for Process {
 	/// Since we're storing ownership of a Process in the linked list,
 	/// we can cause it to deallocate automatically when it is removed.
+	/// A process that ran out of memory while being built can have null
+	/// pointers, so everything is checked before it is freed.
 	fn drop(&mut self) {
 		// We allocate the stack as a page.
-		dealloc(self.stack);
-		// This is unsafe, but it's at the drop stage, so we won't
-		// be using this again.
-		unsafe {
-			// Remember that unmap unmaps all levels of page tables
-			// except for the root. It also deallocates the memory
-			// associated with the tables.
-			unmap(&mut *self.mmu_table);
+		if !self.stack.is_null() {
+			dealloc(self.stack);
+		}
+		if !self.mmu_table.is_null() {
+			// This is unsafe, but it's at the drop stage, so we won't
+			// be using this again.
+			unsafe {
+				// Remember that unmap unmaps all levels of page tables
+				// except for the root. It also deallocates the memory
+				// associated with the tables.
+				unmap(&mut *self.mmu_table);
+			}
+			dealloc(self.mmu_table as *mut u8);
+		}
+		if !self.frame.is_null() {
+			free_frame(self.frame);
 		}
-		dealloc(self.mmu_table as *mut u8);
-		dealloc(self.frame as *mut u8);
 		for i in self.data.pages.drain(..) {
 			dealloc(i as *mut u8);
 		}
+		// Shared memory pages are only freed if we were the last process
+		// using the segment.
+		shm::detach_all(&mut self.data);
 		// Kernel processes don't have a program, instead the program is linked
 		// directly in the kernel.
 		if !self.program.is_null() {
@@ -413,15 +591,10 @@ impl Drop for Process {
 	}
 }
 
+/// Everything a process can open is a file, including devices, which are
+/// files in /dev.
 pub enum Descriptor {
-	File(Inode),
-	Device(usize),
-	Framebuffer,
-	ButtonEvents,
-	AbsoluteEvents,
-	Console,
-	Network,
-	Unknown,
+	File(OpenFile),
 }
 
 // The private data in a process contains information
@@ -435,6 +608,15 @@ pub struct ProcessData {
 	pub fdesc: BTreeMap<u16, Descriptor>,
 	pub cwd: String,
 	pub pages: VecDeque<usize>,
+	// Shared memory segments mapped into this process. These pages are
+	// NOT in pages since they belong to the segment. See shm.rs.
+	pub shm: Vec<ShmMapping>,
+	// Real and effective user and group IDs. Everything starts out as root.
+	// The file system checks permissions against the effective IDs.
+	pub uid: u16,
+	pub euid: u16,
+	pub gid: u16,
+	pub egid: u16,
 }
 
 // This is private data that we can query with system calls.
@@ -447,6 +629,22 @@ impl ProcessData {
 			fdesc: BTreeMap::new(),
 			cwd: String::from("/"),
 			pages: VecDeque::new(),
+			shm: Vec::new(),
+			uid: 0,
+			euid: 0,
+			gid: 0,
+			egid: 0,
 		 }
 	}
+
+	/// Give a descriptor the lowest number above stdin, stdout, and stderr
+	/// that isn't in use, and return that number.
+	pub fn add_fdesc(&mut self, desc: Descriptor) -> u16 {
+		let mut fd = 3;
+		while self.fdesc.contains_key(&fd) {
+			fd += 1;
+		}
+		self.fdesc.insert(fd, desc);
+		fd
+	}
 }
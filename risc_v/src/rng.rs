@@ -4,10 +4,15 @@
 // 16 March 2020
 
 #![allow(dead_code)]
-use crate::{kmem::{kfree, kmalloc},
+use crate::{devfs::{self, Device, DeviceKind},
+            kmem::{kfree, kmalloc},
+            lock::Mutex,
             page::{zalloc, PAGE_SIZE},
+            syscall::syscall_yield,
             virtio,
-            virtio::{Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE}};
+            virtio::{Descriptor, MmioOffsets, Queue, StatusField, VIRTIO_RING_SIZE},
+            vfs::FsError};
+use alloc::sync::Arc;
 use core::{mem::size_of, ptr::null_mut};
 
 pub struct EntropyDevice {
@@ -126,11 +131,72 @@ pub fn setup_entropy_device(ptr: *mut u32) -> bool {
 		};
 
 		ENTROPY_DEVICES[idx] = Some(rngdev);
+		// If there's more than one, the first is as good as any other.
+		devfs::register("random", DeviceKind::Char, 0o444, Arc::new(Random { idx }));
 
 		true
 	}
 }
 
+// One request at a time, so that we know the next used element is ours.
+static mut RANDOM_LOCK: Mutex = Mutex::new();
+
+/// /dev/random. The device fills the buffer it's handed, which may be with
+/// fewer bytes than we asked for.
+struct Random {
+	idx: usize,
+}
+
+impl Device for Random {
+	fn read(&self, buffer: *mut u8, size: u32, _offset: u32) -> Result<u32, FsError> {
+		if size == 0 {
+			return Ok(0);
+		}
+		unsafe {
+			RANDOM_LOCK.sleep_lock();
+			let (queue, used) = match ENTROPY_DEVICES[self.idx].as_mut() {
+				Some(edev) => {
+					// The buffer is kernel memory, so its address is
+					// physical, which is what the device needs.
+					let desc = Descriptor { addr:  buffer as u64,
+					                        len:   size,
+					                        flags: virtio::VIRTIO_DESC_F_WRITE,
+					                        next:  0, };
+					let head = edev.idx;
+					(*edev.queue).desc[head as usize] = desc;
+					edev.idx = (edev.idx + 1) % VIRTIO_RING_SIZE as u16;
+					let used = edev.ack_used_idx;
+					edev.ack_used_idx = used.wrapping_add(1);
+					(*edev.queue).avail.ring[(*edev.queue).avail.idx as usize % VIRTIO_RING_SIZE] = head;
+					(*edev.queue).avail.idx = (*edev.queue).avail.idx.wrapping_add(1);
+					edev.dev.add(MmioOffsets::QueueNotify.scale32()).write_volatile(0);
+					(edev.queue, used)
+				},
+				None => {
+					RANDOM_LOCK.unlock();
+					return Err(FsError::Io);
+				},
+			};
+			// The device doesn't take long, so instead of having the
+			// interrupt wake us up, we let everyone else run until it's done.
+			while (&(*queue).used.idx as *const u16).read_volatile() == used {
+				syscall_yield();
+			}
+			let len = (&(*queue).used.ring[used as usize % VIRTIO_RING_SIZE].len as *const u32).read_volatile();
+			RANDOM_LOCK.unlock();
+			Ok(if len < size { len } else { size })
+		}
+	}
+
+	fn write(&self, _buffer: *const u8, _size: u32, _offset: u32) -> Result<u32, FsError> {
+		Err(FsError::NotPermitted)
+	}
+}
+
+/// Random requests are finished by polling in Random::read(), so there's
+/// nothing left to do here but keep the interrupt from going unclaimed.
+pub fn handle_interrupt(_idx: usize) {}
+
 pub fn get_random() -> u64 {
 	unsafe {
 		for i in ENTROPY_DEVICES.iter() {
@@ -16,6 +16,10 @@ pub fn schedule() -> usize {
 			return 0;
 		}
 		if let Some(mut pl) = PROCESS_LIST.take() {
+			// Reap whatever the OOM killer picked. Dropping a process
+			// frees everything it had. This runs at the end of a trap,
+			// where nobody holds a lock that dropping needs.
+			pl.retain(|prc| !matches!(prc.state, ProcessState::Dead));
 			// Rust allows us to label loops so that break statements can be
 			// targeted.
 			'procfindloop: loop {
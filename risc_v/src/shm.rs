@@ -0,0 +1,289 @@
+// shm.rs
+// Shared memory segments between processes
+// Stephen Marz
+// 19 October 2026
+
+use crate::{cpu::satp_fence_asid,
+            lock::Mutex,
+            page::{dealloc, map, unmap_page, zalloc, EntryBits, Table, PAGE_SIZE},
+            process::ProcessData};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+// Shared memory gets mapped into a process starting here, unless the
+// process asks for a specific address. This is well above where
+// PROCESS_STARTING_ADDR programs and their break live and below the stack
+// at STACK_ADDR.
+pub const SHM_BASE: usize = 0x5000_0000;
+pub const SHM_END: usize = 0x9000_0000;
+
+// Flags for get() -- these are the System V values newlib uses.
+pub const IPC_PRIVATE: usize = 0;
+pub const IPC_CREAT: usize = 0o1000;
+pub const IPC_EXCL: usize = 0o2000;
+// Flags for attach()
+pub const SHM_RDONLY: usize = 0o10000;
+pub const SHM_EXEC: usize = 0o100000;
+// Commands for control()
+pub const IPC_RMID: usize = 0;
+
+pub enum ShmError {
+	NotFound,
+	Exists,
+	InvalidArgument,
+	OutOfMemory,
+}
+
+/// How a segment is found again by another process.
+#[derive(PartialEq)]
+pub enum ShmKey {
+	Private,
+	Key(usize),
+	Name(String),
+}
+
+/// A shared memory segment. The physical pages belong to the segment, not
+/// to any one process, so they are freed only once nobody refers to the
+/// segment anymore. A reference is either a process mapping the segment or
+/// the key/name itself, which goes away with IPC_RMID.
+pub struct Segment {
+	pub key:      ShmKey,
+	pub size:     usize,
+	pub pages:    Vec<usize>,
+	pub refcount: usize,
+	pub removed:  bool,
+}
+
+/// One segment mapped into one process. These live in ProcessData so that
+/// the mappings are dropped when the process is.
+pub struct ShmMapping {
+	pub id:    usize,
+	pub vaddr: usize,
+	pub pages: usize,
+}
+
+// Segments by ID. Like the process list, we take() the map to use it and
+// replace() it when we're done, holding SHM_LOCK the whole time. This is
+// used from the trap handler, so it's a spin lock. Dropping a process takes
+// it too, so the pages of a new segment and the room for a new mapping are
+// allocated before we take it, and under it we only link them in. The one
+// allocation left is the table's node for a new segment, and the OOM
+// killer never drops a process from inside an allocation (the scheduler
+// does), so running out there can't come back around to this lock.
+static mut SHM_SEGMENTS: Option<BTreeMap<usize, Segment>> = None;
+static mut SHM_LOCK: Mutex = Mutex::new();
+static mut NEXT_SHM_ID: usize = 1;
+
+pub fn init() {
+	unsafe {
+		SHM_SEGMENTS.replace(BTreeMap::new());
+	}
+}
+
+/// Run f with the segment table. We wait for the lock rather than fail,
+/// since a process that couldn't drop its references would leak them.
+fn with_segments<T>(f: impl FnOnce(&mut BTreeMap<usize, Segment>) -> Result<T, ShmError>) -> Result<T, ShmError> {
+	unsafe {
+		SHM_LOCK.spin_lock();
+		let ret = if let Some(mut segs) = SHM_SEGMENTS.take() {
+			let ret = f(&mut segs);
+			SHM_SEGMENTS.replace(segs);
+			ret
+		}
+		else {
+			// shm::init() hasn't run yet.
+			Err(ShmError::NotFound)
+		};
+		SHM_LOCK.unlock();
+		ret
+	}
+}
+
+/// Drop one reference to a segment, and free it if that was the last one.
+fn put(segs: &mut BTreeMap<usize, Segment>, id: usize) {
+	let free = if let Some(seg) = segs.get_mut(&id) {
+		seg.refcount -= 1;
+		seg.refcount == 0
+	}
+	else {
+		false
+	};
+	if free {
+		let seg = segs.remove(&id).unwrap();
+		free_pages(&seg.pages);
+	}
+}
+
+/// Get the zeroed pages for a segment of size bytes. Each page is its own
+/// allocation, so we don't need them to be contiguous. Returns None if we
+/// run out.
+fn alloc_pages(size: usize) -> Option<Vec<usize>> {
+	let num_pages = (size + PAGE_SIZE - 1) / PAGE_SIZE;
+	let mut pages = Vec::with_capacity(num_pages);
+	for _ in 0..num_pages {
+		let page = zalloc(1);
+		if page.is_null() {
+			free_pages(&pages);
+			return None;
+		}
+		pages.push(page as usize);
+	}
+	Some(pages)
+}
+
+fn free_pages(pages: &[usize]) {
+	for page in pages.iter() {
+		dealloc(*page as *mut u8);
+	}
+}
+
+/// Find or create a segment. This is shmget() when key is a number and
+/// shm_open() when key is a name. A private key always creates a new
+/// segment. Returns the segment ID.
+pub fn get(key: ShmKey, size: usize, flags: usize) -> Result<usize, ShmError> {
+	// If this might make a new segment, its pages come first. They go back
+	// if it turns out the segment is already there.
+	let mut pages = if key == ShmKey::Private || flags & IPC_CREAT != 0 { alloc_pages(size) } else { None };
+	let ret = with_segments(|segs| {
+		if key != ShmKey::Private {
+			let found = segs.iter().find(|(_, s)| s.key == key && !s.removed).map(|(id, s)| (*id, s.size));
+			if let Some((id, seg_size)) = found {
+				if flags & IPC_CREAT != 0 && flags & IPC_EXCL != 0 {
+					return Err(ShmError::Exists);
+				}
+				if size > seg_size {
+					return Err(ShmError::InvalidArgument);
+				}
+				return Ok(id);
+			}
+			if flags & IPC_CREAT == 0 {
+				return Err(ShmError::NotFound);
+			}
+		}
+		if size == 0 {
+			return Err(ShmError::InvalidArgument);
+		}
+		let pages = pages.take().ok_or(ShmError::OutOfMemory)?;
+		let id = unsafe {
+			let id = NEXT_SHM_ID;
+			NEXT_SHM_ID += 1;
+			id
+		};
+		segs.insert(id,
+		            Segment { key,
+		                      size,
+		                      pages,
+		                      refcount: 1,
+		                      removed: false });
+		Ok(id)
+	});
+	if let Some(pages) = pages {
+		free_pages(&pages);
+	}
+	ret
+}
+
+/// Map segment id into a process. If vaddr is 0, we pick an address in
+/// the SHM_BASE..SHM_END window that doesn't overlap the process' other
+/// segments. Returns the virtual address the segment was mapped to.
+pub fn attach(data: &mut ProcessData,
+              table: &mut Table,
+              asid: usize,
+              id: usize,
+              vaddr: usize,
+              flags: usize)
+              -> Result<usize, ShmError>
+{
+	// So that the push below doesn't have to allocate.
+	data.shm.reserve(1);
+	with_segments(|segs| {
+		let seg = segs.get_mut(&id).ok_or(ShmError::NotFound)?;
+		if seg.removed {
+			return Err(ShmError::NotFound);
+		}
+		let num_pages = seg.pages.len();
+		let vaddr = if vaddr == 0 {
+			// The next address past every segment this process has.
+			data.shm
+			    .iter()
+			    .map(|m| m.vaddr + m.pages * PAGE_SIZE)
+			    .filter(|end| *end >= SHM_BASE)
+			    .max()
+			    .unwrap_or(SHM_BASE)
+		}
+		else {
+			vaddr
+		};
+		if vaddr % PAGE_SIZE != 0 || vaddr < SHM_BASE || vaddr + num_pages * PAGE_SIZE > SHM_END {
+			return Err(ShmError::InvalidArgument);
+		}
+		if data.shm.iter().any(|m| vaddr < m.vaddr + m.pages * PAGE_SIZE && m.vaddr < vaddr + num_pages * PAGE_SIZE) {
+			return Err(ShmError::InvalidArgument);
+		}
+		let mut bits = EntryBits::User.val() | EntryBits::Read.val();
+		if flags & SHM_RDONLY == 0 {
+			bits |= EntryBits::Write.val();
+		}
+		if flags & SHM_EXEC != 0 {
+			bits |= EntryBits::Execute.val();
+		}
+		for (i, page) in seg.pages.iter().enumerate() {
+			if !map(table, vaddr + i * PAGE_SIZE, *page, bits, 0) {
+				for j in 0..i {
+					unmap_page(table, vaddr + j * PAGE_SIZE);
+				}
+				satp_fence_asid(asid);
+				return Err(ShmError::OutOfMemory);
+			}
+		}
+		seg.refcount += 1;
+		data.shm.push(ShmMapping { id,
+		                           vaddr,
+		                           pages: num_pages });
+		Ok(vaddr)
+	})
+}
+
+/// Unmap the segment at vaddr from a process.
+pub fn detach(data: &mut ProcessData, table: &mut Table, asid: usize, vaddr: usize) -> Result<(), ShmError> {
+	let idx = data.shm.iter().position(|m| m.vaddr == vaddr).ok_or(ShmError::InvalidArgument)?;
+	let mapping = data.shm.remove(idx);
+	for i in 0..mapping.pages {
+		unmap_page(table, mapping.vaddr + i * PAGE_SIZE);
+	}
+	satp_fence_asid(asid);
+	with_segments(|segs| {
+		put(segs, mapping.id);
+		Ok(())
+	})
+}
+
+/// Drop every mapping a process has. The page table is about to be thrown
+/// away, so we don't bother unmapping. Process::drop calls this.
+pub fn detach_all(data: &mut ProcessData) {
+	let _ = with_segments(|segs| {
+		for mapping in data.shm.drain(..) {
+			put(segs, mapping.id);
+		}
+		Ok(())
+	});
+}
+
+/// shmctl(). The only command is IPC_RMID, which removes the key so nobody
+/// else can find the segment. The memory stays until the last process
+/// detaches.
+pub fn control(id: usize, cmd: usize) -> Result<(), ShmError> {
+	with_segments(|segs| {
+		match cmd {
+			IPC_RMID => {
+				let seg = segs.get_mut(&id).ok_or(ShmError::NotFound)?;
+				if seg.removed {
+					return Err(ShmError::NotFound);
+				}
+				seg.removed = true;
+				put(segs, id);
+				Ok(())
+			},
+			_ => Err(ShmError::InvalidArgument),
+		}
+	})
+}
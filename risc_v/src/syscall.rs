@@ -3,17 +3,162 @@
 // Stephen Marz
 // 3 Jan 2020
 
-use crate::{block::block_op,
+use crate::{block::{block_flush, block_op},
             buffer::Buffer,
-            cpu::{dump_registers, Registers, TrapFrame, gp},
+            cpu::{dump_registers, CpuMode, Registers, TrapFrame, gp},
             elf,
-            fs,
+            fs::{S_ISGID, S_ISUID},
+            vfs::{self, FsError, FsOp, Stat},
             gpu,
             input::{Event, ABS_EVENTS, KEY_EVENTS},
-            page::{map, virt_to_phys, EntryBits, Table, PAGE_SIZE, zalloc},
-			process::{add_kernel_process_args, delete_process, get_by_pid, set_sleeping, set_waiting, PROCESS_LIST, PROCESS_LIST_MUTEX, Descriptor}};
+            kmem,
+            pmp,
+            shm::{self, ShmError, ShmKey},
+            page::{dealloc, map, virt_to_phys, EntryBits, Table, PAGE_SIZE, zalloc},
+			process::{add_kernel_process_args, delete_process, get_by_pid, oom_kill, set_running, set_sleeping, set_waiting, PROCESS_LIST, PROCESS_LIST_MUTEX, Descriptor}};
 use crate::console::{IN_LOCK, IN_BUFFER, push_queue};
-use alloc::{boxed::Box, string::String};
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+// Error numbers. System calls that fail return the negative of one of
+// these in A0, which is what newlib expects.
+pub const EPERM: usize = 1;
+pub const ENOENT: usize = 2;
+pub const EIO: usize = 5;
+pub const EBADF: usize = 9;
+pub const ENOMEM: usize = 12;
+pub const EACCES: usize = 13;
+pub const EFAULT: usize = 14;
+pub const EBUSY: usize = 16;
+pub const EEXIST: usize = 17;
+pub const EXDEV: usize = 18;
+pub const ENOTDIR: usize = 20;
+pub const EISDIR: usize = 21;
+pub const EINVAL: usize = 22;
+pub const ENOSPC: usize = 28;
+pub const EROFS: usize = 30;
+pub const ENOTEMPTY: usize = 90;
+pub const ENAMETOOLONG: usize = 91;
+pub const ELOOP: usize = 92;
+
+/// Turn an error number into what we put in A0.
+pub const fn neg_errno(errno: usize) -> usize {
+	(-(errno as isize)) as usize
+}
+
+/// Copy a NUL-terminated string out of the calling process' memory. If the
+/// MMU is on, each byte is translated through the process' page table since
+/// the string might cross a page boundary. This gives up after max bytes or
+/// at the first unmapped byte by returning None.
+unsafe fn copy_user_string(frame: *const TrapFrame, addr: usize, max: usize) -> Option<String> {
+	let mut ret = String::new();
+	let table = if (*frame).satp >> 60 != 0 {
+		let p = get_by_pid((*frame).pid as u16);
+		if p.is_null() {
+			return None;
+		}
+		(*p).mmu_table.as_ref()
+	}
+	else {
+		None
+	};
+	for i in 0..max {
+		let paddr = match table {
+			Some(t) => virt_to_phys(t, addr + i)?,
+			None => addr + i,
+		};
+		let c = (paddr as *const u8).read();
+		if c == 0 {
+			return Some(ret);
+		}
+		ret.push(c as char);
+	}
+	None
+}
+
+/// Copy len bytes from src into process pid's memory at addr. This goes by
+/// pid rather than by a trap frame, so kernel processes can use it to hand
+/// results back to the process they work for. Returns false if any of addr
+/// isn't mapped.
+pub unsafe fn copy_to_user(pid: u16, addr: usize, src: *const u8, len: usize) -> bool {
+	let p = get_by_pid(pid);
+	if p.is_null() {
+		return false;
+	}
+	let table = if (*(*p).frame).satp >> 60 != 0 { (*p).mmu_table.as_ref() } else { None };
+	for i in 0..len {
+		let paddr = match table {
+			Some(t) => match virt_to_phys(t, addr + i) {
+				Some(paddr) => paddr,
+				None => return false,
+			},
+			None => addr + i,
+		};
+		(paddr as *mut u8).write(src.add(i).read());
+	}
+	true
+}
+
+/// Copy up to len bytes from process pid's memory at addr into dst, the
+/// other way from copy_to_user. Stops at the first byte that isn't mapped,
+/// and returns how many bytes were copied.
+pub unsafe fn copy_from_user(pid: u16, addr: usize, dst: *mut u8, len: usize) -> usize {
+	let p = get_by_pid(pid);
+	if p.is_null() {
+		return 0;
+	}
+	let table = if (*(*p).frame).satp >> 60 != 0 { (*p).mmu_table.as_ref() } else { None };
+	for i in 0..len {
+		let paddr = match table {
+			Some(t) => match virt_to_phys(t, addr + i) {
+				Some(paddr) => paddr,
+				None => return i,
+			},
+			None => addr + i,
+		};
+		dst.add(i).write((paddr as *const u8).read());
+	}
+	len
+}
+
+/// chown() takes -1 to mean "leave this ID alone".
+fn user_id(reg: usize) -> Option<u16> {
+	if reg as u32 == u32::MAX { None } else { Some(reg as u16) }
+}
+
+/// The file system runs in kernel processes that put their own results in
+/// A0, so this one is public.
+pub fn fs_errno(e: FsError) -> usize {
+	neg_errno(match e {
+		FsError::Success => return 0,
+		FsError::FileNotFound => ENOENT,
+		FsError::Permission => EACCES,
+		FsError::IsFile => ENOTDIR,
+		FsError::IsDirectory => EISDIR,
+		FsError::NoSpace => ENOSPC,
+		FsError::Exists => EEXIST,
+		FsError::NotEmpty => ENOTEMPTY,
+		FsError::NameTooLong => ENAMETOOLONG,
+		FsError::InvalidArgument => EINVAL,
+		FsError::CrossDevice => EXDEV,
+		FsError::NotPermitted => EPERM,
+		FsError::TooManyLinks => ELOOP,
+		FsError::Io => EIO,
+		FsError::BadDescriptor => EBADF,
+		FsError::BadAddress => EFAULT,
+		FsError::ReadOnly => EROFS,
+		FsError::Busy => EBUSY,
+		FsError::OutOfMemory => ENOMEM,
+	})
+}
+
+fn shm_errno(e: ShmError) -> usize {
+	neg_errno(match e {
+		ShmError::NotFound => ENOENT,
+		ShmError::Exists => EEXIST,
+		ShmError::InvalidArgument => EINVAL,
+		ShmError::OutOfMemory => ENOMEM,
+	})
+}
 
 /// do_syscall is called from trap.rs to invoke a system call. No discernment is
 /// made here whether this is a U-mode, S-mode, or M-mode system call.
@@ -29,9 +174,24 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 	let syscall_number = (*frame).regs[gp(Registers::A7)];
 	// skip the ecall
 	(*frame).pc = mepc + 4;
+	// A system call can't back out if the kernel heap runs dry partway
+	// through, so one from a process that starts with the heap down to its
+	// reserve gets ENOMEM right away. The OOM killer picks someone to free
+	// memory for the next try. Exiting gives memory back, so it always goes
+	// through.
+	if (*frame).mode == CpuMode::User as usize
+	   && syscall_number != 93
+	   && syscall_number != 94
+	   && kmem::low_memory()
+	{
+		oom_kill();
+		(*frame).regs[gp(Registers::A0)] = neg_errno(ENOMEM);
+		return;
+	}
 	match syscall_number {
 		93 | 94 => {
 			// exit and exit_group
+			pmp::self_test_exit((*frame).pid as u16);
 			delete_process((*frame).pid as u16);
 		}
 		1 => {
@@ -75,27 +235,13 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 				iterator += 1;
 				path.push(ch as char);
 			}
-			// See if we can find the path.
-			if let Ok(inode) = fs::MinixFileSystem::open(8, &path) {
-				let inode_heap = Box::new(inode);
-				// The Box above moves the Inode to a new memory location on the heap.
-				// This needs to be on the heap since we are about to hand over control
-				// to a kernel process.
-				// THERE is an issue here. If we fail somewhere inside the kernel process,
-				// we shouldn't delete our process here. However, since this is asynchronous
-				// our process will still get deleted and the error won't be reported.
-				// We have to make sure we relinquish Box control here by using into_raw.
-				// Otherwise, the Box will free the memory associated with this inode.
-				add_kernel_process_args(exec_func, Box::into_raw(inode_heap) as usize);
-				// This deletes us, which is what we want.
-				delete_process((*frame).pid as u16);
-			}
-			else {
-				// If we get here, the path couldn't be found, or for some reason
-				// open failed. So, we return -1 and move on.
-				println!("Could not open path '{}'.", path);
-				(*frame).regs[Registers::A0 as usize] = -1isize as usize;
-			}
+			// Finding the file may block, so the kernel process does it. We
+			// wait until it either replaces us or tells us why it couldn't.
+			// We have to make sure we relinquish Box control here by using into_raw.
+			// Otherwise, the Box will free the memory associated with the arguments.
+			let args = Box::new(ExecArgs { pid: (*frame).pid as u16, path });
+			set_waiting((*frame).pid as u16);
+			add_kernel_process_args(exec_func, Box::into_raw(args) as usize);
 		}
 		17 => { //getcwd
 			let mut buf = (*frame).regs[gp(Registers::A0)] as *mut u8;
@@ -113,17 +259,111 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 					return;
 				}
 			}
+			// The path and its NUL have to fit.
+			if process.data.cwd.len() >= size {
+				(*frame).regs[gp(Registers::A0)] = -1isize as usize;
+				return;
+			}
 			for i in process.data.cwd.as_bytes() {
-				if iter == 0 || iter >= size {
-					break;
-				}
 				buf.add(iter).write(*i);
 				iter += 1;
 			}
+			buf.add(iter).write(0);
+		}
+		36 => {
+			// int symlinkat(const char *target, int newdirfd, const char *linkpath)
+			let target = copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 1024);
+			let path = copy_user_string(frame, (*frame).regs[gp(Registers::A2)], 256);
+			if target.is_none() || path.is_none() {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
+			}
+			let dirfd = (*frame).regs[gp(Registers::A1)] as isize;
+			vfs::process_op((*frame).pid as u16, FsOp::Symlink { target: target.unwrap(), dirfd, path: path.unwrap() });
+		}
+		39 => {
+			// int umount2(const char *target, int flags)
+			// There are no flags we know, so a forced or lazy unmount
+			// is just an unmount.
+			let target = match copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
+				}
+			};
+			vfs::process_op((*frame).pid as u16, FsOp::Umount(target));
+		}
+		40 => {
+			// int mount(const char *source, const char *target, const char *fstype,
+			//           unsigned long flags, const void *data)
+			// A null fstype mounts whatever is on the device. Flags and
+			// data aren't used.
+			let source = copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256);
+			let target = copy_user_string(frame, (*frame).regs[gp(Registers::A1)], 256);
+			let fstype = match (*frame).regs[gp(Registers::A2)] {
+				0 => None,
+				addr => match copy_user_string(frame, addr, 16) {
+					Some(t) => Some(t),
+					None => {
+						(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+						return;
+					}
+				},
+			};
+			if source.is_none() || target.is_none() {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
+			}
+			vfs::process_op((*frame).pid as u16, FsOp::Mount { source: source.unwrap(), target: target.unwrap(), fstype });
 		}
 		48 => {
-		// #define SYS_faccessat 48
-			(*frame).regs[gp(Registers::A0)] = -1isize as usize;
+			// int faccessat(int dirfd, const char *path, int mode, int flags)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A1)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
+				}
+			};
+			let dirfd = (*frame).regs[gp(Registers::A0)] as isize;
+			let mode = (*frame).regs[gp(Registers::A2)] as u16;
+			let flags = (*frame).regs[gp(Registers::A3)];
+			vfs::process_op((*frame).pid as u16, FsOp::Access { dirfd, path, mode, flags });
+		}
+		49 => {
+			// int chdir(const char *path)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
+				}
+			};
+			vfs::process_op((*frame).pid as u16, FsOp::Chdir(path));
+		}
+		53 | 54 => {
+			// int fchmodat(int dirfd, const char *path, mode_t mode, int flags)
+			// int fchownat(int dirfd, const char *path, uid_t owner, gid_t group, int flags)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A1)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
+				}
+			};
+			let dirfd = (*frame).regs[gp(Registers::A0)] as isize;
+			let op = if syscall_number == 53 {
+				FsOp::Chmod { dirfd, path, mode: (*frame).regs[gp(Registers::A2)] as u16 }
+			}
+			else {
+				FsOp::Chown { dirfd,
+				              path,
+				              uid: user_id((*frame).regs[gp(Registers::A2)]),
+				              gid: user_id((*frame).regs[gp(Registers::A3)]),
+				              flags: (*frame).regs[gp(Registers::A4)] }
+			};
+			vfs::process_op((*frame).pid as u16, op);
 		}
 		57 => {
 			// #define SYS_close 57
@@ -138,6 +378,21 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 			}
 			// Flush?
 		}
+		61 => {
+			// int getdents64(int fd, struct linux_dirent64 *dirp, unsigned int count)
+			let fd = (*frame).regs[gp(Registers::A0)] as u16;
+			let buffer = (*frame).regs[gp(Registers::A1)];
+			let count = (*frame).regs[gp(Registers::A2)];
+			vfs::process_op((*frame).pid as u16, FsOp::Getdents { fd, buffer, count });
+		}
+		62 => {
+			// off_t lseek(int fd, off_t offset, int whence)
+			// SEEK_END needs the size of the file, which may block.
+			let fd = (*frame).regs[gp(Registers::A0)] as u16;
+			let offset = (*frame).regs[gp(Registers::A1)] as isize;
+			let whence = (*frame).regs[gp(Registers::A2)];
+			vfs::process_op((*frame).pid as u16, FsOp::Lseek { fd, offset, whence });
+		}
 		63 => { // sys_read
 			let fd = (*frame).regs[gp(Registers::A0)] as u16;
 			let mut buf = (*frame).regs[gp(Registers::A1)] as *mut u8;
@@ -176,6 +431,10 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 				}
 				IN_LOCK.unlock();
 			}
+			else {
+				vfs::process_op((*frame).pid as u16, FsOp::Read { fd, buffer: buf as usize, count: size, offset: None });
+				return;
+			}
 			(*frame).regs[gp(Registers::A0)] = ret;
 		}
 		64 => { // sys_write
@@ -205,51 +464,269 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 				(*frame).regs[gp(Registers::A0)] = iter as usize;
 			}
 			else {
-				let descriptor = process.data.fdesc.get(&fd);
-				if descriptor.is_none() {
-					(*frame).regs[gp(Registers::A0)] = 0;
-					return;
-				}
-				else {
-					let descriptor = descriptor.unwrap();
-					match descriptor {
-						Descriptor::Framebuffer => {
-
-						}
-						Descriptor::File(inode) => {
-
-						
-						}
-						_ => {
-							// unsupported
-							(*frame).regs[gp(Registers::A0)] = 0;
-						}
-					}
-				}
+				vfs::process_op((*frame).pid as u16, FsOp::Write { fd, buffer: buf as usize, count: size, offset: None });
 			}
 		}
 		66 => {
 			(*frame).regs[gp(Registers::A0)] = -1isize as usize;
 		}
+		67 => {
+			// ssize_t pread(int fd, void *buf, size_t count, off_t offset)
+			// These don't use or move the descriptor's offset.
+			let fd = (*frame).regs[gp(Registers::A0)] as u16;
+			let buf = (*frame).regs[gp(Registers::A1)];
+			let size = (*frame).regs[gp(Registers::A2)];
+			let offset = (*frame).regs[gp(Registers::A3)];
+			if offset > u32::MAX as usize {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
+			}
+			vfs::process_op((*frame).pid as u16, FsOp::Read { fd, buffer: buf, count: size, offset: Some(offset as u32) });
+		}
+		68 => {
+			// ssize_t pwrite(int fd, const void *buf, size_t count, off_t offset)
+			let fd = (*frame).regs[gp(Registers::A0)] as u16;
+			let buf = (*frame).regs[gp(Registers::A1)] as *const u8;
+			let size = (*frame).regs[gp(Registers::A2)];
+			let offset = (*frame).regs[gp(Registers::A3)];
+			if offset > u32::MAX as usize {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
+			}
+			vfs::process_op((*frame).pid as u16, FsOp::Write { fd, buffer: buf as usize, count: size, offset: Some(offset as u32) });
+		}
+		78 => {
+			// ssize_t readlinkat(int dirfd, const char *path, char *buf, size_t bufsiz)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A1)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
+				}
+			};
+			let dirfd = (*frame).regs[gp(Registers::A0)] as isize;
+			let buffer = (*frame).regs[gp(Registers::A2)];
+			let size = (*frame).regs[gp(Registers::A3)];
+			vfs::process_op((*frame).pid as u16, FsOp::Readlink { dirfd, path, buffer, size });
+		}
+		79 => {
+			// int fstatat(int dirfd, const char *path, struct stat *buf, int flags)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A1)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
+				}
+			};
+			let dirfd = (*frame).regs[gp(Registers::A0)] as isize;
+			let buffer = (*frame).regs[gp(Registers::A2)];
+			let flags = (*frame).regs[gp(Registers::A3)];
+			vfs::process_op((*frame).pid as u16, FsOp::Stat { dirfd, path, buffer, flags });
+		}
 		// #define SYS_fstat 80
 		80 => {
 			// int fstat(int filedes, struct stat *buf)
-			(*frame).regs[gp(Registers::A0)] = 0;
+			let fd = (*frame).regs[gp(Registers::A0)] as u16;
+			let buffer = (*frame).regs[gp(Registers::A1)];
+			let process = get_by_pid((*frame).pid as u16).as_ref().unwrap();
+			match process.data.fdesc.get(&fd) {
+				Some(Descriptor::File(_)) => {
+					vfs::process_op((*frame).pid as u16, FsOp::Fstat { fd, buffer });
+				}
+				None if fd > 2 => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+				}
+				None => {
+					// stdin, stdout and stderr are the console. newlib's
+					// isatty() and its buffering look for a character
+					// device here.
+					(*frame).regs[gp(Registers::A0)] = match vfs::copy_stat((*frame).pid as u16, buffer, &Stat::char_device()) {
+						Ok(_) => 0,
+						Err(e) => fs_errno(e),
+					};
+				}
+			}
+		}
+		81 => {
+			// void sync(void)
+			// Writing the block cache back blocks, so it's done in a kernel
+			// process.
+			vfs::process_op((*frame).pid as u16, FsOp::Sync);
+		}
+		82 => {
+			// int fsync(int fd)
+			let fd = (*frame).regs[gp(Registers::A0)] as u16;
+			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+			match process.data.fdesc.get(&fd) {
+				Some(Descriptor::File(file)) => {
+					vfs::process_op((*frame).pid as u16, FsOp::Fsync(file.inode.clone()));
+				}
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EBADF);
+				}
+			}
+		}
+		144 | 146 => {
+			// int setgid(gid_t gid)
+			// int setuid(uid_t uid)
+			// Root sets both the real and the effective ID. Anyone else can
+			// only go back to their real ID.
+			let id = (*frame).regs[gp(Registers::A0)] as u16;
+			let data = &mut get_by_pid((*frame).pid as u16).as_mut().unwrap().data;
+			let root = data.euid == 0;
+			let (real, effective) = if syscall_number == 146 { (&mut data.uid, &mut data.euid) } else { (&mut data.gid, &mut data.egid) };
+			(*frame).regs[gp(Registers::A0)] = if root {
+				*real = id;
+				*effective = id;
+				0
+			}
+			else if id == *real {
+				*effective = id;
+				0
+			}
+			else {
+				neg_errno(EPERM)
+			};
 		}
 		172 => {
 			// A0 = pid
 			(*frame).regs[Registers::A0 as usize] = (*frame).pid;
 		}
+		174..=177 => {
+			// getuid, geteuid, getgid, getegid
+			let data = &get_by_pid((*frame).pid as u16).as_ref().unwrap().data;
+			(*frame).regs[gp(Registers::A0)] = match syscall_number {
+				174 => data.uid,
+				175 => data.euid,
+				176 => data.gid,
+				_ => data.egid,
+			} as usize;
+		}
 		180 => {
+			// This goes straight to the device, around the block cache,
+			// since it is how the cache itself gets to the device.
+			// Everyone else wants block::process_read() and
+			// process_write(), which go through the cache.
+			// A1 is a physical address that the device DMAs into, and
+			// there are no permission checks, so only kernel processes
+			// get to do this.
+			if (*frame).mode != CpuMode::Machine as usize {
+				(*frame).regs[Registers::A0 as usize] = neg_errno(EPERM);
+				return;
+			}
 			set_waiting((*frame).pid as u16);
-			let _ = block_op(
-			                 (*frame).regs[Registers::A0 as usize],
-			                 (*frame).regs[Registers::A1 as usize] as *mut u8,
-			                 (*frame).regs[Registers::A2 as usize] as u32,
-			                 (*frame).regs[Registers::A3 as usize] as u64,
-			                 false,
-			                 (*frame).pid as u16
+			let res = block_op(
+			                   (*frame).regs[Registers::A0 as usize],
+			                   (*frame).regs[Registers::A1 as usize] as *mut u8,
+			                   (*frame).regs[Registers::A2 as usize] as u32,
+			                   (*frame).regs[Registers::A3 as usize] as u64,
+			                   false,
+			                   (*frame).pid as u16
 			);
+			// Nobody will wake us up if the request never made it to the
+			// device.
+			if res.is_err() {
+				set_running((*frame).pid as u16);
+				(*frame).regs[Registers::A0 as usize] = -1isize as usize;
+			}
+		}
+		181 => {
+			// The write version of 180, with the same restriction.
+			if (*frame).mode != CpuMode::Machine as usize {
+				(*frame).regs[Registers::A0 as usize] = neg_errno(EPERM);
+				return;
+			}
+			set_waiting((*frame).pid as u16);
+			let res = block_op(
+			                   (*frame).regs[Registers::A0 as usize],
+			                   (*frame).regs[Registers::A1 as usize] as *mut u8,
+			                   (*frame).regs[Registers::A2 as usize] as u32,
+			                   (*frame).regs[Registers::A3 as usize] as u64,
+			                   true,
+			                   (*frame).pid as u16
+			);
+			// Nobody will wake us up if the request never made it to the
+			// device.
+			if res.is_err() {
+				set_running((*frame).pid as u16);
+				(*frame).regs[Registers::A0 as usize] = -1isize as usize;
+			}
+		}
+		182 => {
+			// Flush the device's write cache. Like 180 and 181, this is
+			// for the block cache and the journal, so it's kernel only too.
+			if (*frame).mode != CpuMode::Machine as usize {
+				(*frame).regs[Registers::A0 as usize] = neg_errno(EPERM);
+				return;
+			}
+			set_waiting((*frame).pid as u16);
+			match block_flush((*frame).regs[Registers::A0 as usize], (*frame).pid as u16) {
+				Ok(true) => {},
+				// There was nothing to flush, so there's no interrupt
+				// coming.
+				Ok(false) => {
+					set_running((*frame).pid as u16);
+					(*frame).regs[Registers::A0 as usize] = 0;
+				},
+				Err(_) => {
+					set_running((*frame).pid as u16);
+					(*frame).regs[Registers::A0 as usize] = -1isize as usize;
+				},
+			}
+		}
+		194 => {
+			// shmget(key, size, flags)
+			let key = (*frame).regs[gp(Registers::A0)];
+			let size = (*frame).regs[gp(Registers::A1)];
+			let flags = (*frame).regs[gp(Registers::A2)];
+			let key = if key == shm::IPC_PRIVATE { ShmKey::Private } else { ShmKey::Key(key) };
+			(*frame).regs[gp(Registers::A0)] = match shm::get(key, size, flags) {
+				Ok(id) => id,
+				Err(e) => shm_errno(e),
+			};
+		}
+		195 => {
+			// shmctl(id, cmd, buf)
+			let id = (*frame).regs[gp(Registers::A0)];
+			let cmd = (*frame).regs[gp(Registers::A1)];
+			(*frame).regs[gp(Registers::A0)] = match shm::control(id, cmd) {
+				Ok(_) => 0,
+				Err(e) => shm_errno(e),
+			};
+		}
+		196 => {
+			// shmat(id, addr, flags)
+			let id = (*frame).regs[gp(Registers::A0)];
+			let addr = (*frame).regs[gp(Registers::A1)];
+			let flags = (*frame).regs[gp(Registers::A2)];
+			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+			// Shared memory only makes sense for processes with their
+			// own page table.
+			if (*frame).satp >> 60 == 0 {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
+			}
+			let table = process.mmu_table.as_mut().unwrap();
+			(*frame).regs[gp(Registers::A0)] =
+				match shm::attach(&mut process.data, table, process.pid as usize, id, addr, flags) {
+					Ok(vaddr) => vaddr,
+					Err(e) => shm_errno(e),
+				};
+		}
+		197 => {
+			// shmdt(addr)
+			let addr = (*frame).regs[gp(Registers::A0)];
+			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
+			if (*frame).satp >> 60 == 0 {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
+			}
+			let table = process.mmu_table.as_mut().unwrap();
+			(*frame).regs[gp(Registers::A0)] =
+				match shm::detach(&mut process.data, table, process.pid as usize, addr) {
+					Ok(_) => 0,
+					Err(e) => shm_errno(e),
+				};
 		}
 		214 => { // brk
 			// #define SYS_brk 214
@@ -261,10 +738,32 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 				if (*frame).satp >> 60 != 0 {
 					let table = ((*process).mmu_table).as_mut().unwrap();
 					let diff = (addr + PAGE_SIZE - process.brk) / PAGE_SIZE;
-					for i in 0..diff {
-						let new_addr = zalloc(1) as usize;
+					// Get all of the pages before we map any of them. That
+					// way, running out of memory doesn't leave us with a
+					// half-moved break.
+					let mut new_pages = Vec::with_capacity(diff);
+					for _ in 0..diff {
+						let new_addr = zalloc(1);
+						if new_addr.is_null() {
+							break;
+						}
+						new_pages.push(new_addr as usize);
+					}
+					if new_pages.len() < diff {
+						for p in new_pages.drain(..) {
+							dealloc(p as *mut u8);
+						}
+						(*frame).regs[gp(Registers::A0)] = neg_errno(ENOMEM);
+						return;
+					}
+					for (i, new_addr) in new_pages.drain(..).enumerate() {
+						// The page belongs to the process from here on, so
+						// even if we can't map it, it is freed on exit.
 						process.data.pages.push_back(new_addr);
-						map(table, process.brk + (i << 12), new_addr, EntryBits::UserReadWrite.val(), 0);
+						if !map(table, process.brk + (i << 12), new_addr, EntryBits::UserReadWrite.val(), 0) {
+							(*frame).regs[gp(Registers::A0)] = neg_errno(ENOMEM);
+							return;
+						}
 					}
 				}
 				process.brk = addr;
@@ -286,12 +785,20 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 						let process = get_by_pid((*frame).pid as u16);
 						let table = ((*process).mmu_table).as_mut().unwrap();
 						let num_pages = (p.get_width() * p.get_height() * 4) as usize / PAGE_SIZE;
+						let mut mapped = true;
 						for i in 0..num_pages {
 							let vaddr = 0x3000_0000 + (i << 12);
 							let paddr = ptr + (i << 12);
-							map(table, vaddr, paddr, EntryBits::UserReadWrite as usize, 0);
+							if !map(table, vaddr, paddr, EntryBits::UserReadWrite as usize, 0) {
+								mapped = false;
+								break;
+							}
 						}
 						gpu::GPU_DEVICES[dev - 1].replace(p);
+						if !mapped {
+							(*frame).regs[Registers::A0 as usize] = neg_errno(ENOMEM);
+							return;
+						}
 					}
 					(*frame).regs[Registers::A0 as usize] = 0x3000_0000;
 				}
@@ -361,64 +868,139 @@ pub unsafe fn do_syscall(mepc: usize, frame: *mut TrapFrame) {
 		}
 		1024 => {
 			// #define SYS_open 1024
-			let mut path = (*frame).regs[gp(Registers::A0)];
-			let _perm = (*frame).regs[gp(Registers::A1)];
-			let process = get_by_pid((*frame).pid as u16).as_mut().unwrap();
-			if (*frame).satp >> 60 != 0 {
-				let table = process.mmu_table.as_mut().unwrap();
-				let paddr = virt_to_phys(table, path);
-				if paddr.is_none() {
+			// int open(const char *path, int flags, mode_t mode)
+			let str_path = match copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256) {
+				Some(p) => p,
+				None => {
 					(*frame).regs[gp(Registers::A0)] = -1isize as usize;
 					return;
 				}
-				path = paddr.unwrap();
-			}
-			let path_ptr = path as *const u8;
-			let mut str_path = String::new();
-			for i in 0..256 {
-				let c = path_ptr.add(i).read();
-				if c == 0 {
-					break;
-				}
-				str_path.push(c as char);
-			}
-			// Allocate a blank file descriptor
-			let mut max_fd = 2;
-			for k in process.data.fdesc.keys() {
-				if *k > max_fd {
-					max_fd = *k;
-				}
+			};
+			let flags = (*frame).regs[gp(Registers::A1)];
+			let mode = (*frame).regs[gp(Registers::A2)] as u16;
+			// Finding the file means going to the file system it is
+			// mounted on, which may block, so a kernel process finishes
+			// the open and gives us the descriptor. Devices are files in
+			// /dev, so they come through here too.
+			vfs::process_op((*frame).pid as u16, FsOp::Open { path: str_path, flags, mode });
+		}
+		1025 | 1034 => {
+			// int link(const char *old, const char *new)
+			// int rename(const char *old, const char *new)
+			let old = copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256);
+			let new = copy_user_string(frame, (*frame).regs[gp(Registers::A1)], 256);
+			if old.is_none() || new.is_none() {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
 			}
-			max_fd += 1;
-			match str_path.as_str() {
-				"/dev/fb" => {
-					// framebuffer
-					process.data.fdesc.insert(max_fd, Descriptor::Framebuffer);
-				}
-				"/dev/butev" => {
-					process.data.fdesc.insert(max_fd, Descriptor::ButtonEvents);
+			let (old, new) = (old.unwrap(), new.unwrap());
+			let op = if syscall_number == 1025 { FsOp::Link(old, new) } else { FsOp::Rename(old, new) };
+			vfs::process_op((*frame).pid as u16, op);
+		}
+		1026 | 1030 | 1031 => {
+			// int unlink(const char *path)
+			// int mkdir(const char *path, mode_t mode)
+			// int rmdir(const char *path)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
 				}
-				"/dev/absev" => {
-					process.data.fdesc.insert(max_fd, Descriptor::AbsoluteEvents);
+			};
+			let op = match syscall_number {
+				1026 => FsOp::Unlink(path),
+				1030 => FsOp::Mkdir(path, (*frame).regs[gp(Registers::A1)] as u16),
+				_ => FsOp::Rmdir(path),
+			};
+			vfs::process_op((*frame).pid as u16, op);
+		}
+		1028 | 1029 | 1032 | 1033 => {
+			// int chmod(const char *path, mode_t mode)
+			// int chown(const char *path, uid_t owner, gid_t group)
+			// int lchown(const char *path, uid_t owner, gid_t group)
+			// int access(const char *path, int mode)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
 				}
-				_ => {
-					let res = fs::MinixFileSystem::open(8, &str_path);
-					if res.is_err() {
-						(*frame).regs[gp(Registers::A0)] = -1isize as usize;
-						return;
-					}
-					else {
-						let inode = res.ok().unwrap();
-						process.data.fdesc.insert(max_fd, Descriptor::File(inode));
-					}
+			};
+			let dirfd = vfs::AT_FDCWD;
+			let op = match syscall_number {
+				1028 => FsOp::Chmod { dirfd, path, mode: (*frame).regs[gp(Registers::A1)] as u16 },
+				1033 => FsOp::Access { dirfd, path, mode: (*frame).regs[gp(Registers::A1)] as u16, flags: 0 },
+				_ => FsOp::Chown { dirfd,
+				                   path,
+				                   uid: user_id((*frame).regs[gp(Registers::A1)]),
+				                   gid: user_id((*frame).regs[gp(Registers::A2)]),
+				                   flags: if syscall_number == 1032 { vfs::AT_SYMLINK_NOFOLLOW } else { 0 } },
+			};
+			vfs::process_op((*frame).pid as u16, op);
+		}
+		1035 => {
+			// ssize_t readlink(const char *path, char *buf, size_t bufsiz)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
 				}
+			};
+			let buffer = (*frame).regs[gp(Registers::A1)];
+			let size = (*frame).regs[gp(Registers::A2)];
+			vfs::process_op((*frame).pid as u16, FsOp::Readlink { dirfd: vfs::AT_FDCWD, path, buffer, size });
+		}
+		1036 => {
+			// int symlink(const char *target, const char *linkpath)
+			let target = copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 1024);
+			let path = copy_user_string(frame, (*frame).regs[gp(Registers::A1)], 256);
+			if target.is_none() || path.is_none() {
+				(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+				return;
 			}
-			(*frame).regs[gp(Registers::A0)] = max_fd as usize;
+			vfs::process_op((*frame).pid as u16,
+			                FsOp::Symlink { target: target.unwrap(), dirfd: vfs::AT_FDCWD, path: path.unwrap() });
+		}
+		1038 | 1039 => {
+			// int stat(const char *path, struct stat *buf)
+			// int lstat(const char *path, struct stat *buf)
+			let path = match copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256) {
+				Some(p) => p,
+				None => {
+					(*frame).regs[gp(Registers::A0)] = neg_errno(EINVAL);
+					return;
+				}
+			};
+			let buffer = (*frame).regs[gp(Registers::A1)];
+			let flags = if syscall_number == 1039 { vfs::AT_SYMLINK_NOFOLLOW } else { 0 };
+			vfs::process_op((*frame).pid as u16, FsOp::Stat { dirfd: vfs::AT_FDCWD, path, buffer, flags });
+		}
+		1040 => {
+			// shm_open(name, size, flags)
+			// This is shmget() except that the segment is found by name.
+			// The ID that comes back goes to shmat() like any other.
+			let name = copy_user_string(frame, (*frame).regs[gp(Registers::A0)], 256);
+			let size = (*frame).regs[gp(Registers::A1)];
+			let flags = (*frame).regs[gp(Registers::A2)];
+			(*frame).regs[gp(Registers::A0)] = match name {
+				Some(name) => match shm::get(ShmKey::Name(name), size, flags) {
+					Ok(id) => id,
+					Err(e) => shm_errno(e),
+				},
+				None => neg_errno(EINVAL),
+			};
 		}
 		1062 => {
 			// gettime
 			(*frame).regs[Registers::A0 as usize] = crate::cpu::get_mtime();
 		}
+		1090 => {
+			// Print the kernel heap. With the kmem-debug feature, this
+			// also reports live allocations by the site that made them.
+			kmem::print_table();
+		}
 		_ => {
 			println!("Unknown syscall number {}", syscall_number);
 		}
@@ -449,10 +1031,19 @@ pub fn syscall_fs_read(dev: usize, inode: u32, buffer: *mut u8, size: u32, offse
 	do_make_syscall(63, dev, inode as usize, buffer as usize, size as usize, offset as usize, 0)
 }
 
-pub fn syscall_block_read(dev: usize, buffer: *mut u8, size: u32, offset: u32) -> u8 {
+pub fn syscall_block_read(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> u8 {
 	do_make_syscall(180, dev, buffer as usize, size as usize, offset as usize, 0, 0) as u8
 }
 
+pub fn syscall_block_write(dev: usize, buffer: *mut u8, size: u32, offset: u64) -> u8 {
+	do_make_syscall(181, dev, buffer as usize, size as usize, offset as usize, 0, 0) as u8
+}
+
+/// Returns 0 once everything written to dev so far is on the disk.
+pub fn syscall_block_flush(dev: usize) -> u8 {
+	do_make_syscall(182, dev, 0, 0, 0, 0, 0) as u8
+}
+
 pub fn syscall_sleep(duration: usize) {
 	let _ = do_make_syscall(10, duration, 0, 0, 0, 0, 0);
 }
@@ -461,25 +1052,83 @@ pub fn syscall_get_pid() -> u16 {
 	do_make_syscall(172, 0, 0, 0, 0, 0, 0) as u16
 }
 
+struct ExecArgs {
+	pid:  u16,
+	path: String,
+}
+
 /// This is a helper function ran as a process in kernel space
 /// to finish loading and executing a process.
 fn exec_func(args: usize) {
 	unsafe {
-		// We got the inode from the syscall. Its Box rid itself of control, so
-		// we take control back here. The Box now owns the Inode and will complete
-		// freeing the heap memory allocated for it.
-		let inode = Box::from_raw(args as *mut fs::Inode);
-		let mut buffer = Buffer::new(inode.size as usize);
+		// We got the arguments from the syscall. Its Box rid itself of control, so
+		// we take control back here. The Box now owns the arguments and will complete
+		// freeing the heap memory allocated for them.
+		let args = Box::from_raw(args as *mut ExecArgs);
+		// If we can't find or read the file, the process that called exec
+		// is still there, so we can tell it.
+		let fail = |pid: u16| {
+			let p = get_by_pid(pid);
+			if !p.is_null() {
+				(*(*p).frame).regs[Registers::A0 as usize] = -1isize as usize;
+			}
+			set_running(pid);
+		};
+		let inode = match vfs::lookup(args.pid, &args.path).and_then(|inode| inode.stat().map(|st| (inode, st))) {
+			Ok(found) => found,
+			Err(_) => {
+				println!("Could not open path '{}'.", args.path);
+				fail(args.pid);
+				return;
+			}
+		};
+		let (inode, st) = inode;
+		// The caller has to be allowed to execute the file. Whoever runs it
+		// keeps their IDs, unless it's set-user-ID or set-group-ID, which
+		// makes the owner of the file the effective user or group.
+		let old = get_by_pid(args.pid);
+		if old.is_null() {
+			return;
+		}
+		let old = &(*old).data;
+		let (uid, gid) = (old.uid, old.gid);
+		let euid = if st.mode & S_ISUID != 0 { st.uid } else { old.euid };
+		let egid = if st.mode & S_ISGID != 0 { st.gid } else { old.egid };
+		if vfs::check(&st, &vfs::cred_of(args.pid, true), vfs::MAY_EXEC).is_err() {
+			println!("Permission denied: '{}'.", args.path);
+			fail(args.pid);
+			return;
+		}
+		let mut buffer = Buffer::new(st.size as usize);
+		if buffer.get().is_null() {
+			println!("Failed to launch process: out of memory.");
+			fail(args.pid);
+			return;
+		}
 		// This is why we need to be in a process context. The read() call may sleep as it
 		// waits for the block driver to return.
-		fs::MinixFileSystem::read(8, &inode, buffer.get_mut(), inode.size, 0);
+		if inode.read(buffer.get_mut(), st.size, 0).is_err() {
+			println!("Could not read '{}'.", args.path);
+			fail(args.pid);
+			return;
+		}
+		// From here on, the process that called exec is replaced, whether
+		// or not the new one loads.
+		delete_process(args.pid);
 		// Now we have the data, so the following will load the ELF file and give us a process.
 		let proc = elf::File::load_proc(&buffer);
-		if proc.is_err() {
+		if let Err(elf::LoadErrors::OutOfMemory) = proc {
+			println!("Failed to launch process: out of memory.");
+		}
+		else if proc.is_err() {
 			println!("Failed to launch process.");
 		}
 		else {
-			let process = proc.ok().unwrap();
+			let mut process = proc.ok().unwrap();
+			process.data.uid = uid;
+			process.data.euid = euid;
+			process.data.gid = gid;
+			process.data.egid = egid;
 			// If we hold this lock, we can still be preempted, but the scheduler will
 			// return control to us. This required us to use try_lock in the scheduler.
 			PROCESS_LIST_MUTEX.sleep_lock();
@@ -496,33 +1145,53 @@ fn exec_func(args: usize) {
 // Libgloss wants the system call number in A7 and arguments in A0..A6
 // #define SYS_dup 23
 // #define SYS_fcntl 25
+// #define SYS_symlinkat 36 (Linux, not libgloss)
 // #define SYS_faccessat 48
 // #define SYS_chdir 49
+// #define SYS_fchmodat 53 (Linux, not libgloss)
+// #define SYS_fchownat 54 (Linux, not libgloss)
 // #define SYS_openat 56
-// #define SYS_getdents 61
+// #define SYS_getdents 61 (we do getdents64)
 // #define SYS_lseek 62
 // #define SYS_read 63
 // #define SYS_pread 67
 // #define SYS_pwrite 68
+// #define SYS_readlinkat 78 (Linux, not libgloss)
 // #define SYS_fstatat 79
+// #define SYS_sync 81 (Linux, not libgloss)
+// #define SYS_fsync 82 (Linux, not libgloss)
 
 // #define SYS_kill 129
 // #define SYS_rt_sigaction 134
 // #define SYS_times 153
 // #define SYS_uname 160
+// #define SYS_setgid 144 (Linux, not libgloss)
+// #define SYS_setuid 146 (Linux, not libgloss)
 // #define SYS_gettimeofday 169
 // #define SYS_getpid 172
 // #define SYS_getuid 174
 // #define SYS_geteuid 175
 // #define SYS_getgid 176
 // #define SYS_getegid 177
+// #define SYS_shmget 194
+// #define SYS_shmctl 195
+// #define SYS_shmat 196
+// #define SYS_shmdt 197
 // #define SYS_munmap 215
 // #define SYS_mremap 216
 // #define SYS_mmap 222
 // #define SYS_link 1025
+// #define SYS_shm_open 1040 (ours, not libgloss)
 // #define SYS_unlink 1026
+// #define SYS_chmod 1028 (Linux, not libgloss)
+// #define SYS_chown 1029 (Linux, not libgloss)
 // #define SYS_mkdir 1030
+// #define SYS_rmdir 1031
+// #define SYS_lchown 1032 (Linux, not libgloss)
 // #define SYS_access 1033
+// #define SYS_rename 1034
+// #define SYS_readlink 1035 (Linux, not libgloss)
+// #define SYS_symlink 1036 (Linux, not libgloss)
 // #define SYS_stat 1038
 // #define SYS_lstat 1039
 // #define SYS_time 1062
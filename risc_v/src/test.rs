@@ -1,13 +1,109 @@
 // test.rs
-use crate::fs::MinixFileSystem;
-use crate::syscall;
+use crate::{devfs::DevFileSystem, tmpfs::TmpFileSystem};
+use crate::{block, initramfs, syscall, vfs::{self, FileSystem, FsError}};
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
 /// Test block will load raw binaries into memory to execute them. This function
 /// will load ELF files and try to execute them.
 pub fn test() {
 	// The majority of the testing code needs to move into a system call (execv maybe?)
-	MinixFileSystem::init(8);
-	let path = "/shell\0".as_bytes().as_ptr();
+	// The root is the first disk or partition with a Minix file system on
+	// it. Failing that, it's the first one we can mount at all.
+	let devices = block::devices();
+	if !devices.iter().any(|&dev| vfs::mount_device("/", dev, Some("minix")).is_ok())
+	   && !devices.iter().any(|&dev| vfs::mount_device("/", dev, None).is_ok())
+	{
+		// Without a disk (or one we can read), the root is in memory. If
+		// the kernel was built with an initramfs, that's what's in it,
+		// otherwise it at least gives us somewhere to put files.
+		println!("Could not mount the root file system, using one in memory.");
+		if TmpFileSystem::new(0o755).and_then(|tmp| vfs::mount("/", Arc::new(tmp))).is_err() {
+			println!("Could not mount a tmpfs on /.");
+		}
+		else if initramfs::present() {
+			match initramfs::unpack() {
+				Ok(count) => println!("Unpacked {} files from the initramfs.", count),
+				Err(_) => println!("Could not unpack all of the initramfs."),
+			}
+		}
+	}
+	// Scratch files go in memory rather than on the disk.
+	if TmpFileSystem::new(0o777).and_then(|tmp| mount_on("/tmp", Arc::new(tmp))).is_err() {
+		println!("Could not mount a tmpfs on /tmp.");
+	}
+	if mount_on("/dev", Arc::new(DevFileSystem)).is_err() {
+		println!("Could not mount the devices on /dev.");
+	}
+	// An initramfs has an /init to start with. Otherwise, go straight to
+	// the shell.
+	let init = if vfs::walk("/init", "/", &vfs::Credentials::ROOT).is_ok() { "/init\0" } else { "/shell\0" };
+	let path = init.as_bytes().as_ptr();
 	syscall::syscall_execv(path,0);
 	println!("I should never get here, execv should destroy our process.");
 }
 
+/// Mount fs on path, which is a directory right under the root. It has to be
+/// there to mount on, so we make it if the root doesn't have one.
+fn mount_on(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+	let (root, _) = vfs::walk("/", "/", &vfs::Credentials::ROOT)?;
+	if root.lookup(&path[1..]).is_err() {
+		root.mkdir(&path[1..], 0o755)?;
+	}
+	vfs::mount(path, fs)
+}
+
+
+#[repr(C, align(16))]
+struct Align16([u8; 24]);
+#[repr(C, align(64))]
+struct Align64([u8; 100]);
+#[repr(C, align(4096))]
+struct Align4096([u8; 5000]);
+#[repr(C, align(8192))]
+struct Align8192([u8; 16]);
+
+/// The global allocator has three different paths depending on the
+/// alignment that is asked for. This walks through all of them, including
+/// growing and shrinking through realloc, and panics if any address comes
+/// back misaligned or if data doesn't survive a realloc.
+pub fn alloc_test() {
+	for _ in 0..4 {
+		// Interleave the allocations so that the kmalloc heap isn't
+		// just handing us the same chunk over and over.
+		let small = Box::new(0u8);
+		let a16 = Box::new(Align16([1; 24]));
+		let a64 = Box::new(Align64([2; 100]));
+		let a4096 = Box::new(Align4096([3; 5000]));
+		let a8192 = Box::new(Align8192([4; 16]));
+		assert_eq!(&*small as *const u8 as usize % 8, 0);
+		assert_eq!(&*a16 as *const Align16 as usize % 16, 0);
+		assert_eq!(&*a64 as *const Align64 as usize % 64, 0);
+		assert_eq!(&*a4096 as *const Align4096 as usize % 4096, 0);
+		assert_eq!(&*a8192 as *const Align8192 as usize % 8192, 0);
+		assert!(a16.0.iter().all(|x| *x == 1));
+		assert!(a64.0.iter().all(|x| *x == 2));
+		assert!(a4096.0.iter().all(|x| *x == 3));
+		assert!(a8192.0.iter().all(|x| *x == 4));
+	}
+	// Vectors of over-aligned types go through realloc as they grow and
+	// shrink. Every element has to keep its alignment and its value.
+	let mut v: Vec<Align64> = Vec::new();
+	for i in 0..40 {
+		v.push(Align64([i as u8; 100]));
+		assert_eq!(v.as_ptr() as usize % 64, 0);
+	}
+	v.truncate(3);
+	v.shrink_to_fit();
+	assert_eq!(v.as_ptr() as usize % 64, 0);
+	for (i, a) in v.iter().enumerate() {
+		assert!(a.0.iter().all(|x| *x == i as u8));
+	}
+	// Plain byte vectors take the kresize path in realloc.
+	let mut bytes: Vec<u8> = Vec::with_capacity(8);
+	bytes.push(0xaa);
+	bytes.reserve_exact(64);
+	bytes.push(0xbb);
+	bytes.shrink_to_fit();
+	assert_eq!(bytes[0], 0xaa);
+	assert_eq!(bytes[1], 0xbb);
+	println!("Allocator alignment tests passed.");
+}
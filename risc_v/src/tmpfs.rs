@@ -0,0 +1,641 @@
+// tmpfs.rs
+// File system in memory
+// Stephen Marz
+// 19 October 2026
+
+use crate::{block::TMPFS_FIRST_DEV,
+            cpu::{get_unix_time, memcpy, mstatus_read, mstatus_write, MSTATUS_MIE},
+            fs::{S_IFDIR, S_IFLNK, S_IFMT, S_IFREG},
+            lock::Mutex,
+            page::{dealloc, zalloc, PAGE_SIZE},
+            vfs::{dirent_type, DirEnt, File, FileSystem, FsError, Inode as VfsInode, InodeRef, Stat}};
+use alloc::{collections::{BTreeMap, BTreeSet}, string::String, sync::Arc, vec::Vec};
+
+const ROOT_INO: u32 = 1;
+const MAX_NAME: usize = 255;
+
+/// What a node holds. A file is a map from page index to page, for each
+/// PAGE_SIZE of it that was written. A page that isn't in the map reads as
+/// zeros, so a hole costs nothing, however far out it is. A directory
+/// is a list of (name, inode) slots with . and .. in the first two, like a
+/// Minix directory, and a removed entry leaves an empty name behind so
+/// that the slots, which readdir() hands out as offsets, don't move.
+enum Contents {
+	File(BTreeMap<usize, usize>),
+	Dir(Vec<(String, u32)>),
+	Link(String)
+}
+
+struct Node {
+	mode:     u16,
+	nlinks:   u16,
+	uid:      u16,
+	gid:      u16,
+	size:     u32,
+	atime:    u32,
+	mtime:    u32,
+	ctime:    u32,
+	contents: Contents
+}
+
+impl Node {
+	fn new(mode: u16, contents: Contents) -> Self {
+		let now = get_unix_time();
+		Node { mode,
+		       nlinks: 1,
+		       uid: 0,
+		       gid: 0,
+		       size: 0,
+		       atime: now,
+		       mtime: now,
+		       ctime: now,
+		       contents }
+	}
+
+	fn is_dir(&self) -> bool {
+		self.mode & S_IFMT == S_IFDIR
+	}
+
+	/// Give back every page of a file.
+	fn free_pages(&mut self) {
+		if let Contents::File(pages) = &mut self.contents {
+			for (_, page) in core::mem::take(pages) {
+				dealloc(page as *mut u8);
+			}
+		}
+		self.size = 0;
+	}
+}
+
+/// Every node of every tmpfs, by device and inode number. Files whose last
+/// link went away while they were open are orphans, which keep their pages
+/// until the last TmpInode for them is dropped.
+struct Tmpfs {
+	nodes:    BTreeMap<(usize, u32), Node>,
+	orphans:  BTreeSet<(usize, u32)>,
+	next_dev: usize,
+	next_ino: u32
+}
+
+// Like the block cache, we take() the table to use it and replace() it
+// when we're done, and a sleep lock keeps two processes from doing that at
+// once. Nothing in here blocks otherwise.
+static mut TMPFS: Option<Tmpfs> = None;
+static mut TMPFS_LOCK: Mutex = Mutex::new();
+
+// How many TmpInodes there are for each (device, inode). Like Minix's open
+// inodes, these are dropped in the trap handler when a process closes a
+// file or dies, so this is a spin lock with interrupts off, and the table
+// above, which needs a sleep lock, isn't touched until someone next uses
+// it.
+static mut OPEN_NODES: Option<BTreeMap<(usize, u32), usize>> = None;
+static mut OPEN_NODES_LOCK: Mutex = Mutex::new();
+
+fn with_open_nodes<T>(f: impl FnOnce(&mut BTreeMap<(usize, u32), usize>) -> T) -> T {
+	let mstatus = mstatus_read();
+	mstatus_write(mstatus & !MSTATUS_MIE);
+	let ret = unsafe {
+		OPEN_NODES_LOCK.spin_lock();
+		let ret = f(OPEN_NODES.get_or_insert_with(BTreeMap::new));
+		OPEN_NODES_LOCK.unlock();
+		ret
+	};
+	mstatus_write(mstatus);
+	ret
+}
+
+fn is_open(dev: usize, ino: u32) -> bool {
+	with_open_nodes(|open| open.contains_key(&(dev, ino)))
+}
+
+pub fn init() {
+	unsafe {
+		TMPFS.replace(Tmpfs { nodes:    BTreeMap::new(),
+		                      orphans:  BTreeSet::new(),
+		                      next_dev: TMPFS_FIRST_DEV,
+		                      next_ino: ROOT_INO + 1 });
+	}
+}
+
+/// Run f with the table locked. Orphans that have since been closed are
+/// freed first.
+fn with_tmpfs<T>(f: impl FnOnce(&mut Tmpfs) -> Result<T, FsError>) -> Result<T, FsError> {
+	unsafe {
+		TMPFS_LOCK.sleep_lock();
+		let ret = if let Some(mut tmpfs) = TMPFS.take() {
+			tmpfs.reap_orphans();
+			let ret = f(&mut tmpfs);
+			TMPFS.replace(tmpfs);
+			ret
+		}
+		else {
+			Err(FsError::Io)
+		};
+		TMPFS_LOCK.unlock();
+		ret
+	}
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+	if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+		return Err(FsError::InvalidArgument);
+	}
+	if name.len() > MAX_NAME {
+		return Err(FsError::NameTooLong);
+	}
+	Ok(())
+}
+
+impl Tmpfs {
+	fn get(&mut self, dev: usize, ino: u32) -> Result<&mut Node, FsError> {
+		self.nodes.get_mut(&(dev, ino)).ok_or(FsError::FileNotFound)
+	}
+
+	fn dir(&mut self, dev: usize, ino: u32) -> Result<&mut Vec<(String, u32)>, FsError> {
+		match &mut self.get(dev, ino)?.contents {
+			Contents::Dir(entries) => Ok(entries),
+			_ => Err(FsError::IsFile),
+		}
+	}
+
+	/// Find name in directory dir, and give back its slot and inode number.
+	fn find(&mut self, dev: usize, dir: u32, name: &str) -> Result<(usize, u32), FsError> {
+		self.dir(dev, dir)?
+		    .iter()
+		    .enumerate()
+		    .skip(2)
+		    .find(|(_, (n, _))| n == name)
+		    .map(|(slot, (_, ino))| (slot, *ino))
+		    .ok_or(FsError::FileNotFound)
+	}
+
+	/// Put name in the first empty slot of dir, or on the end.
+	fn add_entry(&mut self, dev: usize, dir: u32, name: &str, ino: u32) -> Result<(), FsError> {
+		check_name(name)?;
+		if self.find(dev, dir, name).is_ok() {
+			return Err(FsError::Exists);
+		}
+		let entries = self.dir(dev, dir)?;
+		match entries.iter().position(|(n, _)| n.is_empty()) {
+			Some(slot) => entries[slot] = (String::from(name), ino),
+			None => entries.push((String::from(name), ino)),
+		}
+		let now = get_unix_time();
+		let node = self.get(dev, dir)?;
+		node.mtime = now;
+		node.ctime = now;
+		Ok(())
+	}
+
+	fn remove_entry(&mut self, dev: usize, dir: u32, slot: usize) -> Result<(), FsError> {
+		let entries = self.dir(dev, dir)?;
+		entries[slot].0.clear();
+		// Empty slots at the end can go.
+		while entries.len() > 2 && entries.last().map(|(n, _)| n.is_empty()) == Some(true) {
+			entries.pop();
+		}
+		let now = get_unix_time();
+		let node = self.get(dev, dir)?;
+		node.mtime = now;
+		node.ctime = now;
+		Ok(())
+	}
+
+	/// Make a new node with one link and name it in dir.
+	fn new_node(&mut self, dev: usize, dir: u32, name: &str, node: Node) -> Result<u32, FsError> {
+		check_name(name)?;
+		let ino = self.next_ino;
+		self.next_ino += 1;
+		self.nodes.insert((dev, ino), node);
+		if let Err(e) = self.add_entry(dev, dir, name, ino) {
+			self.nodes.remove(&(dev, ino));
+			return Err(e);
+		}
+		Ok(ino)
+	}
+
+	/// Drop one link to ino, and free it if that was the last. If someone
+	/// still has it open, reads and writes through their descriptor still
+	/// have to work, so it becomes an orphan instead.
+	fn put_link(&mut self, dev: usize, ino: u32) -> Result<(), FsError> {
+		let node = self.get(dev, ino)?;
+		node.nlinks -= 1;
+		node.ctime = get_unix_time();
+		if node.nlinks == 0 {
+			if is_open(dev, ino) {
+				self.orphans.insert((dev, ino));
+			}
+			else {
+				node.free_pages();
+				self.nodes.remove(&(dev, ino));
+			}
+		}
+		Ok(())
+	}
+
+	/// Free the orphans nobody has open anymore. Nothing can open one
+	/// again, since no directory names it.
+	fn reap_orphans(&mut self) {
+		let closed: Vec<(usize, u32)> = self.orphans
+		                                    .iter()
+		                                    .filter(|(dev, ino)| !is_open(*dev, *ino))
+		                                    .cloned()
+		                                    .collect();
+		for key in closed {
+			self.orphans.remove(&key);
+			if let Some(mut node) = self.nodes.remove(&key) {
+				node.free_pages();
+			}
+		}
+	}
+
+	fn mkdir(&mut self, dev: usize, dir: u32, name: &str, mode: u16) -> Result<(), FsError> {
+		let ino = self.next_ino;
+		let contents = Contents::Dir(alloc::vec![(String::from("."), ino), (String::from(".."), dir)]);
+		let mut node = Node::new(S_IFDIR | (mode & !S_IFMT), contents);
+		// Its entry in dir and its own ".".
+		node.nlinks = 2;
+		self.new_node(dev, dir, name, node)?;
+		// The new ".." links to dir.
+		self.get(dev, dir)?.nlinks += 1;
+		Ok(())
+	}
+
+	fn unlink(&mut self, dev: usize, dir: u32, name: &str) -> Result<(), FsError> {
+		let (slot, ino) = self.find(dev, dir, name)?;
+		if self.get(dev, ino)?.is_dir() {
+			return Err(FsError::IsDirectory);
+		}
+		self.remove_entry(dev, dir, slot)?;
+		self.put_link(dev, ino)
+	}
+
+	fn rmdir(&mut self, dev: usize, dir: u32, name: &str) -> Result<(), FsError> {
+		let (slot, ino) = self.find(dev, dir, name)?;
+		if self.dir(dev, ino)?.iter().skip(2).any(|(n, _)| !n.is_empty()) {
+			return Err(FsError::NotEmpty);
+		}
+		self.remove_entry(dev, dir, slot)?;
+		self.get(dev, dir)?.nlinks -= 1;
+		self.nodes.remove(&(dev, ino));
+		Ok(())
+	}
+
+	fn link(&mut self, dev: usize, ino: u32, dir: u32, name: &str) -> Result<(), FsError> {
+		if self.get(dev, ino)?.is_dir() {
+			return Err(FsError::Permission);
+		}
+		self.add_entry(dev, dir, name, ino)?;
+		let node = self.get(dev, ino)?;
+		node.nlinks += 1;
+		node.ctime = get_unix_time();
+		Ok(())
+	}
+
+	/// Is ancestor the directory dir or one of the directories above it?
+	fn is_ancestor(&mut self, dev: usize, ancestor: u32, dir: u32) -> Result<bool, FsError> {
+		let mut dir = dir;
+		loop {
+			if dir == ancestor {
+				return Ok(true);
+			}
+			if dir == ROOT_INO {
+				return Ok(false);
+			}
+			dir = self.dir(dev, dir)?[1].1;
+		}
+	}
+
+	/// Move old_name in old_dir to new_name in new_dir, replacing what is
+	/// there as long as it is the same kind of thing and, for a directory,
+	/// empty.
+	fn rename(&mut self, dev: usize, old_dir: u32, old_name: &str, new_dir: u32, new_name: &str) -> Result<(), FsError> {
+		check_name(new_name)?;
+		let (_, ino) = self.find(dev, old_dir, old_name)?;
+		let is_dir = self.get(dev, ino)?.is_dir();
+		// A directory can't be moved inside of itself.
+		if is_dir && self.is_ancestor(dev, ino, new_dir)? {
+			return Err(FsError::InvalidArgument);
+		}
+		if let Ok((_, target)) = self.find(dev, new_dir, new_name) {
+			if target == ino {
+				return Ok(());
+			}
+			match (is_dir, self.get(dev, target)?.is_dir()) {
+				(true, true) => self.rmdir(dev, new_dir, new_name)?,
+				(false, false) => self.unlink(dev, new_dir, new_name)?,
+				(true, false) => return Err(FsError::IsFile),
+				(false, true) => return Err(FsError::IsDirectory),
+			}
+		}
+		self.add_entry(dev, new_dir, new_name, ino)?;
+		let (old_slot, _) = self.find(dev, old_dir, old_name)?;
+		self.remove_entry(dev, old_dir, old_slot)?;
+		if is_dir && old_dir != new_dir {
+			self.dir(dev, ino)?[1].1 = new_dir;
+			self.get(dev, old_dir)?.nlinks -= 1;
+			self.get(dev, new_dir)?.nlinks += 1;
+		}
+		self.get(dev, ino)?.ctime = get_unix_time();
+		Ok(())
+	}
+
+	/// Copy out of a file. Pages that were never written read as zeros.
+	fn read(&mut self, dev: usize, ino: u32, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let node = self.get(dev, ino)?;
+		let pages = match &node.contents {
+			Contents::File(pages) => pages,
+			Contents::Dir(_) => return Err(FsError::IsDirectory),
+			Contents::Link(_) => return Err(FsError::InvalidArgument),
+		};
+		if offset >= node.size {
+			return Ok(0);
+		}
+		let size = if size > node.size - offset { node.size - offset } else { size };
+		let mut done = 0;
+		while done < size {
+			let pos = (offset + done) as usize;
+			let in_page = pos % PAGE_SIZE;
+			let count = (PAGE_SIZE - in_page).min((size - done) as usize);
+			let page = pages.get(&(pos / PAGE_SIZE)).cloned().unwrap_or(0);
+			unsafe {
+				let dest = buffer.add(done as usize);
+				if page == 0 {
+					for i in 0..count {
+						dest.add(i).write(0);
+					}
+				}
+				else {
+					memcpy(dest, (page + in_page) as *const u8, count);
+				}
+			}
+			done += count as u32;
+		}
+		node.atime = get_unix_time();
+		Ok(done)
+	}
+
+	/// Copy into a file, taking pages as we need them. A write past the end
+	/// leaves a hole that costs nothing. If we run out of pages partway,
+	/// we say how much we wrote.
+	fn write(&mut self, dev: usize, ino: u32, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		let node = self.get(dev, ino)?;
+		let pages = match &mut node.contents {
+			Contents::File(pages) => pages,
+			Contents::Dir(_) => return Err(FsError::IsDirectory),
+			Contents::Link(_) => return Err(FsError::InvalidArgument),
+		};
+		if offset as u64 + size as u64 > u32::MAX as u64 {
+			return Err(FsError::NoSpace);
+		}
+		let mut done = 0;
+		while done < size {
+			let pos = (offset + done) as usize;
+			let in_page = pos % PAGE_SIZE;
+			let count = (PAGE_SIZE - in_page).min((size - done) as usize);
+			let index = pos / PAGE_SIZE;
+			let page = match pages.get(&index) {
+				Some(page) => *page,
+				None => {
+					let page = zalloc(1);
+					if page.is_null() {
+						break;
+					}
+					pages.insert(index, page as usize);
+					page as usize
+				},
+			};
+			unsafe {
+				memcpy((page + in_page) as *mut u8, buffer.add(done as usize), count);
+			}
+			done += count as u32;
+		}
+		if done == 0 && size > 0 {
+			return Err(FsError::NoSpace);
+		}
+		if offset + done > node.size {
+			node.size = offset + done;
+		}
+		let now = get_unix_time();
+		node.mtime = now;
+		node.ctime = now;
+		Ok(done)
+	}
+
+	fn stat(&mut self, dev: usize, ino: u32) -> Result<Stat, FsError> {
+		let node = self.get(dev, ino)?;
+		let pages = match &node.contents {
+			Contents::File(pages) => pages.len() as u32,
+			_ => 0,
+		};
+		// A directory's size is how many slots it has, like Minix's 64-byte
+		// entries, so that it isn't 0.
+		let size = match &node.contents {
+			Contents::Dir(entries) => entries.len() as u32 * 64,
+			_ => node.size,
+		};
+		Ok(Stat { dev,
+		          ino,
+		          mode: node.mode,
+		          nlinks: node.nlinks,
+		          uid: node.uid,
+		          gid: node.gid,
+		          size,
+		          blocks: pages * (PAGE_SIZE / 512) as u32,
+		          blksize: PAGE_SIZE as u32,
+		          atime: node.atime,
+		          mtime: node.mtime,
+		          ctime: node.ctime })
+	}
+
+	fn readdir(&mut self, dev: usize, ino: u32, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		let entries: Vec<(usize, String, u32)> = self.dir(dev, ino)?
+		                                             .iter()
+		                                             .enumerate()
+		                                             .skip(offset as usize)
+		                                             .filter(|(_, (n, _))| !n.is_empty())
+		                                             .map(|(slot, (n, ino))| (slot, n.clone(), *ino))
+		                                             .collect();
+		let mut ret = Vec::new();
+		for (slot, name, child) in entries {
+			let kind = dirent_type(self.get(dev, child)?.mode);
+			ret.push(DirEnt { ino: child,
+			                  kind,
+			                  name,
+			                  next: slot as u32 + 1 });
+		}
+		Ok(ret)
+	}
+}
+
+/// A file system that lives in memory and is gone when we power off. Each
+/// one gets its own device number and starts out as an empty root
+/// directory, made with mode.
+pub struct TmpFileSystem {
+	dev: usize
+}
+
+impl TmpFileSystem {
+	/// This takes the tmpfs lock, so it has to run in a process.
+	pub fn new(mode: u16) -> Result<Self, FsError> {
+		with_tmpfs(|tmpfs| {
+			let dev = tmpfs.next_dev;
+			tmpfs.next_dev += 1;
+			let contents = Contents::Dir(alloc::vec![(String::from("."), ROOT_INO), (String::from(".."), ROOT_INO)]);
+			let mut root = Node::new(S_IFDIR | (mode & !S_IFMT), contents);
+			root.nlinks = 2;
+			tmpfs.nodes.insert((dev, ROOT_INO), root);
+			Ok(Self { dev })
+		})
+	}
+}
+
+impl FileSystem for TmpFileSystem {
+	fn root(&self) -> Result<InodeRef, FsError> {
+		Ok(TmpInode::new(self.dev, ROOT_INO))
+	}
+}
+
+/// A node of a tmpfs. Like a MinixInode, this is only the number, and the
+/// node itself is looked up every time.
+pub struct TmpInode {
+	dev: usize,
+	ino: u32
+}
+
+impl TmpInode {
+	fn new(dev: usize, ino: u32) -> InodeRef {
+		with_open_nodes(|open| *open.entry((dev, ino)).or_insert(0) += 1);
+		Arc::new(TmpInode { dev, ino })
+	}
+
+	fn node(&self, ino: u32) -> InodeRef {
+		TmpInode::new(self.dev, ino)
+	}
+
+	/// Other inodes we're handed have to be on the same tmpfs as us.
+	fn same_fs(&self, other: &InodeRef) -> Result<u32, FsError> {
+		if other.dev() != self.dev {
+			return Err(FsError::CrossDevice);
+		}
+		Ok(other.ino())
+	}
+}
+
+impl Drop for TmpInode {
+	fn drop(&mut self) {
+		with_open_nodes(|open| {
+			if let Some(count) = open.get_mut(&(self.dev, self.ino)) {
+				*count -= 1;
+				if *count == 0 {
+					open.remove(&(self.dev, self.ino));
+				}
+			}
+		});
+	}
+}
+
+impl File for TmpInode {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		with_tmpfs(|t| t.read(self.dev, self.ino, buffer, size, offset))
+	}
+
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError> {
+		with_tmpfs(|t| t.write(self.dev, self.ino, buffer, size, offset))
+	}
+}
+
+impl VfsInode for TmpInode {
+	fn dev(&self) -> usize {
+		self.dev
+	}
+
+	fn ino(&self) -> u32 {
+		self.ino
+	}
+
+	fn stat(&self) -> Result<Stat, FsError> {
+		with_tmpfs(|t| t.stat(self.dev, self.ino))
+	}
+
+	fn truncate(&self) -> Result<(), FsError> {
+		with_tmpfs(|t| {
+			let node = t.get(self.dev, self.ino)?;
+			if node.is_dir() {
+				return Err(FsError::IsDirectory);
+			}
+			node.free_pages();
+			let now = get_unix_time();
+			node.mtime = now;
+			node.ctime = now;
+			Ok(())
+		})
+	}
+
+	fn lookup(&self, name: &str) -> Result<InodeRef, FsError> {
+		with_tmpfs(|t| t.find(self.dev, self.ino, name).map(|(_, ino)| self.node(ino)))
+	}
+
+	fn create(&self, name: &str, mode: u16) -> Result<InodeRef, FsError> {
+		let node = Node::new(S_IFREG | (mode & !S_IFMT), Contents::File(BTreeMap::new()));
+		with_tmpfs(|t| t.new_node(self.dev, self.ino, name, node).map(|ino| self.node(ino)))
+	}
+
+	fn mkdir(&self, name: &str, mode: u16) -> Result<(), FsError> {
+		with_tmpfs(|t| t.mkdir(self.dev, self.ino, name, mode))
+	}
+
+	fn unlink(&self, name: &str) -> Result<(), FsError> {
+		with_tmpfs(|t| t.unlink(self.dev, self.ino, name))
+	}
+
+	fn rmdir(&self, name: &str) -> Result<(), FsError> {
+		with_tmpfs(|t| t.rmdir(self.dev, self.ino, name))
+	}
+
+	fn link(&self, name: &str, target: &InodeRef) -> Result<(), FsError> {
+		let target = self.same_fs(target)?;
+		with_tmpfs(|t| t.link(self.dev, target, self.ino, name))
+	}
+
+	fn rename(&self, old_name: &str, new_dir: &InodeRef, new_name: &str) -> Result<(), FsError> {
+		let new_dir = self.same_fs(new_dir)?;
+		with_tmpfs(|t| t.rename(self.dev, self.ino, old_name, new_dir, new_name))
+	}
+
+	fn symlink(&self, name: &str, target: &str) -> Result<(), FsError> {
+		let mut node = Node::new(S_IFLNK | 0o777, Contents::Link(String::from(target)));
+		node.size = target.len() as u32;
+		with_tmpfs(|t| t.new_node(self.dev, self.ino, name, node)).map(|_| ())
+	}
+
+	fn readlink(&self) -> Result<String, FsError> {
+		with_tmpfs(|t| match &t.get(self.dev, self.ino)?.contents {
+			Contents::Link(target) => Ok(target.clone()),
+			_ => Err(FsError::InvalidArgument),
+		})
+	}
+
+	fn chmod(&self, mode: u16) -> Result<(), FsError> {
+		with_tmpfs(|t| {
+			let node = t.get(self.dev, self.ino)?;
+			node.mode = (node.mode & S_IFMT) | (mode & !S_IFMT);
+			node.ctime = get_unix_time();
+			Ok(())
+		})
+	}
+
+	fn chown(&self, uid: u16, gid: u16) -> Result<(), FsError> {
+		with_tmpfs(|t| {
+			let node = t.get(self.dev, self.ino)?;
+			node.uid = uid;
+			node.gid = gid;
+			node.ctime = get_unix_time();
+			Ok(())
+		})
+	}
+
+	fn readdir(&self, offset: u32) -> Result<Vec<DirEnt>, FsError> {
+		with_tmpfs(|t| t.readdir(self.dev, self.ino, offset))
+	}
+}
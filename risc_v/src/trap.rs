@@ -3,8 +3,9 @@
 // Stephen Marz
 // 10 October 2019
 
-use crate::{cpu::{TrapFrame, CONTEXT_SWITCH_TIME},
+use crate::{cpu::{CpuMode, TrapFrame, CONTEXT_SWITCH_TIME},
             plic,
+            pmp,
             process::delete_process,
             rust_switch_to_user,
             sched::schedule,
@@ -74,6 +75,21 @@ extern "C" fn m_trap(epc: usize,
 	else {
 		// Synchronous trap
 		match cause_num {
+			1 | 5 => unsafe {
+				// Instruction and load access faults. These come from PMP
+				// when a process touches physical memory it isn't allowed to.
+				// The kernel isn't held to PMP, so one of these in machine
+				// mode is a kernel bug.
+				if (*frame).mode == CpuMode::Machine as usize {
+					panic!("Access fault ({}) in kernel CPU#{} -> 0x{:08x}: 0x{:08x}\n", cause_num, hart, epc, tval);
+				}
+				println!("Access fault ({}) CPU#{} -> 0x{:08x}: 0x{:08x}", cause_num, hart, epc, tval);
+				pmp::self_test_fault((*frame).pid as u16, cause_num);
+				delete_process((*frame).pid as u16);
+				let frame = schedule();
+				schedule_next_context_switch(1);
+				rust_switch_to_user(frame);
+			}
 			2 => unsafe {
 				// Illegal instruction
 				println!("Illegal instruction CPU#{} -> 0x{:08x}: 0x{:08x}\n", hart, epc, tval);
@@ -93,6 +109,7 @@ extern "C" fn m_trap(epc: usize,
 			}
 			7 => unsafe {
 				println!("Error with pid {}, at PC 0x{:08x}, mepc 0x{:08x}", (*frame).pid, (*frame).pc, epc);
+				pmp::self_test_fault((*frame).pid as u16, cause_num);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
 				schedule_next_context_switch(1);
@@ -110,6 +127,7 @@ extern "C" fn m_trap(epc: usize,
 			12 => unsafe {
 				// Instruction page fault
 				println!("Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				pmp::self_test_fault((*frame).pid as u16, cause_num);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
 				schedule_next_context_switch(1);
@@ -118,6 +136,7 @@ extern "C" fn m_trap(epc: usize,
 			13 => unsafe {
 				// Load page fault
 				println!("Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				pmp::self_test_fault((*frame).pid as u16, cause_num);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
 				schedule_next_context_switch(1);
@@ -126,6 +145,7 @@ extern "C" fn m_trap(epc: usize,
 			15 => unsafe {
 				// Store page fault
 				println!("Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}", hart, epc, tval);
+				pmp::self_test_fault((*frame).pid as u16, cause_num);
 				delete_process((*frame).pid as u16);
 				let frame = schedule();
 				schedule_next_context_switch(1);
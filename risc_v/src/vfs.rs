@@ -3,3 +3,1230 @@
 // Stephen Marz
 // 4 June 2020
 
+use crate::{bcache,
+            buffer::Buffer,
+            cpu::Registers,
+            devfs,
+            ext2::Ext2FileSystem,
+            fat::FatFileSystem,
+            fs::MinixFileSystem,
+            journal,
+            lock::Mutex,
+            process::{add_kernel_process_args, get_by_pid, set_running, set_waiting, Descriptor, PROCESS_LIST},
+            syscall::{copy_from_user, copy_to_user, fs_errno}};
+use crate::fs::{S_IFCHR, S_IFDIR, S_IFLNK, S_IFMT};
+use alloc::{boxed::Box, collections::BTreeMap, string::String, sync::Arc, vec::Vec};
+use core::cmp::min;
+
+/// Something that can be read and written once it is open. Everything on a
+/// file system is an Inode, which is also a File. Offsets are in bytes.
+/// These may block, so only call them from a process.
+pub trait File {
+	fn read(&self, buffer: *mut u8, size: u32, offset: u32) -> Result<u32, FsError>;
+	fn write(&self, buffer: *const u8, size: u32, offset: u32) -> Result<u32, FsError>;
+}
+
+/// A file or directory on some file system. The directory operations take a
+/// single name in this directory. Paths are the VFS' problem, so file systems
+/// never see a /, ".", or "..".
+pub trait Inode: File {
+	/// Which file system this is on. No two mounted file systems share one.
+	fn dev(&self) -> usize;
+	/// The inode number, which is unique within its file system.
+	fn ino(&self) -> u32;
+	fn stat(&self) -> Result<Stat, FsError>;
+	/// Throw away the contents of a file, leaving it empty.
+	fn truncate(&self) -> Result<(), FsError>;
+	fn lookup(&self, name: &str) -> Result<InodeRef, FsError>;
+	/// Create an empty regular file.
+	fn create(&self, name: &str, mode: u16) -> Result<InodeRef, FsError>;
+	fn mkdir(&self, name: &str, mode: u16) -> Result<(), FsError>;
+	fn unlink(&self, name: &str) -> Result<(), FsError>;
+	fn rmdir(&self, name: &str) -> Result<(), FsError>;
+	/// Make name in this directory another link to target.
+	fn link(&self, name: &str, target: &InodeRef) -> Result<(), FsError>;
+	fn rename(&self, old_name: &str, new_dir: &InodeRef, new_name: &str) -> Result<(), FsError>;
+	/// Make name in this directory a symbolic link to target.
+	fn symlink(&self, name: &str, target: &str) -> Result<(), FsError>;
+	/// Where this symbolic link points.
+	fn readlink(&self) -> Result<String, FsError>;
+	/// Change the permission bits. The file type bits of mode are ignored.
+	fn chmod(&self, mode: u16) -> Result<(), FsError>;
+	fn chown(&self, uid: u16, gid: u16) -> Result<(), FsError>;
+	/// The entries of a directory starting at offset, which is 0 or the
+	/// next of an entry we gave back before. What offsets mean is up to the
+	/// file system, but they have to stay good between calls.
+	fn readdir(&self, offset: u32) -> Result<Vec<DirEnt>, FsError>;
+	/// Get anything this file system still has in memory onto its device.
+	/// File systems without a device have nothing to do.
+	fn sync(&self) -> Result<(), FsError> {
+		Ok(())
+	}
+}
+
+pub type InodeRef = Arc<dyn Inode>;
+
+/// What a descriptor for an open file or directory holds. flags are what it
+/// was opened with. Reads and writes that don't bring their own offset start
+/// at offset and move it along. For a directory, offset is where getdents
+/// picks up.
+pub struct OpenFile {
+	pub inode:  InodeRef,
+	pub flags:  usize,
+	pub offset: u32,
+	/// The absolute path we opened, so that the *at() calls can start from
+	/// a directory descriptor.
+	pub path:   String
+}
+
+/// A file system that can be mounted. Everything else is found by looking
+/// up names starting at its root directory.
+pub trait FileSystem {
+	fn root(&self) -> Result<InodeRef, FsError>;
+	/// We're done with the file system. Anything it still has in memory
+	/// goes to its device, and nothing else will use it after this.
+	fn unmount(&self) -> Result<(), FsError> {
+		Ok(())
+	}
+}
+
+/// Stats on a file. This generally mimics an inode
+/// since that's the information we want anyway.
+/// However, inodes are filesystem specific, and we
+/// want a more generic stat.
+pub struct Stat {
+	pub dev:     usize,
+	pub ino:     u32,
+	pub mode:    u16,
+	pub nlinks:  u16,
+	pub uid:     u16,
+	pub gid:     u16,
+	pub size:    u32,
+	/// How many 512-byte blocks the file takes up.
+	pub blocks:  u32,
+	/// The block size I/O should be done in.
+	pub blksize: u32,
+	pub atime:   u32,
+	pub mtime:   u32,
+	pub ctime:   u32
+}
+
+/// One entry of a directory. The type is one of the DT_ values below.
+pub struct DirEnt {
+	pub ino:  u32,
+	pub kind: u8,
+	pub name: String,
+	/// Where the entry after this one starts.
+	pub next: u32
+}
+
+// Directory entry types, as getdents64 reports them. These happen to be the
+// S_IFMT bits of the mode shifted down, which is what dirent_type() does.
+pub const DT_UNKNOWN: u8 = 0;
+pub const DT_DIR: u8 = 4;
+pub const DT_REG: u8 = 8;
+
+pub fn dirent_type(mode: u16) -> u8 {
+	((mode & S_IFMT) >> 12) as u8
+}
+
+impl Stat {
+	/// Devices that aren't on any file system, such as the console, are
+	/// character devices with nothing else to say about them.
+	pub fn char_device() -> Self {
+		Stat { dev:     0,
+		       ino:     0,
+		       mode:    S_IFCHR | 0o620,
+		       nlinks:  1,
+		       uid:     0,
+		       gid:     0,
+		       size:    0,
+		       blocks:  0,
+		       blksize: 1024,
+		       atime:   0,
+		       mtime:   0,
+		       ctime:   0 }
+	}
+}
+
+// A Linux struct stat, which is what newlib wants from fstat and friends:
+//   0 u64 st_dev      8 u64 st_ino      16 u32 st_mode     20 u32 st_nlink
+//  24 u32 st_uid     28 u32 st_gid      32 u64 st_rdev     40 padding
+//  48 i64 st_size    56 i32 st_blksize  60 padding         64 i64 st_blocks
+//  72 i64 st_atime   80 u64 nsec        88 i64 st_mtime    96 u64 nsec
+// 104 i64 st_ctime  112 u64 nsec       120 unused
+pub const STAT_SIZE: usize = 128;
+
+/// Put st at buf as a Linux struct stat. buf has to be 8-byte aligned and
+/// STAT_SIZE bytes.
+pub unsafe fn put_stat(buf: *mut u8, st: &Stat) {
+	for i in 0..STAT_SIZE {
+		buf.add(i).write(0);
+	}
+	(buf as *mut u64).write(st.dev as u64);
+	(buf.add(8) as *mut u64).write(st.ino as u64);
+	(buf.add(16) as *mut u32).write(st.mode as u32);
+	(buf.add(20) as *mut u32).write(st.nlinks as u32);
+	(buf.add(24) as *mut u32).write(st.uid as u32);
+	(buf.add(28) as *mut u32).write(st.gid as u32);
+	(buf.add(48) as *mut i64).write(st.size as i64);
+	(buf.add(56) as *mut i32).write(st.blksize as i32);
+	(buf.add(64) as *mut i64).write(st.blocks as i64);
+	(buf.add(72) as *mut i64).write(st.atime as i64);
+	(buf.add(88) as *mut i64).write(st.mtime as i64);
+	(buf.add(104) as *mut i64).write(st.ctime as i64);
+}
+
+/// Copy st out to the user's struct stat at addr.
+pub fn copy_stat(pid: u16, addr: usize, st: &Stat) -> Result<(), FsError> {
+	let mut buffer = Buffer::new(STAT_SIZE);
+	unsafe {
+		put_stat(buffer.get_mut(), st);
+		if !copy_to_user(pid, addr, buffer.get(), STAT_SIZE) {
+			return Err(FsError::BadAddress);
+		}
+	}
+	Ok(())
+}
+
+pub enum FsError {
+	Success,
+	FileNotFound,
+	Permission,
+	IsFile,
+	IsDirectory,
+	NoSpace,
+	Exists,
+	NotEmpty,
+	NameTooLong,
+	InvalidArgument,
+	CrossDevice,
+	NotPermitted,
+	TooManyLinks,
+	Io,
+	BadDescriptor,
+	BadAddress,
+	/// The file system can't be changed, like ext2, which we only read.
+	ReadOnly,
+	/// Something is still using it, like a file system with open files
+	/// that someone wants to unmount.
+	Busy,
+	/// The kernel couldn't get memory for the operation.
+	OutOfMemory
+}
+
+// ///////////////////////////////////
+// / MOUNT TABLE
+// ///////////////////////////////////
+
+/// A file system and the absolute path it is mounted at. The path never
+/// ends with a /, except for the root, which is just "/". We get the root
+/// inode once when mounting since path resolution needs it all the time.
+struct Mount {
+	path: String,
+	fs:   Arc<dyn FileSystem>,
+	root: InodeRef
+}
+
+// Like the block cache, we take() the mount table to use it and replace()
+// it when we're done, and a sleep lock keeps two processes from doing that
+// at once. Without it, a path walk that found the table taken would think
+// nothing was mounted. Inodes are reference counted so that we can let go
+// of the table before we call into a file system, which might block.
+static mut MOUNTS: Option<Vec<Mount>> = None;
+static mut MOUNTS_LOCK: Mutex = Mutex::new();
+
+pub fn init() {
+	unsafe {
+		MOUNTS.replace(Vec::new());
+		DCACHE.replace(DentryCache { entries: BTreeMap::new(),
+		                             clock:   0 });
+	}
+}
+
+/// Run f with the mount table locked.
+fn with_mounts<T>(f: impl FnOnce(&mut Vec<Mount>) -> T) -> T {
+	unsafe {
+		MOUNTS_LOCK.sleep_lock();
+		let mut mounts = MOUNTS.take().unwrap_or_default();
+		let ret = f(&mut mounts);
+		MOUNTS.replace(mounts);
+		MOUNTS_LOCK.unlock();
+		ret
+	}
+}
+
+/// Mount fs at path. Something already mounted there has to be unmounted
+/// first, and a device can only be mounted once. Getting the root may block,
+/// so this has to run in a process.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+	let path = if path == "/" {
+		String::from("/")
+	}
+	else {
+		// The mount point has to be an existing directory, unless this is
+		// the first mount, which goes on /.
+		let (dir, canonical) = walk(path, "/", &Credentials::ROOT)?;
+		if !is_dir(&dir)? {
+			return Err(FsError::IsFile);
+		}
+		canonical
+	};
+	let root = fs.root()?;
+	with_mounts(|mounts| {
+		if mounts.iter().any(|m| m.path == path) {
+			Err(FsError::Exists)
+		}
+		else if mounts.iter().any(|m| m.root.dev() == root.dev()) {
+			Err(FsError::Busy)
+		}
+		else {
+			mounts.push(Mount { path, fs, root });
+			Ok(())
+		}
+	})
+}
+
+/// The file systems that live on a block device, by the names mount_device()
+/// knows them by, in the order we try them.
+const FILE_SYSTEMS: [&str; 3] = ["minix", "ext2", "vfat"];
+
+/// Mount the file system on block device dev at path. fstype is one of
+/// FILE_SYSTEMS, or None to take whichever of them is on the device.
+pub fn mount_device(path: &str, dev: usize, fstype: Option<&str>) -> Result<(), FsError> {
+	// Getting the root of a Minix disk opens its journal, so we can't even
+	// try one that is already mounted.
+	if is_mounted(dev) {
+		return Err(FsError::Busy);
+	}
+	for &name in FILE_SYSTEMS.iter() {
+		if fstype.is_some() && fstype != Some(name) {
+			continue;
+		}
+		let fs: Arc<dyn FileSystem> = match name {
+			"minix" => Arc::new(MinixFileSystem::new(dev)),
+			"ext2" => Arc::new(Ext2FileSystem::new(dev)),
+			_ => Arc::new(FatFileSystem::new(dev)),
+		};
+		// Each file system's root() checks for its own magic, so the ones
+		// that aren't on the device fail with InvalidArgument.
+		match mount(path, fs.clone()) {
+			Err(FsError::InvalidArgument) => continue,
+			Err(e) => {
+				let _ = fs.unmount();
+				return Err(e);
+			},
+			Ok(()) => return Ok(()),
+		}
+	}
+	Err(FsError::InvalidArgument)
+}
+
+/// Is a file system on dev mounted anywhere?
+fn is_mounted(dev: usize) -> bool {
+	with_mounts(|mounts| mounts.iter().any(|m| m.root.dev() == dev))
+}
+
+/// Unmount the file system at path. The root can't be unmounted, and
+/// neither can a file system with another one mounted under it, or that a
+/// process has a file open on or is working in.
+pub fn umount(path: &str) -> Result<(), FsError> {
+	let (_, path) = walk(path, "/", &Credentials::ROOT)?;
+	if path == "/" {
+		return Err(FsError::Busy);
+	}
+	let dev = mounted_at(&path).ok_or(FsError::InvalidArgument)?.dev();
+	let under = {
+		let mut under = path.clone();
+		under.push('/');
+		under
+	};
+	if in_use(&under, dev) {
+		return Err(FsError::Busy);
+	}
+	let mount = with_mounts(|mounts| match mounts.iter().position(|m| m.path == path) {
+		None => Err(FsError::InvalidArgument),
+		Some(_) if mounts.iter().any(|m| m.path.starts_with(&under)) => Err(FsError::Busy),
+		Some(i) => Ok(mounts.remove(i)),
+	})?;
+	dcache_forget(dev);
+	mount.fs.unmount()
+}
+
+/// Does any process have a file open on dev, or a working directory under
+/// the path prefix under?
+fn in_use(under: &str, dev: usize) -> bool {
+	unsafe {
+		if let Some(pl) = PROCESS_LIST.take() {
+			let mut ret = false;
+			for proc in pl.iter() {
+				let mut cwd = proc.data.cwd.clone();
+				cwd.push('/');
+				if cwd.starts_with(under) {
+					ret = true;
+				}
+				for desc in proc.data.fdesc.values() {
+					let Descriptor::File(file) = desc;
+					if file.inode.dev() == dev {
+						ret = true;
+					}
+				}
+			}
+			PROCESS_LIST.replace(pl);
+			ret
+		}
+		else {
+			// Somebody else has the process list, so we can't tell.
+			true
+		}
+	}
+}
+
+/// If a file system is mounted exactly at path, give back its root.
+fn mounted_at(path: &str) -> Option<InodeRef> {
+	with_mounts(|mounts| mounts.iter().find(|m| m.path == path).map(|m| m.root.clone()))
+}
+
+// ///////////////////////////////////
+// / DENTRY CACHE
+// ///////////////////////////////////
+
+// Looking a name up in a directory means reading the directory from the
+// disk, so we remember the last few hundred answers. An entry is keyed by
+// the directory's device and inode number and the name in it. When the cache
+// is full, the entry that was used longest ago is thrown out.
+const DCACHE_SIZE: usize = 256;
+
+struct Dentry {
+	inode:     InodeRef,
+	last_used: usize
+}
+
+struct DentryCache {
+	entries: BTreeMap<(usize, u32, String), Dentry>,
+	clock:   usize
+}
+
+// The dentry cache is used by every path walk, so it gets a sleep lock like
+// the mount table, and a miss never comes from somebody else having it.
+static mut DCACHE: Option<DentryCache> = None;
+static mut DCACHE_LOCK: Mutex = Mutex::new();
+
+/// Run f with the dentry cache locked.
+fn with_dcache<T>(f: impl FnOnce(&mut DentryCache) -> T) -> T {
+	unsafe {
+		DCACHE_LOCK.sleep_lock();
+		let mut cache = DCACHE.take().unwrap_or_else(|| DentryCache { entries: BTreeMap::new(),
+		                                                              clock:   0 });
+		let ret = f(&mut cache);
+		DCACHE.replace(cache);
+		DCACHE_LOCK.unlock();
+		ret
+	}
+}
+
+fn dcache_get(dir: &InodeRef, name: &str) -> Option<InodeRef> {
+	with_dcache(|cache| {
+		cache.clock += 1;
+		let clock = cache.clock;
+		let d = cache.entries.get_mut(&(dir.dev(), dir.ino(), String::from(name)))?;
+		d.last_used = clock;
+		Some(d.inode.clone())
+	})
+}
+
+fn dcache_put(dir: &InodeRef, name: &str, inode: &InodeRef) {
+	with_dcache(|cache| {
+		if cache.entries.len() >= DCACHE_SIZE {
+			let oldest = cache.entries.iter().min_by_key(|(_, d)| d.last_used).map(|(k, _)| k.clone());
+			if let Some(key) = oldest {
+				cache.entries.remove(&key);
+			}
+		}
+		cache.clock += 1;
+		let dentry = Dentry { inode:     inode.clone(),
+		                      last_used: cache.clock };
+		cache.entries.insert((dir.dev(), dir.ino(), String::from(name)), dentry);
+	});
+}
+
+/// Forget everything we know about the names on dev, which is going away.
+fn dcache_forget(dev: usize) {
+	with_dcache(|cache| {
+		let keys: Vec<(usize, u32, String)> = cache.entries
+		                                           .iter()
+		                                           .filter(|(k, d)| k.0 == dev || d.inode.dev() == dev)
+		                                           .map(|(k, _)| k.clone())
+		                                           .collect();
+		for key in keys {
+			cache.entries.remove(&key);
+		}
+	});
+}
+
+/// Forget name in dir. Anything that removes or moves a name calls this.
+fn dcache_remove(dir: &InodeRef, name: &str) {
+	with_dcache(|cache| {
+		cache.entries.remove(&(dir.dev(), dir.ino(), String::from(name)));
+	});
+}
+
+// ///////////////////////////////////
+// / PERMISSIONS
+// ///////////////////////////////////
+
+/// Who is asking for a file. Permission checks go by the effective IDs of a
+/// process, except access(), which asks about the real ones.
+#[derive(Clone, Copy)]
+pub struct Credentials {
+	pub uid: u16,
+	pub gid: u16
+}
+
+impl Credentials {
+	/// The kernel itself, which can do anything.
+	pub const ROOT: Credentials = Credentials { uid: 0, gid: 0 };
+}
+
+pub fn cred_of(pid: u16, effective: bool) -> Credentials {
+	unsafe {
+		let process = get_by_pid(pid);
+		if process.is_null() {
+			return Credentials::ROOT;
+		}
+		let data = &(*process).data;
+		if effective {
+			Credentials { uid: data.euid, gid: data.egid }
+		}
+		else {
+			Credentials { uid: data.uid, gid: data.gid }
+		}
+	}
+}
+
+// What a caller wants to do to a file, which lines up with the rwx bits of
+// each of owner, group and other. These are also access()'s R_OK, W_OK and
+// X_OK.
+pub const MAY_READ: u16 = 4;
+pub const MAY_WRITE: u16 = 2;
+pub const MAY_EXEC: u16 = 1;
+
+/// Can cred do everything in want to a file with st? Root can read and
+/// write anything, and it can execute anything that has an execute bit for
+/// someone. Directories are "executed" when they are searched, so root can
+/// always do that.
+pub fn check(st: &Stat, cred: &Credentials, want: u16) -> Result<(), FsError> {
+	let bits = if cred.uid == 0 {
+		if want & MAY_EXEC != 0 && st.mode & S_IFMT != S_IFDIR && st.mode & 0o111 == 0 {
+			return Err(FsError::Permission);
+		}
+		return Ok(());
+	}
+	else if cred.uid == st.uid {
+		st.mode >> 6
+	}
+	else if cred.gid == st.gid {
+		st.mode >> 3
+	}
+	else {
+		st.mode
+	};
+	if bits & want == want { Ok(()) } else { Err(FsError::Permission) }
+}
+
+pub fn permission(inode: &InodeRef, cred: &Credentials, want: u16) -> Result<(), FsError> {
+	check(&inode.stat()?, cred, want)
+}
+
+/// Only the owner of a file, or root, gets to change its mode.
+fn owner_or_root(inode: &InodeRef, cred: &Credentials) -> Result<(), FsError> {
+	if cred.uid != 0 && cred.uid != inode.stat()?.uid {
+		return Err(FsError::NotPermitted);
+	}
+	Ok(())
+}
+
+// ///////////////////////////////////
+// / PATH RESOLUTION
+// ///////////////////////////////////
+
+/// Look name up in dir, going to the cache first.
+fn lookup_in(dir: &InodeRef, name: &str) -> Result<InodeRef, FsError> {
+	if let Some(inode) = dcache_get(dir, name) {
+		return Ok(inode);
+	}
+	let inode = dir.lookup(name)?;
+	dcache_put(dir, name, &inode);
+	Ok(inode)
+}
+
+fn is_dir(inode: &InodeRef) -> Result<bool, FsError> {
+	Ok(inode.stat()?.mode & S_IFMT == S_IFDIR)
+}
+
+/// Find the inode at path, following symbolic links. See walk_link().
+pub fn walk(path: &str, cwd: &str, cred: &Credentials) -> Result<(InodeRef, String), FsError> {
+	walk_link(path, cwd, cred, true)
+}
+
+// How many symbolic links one walk follows before we decide they go around
+// in a circle.
+pub const MAX_SYMLINKS: usize = 8;
+
+fn is_link(inode: &InodeRef) -> Result<bool, FsError> {
+	Ok(inode.stat()?.mode & S_IFMT == S_IFLNK)
+}
+
+/// Find the inode at path. A relative path starts at cwd, which has to be
+/// absolute. We walk one component at a time and keep every directory we
+/// went through, so ".." goes back to the directory we came from, even if
+/// that is on another file system. Gives back the inode and the absolute
+/// path to it without any "." or "..". cred needs search (execute)
+/// permission on every directory we look a name up in.
+///
+/// A symbolic link in the middle of path is always followed: its target
+/// goes in place of its name, starting over at the root if it is absolute.
+/// follow says whether a link at the very end is followed too, or given
+/// back itself, which is what lstat() and readlink() want.
+pub fn walk_link(path: &str, cwd: &str, cred: &Credentials, follow: bool) -> Result<(InodeRef, String), FsError> {
+	let root = mounted_at("/").ok_or(FsError::FileNotFound)?;
+	let mut stack: Vec<(String, InodeRef)> = Vec::new();
+	let mut current = root.clone();
+	let start = if path.starts_with('/') { "" } else { cwd };
+	// The components we still have to go through, last first, so that the
+	// next one is always at the end.
+	let mut todo: Vec<String> = start.split('/')
+	                                 .chain(path.split('/'))
+	                                 .filter(|c| !c.is_empty())
+	                                 .rev()
+	                                 .map(String::from)
+	                                 .collect();
+	let mut links = 0;
+	while let Some(name) = todo.pop() {
+		match name.as_str() {
+			"." => {}
+			".." => {
+				// The root's parent is the root.
+				if let Some((_, parent)) = stack.pop() {
+					current = parent;
+				}
+			}
+			_ => {
+				let st = current.stat()?;
+				if st.mode & S_IFMT != S_IFDIR {
+					return Err(FsError::IsFile);
+				}
+				check(&st, cred, MAY_EXEC)?;
+				let mut next = lookup_in(&current, &name)?;
+				stack.push((name, current));
+				// If something is mounted here, we go into its root instead.
+				if let Some(mount_root) = mounted_at(&canonical(&stack)) {
+					next = mount_root;
+				}
+				if (follow || !todo.is_empty()) && is_link(&next)? {
+					links += 1;
+					if links > MAX_SYMLINKS {
+						return Err(FsError::TooManyLinks);
+					}
+					let target = next.readlink()?;
+					// The link's name comes back off, since we're going to
+					// where it points instead.
+					current = stack.pop().unwrap().1;
+					if target.starts_with('/') {
+						stack.clear();
+						current = root.clone();
+					}
+					todo.extend(target.split('/').filter(|c| !c.is_empty()).rev().map(String::from));
+					continue;
+				}
+				current = next;
+			}
+		}
+	}
+	let path = canonical(&stack);
+	Ok((current, path))
+}
+
+/// The absolute path we took to get to the top of stack.
+fn canonical(stack: &[(String, InodeRef)]) -> String {
+	let mut ret = String::new();
+	for (name, _) in stack {
+		ret.push('/');
+		ret.push_str(name);
+	}
+	if ret.is_empty() {
+		ret.push('/');
+	}
+	ret
+}
+
+/// Split path into the directory it names something in and the last
+/// component, then find that directory. The last component can't be "." or
+/// ".." since those can't be created or removed. Everyone who wants the
+/// parent is about to change it, so cred needs write and search permission
+/// on it.
+pub fn walk_parent<'a>(path: &'a str, cwd: &str, cred: &Credentials) -> Result<(InodeRef, &'a str), FsError> {
+	let trimmed = path.trim_end_matches('/');
+	let (dir, name) = match trimmed.rfind('/') {
+		Some(i) => (if i == 0 { "/" } else { &trimmed[..i] }, &trimmed[i + 1..]),
+		None => (".", trimmed),
+	};
+	if name.is_empty() || name == "." || name == ".." {
+		return Err(FsError::InvalidArgument);
+	}
+	let (dir, _) = walk(dir, cwd, cred)?;
+	let st = dir.stat()?;
+	if st.mode & S_IFMT != S_IFDIR {
+		return Err(FsError::IsFile);
+	}
+	check(&st, cred, MAY_WRITE | MAY_EXEC)?;
+	Ok((dir, name))
+}
+
+/// Look up a path relative to the working directory of process pid, with
+/// its permissions. This is what everything that takes a path from a
+/// process should use.
+pub fn lookup(pid: u16, path: &str) -> Result<InodeRef, FsError> {
+	walk(path, &cwd_of(pid), &cred_of(pid, true)).map(|(inode, _)| inode)
+}
+
+fn cwd_of(pid: u16) -> String {
+	unsafe {
+		let process = get_by_pid(pid);
+		if process.is_null() {
+			String::from("/")
+		}
+		else {
+			(*process).data.cwd.clone()
+		}
+	}
+}
+
+/// Link and rename can't go from one file system to another.
+fn same_fs(a: &InodeRef, b: &InodeRef) -> Result<(), FsError> {
+	if a.dev() != b.dev() {
+		return Err(FsError::CrossDevice);
+	}
+	Ok(())
+}
+
+fn link(old_path: &str, new_path: &str, cwd: &str, cred: &Credentials) -> Result<(), FsError> {
+	// Like Linux, a hard link to a symbolic link is to the link itself.
+	let (target, _) = walk_link(old_path, cwd, cred, false)?;
+	let (dir, name) = walk_parent(new_path, cwd, cred)?;
+	same_fs(&target, &dir)?;
+	dir.link(name, &target)
+}
+
+/// unlink() or, if directory is true, rmdir(). The cache lets go of the
+/// name first, since a file system counts the inode it holds as in use, and
+/// one that's in use keeps its contents after its last link is gone. It
+/// lets go again after, in case a walk put it back in the meantime.
+fn remove(path: &str, cwd: &str, cred: &Credentials, directory: bool) -> Result<(), FsError> {
+	let (dir, name) = walk_parent(path, cwd, cred)?;
+	dcache_remove(&dir, name);
+	let ret = if directory { dir.rmdir(name) } else { dir.unlink(name) };
+	dcache_remove(&dir, name);
+	ret
+}
+
+fn rename(old_path: &str, new_path: &str, cwd: &str, cred: &Credentials) -> Result<(), FsError> {
+	let (old_dir, old_name) = walk_parent(old_path, cwd, cred)?;
+	let (new_dir, new_name) = walk_parent(new_path, cwd, cred)?;
+	same_fs(&old_dir, &new_dir)?;
+	// Whatever was at new_name may lose its last link. See remove().
+	dcache_remove(&new_dir, new_name);
+	let ret = old_dir.rename(old_name, &new_dir, new_name);
+	dcache_remove(&old_dir, old_name);
+	dcache_remove(&new_dir, new_name);
+	ret
+}
+
+// ///////////////////////////////////
+// / KERNEL PROCESSES
+// ///////////////////////////////////
+
+// Flags for open(). These are newlib's values, which aren't the same as Linux's.
+pub const O_ACCMODE: usize = 3;
+pub const O_RDONLY: usize = 0;
+pub const O_WRONLY: usize = 1;
+pub const O_RDWR: usize = 2;
+pub const O_APPEND: usize = 0x0008;
+pub const O_CREAT: usize = 0x0200;
+pub const O_TRUNC: usize = 0x0400;
+pub const O_EXCL: usize = 0x0800;
+pub const O_DIRECTORY: usize = 0x0020_0000;
+// For the *at() calls, also newlib's values.
+pub const AT_FDCWD: isize = -2;
+pub const AT_EACCESS: usize = 1;
+pub const AT_SYMLINK_NOFOLLOW: usize = 2;
+
+/// Operations on paths. File systems block, so the system call hands these
+/// to a kernel process.
+pub enum FsOp {
+	Open { path: String, flags: usize, mode: u16 },
+	Link(String, String),
+	Unlink(String),
+	Mkdir(String, u16),
+	Rmdir(String),
+	Rename(String, String),
+	Chdir(String),
+	Getdents { fd: u16, buffer: usize, count: usize },
+	Read { fd: u16, buffer: usize, count: usize, offset: Option<u32> },
+	Write { fd: u16, buffer: usize, count: usize, offset: Option<u32> },
+	Lseek { fd: u16, offset: isize, whence: usize },
+	Fstat { fd: u16, buffer: usize },
+	Stat { dirfd: isize, path: String, buffer: usize, flags: usize },
+	Access { dirfd: isize, path: String, mode: u16, flags: usize },
+	Chmod { dirfd: isize, path: String, mode: u16 },
+	Symlink { target: String, dirfd: isize, path: String },
+	Readlink { dirfd: isize, path: String, buffer: usize, size: usize },
+	Chown { dirfd: isize, path: String, uid: Option<u16>, gid: Option<u16>, flags: usize },
+	Sync,
+	Fsync(InodeRef),
+	Mount { source: String, target: String, fstype: Option<String> },
+	Umount(String),
+}
+
+struct OpArgs {
+	pub pid: u16,
+	pub op:  FsOp
+}
+
+/// Open a file, creating or truncating it first if flags say to. Returns the
+/// new descriptor. A file we create belongs to whoever created it, and they
+/// can open it however they asked even if mode doesn't let them.
+fn open(pid: u16, path: &str, flags: usize, mode: u16, cwd: &str, cred: &Credentials) -> Result<usize, FsError> {
+	let (inode, path) = match walk(path, cwd, cred) {
+		Ok(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => Err(FsError::Exists),
+		Err(FsError::FileNotFound) if flags & O_CREAT != 0 => {
+			let (dir, name) = walk_parent(path, cwd, cred)?;
+			dir.create(name, mode)?.chown(cred.uid, cred.gid)?;
+			walk(path, cwd, cred)
+		}
+		Ok(found) => {
+			let want = match flags & O_ACCMODE {
+				O_RDONLY => MAY_READ,
+				O_WRONLY => MAY_WRITE,
+				_ => MAY_READ | MAY_WRITE,
+			};
+			permission(&found.0, cred, want)?;
+			Ok(found)
+		}
+		other => other,
+	}?;
+	let dir = is_dir(&inode)?;
+	if flags & O_ACCMODE != O_RDONLY && dir {
+		return Err(FsError::IsDirectory);
+	}
+	if flags & O_DIRECTORY != 0 && !dir {
+		return Err(FsError::IsFile);
+	}
+	if flags & O_TRUNC != 0 && flags & O_ACCMODE != O_RDONLY {
+		inode.truncate()?;
+	}
+	unsafe {
+		let process = get_by_pid(pid);
+		if process.is_null() {
+			return Err(FsError::FileNotFound);
+		}
+		Ok((*process).data.add_fdesc(Descriptor::File(OpenFile { inode, flags, offset: 0, path })) as usize)
+	}
+}
+
+/// The inode, flags and offset of descriptor fd of process pid. The offset
+/// is only a snapshot. It's put back with set_offset() when we're done.
+fn open_file(pid: u16, fd: u16) -> Result<(InodeRef, usize, u32), FsError> {
+	unsafe {
+		let process = get_by_pid(pid);
+		if process.is_null() {
+			return Err(FsError::BadDescriptor);
+		}
+		match (*process).data.fdesc.get(&fd) {
+			Some(Descriptor::File(file)) => Ok((file.inode.clone(), file.flags, file.offset)),
+			_ => Err(FsError::BadDescriptor),
+		}
+	}
+}
+
+fn set_offset(pid: u16, fd: u16, offset: u32) {
+	unsafe {
+		let process = get_by_pid(pid);
+		if process.is_null() {
+			return;
+		}
+		if let Some(Descriptor::File(file)) = (*process).data.fdesc.get_mut(&fd) {
+			file.offset = offset;
+		}
+	}
+}
+
+// read() and write() go through a kernel buffer at most this big at a
+// time, so a huge count doesn't have to be allocated all at once.
+const IO_CHUNK: u32 = 64 * 1024;
+
+/// Read up to count bytes of fd into the user's buffer at addr. With no
+/// offset, we read at the descriptor's offset and move it past what we read.
+fn read(pid: u16, fd: u16, addr: usize, count: usize, offset: Option<u32>) -> Result<usize, FsError> {
+	let (inode, flags, pos) = open_file(pid, fd)?;
+	if flags & O_ACCMODE == O_WRONLY {
+		return Err(FsError::BadDescriptor);
+	}
+	let at = offset.unwrap_or(pos);
+	let count = if count > u32::MAX as usize { u32::MAX } else { count as u32 };
+	let mut buffer = Buffer::new(min(count, IO_CHUNK) as usize);
+	if buffer.get().is_null() {
+		return Err(FsError::OutOfMemory);
+	}
+	let mut bytes = 0;
+	while bytes < count {
+		let want = min(count - bytes, IO_CHUNK);
+		let got = inode.read(buffer.get_mut(), want, at + bytes)?;
+		unsafe {
+			if !copy_to_user(pid, addr + bytes as usize, buffer.get(), got as usize) {
+				return Err(FsError::BadAddress);
+			}
+		}
+		bytes += got;
+		// A short read is the end of the file, or all a device had.
+		if got < want {
+			break;
+		}
+	}
+	if offset.is_none() {
+		set_offset(pid, fd, at + bytes);
+	}
+	Ok(bytes as usize)
+}
+
+/// Write count bytes from the user's buffer at addr to fd. With no offset,
+/// we write at the descriptor's offset, or the end of the file for O_APPEND,
+/// and move it past what we wrote. If addr runs into memory that isn't
+/// mapped, we stop there, and it's only an error if nothing got written.
+fn write(pid: u16, fd: u16, addr: usize, count: usize, offset: Option<u32>) -> Result<usize, FsError> {
+	let (inode, flags, pos) = open_file(pid, fd)?;
+	if flags & O_ACCMODE == O_RDONLY {
+		return Err(FsError::BadDescriptor);
+	}
+	// With O_APPEND, where the end is can only be figured out now that we
+	// can block to get the size.
+	let at = match offset {
+		Some(offset) => offset,
+		None if flags & O_APPEND != 0 => inode.stat()?.size,
+		None => pos,
+	};
+	let count = if count > u32::MAX as usize { u32::MAX } else { count as u32 };
+	let mut buffer = Buffer::new(min(count, IO_CHUNK) as usize);
+	if buffer.get().is_null() {
+		return Err(FsError::OutOfMemory);
+	}
+	let mut bytes = 0;
+	while bytes < count {
+		let want = min(count - bytes, IO_CHUNK);
+		let copied = unsafe { copy_from_user(pid, addr + bytes as usize, buffer.get_mut(), want as usize) as u32 };
+		if copied == 0 {
+			if bytes == 0 {
+				return Err(FsError::BadAddress);
+			}
+			break;
+		}
+		let wrote = inode.write(buffer.get(), copied, at + bytes)?;
+		bytes += wrote;
+		if wrote < copied || copied < want {
+			break;
+		}
+	}
+	if offset.is_none() {
+		set_offset(pid, fd, at + bytes);
+	}
+	Ok(bytes as usize)
+}
+
+/// Where a path given to an *at() call starts if it's relative: the working
+/// directory for AT_FDCWD, or else the directory open as dirfd.
+fn at_cwd(pid: u16, dirfd: isize, path: &str) -> Result<String, FsError> {
+	if dirfd == AT_FDCWD || path.starts_with('/') {
+		return Ok(cwd_of(pid));
+	}
+	let (inode, path) = unsafe {
+		let process = get_by_pid(pid);
+		if process.is_null() {
+			return Err(FsError::BadDescriptor);
+		}
+		match (*process).data.fdesc.get(&(dirfd as u16)) {
+			Some(Descriptor::File(file)) => (file.inode.clone(), file.path.clone()),
+			_ => return Err(FsError::BadDescriptor),
+		}
+	};
+	if !is_dir(&inode)? {
+		return Err(FsError::IsFile);
+	}
+	Ok(path)
+}
+
+fn fstat(pid: u16, fd: u16, addr: usize) -> Result<(), FsError> {
+	let (inode, ..) = open_file(pid, fd)?;
+	copy_stat(pid, addr, &inode.stat()?)
+}
+
+/// stat(), lstat() and fstatat() all come here. With AT_SYMLINK_NOFOLLOW, a
+/// symbolic link at the end of path is stat'ed itself.
+fn stat_at(pid: u16, dirfd: isize, path: &str, addr: usize, flags: usize) -> Result<(), FsError> {
+	let cwd = at_cwd(pid, dirfd, path)?;
+	let (inode, _) = walk_link(path, &cwd, &cred_of(pid, true), flags & AT_SYMLINK_NOFOLLOW == 0)?;
+	copy_stat(pid, addr, &inode.stat()?)
+}
+
+pub const SEEK_SET: usize = 0;
+pub const SEEK_CUR: usize = 1;
+pub const SEEK_END: usize = 2;
+
+/// access() and faccessat(). mode is some of MAY_READ, MAY_WRITE and
+/// MAY_EXEC, or 0 to see if the file is there. Unless flags has
+/// AT_EACCESS, the question is about the real IDs of the process, which is
+/// what a set-user-ID program wants to know. Walking to the file is still
+/// done with the effective IDs.
+fn access(pid: u16, dirfd: isize, path: &str, mode: u16, flags: usize) -> Result<(), FsError> {
+	let cwd = at_cwd(pid, dirfd, path)?;
+	let (inode, _) = walk(path, &cwd, &cred_of(pid, true))?;
+	permission(&inode, &cred_of(pid, flags & AT_EACCESS != 0), mode & (MAY_READ | MAY_WRITE | MAY_EXEC))
+}
+
+fn symlink(pid: u16, target: &str, dirfd: isize, path: &str) -> Result<(), FsError> {
+	let cwd = at_cwd(pid, dirfd, path)?;
+	let cred = cred_of(pid, true);
+	let (dir, name) = walk_parent(path, &cwd, &cred)?;
+	dir.symlink(name, target)?;
+	lookup_in(&dir, name)?.chown(cred.uid, cred.gid)
+}
+
+/// Copy where the link at path points into the user's buffer at addr, which
+/// is size bytes. Like readlink() on Linux, there is no NUL at the end, and
+/// a target that doesn't fit is cut off. Returns how many bytes we copied.
+fn readlink(pid: u16, dirfd: isize, path: &str, addr: usize, size: usize) -> Result<usize, FsError> {
+	let cwd = at_cwd(pid, dirfd, path)?;
+	let (inode, _) = walk_link(path, &cwd, &cred_of(pid, true), false)?;
+	let target = inode.readlink()?;
+	let len = if target.len() < size { target.len() } else { size };
+	unsafe {
+		if !copy_to_user(pid, addr, target.as_ptr(), len) {
+			return Err(FsError::BadAddress);
+		}
+	}
+	Ok(len)
+}
+
+fn chmod(pid: u16, dirfd: isize, path: &str, mode: u16) -> Result<(), FsError> {
+	let cwd = at_cwd(pid, dirfd, path)?;
+	let cred = cred_of(pid, true);
+	let (inode, _) = walk(path, &cwd, &cred)?;
+	owner_or_root(&inode, &cred)?;
+	inode.chmod(mode)
+}
+
+/// Only root can give a file away. The owner can change the group, but only
+/// to their own. None leaves that ID alone. With AT_SYMLINK_NOFOLLOW, this is
+/// lchown(), which changes a symbolic link rather than what it points to.
+fn chown(pid: u16, dirfd: isize, path: &str, uid: Option<u16>, gid: Option<u16>, flags: usize) -> Result<(), FsError> {
+	let cwd = at_cwd(pid, dirfd, path)?;
+	let cred = cred_of(pid, true);
+	let (inode, _) = walk_link(path, &cwd, &cred, flags & AT_SYMLINK_NOFOLLOW == 0)?;
+	let st = inode.stat()?;
+	let uid = uid.unwrap_or(st.uid);
+	let gid = gid.unwrap_or(st.gid);
+	if cred.uid != 0 && (cred.uid != st.uid || uid != st.uid || (gid != st.gid && gid != cred.gid)) {
+		return Err(FsError::NotPermitted);
+	}
+	inode.chown(uid, gid)
+}
+
+/// Move the offset of fd. Going past the end of the file is fine, but the
+/// offset has to stay in what a u32 can hold.
+fn lseek(pid: u16, fd: u16, offset: isize, whence: usize) -> Result<usize, FsError> {
+	let (inode, _, pos) = open_file(pid, fd)?;
+	let base = match whence {
+		SEEK_SET => 0,
+		SEEK_CUR => pos as isize,
+		SEEK_END => inode.stat()?.size as isize,
+		_ => return Err(FsError::InvalidArgument),
+	};
+	let new_pos = base + offset;
+	if new_pos < 0 || new_pos > u32::MAX as isize {
+		return Err(FsError::InvalidArgument);
+	}
+	set_offset(pid, fd, new_pos as u32);
+	Ok(new_pos as usize)
+}
+
+// A linux_dirent64 is
+//   u64 d_ino, i64 d_off, u16 d_reclen, u8 d_type, char d_name[]
+// with the name NUL terminated and the whole record padded to 8 bytes.
+const DIRENT64_NAME: usize = 19;
+// The most of the user's buffer getdents fills in one call. Asking for more
+// just gets fewer entries than would fit, and the next call picks up the rest.
+const GETDENTS_MAX: usize = 16 * 1024;
+
+fn dirent64_len(ent: &DirEnt) -> usize {
+	(DIRENT64_NAME + ent.name.len() + 1 + 7) & !7
+}
+
+/// Put ent at buf as a linux_dirent64. buf has to be 8-byte aligned and
+/// have dirent64_len(ent) bytes, which we zero first for the padding.
+unsafe fn put_dirent64(buf: *mut u8, ent: &DirEnt) {
+	let len = dirent64_len(ent);
+	for i in 0..len {
+		buf.add(i).write(0);
+	}
+	(buf as *mut u64).write(ent.ino as u64);
+	(buf.add(8) as *mut i64).write(ent.next as i64);
+	(buf.add(16) as *mut u16).write(len as u16);
+	buf.add(18).write(ent.kind);
+	for (i, c) in ent.name.bytes().enumerate() {
+		buf.add(DIRENT64_NAME + i).write(c);
+	}
+}
+
+/// Fill the user's buffer at addr with as many entries of directory fd as
+/// fit in count bytes, starting where the last call stopped. Returns how
+/// many bytes we filled, which is 0 at the end of the directory.
+fn getdents(pid: u16, fd: u16, addr: usize, count: usize) -> Result<usize, FsError> {
+	let (dir, _, offset) = open_file(pid, fd)?;
+	let entries = dir.readdir(offset)?;
+	let count = min(count, GETDENTS_MAX);
+	let mut buffer = Buffer::new(count);
+	if buffer.get().is_null() {
+		return Err(FsError::OutOfMemory);
+	}
+	let mut used = 0;
+	let mut next = offset;
+	for ent in entries.iter() {
+		let len = dirent64_len(ent);
+		if used + len > count {
+			break;
+		}
+		unsafe {
+			put_dirent64(buffer.get_mut().add(used), ent);
+		}
+		used += len;
+		next = ent.next;
+	}
+	if used == 0 && !entries.is_empty() {
+		// Not even one entry fits.
+		return Err(FsError::InvalidArgument);
+	}
+	unsafe {
+		if !copy_to_user(pid, addr, buffer.get(), used) {
+			return Err(FsError::BadAddress);
+		}
+	}
+	set_offset(pid, fd, next);
+	Ok(used)
+}
+
+/// Change the working directory of process pid. We keep the path we
+/// resolved to, so the working directory never has "." or ".." in it.
+fn chdir(pid: u16, path: &str, cwd: &str, cred: &Credentials) -> Result<(), FsError> {
+	let (inode, canonical) = walk(path, cwd, cred)?;
+	let st = inode.stat()?;
+	if st.mode & S_IFMT != S_IFDIR {
+		return Err(FsError::IsFile);
+	}
+	check(&st, cred, MAY_EXEC)?;
+	unsafe {
+		let process = get_by_pid(pid);
+		if !process.is_null() {
+			(*process).data.cwd = canonical;
+		}
+	}
+	Ok(())
+}
+
+/// Make a directory that belongs to cred.
+fn mkdir(path: &str, mode: u16, cwd: &str, cred: &Credentials) -> Result<(), FsError> {
+	let (dir, name) = walk_parent(path, cwd, cred)?;
+	dir.mkdir(name, mode)?;
+	lookup_in(&dir, name)?.chown(cred.uid, cred.gid)
+}
+
+fn op_proc(args_addr: usize) {
+	let args = unsafe { Box::from_raw(args_addr as *mut OpArgs) };
+	// Relative paths start at the caller's working directory, and we can do
+	// what the caller's effective IDs let it.
+	let cwd = cwd_of(args.pid);
+	let cred = cred_of(args.pid, true);
+	let res = match &args.op {
+		FsOp::Open { path, flags, mode } => open(args.pid, path, *flags, *mode, &cwd, &cred),
+		FsOp::Link(old, new) => link(old, new, &cwd, &cred).map(|_| 0),
+		FsOp::Unlink(path) => remove(path, &cwd, &cred, false).map(|_| 0),
+		FsOp::Mkdir(path, mode) => mkdir(path, *mode, &cwd, &cred).map(|_| 0),
+		FsOp::Rmdir(path) => remove(path, &cwd, &cred, true).map(|_| 0),
+		FsOp::Rename(old, new) => rename(old, new, &cwd, &cred).map(|_| 0),
+		FsOp::Chdir(path) => chdir(args.pid, path, &cwd, &cred).map(|_| 0),
+		FsOp::Access { dirfd, path, mode, flags } => access(args.pid, *dirfd, path, *mode, *flags).map(|_| 0),
+		FsOp::Chmod { dirfd, path, mode } => chmod(args.pid, *dirfd, path, *mode).map(|_| 0),
+		FsOp::Symlink { target, dirfd, path } => symlink(args.pid, target, *dirfd, path).map(|_| 0),
+		FsOp::Readlink { dirfd, path, buffer, size } => readlink(args.pid, *dirfd, path, *buffer, *size),
+		FsOp::Chown { dirfd, path, uid, gid, flags } => chown(args.pid, *dirfd, path, *uid, *gid, *flags).map(|_| 0),
+		FsOp::Getdents { fd, buffer, count } => getdents(args.pid, *fd, *buffer, *count),
+		FsOp::Read { fd, buffer, count, offset } => read(args.pid, *fd, *buffer, *count, *offset),
+		FsOp::Write { fd, buffer, count, offset } => write(args.pid, *fd, *buffer, *count, *offset),
+		FsOp::Lseek { fd, offset, whence } => lseek(args.pid, *fd, *offset, *whence),
+		FsOp::Fstat { fd, buffer } => fstat(args.pid, *fd, *buffer).map(|_| 0),
+		FsOp::Stat { dirfd, path, buffer, flags } => stat_at(args.pid, *dirfd, path, *buffer, *flags).map(|_| 0),
+		FsOp::Sync => {
+			journal::commit_all();
+			bcache::sync(None);
+			Ok(0)
+		},
+		FsOp::Fsync(inode) => inode.sync().map(|_| 0),
+		FsOp::Mount { source, target, fstype } => mount_op(source, target, fstype, &cwd, &cred).map(|_| 0),
+		FsOp::Umount(target) => umount_op(target, &cwd, &cred).map(|_| 0),
+	};
+	finish(args.pid, res);
+}
+
+/// mount(): source is a block device in /dev, and target is the directory
+/// to mount it on. Only root can mount.
+fn mount_op(source: &str, target: &str, fstype: &Option<String>, cwd: &str, cred: &Credentials) -> Result<(), FsError> {
+	if cred.uid != 0 {
+		return Err(FsError::NotPermitted);
+	}
+	let (device, _) = walk(source, cwd, cred)?;
+	let dev = devfs::block_dev(&device).ok_or(FsError::InvalidArgument)?;
+	let (dir, target) = walk(target, cwd, cred)?;
+	if !is_dir(&dir)? {
+		return Err(FsError::IsFile);
+	}
+	mount_device(&target, dev, fstype.as_deref())
+}
+
+/// umount(): target is where the file system is mounted.
+fn umount_op(target: &str, cwd: &str, cred: &Credentials) -> Result<(), FsError> {
+	if cred.uid != 0 {
+		return Err(FsError::NotPermitted);
+	}
+	let (_, target) = walk(target, cwd, cred)?;
+	umount(&target)
+}
+
+/// Put the result of an operation in the waiting process' A0 and wake it up.
+fn finish(pid: u16, res: Result<usize, FsError>) {
+	unsafe {
+		let ptr = get_by_pid(pid);
+		if !ptr.is_null() {
+			(*(*ptr).frame).regs[Registers::A0 as usize] = match res {
+				Ok(v) => v,
+				Err(e) => fs_errno(e),
+			};
+		}
+	}
+	set_running(pid);
+}
+
+/// Run op in a kernel process on behalf of process pid, which waits until
+/// the result is in its A0.
+pub fn process_op(pid: u16, op: FsOp) {
+	let boxed_args = Box::new(OpArgs { pid, op });
+	set_waiting(pid);
+	let _ = add_kernel_process_args(op_proc, Box::into_raw(boxed_args) as usize);
+}
@@ -43,7 +43,7 @@ void draw_circle(Pixel *fb, u32 x, u32 y, f64 r, Pixel &color);
 const u64 noevt_slptm = 10000;
 const u64 evt_slptm   = 10000;
 
-#define FB_DEV "/dev/fb"
+#define FB_DEV "/dev/fb0"
 #define BUT_DEV "/dev/butev"
 #define ABS_DEV "/dev/absev"
 
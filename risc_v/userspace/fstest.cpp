@@ -0,0 +1,91 @@
+#include <cerrno>
+#include <cstdio>
+#include <unistd.h>
+#include <fcntl.h>
+#include <sys/stat.h>
+
+// Exercises the file system calls that change the disk. Run it, then mount
+// hdd.dsk on the host and look for /fstest/out.txt and /fstest/moved.txt.
+// newlib doesn't have symlink and readlink, so we make those system calls
+// ourselves.
+static long sys(long num, long a = 0, long b = 0, long c = 0)
+{
+	register long a0 asm("a0") = a;
+	register long a1 asm("a1") = b;
+	register long a2 asm("a2") = c;
+	register long a7 asm("a7") = num;
+	asm volatile("ecall" : "+r"(a0) : "r"(a1), "r"(a2), "r"(a7) : "memory");
+	return a0;
+}
+
+static int check(const char *what, int r)
+{
+	printf("%-32s %s (%d)\n", what, r < 0 ? "FAILED" : "ok", r);
+	return r;
+}
+
+int main()
+{
+	check("mkdir /fstest", mkdir("/fstest", 0755));
+	check("mkdir /fstest/sub", mkdir("/fstest/sub", 0755));
+
+	int fd = check("open /fstest/out.txt", open("/fstest/out.txt", O_WRONLY | O_CREAT | O_TRUNC, 0644));
+	if (fd >= 0) {
+		char line[64];
+		for (int i = 0; i < 200; i++) {
+			int len = snprintf(line, sizeof(line), "line %d of the write test\n", i);
+			write(fd, line, len);
+		}
+		close(fd);
+	}
+
+	fd = check("reopen /fstest/out.txt", open("/fstest/out.txt", O_RDWR));
+	if (fd >= 0) {
+		char buf[32] = {0};
+		// Each read picks up where the last one stopped.
+		check("read first 10 bytes", read(fd, buf, 10));
+		check("read next 10 bytes", read(fd, buf + 10, 10));
+		printf("  got \"%.20s\"\n", buf);
+		check("lseek to the end", lseek(fd, 0, SEEK_END));
+		check("write at the end", write(fd, "the end\n", 8));
+		check("pread line 1", pread(fd, buf, 25, 25));
+		printf("  got \"%.24s\"\n", buf);
+		check("lseek back to the start", lseek(fd, 0, SEEK_SET));
+		close(fd);
+	}
+
+	check("link out.txt -> sub/copy.txt", link("/fstest/out.txt", "/fstest/sub/copy.txt"));
+	check("rename sub/copy.txt -> moved.txt", rename("/fstest/sub/copy.txt", "/fstest/moved.txt"));
+	check("rmdir /fstest/sub", rmdir("/fstest/sub"));
+
+	struct stat st;
+	if (check("stat /fstest/out.txt", stat("/fstest/out.txt", &st)) == 0) {
+		// out.txt and moved.txt are the same inode, so there are two links.
+		printf("  ino %lu, %lu links, %ld bytes, %ld blocks\n", (unsigned long)st.st_ino,
+		       (unsigned long)st.st_nlink, (long)st.st_size, (long)st.st_blocks);
+	}
+	check("isatty(1)", isatty(1) ? 0 : -1);
+
+	check("symlink moved.txt -> link", sys(1036, (long)"moved.txt", (long)"/fstest/link"));
+	char target[64] = {0};
+	check("readlink /fstest/link", sys(1035, (long)"/fstest/link", (long)target, sizeof(target) - 1));
+	printf("  points to \"%s\"\n", target);
+	struct stat lst;
+	if (check("stat through the link", stat("/fstest/link", &st)) == 0 &&
+	    check("lstat the link itself", lstat("/fstest/link", &lst)) == 0) {
+		printf("  stat ino %lu, lstat ino %lu\n", (unsigned long)st.st_ino, (unsigned long)lst.st_ino);
+	}
+	sys(1036, (long)"loop2", (long)"/fstest/loop1");
+	sys(1036, (long)"loop1", (long)"/fstest/loop2");
+	// This one should fail with ELOOP.
+	int r = open("/fstest/loop1", O_RDONLY);
+	printf("open a link loop: %d, errno %d (ELOOP is %d)\n", r, errno, ELOOP);
+
+	fd = check("open /fstest/tmp.txt", open("/fstest/tmp.txt", O_WRONLY | O_CREAT, 0644));
+	if (fd >= 0) {
+		write(fd, "temporary\n", 10);
+		close(fd);
+	}
+	check("unlink /fstest/tmp.txt", unlink("/fstest/tmp.txt"));
+	return 0;
+}
@@ -0,0 +1,60 @@
+#include <cstdio>
+#include <cstdint>
+#include <fcntl.h>
+#include <unistd.h>
+
+// newlib doesn't have getdents, so we make the system call ourselves.
+struct linux_dirent64 {
+	uint64_t d_ino;
+	int64_t d_off;
+	unsigned short d_reclen;
+	unsigned char d_type;
+	char d_name[];
+};
+
+static long getdents64(int fd, void *dirp, unsigned long count)
+{
+	register long a0 asm("a0") = fd;
+	register long a1 asm("a1") = (long)dirp;
+	register long a2 asm("a2") = count;
+	register long a7 asm("a7") = 61;
+	asm volatile("ecall" : "+r"(a0) : "r"(a1), "r"(a2), "r"(a7) : "memory");
+	return a0;
+}
+
+static char type_char(unsigned char type)
+{
+	switch (type) {
+		case 4: return 'd';
+		case 8: return '-';
+		case 10: return 'l';
+		case 2: return 'c';
+		case 6: return 'b';
+		default: return '?';
+	}
+}
+
+int main(int argc, char *argv[])
+{
+	const char *path = argc > 1 ? argv[1] : "/";
+	int fd = open(path, O_RDONLY | O_DIRECTORY);
+	if (fd < 0) {
+		printf("ls: cannot open %s (%d)\n", path, fd);
+		return 1;
+	}
+	// A small buffer, so that big directories take more than one call.
+	alignas(8) char buf[256];
+	long n;
+	while ((n = getdents64(fd, buf, sizeof(buf))) > 0) {
+		for (long pos = 0; pos < n;) {
+			struct linux_dirent64 *d = (struct linux_dirent64 *)(buf + pos);
+			printf("%c %6lu  %s\n", type_char(d->d_type), (unsigned long)d->d_ino, d->d_name);
+			pos += d->d_reclen;
+		}
+	}
+	if (n < 0) {
+		printf("ls: getdents failed (%ld)\n", n);
+	}
+	close(fd);
+	return 0;
+}
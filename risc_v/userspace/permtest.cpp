@@ -0,0 +1,47 @@
+#include <cstdio>
+#include <fcntl.h>
+#include <unistd.h>
+
+// newlib's chmod and setuid are stubs, so we make those system calls
+// ourselves.
+static long sys(long num, long a = 0, long b = 0, long c = 0)
+{
+	register long a0 asm("a0") = a;
+	register long a1 asm("a1") = b;
+	register long a2 asm("a2") = c;
+	register long a7 asm("a7") = num;
+	asm volatile("ecall" : "+r"(a0) : "r"(a1), "r"(a2), "r"(a7) : "memory");
+	return a0;
+}
+
+static void expect(const char *what, long r, bool should_work)
+{
+	bool worked = r >= 0;
+	printf("%-40s %s (%ld)\n", what, worked == should_work ? "ok" : "WRONG", r);
+}
+
+// Run this as root after fstest. It makes a file only its owner can read,
+// becomes someone else, and makes sure it's locked out.
+int main()
+{
+	const char *path = "/fstest/secret.txt";
+	int fd = open(path, O_WRONLY | O_CREAT | O_TRUNC, 0600);
+	expect("create secret.txt as root", fd, true);
+	if (fd >= 0) {
+		write(fd, "root only\n", 10);
+		close(fd);
+	}
+	expect("chmod 0640", sys(1028, (long)path, 0640), true);
+
+	expect("setgid(100)", sys(144, 100), true);
+	expect("setuid(1000)", sys(146, 1000), true);
+	printf("uid %ld euid %ld gid %ld egid %ld\n", sys(174), sys(175), sys(176), sys(177));
+
+	expect("open secret.txt for reading", open(path, O_RDONLY), false);
+	expect("access(R_OK)", sys(1033, (long)path, 4), false);
+	expect("access(F_OK)", sys(1033, (long)path, 0), true);
+	expect("unlink secret.txt", unlink(path), false);
+	expect("chmod 0777", sys(1028, (long)path, 0777), false);
+	expect("setuid(0)", sys(146, 0), false);
+	return 0;
+}
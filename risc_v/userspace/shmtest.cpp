@@ -0,0 +1,107 @@
+#include <cstdio>
+
+// newlib has no shmget and friends, so we make those system calls
+// ourselves.
+static long sys(long num, long a = 0, long b = 0, long c = 0)
+{
+	register long a0 asm("a0") = a;
+	register long a1 asm("a1") = b;
+	register long a2 asm("a2") = c;
+	register long a7 asm("a7") = num;
+	asm volatile("ecall" : "+r"(a0) : "r"(a1), "r"(a2), "r"(a7) : "memory");
+	return a0;
+}
+
+#define SYS_execv  11
+#define SYS_shmget 194
+#define SYS_shmctl 195
+#define SYS_shmat  196
+#define SYS_shmdt  197
+
+#define IPC_PRIVATE 0
+#define IPC_CREAT   01000
+#define IPC_EXCL    02000
+#define IPC_RMID    0
+#define ENOENT      2
+
+static const long KEY = 0x5348;
+static const long SIZE = 8192;
+
+static void expect(const char *what, bool worked)
+{
+	printf("%-40s %s\n", what, worked ? "ok" : "WRONG");
+}
+
+// Put /shmtest on the disk and run it. The first process creates the
+// segment, fills it and execs itself. That makes a second process, which
+// finds the segment by its key, checks what the first one wrote, and then
+// removes it.
+int main()
+{
+	long id = sys(SYS_shmget, KEY, SIZE, IPC_CREAT | IPC_EXCL);
+	if (id >= 0) {
+		long addr = sys(SYS_shmat, id, 0, 0);
+		expect("writer: shmat", addr >= 0);
+		if (addr < 0) {
+			return 1;
+		}
+		unsigned char *p = (unsigned char *)addr;
+		for (long i = 0; i < SIZE; i++) {
+			p[i] = (unsigned char)(i * 7);
+		}
+		expect("writer: shmdt", sys(SYS_shmdt, addr) == 0);
+		// The key keeps the segment around after we're gone.
+		sys(SYS_execv, (long)"/shmtest", 0);
+		printf("writer: execv failed\n");
+		return 1;
+	}
+
+	id = sys(SYS_shmget, KEY, SIZE, 0);
+	expect("reader: shmget finds the segment", id >= 0);
+	if (id < 0) {
+		return 1;
+	}
+	long addr = sys(SYS_shmat, id, 0, 0);
+	expect("reader: shmat", addr >= 0);
+	if (addr < 0) {
+		return 1;
+	}
+	unsigned char *p = (unsigned char *)addr;
+	bool same = true;
+	for (long i = 0; i < SIZE; i++) {
+		if (p[i] != (unsigned char)(i * 7)) {
+			same = false;
+			break;
+		}
+	}
+	expect("reader: sees the writer's data", same);
+
+	expect("IPC_RMID", sys(SYS_shmctl, id, IPC_RMID) == 0);
+	// Our mapping still works until we detach, but nobody can find the
+	// segment anymore.
+	expect("still mapped after IPC_RMID", p[1] == 7);
+	expect("shmget after IPC_RMID", sys(SYS_shmget, KEY, SIZE, 0) == -ENOENT);
+	expect("shmat after IPC_RMID", sys(SYS_shmat, id, 0, 0) == -ENOENT);
+	expect("shmdt", sys(SYS_shmdt, addr) == 0);
+	// That was the last reference, so the segment is gone and the key
+	// can make a new one.
+	expect("IPC_RMID again", sys(SYS_shmctl, id, IPC_RMID) == -ENOENT);
+	long again = sys(SYS_shmget, KEY, SIZE, IPC_CREAT | IPC_EXCL);
+	expect("key is free again", again >= 0);
+	if (again >= 0) {
+		sys(SYS_shmctl, again, IPC_RMID);
+	}
+
+	// An unmapped segment is freed right away by IPC_RMID. If it weren't,
+	// 64 segments of 4 MiB would run a 128 MiB machine out of memory.
+	bool freed = true;
+	for (int i = 0; i < 64; i++) {
+		long big = sys(SYS_shmget, IPC_PRIVATE, 4 << 20, IPC_CREAT);
+		if (big < 0 || sys(SYS_shmctl, big, IPC_RMID) != 0) {
+			freed = false;
+			break;
+		}
+	}
+	expect("IPC_RMID frees the memory", freed);
+	return 0;
+}